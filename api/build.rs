@@ -34,6 +34,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("cargo:rerun-if-changed=migrations");
     println!("cargo:rerun-if-changed=.env");
+    git_hash();
 
     let db_file = match var("DATABASE_URL") {
         Ok(url) => url,
@@ -64,6 +65,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+// Expose the commit being built as LOKR_GIT_HASH for the admin config endpoint.
+// A hash given in the environment wins, for builds that don't have the .git directory.
+fn git_hash() {
+    println!("cargo:rerun-if-env-changed=LOKR_GIT_HASH");
+    if env::var("LOKR_GIT_HASH").is_ok() {
+        return;
+    }
+    // Rebuild when a commit is made or another one is checked out
+    let git_dir = PathBuf::from("../.git");
+    println!("cargo:rerun-if-changed={}", git_dir.join("HEAD").display());
+    if let Some(head) = read_to_string(git_dir.join("HEAD"))
+        .ok()
+        .and_then(|head| head.strip_prefix("ref: ").map(|r| r.trim().to_string()))
+    {
+        println!("cargo:rerun-if-changed={}", git_dir.join(head).display());
+    }
+    let hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=LOKR_GIT_HASH={}", hash.trim());
+    }
+}
+
 fn default_db_url() -> Result<String> {
     let data_dir = dirs::data_dir()
         .ok_or(anyhow!("Could not find data directory!"))?
@@ -0,0 +1,20 @@
+-- Background jobs that are run outside of the request that created them
+CREATE TABLE job (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    payload TEXT NOT NULL, -- JSON encoded job data
+    status INTEGER NOT NULL DEFAULT 0, -- 0 = pending, 1 = failed
+    attempts INTEGER NOT NULL DEFAULT 0, -- Number of times the job has been attempted
+    last_error TEXT, -- Error message from the most recent failed attempt
+    run_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP, -- Earliest time the job should be run at
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    modified_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_job_status_run_at ON job(status, run_at);
+
+CREATE TRIGGER job_update_modified_at AFTER UPDATE ON job
+BEGIN
+    UPDATE job
+    SET modified_at = CURRENT_TIMESTAMP
+    WHERE id = NEW.id;
+END;
@@ -0,0 +1,5 @@
+-- Links that can only be used to add files, used for anonymous drop folders
+ALTER TABLE share_link ADD COLUMN add_only BOOLEAN NOT NULL DEFAULT FALSE;
+-- The maximum total size in bytes of the files under the shared file
+-- that can be reached by uploading through the link, NULL means no limit
+ALTER TABLE share_link ADD COLUMN max_size INTEGER;
@@ -0,0 +1,3 @@
+-- Incremented every time the user uploads a new avatar so
+-- clients can cache avatars until the version changes
+ALTER TABLE user ADD COLUMN avatar_version INTEGER NOT NULL DEFAULT 0;
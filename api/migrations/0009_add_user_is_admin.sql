@@ -0,0 +1,4 @@
+-- Admins can see statistics about the whole instance.
+-- There is no way to become an admin through the API,
+-- this has to be set in the database directly.
+ALTER TABLE user ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT FALSE;
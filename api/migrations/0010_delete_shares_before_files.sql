@@ -0,0 +1,9 @@
+-- Shares of a deleted file used to be removed by the foreign key cascade
+-- after the file was already gone, so their triggers couldn't find the
+-- owner of the file to give the used space back to.
+-- Remove them before the file instead so the triggers still see it.
+CREATE TRIGGER delete_file_shares BEFORE DELETE ON file
+BEGIN
+    DELETE FROM share_user WHERE file_id = OLD.id;
+    DELETE FROM share_link WHERE file_id = OLD.id;
+END;
@@ -0,0 +1,14 @@
+-- Users can opt into a public profile that lists some of their share links
+ALTER TABLE user ADD COLUMN public_profile BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE public_file (
+    link_id BLOB PRIMARY KEY NOT NULL, -- The share link being published
+    user_id BLOB NOT NULL,
+    title TEXT NOT NULL, -- Shown instead of the encrypted file name
+    link_key TEXT NOT NULL, -- The key from the fragment of the link, visitors need it to decrypt the file
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (link_id) REFERENCES share_link(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_public_file_user_id ON public_file(user_id);
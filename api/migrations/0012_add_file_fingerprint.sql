@@ -0,0 +1,6 @@
+-- A keyed hash of the plaintext of a file computed by the client, which sync
+-- clients use to find files they already uploaded. It is keyed with a key only
+-- the client knows, so the server can't learn anything about the contents from it.
+ALTER TABLE file ADD COLUMN fingerprint TEXT;
+
+CREATE INDEX idx_files_owner_id_fingerprint ON file(owner_id, fingerprint) WHERE fingerprint IS NOT NULL;
@@ -0,0 +1,25 @@
+-- Owners can choose to tell users they have shared files with why they can't
+-- access one of their files, instead of pretending the file doesn't exist
+ALTER TABLE user ADD COLUMN explain_denials BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Every user an owner has shared a file with. Rows are kept when the shares are
+-- revoked, so users that lost access still count as known to the owner.
+CREATE TABLE share_contact (
+    owner_id BLOB NOT NULL,
+    user_id BLOB NOT NULL,
+    PRIMARY KEY (owner_id, user_id),
+    FOREIGN KEY (owner_id) REFERENCES user(id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE
+);
+
+CREATE TRIGGER add_share_contact AFTER INSERT ON share_user
+BEGIN
+    INSERT OR IGNORE INTO share_contact (owner_id, user_id)
+    SELECT owner_id, NEW.user_id FROM file WHERE id = NEW.file_id AND owner_id IS NOT NULL;
+END;
+
+INSERT OR IGNORE INTO share_contact (owner_id, user_id)
+SELECT f.owner_id, su.user_id
+FROM share_user su
+JOIN file f ON f.id = su.file_id
+WHERE f.owner_id IS NOT NULL;
@@ -0,0 +1,6 @@
+-- When the user last proved who they are in this session, either by logging in
+-- or by entering their password or a TOTP code again. Sensitive operations
+-- require this to be recent so a hijacked session can't do lasting damage.
+ALTER TABLE session ADD COLUMN verified_at TIMESTAMP;
+
+UPDATE session SET verified_at = created_at;
@@ -0,0 +1,7 @@
+-- Optional features that admins can turn on or off at runtime.
+-- Features without a row use their default, which is enabled.
+CREATE TABLE feature_flag (
+    name TEXT PRIMARY KEY NOT NULL,
+    enabled BOOLEAN NOT NULL,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
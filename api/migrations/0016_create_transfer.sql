@@ -0,0 +1,14 @@
+-- Bytes transferred to and from each user's files per month, so heavy use of
+-- share links can be capped even when the files themselves fit in the quota
+CREATE TABLE transfer (
+    user_id BLOB NOT NULL,
+    month TEXT NOT NULL, -- YYYY-MM in UTC
+    uploaded INTEGER NOT NULL DEFAULT 0,
+    downloaded INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY (user_id, month),
+    FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE
+);
+
+-- How many bytes can be downloaded from the user's files each month.
+-- NULL uses the instance default and 0 means there is no cap.
+ALTER TABLE user ADD COLUMN transfer_cap INTEGER CHECK(transfer_cap >= 0);
@@ -0,0 +1,6 @@
+-- Bumped whenever the user's keys are rotated, so clients on other devices
+-- can tell that the keys they cached are stale
+ALTER TABLE user ADD COLUMN key_epoch INTEGER NOT NULL DEFAULT 0;
+
+-- The key epoch of the owner when the file's key was last wrapped
+ALTER TABLE file ADD COLUMN key_epoch INTEGER NOT NULL DEFAULT 0;
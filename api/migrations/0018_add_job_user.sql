@@ -0,0 +1,9 @@
+-- The user a job is run for, so they can follow and manage it.
+-- NULL for jobs the server runs for itself, which are deleted once they complete.
+ALTER TABLE job ADD COLUMN user_id BLOB REFERENCES user(id) ON DELETE CASCADE;
+
+-- When the job completed, failed for good or was cancelled.
+-- The status can now also be 2 = completed or 3 = cancelled.
+ALTER TABLE job ADD COLUMN finished_at TIMESTAMP;
+
+CREATE INDEX idx_job_user_id ON job(user_id);
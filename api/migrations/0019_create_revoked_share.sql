@@ -0,0 +1,32 @@
+-- Shares their owner revoked, kept for a while so they can be restored without
+-- the owner wrapping the file key for the user again.
+-- Rows are moved here from share_user and share_link when they are revoked,
+-- and moved back when they are restored.
+CREATE TABLE revoked_share_user (
+    file_id BLOB NOT NULL,
+    user_id BLOB NOT NULL,
+    encrypted_key BLOB NOT NULL,
+    edit_permission BOOLEAN NOT NULL,
+    created_at TIMESTAMP,
+    revoked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    restorable_until TIMESTAMP NOT NULL,
+    PRIMARY KEY (file_id, user_id),
+    FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE,
+    FOREIGN KEY (file_id) REFERENCES file(id) ON DELETE CASCADE
+);
+
+CREATE TABLE revoked_share_link (
+    id BLOB PRIMARY KEY NOT NULL,
+    file_id BLOB NOT NULL,
+    created_at TIMESTAMP,
+    expires_at TIMESTAMP,
+    password_hash TEXT,
+    edit_permission BOOLEAN NOT NULL,
+    add_only BOOLEAN NOT NULL,
+    max_size INTEGER,
+    revoked_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    restorable_until TIMESTAMP NOT NULL,
+    FOREIGN KEY (file_id) REFERENCES file(id) ON DELETE CASCADE
+);
+
+CREATE INDEX idx_revoked_share_link_file_id ON revoked_share_link(file_id);
@@ -0,0 +1,5 @@
+-- Who a share link works for, as a JSON encoded LinkAudience.
+-- NULL means anyone with the link.
+ALTER TABLE share_link ADD COLUMN audience TEXT;
+
+ALTER TABLE revoked_share_link ADD COLUMN audience TEXT;
@@ -0,0 +1,3 @@
+-- The format the encrypted metadata of the file was written in, so clients know
+-- which nonce sizes and key wrapping to expect. Everything before versioning is version 1.
+ALTER TABLE file ADD COLUMN metadata_version INTEGER NOT NULL DEFAULT 1;
@@ -0,0 +1,5 @@
+-- How the key of the file is wrapped: 0 = AES-GCM, 1 = RSA-OAEP, 2 = XChaCha20-Poly1305.
+-- Keys were wrapped with AES-GCM if they have a nonce and with RSA-OAEP otherwise.
+ALTER TABLE file ADD COLUMN key_algorithm INTEGER NOT NULL DEFAULT 0;
+
+UPDATE file SET key_algorithm = 1 WHERE key_nonce IS NULL;
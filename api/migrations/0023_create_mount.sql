@@ -0,0 +1,12 @@
+-- Directories shared with a user that they mounted into their own root, so they
+-- show up next to the user's own files. Mounts are kept while the share is
+-- revoked so they come back if it is restored, and are cleaned up once it can't be.
+CREATE TABLE mount (
+    id BLOB PRIMARY KEY NOT NULL,
+    user_id BLOB NOT NULL,
+    file_id BLOB NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (user_id, file_id),
+    FOREIGN KEY (user_id) REFERENCES user(id) ON DELETE CASCADE,
+    FOREIGN KEY (file_id) REFERENCES file(id) ON DELETE CASCADE
+);
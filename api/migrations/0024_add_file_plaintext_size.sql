@@ -0,0 +1,6 @@
+-- The size of the file before it was encrypted, as declared by the client.
+-- `size` stays the size of the stored data, which is what counts towards quotas.
+-- Every file so far was encrypted in one piece with AES-GCM, which adds a 16 byte tag.
+ALTER TABLE file ADD COLUMN plaintext_size INTEGER NOT NULL DEFAULT 0;
+
+UPDATE file SET plaintext_size = MAX(size - 16, 0);
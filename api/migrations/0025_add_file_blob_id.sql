@@ -0,0 +1,7 @@
+-- Aliases are files that share the stored data of another file instead of
+-- having their own. NULL means the data is stored under the file's own id.
+-- Only one of the files sharing the data has its size counted towards the
+-- owner's space, the others have a size of 0.
+ALTER TABLE file ADD COLUMN blob_id BLOB;
+
+CREATE INDEX file_blob_id ON file(blob_id);
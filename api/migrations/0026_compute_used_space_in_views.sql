@@ -0,0 +1,151 @@
+-- The space each row takes up, kept in one place so the triggers that keep
+-- `used_space` up to date and `reconcile_used_space` can't disagree.
+-- These are the same formulas the triggers used since 0003 and 0005. Only the
+-- columns below are counted, the ones added to the tables since (fingerprint,
+-- key_epoch, metadata_version, key_algorithm, plaintext_size, blob_id,
+-- add_only, max_size, audience) aren't.
+CREATE VIEW file_space AS
+SELECT id, owner_id,
+    size +
+    -- NULL values consume a single byte
+    COALESCE(LENGTH(encrypted_key), 1) +
+    COALESCE(LENGTH(file_nonce), 1) +
+    COALESCE(LENGTH(key_nonce), 1) +
+    COALESCE(LENGTH(name_nonce), 1) +
+    COALESCE(LENGTH(mime_type_nonce), 1) +
+    COALESCE(LENGTH(encrypted_name), 1) +
+    COALESCE(LENGTH(mime), 1) +
+    IIF(parent_id IS NULL, 1, 16) +
+    IIF(uploader_id IS NULL, 1, 16) +
+    64 -- Size of constant fields
+    AS space
+FROM file;
+
+-- Shares count towards the owner of the file
+CREATE VIEW share_user_space AS
+SELECT file_id, user_id,
+    COALESCE(LENGTH(encrypted_key), 1) +
+    1 + -- Boolean for edit_permission
+    32 + -- Size of the primary key (file_id, user_id)
+    16 -- Size of timestamps
+    AS space
+FROM share_user;
+
+CREATE VIEW share_link_space AS
+SELECT id, file_id,
+    16 + -- Size of id (UUIDv7)
+    16 + -- Size of file_id
+    COALESCE(LENGTH(password_hash), 1) +
+    1 + -- Boolean for edit_permission
+    16 + -- Size of timestamps
+    IIF(expires_at IS NULL, 1, 8) -- Timestamp or NULL marker
+    AS space
+FROM share_link;
+
+-- Rows are only visible through the views while they exist, so the old
+-- space is taken away before a row changes and the new space added after
+DROP TRIGGER update_user_used_space_insert;
+DROP TRIGGER update_user_used_space_update;
+DROP TRIGGER update_user_used_space_delete;
+DROP TRIGGER update_share_user_used_space_insert;
+DROP TRIGGER update_share_user_used_space_update;
+DROP TRIGGER update_share_user_used_space_delete;
+DROP TRIGGER update_share_link_used_space_insert;
+DROP TRIGGER update_share_link_used_space_update;
+DROP TRIGGER update_share_link_used_space_delete;
+
+CREATE TRIGGER update_user_used_space_insert AFTER INSERT ON file
+BEGIN
+    UPDATE user
+    SET used_space = used_space + (SELECT space FROM file_space WHERE id = NEW.id)
+    WHERE id = NEW.owner_id;
+END;
+
+CREATE TRIGGER update_user_used_space_update_old BEFORE UPDATE ON file
+BEGIN
+    UPDATE user
+    SET used_space = used_space - (SELECT space FROM file_space WHERE id = OLD.id)
+    WHERE id = OLD.owner_id;
+END;
+
+CREATE TRIGGER update_user_used_space_update AFTER UPDATE ON file
+BEGIN
+    UPDATE user
+    SET used_space = used_space + (SELECT space FROM file_space WHERE id = NEW.id)
+    WHERE id = NEW.owner_id;
+END;
+
+CREATE TRIGGER update_user_used_space_delete BEFORE DELETE ON file
+BEGIN
+    UPDATE user
+    SET used_space = used_space - (SELECT space FROM file_space WHERE id = OLD.id)
+    WHERE id = OLD.owner_id;
+END;
+
+CREATE TRIGGER update_share_user_used_space_insert AFTER INSERT ON share_user
+BEGIN
+    UPDATE user
+    SET used_space = used_space + (
+        SELECT space FROM share_user_space
+        WHERE file_id = NEW.file_id AND user_id = NEW.user_id
+    )
+    WHERE id = (SELECT owner_id FROM file WHERE id = NEW.file_id);
+END;
+
+CREATE TRIGGER update_share_user_used_space_update_old BEFORE UPDATE ON share_user
+BEGIN
+    UPDATE user
+    SET used_space = used_space - (
+        SELECT space FROM share_user_space
+        WHERE file_id = OLD.file_id AND user_id = OLD.user_id
+    )
+    WHERE id = (SELECT owner_id FROM file WHERE id = OLD.file_id);
+END;
+
+CREATE TRIGGER update_share_user_used_space_update AFTER UPDATE ON share_user
+BEGIN
+    UPDATE user
+    SET used_space = used_space + (
+        SELECT space FROM share_user_space
+        WHERE file_id = NEW.file_id AND user_id = NEW.user_id
+    )
+    WHERE id = (SELECT owner_id FROM file WHERE id = NEW.file_id);
+END;
+
+CREATE TRIGGER update_share_user_used_space_delete BEFORE DELETE ON share_user
+BEGIN
+    UPDATE user
+    SET used_space = used_space - (
+        SELECT space FROM share_user_space
+        WHERE file_id = OLD.file_id AND user_id = OLD.user_id
+    )
+    WHERE id = (SELECT owner_id FROM file WHERE id = OLD.file_id);
+END;
+
+CREATE TRIGGER update_share_link_used_space_insert AFTER INSERT ON share_link
+BEGIN
+    UPDATE user
+    SET used_space = used_space + (SELECT space FROM share_link_space WHERE id = NEW.id)
+    WHERE id = (SELECT owner_id FROM file WHERE id = NEW.file_id);
+END;
+
+CREATE TRIGGER update_share_link_used_space_update_old BEFORE UPDATE ON share_link
+BEGIN
+    UPDATE user
+    SET used_space = used_space - (SELECT space FROM share_link_space WHERE id = OLD.id)
+    WHERE id = (SELECT owner_id FROM file WHERE id = OLD.file_id);
+END;
+
+CREATE TRIGGER update_share_link_used_space_update AFTER UPDATE ON share_link
+BEGIN
+    UPDATE user
+    SET used_space = used_space + (SELECT space FROM share_link_space WHERE id = NEW.id)
+    WHERE id = (SELECT owner_id FROM file WHERE id = NEW.file_id);
+END;
+
+CREATE TRIGGER update_share_link_used_space_delete BEFORE DELETE ON share_link
+BEGIN
+    UPDATE user
+    SET used_space = used_space - (SELECT space FROM share_link_space WHERE id = OLD.id)
+    WHERE id = (SELECT owner_id FROM file WHERE id = OLD.file_id);
+END;
@@ -0,0 +1,174 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use tracing::{info, instrument};
+
+pub use lokr_types::admin::{
+    AdminConfig, AdminStats, BuildInfo, CleanupStats, EffectiveConfig, RetryStats,
+};
+pub use lokr_types::jobs::JobQuery;
+
+use crate::{
+    auth::AdminAuth,
+    build_info,
+    error::{AppError, ErrorResponse},
+    instance::{changed_features, load_features, Features, FeaturesUpdate},
+    jobs::{JobInfo, JobStatus},
+    state::AppState,
+    users,
+};
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/stats",
+    description = "Get statistics about the whole instance, including what the periodic cleanup has removed since the server started.",
+    responses(
+        (status = OK, description = "Statistics found", body = AdminStats),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The user is not an admin", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_stats(
+    State(state): State<AppState>,
+    AdminAuth(_user): AdminAuth,
+) -> Result<Response, AppError> {
+    let row = sqlx::query!(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM user) AS "users!: i64",
+            (SELECT COUNT(*) FROM file) AS "files!: i64",
+            (SELECT COALESCE(SUM(used_space), 0) FROM user) AS "used_space!: i64"
+        "#
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    let legacy_password_hashes =
+        users::count_legacy_hashes(&state.pool, state.argon2.params()).await?;
+    let cleanup = state.cleanup.lock().unwrap();
+    let stats = AdminStats {
+        users: row.users,
+        files: row.files,
+        used_space: row.used_space,
+        legacy_password_hashes,
+        cleanup_runs: cleanup.runs,
+        last_cleanup_at: cleanup.last_run_at,
+        cleanup: cleanup.removed.clone(),
+        transaction_retries: state.retries.stats(),
+    };
+    Ok((StatusCode::OK, Json(stats)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/config",
+    description = "Get the configuration the server is running with and what it was built from, so operators can check what a running instance is actually using. Secrets are only shown as being set or not.",
+    responses(
+        (status = OK, description = "Configuration found", body = AdminConfig),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The user is not an admin", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(skip(state))]
+pub async fn get_config(State(state): State<AppState>, AdminAuth(_user): AdminAuth) -> Response {
+    let config = AdminConfig {
+        build: build_info(),
+        config: state.config.effective(),
+        features: state.features(),
+    };
+    (StatusCode::OK, Json(config)).into_response()
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/admin/features",
+    description = "Turn optional features of the instance on or off. Takes effect immediately, features that aren't included are left as they are.",
+    request_body(content = FeaturesUpdate, description = "The features to change"),
+    responses(
+        (status = OK, description = "Features updated, returns all of the features", body = Features),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The user is not an admin", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn update_features(
+    State(state): State<AppState>,
+    AdminAuth(user): AdminAuth,
+    Json(update): Json<FeaturesUpdate>,
+) -> Result<Response, AppError> {
+    let mut tx = state.pool.begin().await?;
+    for (name, enabled) in changed_features(&update) {
+        sqlx::query!(
+            r#"
+            INSERT INTO feature_flag (name, enabled) VALUES (?, ?)
+            ON CONFLICT(name) DO UPDATE SET enabled = excluded.enabled, updated_at = CURRENT_TIMESTAMP
+            "#,
+            name,
+            enabled
+        )
+        .execute(&mut *tx)
+        .await?;
+        info!("User {} set feature {} to {}", user.id, name, enabled);
+    }
+    // Read the features back in the same transaction so the cache can't
+    // end up with a mix of two updates that happened at the same time
+    let features = load_features(&mut *tx).await?;
+    tx.commit().await?;
+    *state.features.write().unwrap() = features;
+    Ok((StatusCode::OK, Json(features)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/admin/jobs",
+    description = "Get the background jobs of every user as well as the ones the server runs for itself, newest first. Failed jobs include the error from their last attempt.",
+    params(JobQuery),
+    responses(
+        (status = OK, description = "Jobs found", body = [JobInfo]),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The user is not an admin", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_jobs(
+    State(state): State<AppState>,
+    AdminAuth(_user): AdminAuth,
+    Query(query): Query<JobQuery>,
+) -> Result<Response, AppError> {
+    let status = query.status.map(|status| status as i64);
+    let jobs = sqlx::query_as!(
+        JobInfo,
+        r#"
+        SELECT id AS "id!", JSON_EXTRACT(payload, '$.type') AS "kind!: String",
+        status AS "status: JobStatus", attempts, last_error,
+        run_at AS "run_at: _", created_at AS "created_at: _",
+        modified_at AS "modified_at: _", finished_at AS "finished_at: _",
+        user_id AS "user_id: _"
+        FROM job
+        WHERE (? IS NULL OR status = ?) AND (? IS NULL OR user_id = ?)
+        ORDER BY id DESC
+        "#,
+        status,
+        status,
+        query.user_id,
+        query.user_id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    Ok((StatusCode::OK, Json(jobs)).into_response())
+}
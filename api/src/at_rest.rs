@@ -0,0 +1,155 @@
+use std::{
+    fmt::{self, Debug, Formatter},
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use aes_gcm::{
+    aead::{
+        rand_core::RngCore,
+        stream::{DecryptorBE32, EncryptorBE32},
+        Aead, OsRng,
+    },
+    AeadCore, Aes256Gcm, Key, KeyInit, Nonce,
+};
+use anyhow::{anyhow, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
+
+/// Marks data encrypted by [`seal`] so files written before a key was configured
+/// can still be read as they are
+const MAGIC: &[u8] = b"LOKRSEAL";
+const NONCE_LENGTH: usize = 12;
+/// Marks data encrypted in chunks by [`seal_file`], for files that shouldn't
+/// have to fit in memory
+const STREAM_MAGIC: &[u8] = b"LOKRSTRM";
+/// The counter and last chunk flag take up the rest of the nonce
+const STREAM_NONCE_LENGTH: usize = NONCE_LENGTH - 5;
+const CHUNK_LENGTH: usize = 64 * 1024;
+const TAG_LENGTH: usize = 16;
+
+/// Key used to encrypt the files the server writes itself, like avatars and
+/// database backups. User files are already encrypted by their clients.
+#[derive(Clone)]
+pub struct AtRestKey(Key<Aes256Gcm>);
+
+// Keep the key out of logs, `Config` is logged in a few places
+impl Debug for AtRestKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("AtRestKey(..)")
+    }
+}
+
+impl FromStr for AtRestKey {
+    type Err = anyhow::Error;
+
+    /// Parse a base64 encoded 256 bit key
+    fn from_str(s: &str) -> Result<Self> {
+        let key = BASE64_STANDARD.decode(s.trim())?;
+        if key.len() != 32 {
+            return Err(anyhow!("The key must be 32 bytes long"));
+        }
+        Ok(Self(*Key::<Aes256Gcm>::from_slice(&key)))
+    }
+}
+
+/// Encrypt data before writing it to disk, or return it unchanged if there is no key
+pub fn seal(key: Option<&AtRestKey>, data: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(key) = key else {
+        return Ok(data);
+    };
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(&key.0)
+        .encrypt(&nonce, data.as_slice())
+        .map_err(|_| anyhow!("Unable to encrypt data"))?;
+    Ok([MAGIC, nonce.as_slice(), &ciphertext].concat())
+}
+
+/// Decrypt data read from disk. Data that was never sealed is returned unchanged,
+/// so turning encryption on doesn't break anything written before.
+pub fn open(key: Option<&AtRestKey>, data: Vec<u8>) -> Result<Vec<u8>> {
+    let Some(sealed) = data.strip_prefix(MAGIC) else {
+        return Ok(data);
+    };
+    let key = key.ok_or_else(|| anyhow!("Data is encrypted, but no key is configured"))?;
+    if sealed.len() < NONCE_LENGTH {
+        return Err(anyhow!("Encrypted data is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    Aes256Gcm::new(&key.0)
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Unable to decrypt data, the key may be wrong"))
+}
+
+/// Encrypt a file in chunks into a new file, which is created with `options`
+/// so the caller decides how it may be accessed
+pub fn seal_file(
+    key: &AtRestKey,
+    source: &Path,
+    destination: &Path,
+    options: &std::fs::OpenOptions,
+) -> Result<()> {
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut writer = BufWriter::new(options.open(destination)?);
+    let mut nonce = [0; STREAM_NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    writer.write_all(STREAM_MAGIC)?;
+    writer.write_all(&nonce)?;
+    let mut encryptor = EncryptorBE32::from_aead(Aes256Gcm::new(&key.0), (&nonce).into());
+    let error = |_| anyhow!("Unable to encrypt data");
+    // The last chunk is encrypted differently, so read one chunk ahead to find it
+    let mut chunk = read_chunk(&mut reader, CHUNK_LENGTH)?;
+    loop {
+        let next = read_chunk(&mut reader, CHUNK_LENGTH)?;
+        if next.is_empty() {
+            writer.write_all(&encryptor.encrypt_last(chunk.as_slice()).map_err(error)?)?;
+            break;
+        }
+        writer.write_all(&encryptor.encrypt_next(chunk.as_slice()).map_err(error)?)?;
+        chunk = next;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decrypt a file written by [`seal_file`] into `output` one chunk at a time.
+/// Files sealed all at once or not at all are read into memory and passed to [`open`].
+pub fn open_file(key: Option<&AtRestKey>, source: &Path, output: &mut impl Write) -> Result<()> {
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut magic = read_chunk(&mut reader, STREAM_MAGIC.len())?;
+    if magic != STREAM_MAGIC {
+        reader.read_to_end(&mut magic)?;
+        output.write_all(&open(key, magic)?)?;
+        return Ok(());
+    }
+    let key = key.ok_or_else(|| anyhow!("Data is encrypted, but no key is configured"))?;
+    let nonce = read_chunk(&mut reader, STREAM_NONCE_LENGTH)?;
+    if nonce.len() < STREAM_NONCE_LENGTH {
+        return Err(anyhow!("Encrypted data is truncated"));
+    }
+    let mut decryptor = DecryptorBE32::from_aead(Aes256Gcm::new(&key.0), nonce.as_slice().into());
+    let error = |_| anyhow!("Unable to decrypt data, the key may be wrong");
+    let mut chunk = read_chunk(&mut reader, CHUNK_LENGTH + TAG_LENGTH)?;
+    loop {
+        let next = read_chunk(&mut reader, CHUNK_LENGTH + TAG_LENGTH)?;
+        if next.is_empty() {
+            output.write_all(&decryptor.decrypt_last(chunk.as_slice()).map_err(error)?)?;
+            break;
+        }
+        output.write_all(&decryptor.decrypt_next(chunk.as_slice()).map_err(error)?)?;
+        chunk = next;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+/// Read up to `length` bytes, only returning less at the end of the file
+fn read_chunk(reader: &mut impl Read, length: usize) -> Result<Vec<u8>> {
+    let mut chunk = Vec::with_capacity(length);
+    reader
+        .by_ref()
+        .take(length as u64)
+        .read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
@@ -1,12 +1,20 @@
 use anyhow::anyhow;
 use axum::{
-    extract::{FromRequestParts, OptionalFromRequestParts, State},
-    http::{header::COOKIE, request::Parts},
+    extract::{FromRequestParts, OptionalFromRequestParts, Request, State},
+    http::{
+        header::{COOKIE, HOST, ORIGIN, REFERER},
+        request::Parts,
+        StatusCode,
+    },
+    middleware::Next,
+    response::Response,
 };
-use tracing::{instrument, Level};
+use chrono::{DateTime, Utc};
+use tracing::{instrument, warn, Level};
+use url::Url;
 use uuid::Uuid;
 
-use crate::{error::AppError, state::AppState};
+use crate::{error::AppError, state::AppState, DEV_ORIGIN, HOST as LOKR_HOST};
 
 #[derive(Debug)]
 pub struct User {
@@ -14,6 +22,9 @@ pub struct User {
     pub username: String,
     pub email: Option<String>,
     pub session_number: i64,
+    pub is_admin: bool,
+    /// When the password or a TOTP code was last entered in this session
+    pub verified_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug)]
@@ -76,7 +87,8 @@ where
         let user = sqlx::query_as!(
             User,
             r#"
-            SELECT user.id AS "id: _", username, email, session.number AS "session_number: _"
+            SELECT user.id AS "id: _", username, email, session.number AS "session_number: _", is_admin,
+            session.verified_at AS "verified_at: _"
             FROM user
             JOIN session ON user.id = session.user_id
             WHERE session.id = ?
@@ -101,3 +113,104 @@ where
         Ok(Some(SessionAuth(user)))
     }
 }
+
+/// Extract the user from the request's session cookie,
+/// rejecting the request if the user isn't an admin.
+#[derive(Debug)]
+pub struct AdminAuth(pub User);
+
+impl<S> FromRequestParts<S> for AdminAuth
+where
+    S: Send + Sync,
+    State<AppState>: FromRequestParts<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let SessionAuth(user) =
+            <SessionAuth as FromRequestParts<S>>::from_request_parts(parts, state).await?;
+        if !user.is_admin {
+            warn!("User {} tried to access an admin route", user.id);
+            return Err(AppError::UserError((
+                StatusCode::FORBIDDEN,
+                "Only admins can do this".into(),
+            )));
+        }
+        Ok(AdminAuth(user))
+    }
+}
+
+/// Extract the user from the request's session cookie, rejecting the request
+/// unless the user entered their password or a TOTP code within the last
+/// `step_up_window`. Used for operations that can't be undone, so that a stolen
+/// session isn't enough to do lasting damage to an account.
+#[derive(Debug)]
+pub struct StepUpAuth(pub User);
+
+impl<S> FromRequestParts<S> for StepUpAuth
+where
+    S: Send + Sync,
+    State<AppState>: FromRequestParts<S>,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let SessionAuth(user) =
+            <SessionAuth as FromRequestParts<S>>::from_request_parts(parts, state).await?;
+        let State(state) = State::<AppState>::from_request_parts(parts, state)
+            .await
+            .map_err(|_| AppError::Generic(anyhow!("Database error")))?;
+        let window = chrono::Duration::from_std(state.config.step_up_window)
+            .map_err(|e| anyhow!("Invalid step up window: {}", e))?;
+        if user
+            .verified_at
+            .is_none_or(|verified_at| verified_at + window < Utc::now())
+        {
+            return Err(AppError::StepUpRequired);
+        }
+        Ok(StepUpAuth(user))
+    }
+}
+
+/// Reject state changing requests that carry cookies but were sent from another site.
+/// Cookies are sent by the browser automatically, so without this any site could
+/// make requests on behalf of a logged in user. Requests without an `Origin` or
+/// `Referer` header are let through as they don't come from a browser, but an
+/// origin that can't be read, like the `null` sent from sandboxed frames, is rejected.
+pub async fn verify_origin(request: Request, next: Next) -> Result<Response, AppError> {
+    let headers = request.headers();
+    if request.method().is_safe() || !headers.contains_key(COOKIE) {
+        return Ok(next.run(request).await);
+    }
+    let Some(origin) = headers.get(ORIGIN).or_else(|| headers.get(REFERER)) else {
+        return Ok(next.run(request).await);
+    };
+    let cross_site = || {
+        AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "Cross-site requests are not allowed".into(),
+        ))
+    };
+    let Some(origin) = origin
+        .to_str()
+        .ok()
+        .and_then(|origin| Url::parse(origin).ok())
+    else {
+        warn!("Rejected request with an unreadable origin {:?}", origin);
+        return Err(cross_site());
+    };
+    let host = headers.get(HOST).and_then(|host| host.to_str().ok());
+    let origin_host = match (origin.host_str(), origin.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => String::new(),
+    };
+    let trusted = host.is_some_and(|host| host == origin_host)
+        || origin_host == *LOKR_HOST
+        || DEV_ORIGIN.is_match(origin.origin().ascii_serialization().as_str());
+    if !trusted {
+        warn!("Rejected cross-site request from {}", origin);
+        return Err(cross_site());
+    }
+    Ok(next.run(request).await)
+}
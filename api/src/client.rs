@@ -0,0 +1,69 @@
+use std::path::Path;
+
+use axum::{
+    extract::Request,
+    http::{header::CACHE_CONTROL, HeaderValue, StatusCode},
+    response::IntoResponse,
+    Router,
+};
+use tower::ServiceExt;
+use tower_http::services::{ServeDir, ServeFile};
+
+use crate::error::AppError;
+
+/// Vite puts every bundled file in here with a hash of its contents in the name,
+/// so they never change and can be cached forever
+const ASSETS_PATH: &str = "/assets";
+
+/// Serve the web client from the directory it was built into.
+/// Paths that look like client routes get `index.html` so react-router can handle
+/// them on the client side. Anything else that doesn't exist gets a proper 404,
+/// so missing assets and mistyped API routes don't get a page of HTML instead.
+pub fn router(dir: &Path) -> Router {
+    let index = dir.join("index.html");
+    let files = ServeDir::new(dir);
+    Router::new()
+        .nest_service(
+            ASSETS_PATH,
+            ServiceExt::<Request>::map_response(
+                ServeDir::new(dir.join(ASSETS_PATH.trim_start_matches('/'))),
+                |mut response| {
+                    if response.status().is_success() {
+                        response.headers_mut().insert(
+                            CACHE_CONTROL,
+                            HeaderValue::from_static("public, max-age=31536000, immutable"),
+                        );
+                    }
+                    response
+                },
+            ),
+        )
+        .fallback(|request: Request| async move {
+            let path = request.uri().path();
+            if path == "/api" || path.starts_with("/api/") {
+                return AppError::UserError((StatusCode::NOT_FOUND, "Route not found".into()))
+                    .into_response();
+            }
+            // Client routes never have an extension in their last segment,
+            // so these are files from the public directory
+            let is_file = path
+                .rsplit('/')
+                .next()
+                .is_some_and(|segment| segment.contains('.'));
+            if is_file {
+                return files.oneshot(request).await.into_response();
+            }
+            let mut response = ServeFile::new(&index)
+                .oneshot(request)
+                .await
+                .into_response();
+            if response.status().is_success() {
+                // Make sure a new deploy is picked up, as the old index.html
+                // points to assets that may not exist anymore
+                response
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+            }
+            response
+        })
+}
@@ -0,0 +1,306 @@
+use std::{env::current_dir, fmt::Display, path::PathBuf, str::FromStr, time::Duration};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use lokr_types::admin::EffectiveConfig;
+use tracing::warn;
+
+use crate::{
+    at_rest::AtRestKey, cookie::SameSite, instance::PublicStats, retry::RetryPolicy, PKG_NAME,
+};
+
+/// Server configuration that can be changed without recompiling.
+/// Every option is read from an environment variable prefixed with `LOKR_`,
+/// falling back to a default if the variable isn't set.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The maximum size in bytes of a file upload request (`LOKR_MAX_UPLOAD_SIZE`)
+    pub max_upload_size: usize,
+    /// The maximum size in bytes of an avatar upload request (`LOKR_MAX_AVATAR_SIZE`)
+    pub max_avatar_size: usize,
+    /// The maximum size in bytes of the body of any other request (`LOKR_MAX_BODY_SIZE`).
+    /// These are JSON bodies which don't get anywhere near this big.
+    pub max_body_size: usize,
+    /// How long requests to JSON endpoints can take in total (`LOKR_REQUEST_TIMEOUT`, in seconds)
+    pub request_timeout: Duration,
+    /// How long a request or response body can go without sending any data
+    /// (`LOKR_BODY_IDLE_TIMEOUT`, in seconds). This is the only timeout applied to
+    /// uploads and downloads since they can take arbitrarily long on slow connections.
+    pub body_idle_timeout: Duration,
+    /// Whether cookies should only be sent over HTTPS (`LOKR_COOKIE_SECURE`)
+    pub cookie_secure: bool,
+    /// The `SameSite` attribute of cookies (`LOKR_COOKIE_SAME_SITE`)
+    pub cookie_same_site: SameSite,
+    /// Memory used by Argon2 when hashing passwords (`LOKR_ARGON2_MEMORY`, in KiB)
+    pub argon2_memory_cost: u32,
+    /// Number of Argon2 iterations when hashing passwords (`LOKR_ARGON2_ITERATIONS`)
+    pub argon2_iterations: u32,
+    /// Degree of parallelism used by Argon2 (`LOKR_ARGON2_PARALLELISM`)
+    pub argon2_parallelism: u32,
+    /// Where the database, uploads, and avatars are stored (`LOKR_DATA_DIR`)
+    pub data_dir: PathBuf,
+    /// How far past their total space users can keep uploading, as a percentage of
+    /// the total space (`LOKR_QUOTA_GRACE_PERCENT`). Uploads that end up in the grace
+    /// space succeed with a warning instead, so a large folder sync doesn't stop halfway.
+    pub quota_grace_percent: u32,
+    /// Explain why access to a file was denied to users the owner has shared files with
+    /// (`LOKR_EXPLAIN_DENIALS`), as if every owner had turned on `explainDenials`.
+    /// Everyone else is always told the file doesn't exist.
+    pub explain_denials: bool,
+    /// How recently the password or a TOTP code has to have been entered in a session
+    /// for sensitive operations to be allowed (`LOKR_STEP_UP_WINDOW`, in seconds)
+    pub step_up_window: Duration,
+    /// Key for encrypting avatars and database backups on disk, as 32 bytes of base64
+    /// (`LOKR_AT_REST_KEY`), or the path to a file containing it (`LOKR_AT_REST_KEY_FILE`)
+    /// for keys provided by a secret manager. Nothing is encrypted if neither is set.
+    pub at_rest_key: Option<AtRestKey>,
+    /// How many bytes can be downloaded from a user's files each month, including
+    /// through share links (`LOKR_MONTHLY_TRANSFER_CAP`). 0 means there is no cap.
+    /// Admins can change it for a single user with the `transfer_cap` column.
+    pub monthly_transfer_cap: u64,
+    /// Where the built web client is served from (`LOKR_CLIENT_DIR`)
+    pub client_dir: PathBuf,
+    /// Which numbers `/api/instance/stats` shares with everyone (`LOKR_PUBLIC_STATS`),
+    /// as a comma separated list of `users`, `files`, `uptime` and `version`, or `all`.
+    /// The endpoint doesn't exist unless at least one of them is set.
+    pub public_stats: PublicStats,
+    /// How many times a write is attempted when the database is busy (`LOKR_DB_RETRY_ATTEMPTS`)
+    pub db_retry_attempts: u32,
+    /// The delay before retrying a write the first time, doubling with each retry
+    /// (`LOKR_DB_RETRY_DELAY`, in milliseconds)
+    pub db_retry_delay: Duration,
+    /// How long owners can restore a share after revoking it
+    /// (`LOKR_SHARE_RESTORE_WINDOW`, in seconds). 0 turns restoring off.
+    pub share_restore_window: Duration,
+    /// How many uploads a user can have in progress at once (`LOKR_MAX_CONCURRENT_UPLOADS`),
+    /// so one client can't keep the disk busy for everyone else. 0 means there is no limit,
+    /// which is the default because the web client starts every selected file at once.
+    pub max_concurrent_uploads: u32,
+    /// The maximum total size in bytes of the files in a directory uploaded anonymously
+    /// (`LOKR_ANONYMOUS_FOLDER_MAX_SIZE`), which anyone with its link can add files to
+    pub anonymous_folder_max_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_upload_size: 1_000_000_000,
+            max_avatar_size: 10_000_000,
+            max_body_size: 64 * 1024,
+            request_timeout: Duration::from_secs(15),
+            body_idle_timeout: Duration::from_secs(30),
+            cookie_secure: true,
+            cookie_same_site: SameSite::Lax,
+            argon2_memory_cost: Params::DEFAULT_M_COST,
+            argon2_iterations: Params::DEFAULT_T_COST,
+            argon2_parallelism: Params::DEFAULT_P_COST,
+            data_dir: default_data_dir(),
+            quota_grace_percent: 0,
+            explain_denials: false,
+            step_up_window: Duration::from_secs(5 * 60),
+            at_rest_key: None,
+            monthly_transfer_cap: 0,
+            client_dir: PathBuf::from("../client/dist"),
+            public_stats: PublicStats::default(),
+            db_retry_attempts: 6,
+            db_retry_delay: Duration::from_millis(50),
+            share_restore_window: Duration::from_secs(7 * 24 * 60 * 60),
+            max_concurrent_uploads: 0,
+            anonymous_folder_max_size: 100_000_000,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from the environment
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            max_upload_size: env_or("LOKR_MAX_UPLOAD_SIZE", default.max_upload_size),
+            max_avatar_size: env_or("LOKR_MAX_AVATAR_SIZE", default.max_avatar_size),
+            max_body_size: env_or("LOKR_MAX_BODY_SIZE", default.max_body_size),
+            request_timeout: Duration::from_secs(env_or(
+                "LOKR_REQUEST_TIMEOUT",
+                default.request_timeout.as_secs(),
+            )),
+            body_idle_timeout: Duration::from_secs(env_or(
+                "LOKR_BODY_IDLE_TIMEOUT",
+                default.body_idle_timeout.as_secs(),
+            )),
+            cookie_secure: env_or("LOKR_COOKIE_SECURE", default.cookie_secure),
+            cookie_same_site: env_or("LOKR_COOKIE_SAME_SITE", default.cookie_same_site),
+            argon2_memory_cost: env_or("LOKR_ARGON2_MEMORY", default.argon2_memory_cost),
+            argon2_iterations: env_or("LOKR_ARGON2_ITERATIONS", default.argon2_iterations),
+            argon2_parallelism: env_or("LOKR_ARGON2_PARALLELISM", default.argon2_parallelism),
+            data_dir: std::env::var_os("LOKR_DATA_DIR").map_or(default.data_dir, PathBuf::from),
+            client_dir: std::env::var_os("LOKR_CLIENT_DIR")
+                .map_or(default.client_dir, PathBuf::from),
+            quota_grace_percent: env_or("LOKR_QUOTA_GRACE_PERCENT", default.quota_grace_percent),
+            explain_denials: env_or("LOKR_EXPLAIN_DENIALS", default.explain_denials),
+            step_up_window: Duration::from_secs(env_or(
+                "LOKR_STEP_UP_WINDOW",
+                default.step_up_window.as_secs(),
+            )),
+            at_rest_key: at_rest_key_from_env(),
+            monthly_transfer_cap: env_or("LOKR_MONTHLY_TRANSFER_CAP", default.monthly_transfer_cap),
+            public_stats: env_or("LOKR_PUBLIC_STATS", default.public_stats),
+            db_retry_attempts: env_or("LOKR_DB_RETRY_ATTEMPTS", default.db_retry_attempts),
+            db_retry_delay: Duration::from_millis(env_or(
+                "LOKR_DB_RETRY_DELAY",
+                default.db_retry_delay.as_millis() as u64,
+            )),
+            share_restore_window: Duration::from_secs(env_or(
+                "LOKR_SHARE_RESTORE_WINDOW",
+                default.share_restore_window.as_secs(),
+            )),
+            max_concurrent_uploads: env_or(
+                "LOKR_MAX_CONCURRENT_UPLOADS",
+                default.max_concurrent_uploads,
+            ),
+            anonymous_folder_max_size: env_or(
+                "LOKR_ANONYMOUS_FOLDER_MAX_SIZE",
+                default.anonymous_folder_max_size,
+            ),
+        }
+    }
+
+    /// The grace space on top of a user's total space
+    pub fn grace_space(&self, total_space: i64) -> i64 {
+        total_space.saturating_mul(self.quota_grace_percent as i64) / 100
+    }
+
+    /// How writes are retried when the database is busy
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.db_retry_attempts.max(1),
+            base_delay: self.db_retry_delay,
+        }
+    }
+
+    /// Path to the SQLite database
+    pub fn database_path(&self) -> PathBuf {
+        self.data_dir.join("api.db")
+    }
+
+    /// Path to where user uploads are stored
+    pub fn upload_dir(&self) -> PathBuf {
+        self.data_dir.join("uploads")
+    }
+
+    /// Path to where user avatar/profile images are stored
+    pub fn avatar_dir(&self) -> PathBuf {
+        self.data_dir.join("avatars")
+    }
+
+    /// Path to where uploaded file data is written before its database transaction commits.
+    /// Lives under the data directory so that it shares a file system with the upload
+    /// directory, which lets us atomically rename finished files into place.
+    pub fn temp_dir(&self) -> PathBuf {
+        self.data_dir.join("tmp")
+    }
+
+    /// Create the data directory and everything under it if they don't exist yet
+    pub fn create_dirs(&self) -> std::io::Result<()> {
+        for dir in [self.upload_dir(), self.avatar_dir(), self.temp_dir()] {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(())
+    }
+
+    /// The options as they are shown to admins and logged on startup, without secrets
+    pub fn effective(&self) -> EffectiveConfig {
+        EffectiveConfig {
+            max_upload_size: self.max_upload_size as u64,
+            max_avatar_size: self.max_avatar_size as u64,
+            max_body_size: self.max_body_size as u64,
+            request_timeout: self.request_timeout.as_secs(),
+            body_idle_timeout: self.body_idle_timeout.as_secs(),
+            cookie_secure: self.cookie_secure,
+            cookie_same_site: self.cookie_same_site.to_string(),
+            argon2_memory: self.argon2_memory_cost,
+            argon2_iterations: self.argon2_iterations,
+            argon2_parallelism: self.argon2_parallelism,
+            data_dir: self.data_dir.display().to_string(),
+            client_dir: self.client_dir.display().to_string(),
+            quota_grace_percent: self.quota_grace_percent,
+            explain_denials: self.explain_denials,
+            step_up_window: self.step_up_window.as_secs(),
+            at_rest_key: self.at_rest_key.is_some(),
+            monthly_transfer_cap: self.monthly_transfer_cap,
+            public_stats: self.public_stats.to_string(),
+            db_retry_attempts: self.db_retry_attempts,
+            db_retry_delay: self.db_retry_delay.as_millis() as u64,
+            share_restore_window: self.share_restore_window.as_secs(),
+            max_concurrent_uploads: self.max_concurrent_uploads,
+            anonymous_folder_max_size: self.anonymous_folder_max_size,
+        }
+    }
+
+    /// Create the Argon2 instance used to hash passwords with the configured parameters.
+    /// Falls back to the default parameters if the configured ones are invalid.
+    pub fn argon2(&self) -> Argon2<'static> {
+        let params = Params::new(
+            self.argon2_memory_cost,
+            self.argon2_iterations,
+            self.argon2_parallelism,
+            None,
+        )
+        .unwrap_or_else(|e| {
+            warn!(
+                "Invalid Argon2 parameters: {}. Using the defaults instead.",
+                e
+            );
+            Params::default()
+        });
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+    }
+}
+
+/// The platform's data directory for the application.
+/// Falls back to the current directory if the data directory cannot be determined.
+fn default_data_dir() -> PathBuf {
+    let mut path = match dirs::data_dir() {
+        Some(dir) => dir,
+        None => {
+            warn!("Could not determine data directory. Attempting to use current directory.");
+            current_dir().unwrap_or_default()
+        }
+    };
+    path.push(PKG_NAME);
+    path
+}
+
+/// Read the at rest key from the environment. Unlike the other options, an invalid key
+/// stops the server instead of falling back to the default, which would silently write
+/// everything unencrypted.
+fn at_rest_key_from_env() -> Option<AtRestKey> {
+    let key = match (
+        std::env::var("LOKR_AT_REST_KEY"),
+        std::env::var_os("LOKR_AT_REST_KEY_FILE"),
+    ) {
+        (Ok(key), _) => key,
+        (Err(_), Some(path)) => std::fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("Unable to read LOKR_AT_REST_KEY_FILE {:?}: {}", path, e)),
+        (Err(_), None) => return None,
+    };
+    Some(
+        key.parse()
+            .unwrap_or_else(|e| panic!("Invalid at rest key: {}", e)),
+    )
+}
+
+/// Parse an environment variable, using the default if it isn't set or is invalid
+fn env_or<T: FromStr + Display>(name: &str, default: T) -> T
+where
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|e| {
+            warn!(
+                "Invalid value for {}: {}. Using {} instead.",
+                name, e, default
+            );
+            default
+        }),
+        Err(_) => default,
+    }
+}
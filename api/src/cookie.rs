@@ -0,0 +1,133 @@
+use std::{fmt::Display, str::FromStr};
+
+use uuid::Uuid;
+
+use crate::config::Config;
+
+/// How long the session cookies last in seconds
+pub const SESSION_MAX_AGE: u64 = 34560000;
+
+/// The `SameSite` attribute of a cookie
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl FromStr for SameSite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lax" => Ok(Self::Lax),
+            "none" => Ok(Self::None),
+            _ => Err(format!("'{}' is not one of Strict, Lax, or None", s)),
+        }
+    }
+}
+
+impl Display for SameSite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Strict => write!(f, "Strict"),
+            Self::Lax => write!(f, "Lax"),
+            Self::None => write!(f, "None"),
+        }
+    }
+}
+
+/// Builds the value of a `Set-Cookie` header.
+/// All cookies set by the server should go through this so they
+/// get the `Secure` and `SameSite` attributes from the config.
+#[derive(Debug)]
+pub struct SetCookie<'a> {
+    name: &'a str,
+    value: &'a str,
+    path: &'a str,
+    max_age: Option<u64>,
+    http_only: bool,
+}
+
+impl<'a> SetCookie<'a> {
+    /// Create an HttpOnly session cookie that is sent to the API
+    pub fn new(name: &'a str, value: &'a str) -> Self {
+        Self {
+            name,
+            value,
+            path: "/api",
+            max_age: None,
+            http_only: true,
+        }
+    }
+
+    pub fn path(mut self, path: &'a str) -> Self {
+        self.path = path;
+        self
+    }
+
+    pub fn max_age(mut self, max_age: u64) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Allow the client to read the cookie
+    pub fn readable(mut self) -> Self {
+        self.http_only = false;
+        self
+    }
+
+    pub fn build(&self, config: &Config) -> String {
+        let mut cookie = format!("{}={}; Path={}", self.name, self.value, self.path);
+        if let Some(max_age) = self.max_age {
+            cookie.push_str(&format!("; Max-Age={}", max_age));
+        }
+        if self.http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        // Browsers reject `SameSite=None` cookies that aren't secure
+        if config.cookie_secure || config.cookie_same_site == SameSite::None {
+            cookie.push_str("; Secure");
+        }
+        cookie.push_str(&format!("; SameSite={}", config.cookie_same_site));
+        cookie
+    }
+
+    /// Build a header that deletes the cookie. Only the name and path have
+    /// to match for the browser to remove it.
+    pub fn removal(&self) -> String {
+        format!("{}=; Path={}; Max-Age=0", self.name, self.path)
+    }
+}
+
+/// The `session` cookie holding the session id, along with the
+/// `authenticated` cookie that lets the frontend know a user is logged in
+/// since it can't read the session cookie.
+pub fn session_cookies<'a>(session: &'a str) -> [SetCookie<'a>; 2] {
+    [
+        SetCookie::new("session", session).max_age(SESSION_MAX_AGE),
+        SetCookie::new("authenticated", "true")
+            .path("/")
+            .max_age(SESSION_MAX_AGE)
+            .readable(),
+    ]
+}
+
+/// The cookie remembering the password hash of a share link, named after the link,
+/// so the password only has to be entered once. It is scoped to all of `/api` rather
+/// than the share routes since downloads, uploads, and changes made through the link
+/// on the file routes need it too.
+pub fn link_cookie<'a>(link_id: &'a str, password_hash: &'a str) -> SetCookie<'a> {
+    SetCookie::new(link_id, password_hash)
+}
+
+/// Headers that create the session cookies for a new session
+pub fn set_session_cookies(config: &Config, session: Uuid) -> [String; 2] {
+    session_cookies(&session.to_string()).map(|cookie| cookie.build(config))
+}
+
+/// Headers that delete the session cookies
+pub fn clear_session_cookies() -> [String; 2] {
+    session_cookies("").map(|cookie| cookie.removal())
+}
@@ -0,0 +1,104 @@
+use serde_json::json;
+use utoipa::openapi::{
+    path::{HttpMethod, Operation, PathItem},
+    Deprecated, OpenApi,
+};
+
+/// A version of the API. Each version gets its own OpenAPI document at
+/// `/api-docs/{name}/openapi.json` so client generators can track one version
+/// without picking up changes meant for another.
+pub struct ApiVersion {
+    pub name: &'static str,
+    /// Routes that still work in this version, but have a replacement
+    /// that clients should move to
+    pub deprecated: &'static [DeprecatedRoute],
+}
+
+pub struct DeprecatedRoute {
+    pub method: HttpMethod,
+    pub path: &'static str,
+    pub replaced_by: (HttpMethod, &'static str),
+}
+
+/// Every version of the API, oldest first. The last one is the current version,
+/// which is also served at `/api-docs/openapi.json`.
+pub const VERSIONS: &[ApiVersion] = &[ApiVersion {
+    name: "v1",
+    deprecated: &[],
+}];
+
+/// The OpenAPI document of a single version, built from the document of every route
+pub fn version_doc(doc: &OpenApi, version: &ApiVersion) -> OpenApi {
+    let mut doc = doc.clone();
+    doc.info.version = version.name.into();
+    for route in version.deprecated {
+        let (replacement_method, replacement_path) = &route.replaced_by;
+        let replacement_operation = doc
+            .paths
+            .paths
+            .get_mut(*replacement_path)
+            .and_then(|item| operation_mut(item, replacement_method))
+            .and_then(|operation| operation.operation_id.clone());
+        let Some(operation) = doc
+            .paths
+            .paths
+            .get_mut(route.path)
+            .and_then(|item| operation_mut(item, &route.method))
+        else {
+            continue;
+        };
+        let replacement = format!("{} {}", method_name(replacement_method), replacement_path);
+        operation.deprecated = Some(Deprecated::True);
+        operation.description = Some(match operation.description.take() {
+            Some(description) => format!(
+                "{}\n\nDeprecated, use `{}` instead.",
+                description, replacement
+            ),
+            None => format!("Deprecated, use `{}` instead.", replacement),
+        });
+        // There is no standard field for replacements, so they are added as an
+        // extension that generators can pick up along with the operation id
+        let extensions = operation.extensions.get_or_insert_with(Default::default);
+        extensions.insert(
+            "x-replaced-by".into(),
+            json!({
+                "method": method_name(replacement_method),
+                "path": replacement_path,
+                "operationId": replacement_operation,
+                "link": format!("#/paths/{}/{}", pointer_escape(replacement_path), method_name(replacement_method).to_lowercase()),
+            }),
+        );
+    }
+    doc
+}
+
+fn operation_mut<'a>(item: &'a mut PathItem, method: &HttpMethod) -> Option<&'a mut Operation> {
+    match method {
+        HttpMethod::Get => item.get.as_mut(),
+        HttpMethod::Post => item.post.as_mut(),
+        HttpMethod::Put => item.put.as_mut(),
+        HttpMethod::Delete => item.delete.as_mut(),
+        HttpMethod::Options => item.options.as_mut(),
+        HttpMethod::Head => item.head.as_mut(),
+        HttpMethod::Patch => item.patch.as_mut(),
+        HttpMethod::Trace => item.trace.as_mut(),
+    }
+}
+
+fn method_name(method: &HttpMethod) -> &'static str {
+    match method {
+        HttpMethod::Get => "GET",
+        HttpMethod::Post => "POST",
+        HttpMethod::Put => "PUT",
+        HttpMethod::Delete => "DELETE",
+        HttpMethod::Options => "OPTIONS",
+        HttpMethod::Head => "HEAD",
+        HttpMethod::Patch => "PATCH",
+        HttpMethod::Trace => "TRACE",
+    }
+}
+
+/// Escape a path so it can be used in a JSON pointer
+fn pointer_escape(path: &str) -> String {
+    path.replace('~', "~0").replace('/', "~1")
+}
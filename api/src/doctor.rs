@@ -0,0 +1,297 @@
+use std::{fmt::Display, path::Path, time::Duration};
+
+use argon2::Params;
+use chrono::{TimeZone, Utc};
+use url::Url;
+
+use crate::{config::Config, connect_db, cookie::SameSite, migrations::db_path};
+
+/// How far the clock can be behind the newest timestamp in the database before
+/// TOTP codes start getting rejected. Codes are valid for 30 seconds on either side.
+const MAX_CLOCK_DRIFT: Duration = Duration::from_secs(90);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    /// The server works, but probably not how the operator wants it to
+    Warning,
+    /// The server would fail in the middle of requests, so it refuses to start
+    Error,
+}
+
+/// The result of a single check, with a message that says how to fix it if it failed
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Check {
+    fn ok(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Ok,
+            message: message.into(),
+        }
+    }
+
+    fn warning(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Warning,
+            message: message.into(),
+        }
+    }
+
+    fn error(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            severity: Severity::Error,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "[{}] {}: {}", severity, self.name, self.message)
+    }
+}
+
+/// Check that the environment can run the server with the given config.
+/// Creates the data directories if they don't exist yet, but doesn't change the database.
+pub async fn run(config: &Config) -> Vec<Check> {
+    let mut checks = check_dirs(config).await;
+    // Everything below needs the data directories
+    if checks.iter().any(|check| check.severity == Severity::Error) {
+        return checks;
+    }
+    checks.push(check_wal(config).await);
+    checks.push(check_clock(config).await);
+    checks.extend(check_config(config));
+    checks
+}
+
+/// Whether any of the checks failed badly enough that the server shouldn't start
+pub fn has_errors(checks: &[Check]) -> bool {
+    checks.iter().any(|check| check.severity == Severity::Error)
+}
+
+async fn check_dirs(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+    for dir in [config.upload_dir(), config.avatar_dir(), config.temp_dir()] {
+        if let Err(e) = writable(&dir).await {
+            checks.push(Check::error(
+                "directories",
+                format!(
+                    "Unable to write to {}: {}. Make sure the user running the server owns it, or set LOKR_DATA_DIR to somewhere it can write to.",
+                    dir.display(),
+                    e
+                ),
+            ));
+        }
+    }
+    if !checks.is_empty() {
+        return checks;
+    }
+    // Finished uploads are renamed from the temporary directory into place,
+    // which only works if they are on the same file system
+    let from = config.temp_dir().join(".doctor");
+    let to = config.upload_dir().join(".doctor");
+    let renamed = async {
+        tokio::fs::write(&from, b"").await?;
+        tokio::fs::rename(&from, &to).await?;
+        tokio::fs::remove_file(&to).await
+    }
+    .await;
+    let _ = tokio::fs::remove_file(&from).await;
+    checks.push(match renamed {
+        Ok(()) => Check::ok(
+            "directories",
+            format!("{} is writable", config.data_dir.display()),
+        ),
+        Err(e) => Check::error(
+            "directories",
+            format!(
+                "Unable to move files from {} to {}: {}. They have to be on the same file system, don't mount either of them separately.",
+                config.temp_dir().display(),
+                config.upload_dir().display(),
+                e
+            ),
+        ),
+    });
+    checks
+}
+
+/// Create the directory if it doesn't exist and write a file to it
+async fn writable(dir: &Path) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(dir).await?;
+    let probe = dir.join(".doctor");
+    tokio::fs::write(&probe, b"").await?;
+    tokio::fs::remove_file(&probe).await
+}
+
+/// The database is opened in WAL mode, which doesn't work on some network file systems
+async fn check_wal(config: &Config) -> Check {
+    let path = config.temp_dir().join("doctor.db");
+    let result = async {
+        let url = Url::from_file_path(&path).map_err(|_| anyhow::anyhow!("Invalid path"))?;
+        let pool = connect_db(&url)?;
+        let mode: Result<String, _> = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&pool)
+            .await;
+        pool.close().await;
+        anyhow::Ok(mode?)
+    }
+    .await;
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = tokio::fs::remove_file(format!("{}{}", path.display(), suffix)).await;
+    }
+    match result {
+        Ok(mode) if mode.eq_ignore_ascii_case("wal") => {
+            Check::ok("database", "SQLite can use WAL mode")
+        }
+        Ok(mode) => Check::error(
+            "database",
+            format!(
+                "SQLite used the {} journal mode instead of WAL in {}. Network file systems usually don't support WAL, keep LOKR_DATA_DIR on a local disk.",
+                mode,
+                config.data_dir.display()
+            ),
+        ),
+        Err(e) => Check::error(
+            "database",
+            format!(
+                "Unable to open a database in {}: {}",
+                config.temp_dir().display(),
+                e
+            ),
+        ),
+    }
+}
+
+/// TOTP codes depend on the clock, so make sure it is set and hasn't gone
+/// back in time compared to what is already in the database
+async fn check_clock(config: &Config) -> Check {
+    let now = Utc::now();
+    if now < Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap() {
+        return Check::error(
+            "clock",
+            format!(
+                "The system clock is set to {}, which is in the past. TOTP codes and sessions won't work until it is synchronized, for example with NTP.",
+                now
+            ),
+        );
+    }
+    let Ok(url) = Url::from_file_path(config.database_path()) else {
+        return Check::ok("clock", "The system clock is set");
+    };
+    let newest = match db_path(&url) {
+        Ok(path) if path.exists() => newest_timestamp(&url).await,
+        _ => None,
+    };
+    match newest {
+        Some(newest) if newest - now.timestamp() > MAX_CLOCK_DRIFT.as_secs() as i64 => {
+            Check::warning(
+                "clock",
+                format!(
+                    "The system clock is {} seconds behind the newest timestamp in the database. TOTP codes will be rejected if the clock is wrong, make sure it is synchronized, for example with NTP.",
+                    newest - now.timestamp()
+                ),
+            )
+        }
+        _ => Check::ok("clock", "The system clock is set"),
+    }
+}
+
+/// The newest time something was written to the database, as a Unix timestamp.
+/// Errors are ignored since the tables might not exist yet.
+async fn newest_timestamp(url: &Url) -> Option<i64> {
+    let pool = connect_db(url).ok()?;
+    let newest = sqlx::query_scalar(
+        r#"
+        SELECT MAX(CAST(STRFTIME('%s', time) AS INTEGER)) FROM (
+            SELECT MAX(installed_on) AS time FROM _sqlx_migrations
+            UNION ALL
+            SELECT MAX(created_at) FROM session
+        )
+        "#,
+    )
+    .fetch_one(&pool)
+    .await
+    .ok()
+    .flatten();
+    pool.close().await;
+    newest
+}
+
+/// Options that are valid on their own, but don't work together
+fn check_config(config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+    if config.cookie_same_site == SameSite::None && !config.cookie_secure {
+        checks.push(Check::warning(
+            "config",
+            "Cookies with SameSite=None are always sent as Secure, since browsers reject them otherwise, so logging in only works over HTTPS even though LOKR_COOKIE_SECURE is off.",
+        ));
+    }
+    if config.request_timeout.is_zero() || config.body_idle_timeout.is_zero() {
+        checks.push(Check::error(
+            "config",
+            "Every request would time out immediately. Set LOKR_REQUEST_TIMEOUT and LOKR_BODY_IDLE_TIMEOUT to more than 0.",
+        ));
+    }
+    if config.max_upload_size == 0 {
+        checks.push(Check::error(
+            "config",
+            "Every upload would be rejected. Set LOKR_MAX_UPLOAD_SIZE to more than 0.",
+        ));
+    }
+    if config.max_avatar_size > config.max_upload_size {
+        checks.push(Check::warning(
+            "config",
+            format!(
+                "Avatars can be larger than files (LOKR_MAX_AVATAR_SIZE is {} and LOKR_MAX_UPLOAD_SIZE is {}).",
+                config.max_avatar_size, config.max_upload_size
+            ),
+        ));
+    }
+    if let Err(e) = Params::new(
+        config.argon2_memory_cost,
+        config.argon2_iterations,
+        config.argon2_parallelism,
+        None,
+    ) {
+        checks.push(Check::warning(
+            "config",
+            format!(
+                "Invalid Argon2 parameters ({}), the defaults are used instead. Check LOKR_ARGON2_MEMORY, LOKR_ARGON2_ITERATIONS and LOKR_ARGON2_PARALLELISM.",
+                e
+            ),
+        ));
+    }
+    if config.step_up_window.is_zero() {
+        checks.push(Check::warning(
+            "config",
+            "LOKR_STEP_UP_WINDOW is 0, so the password has to be entered again for every sensitive operation.",
+        ));
+    }
+    if !config.client_dir.join("index.html").exists() {
+        checks.push(Check::warning(
+            "config",
+            format!(
+                "There is no web client in {}, only the API will be served. Build the client or set LOKR_CLIENT_DIR.",
+                config.client_dir.display()
+            ),
+        ));
+    }
+    if checks.is_empty() {
+        checks.push(Check::ok("config", "The options are consistent"));
+    }
+    checks
+}
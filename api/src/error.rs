@@ -7,10 +7,14 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use serde::Serialize;
-use utoipa::{openapi::ObjectBuilder, PartialSchema, ToSchema};
 use validator::Validate;
 
+pub use lokr_types::error::{
+    ErrorResponse, ErrorType, ValidationErrorDetail as AppValidationError,
+};
+
+use crate::{cookie::clear_session_cookies, i18n::translate};
+
 /// Error that wraps `anyhow::Error`.
 /// Useful to provide more fine grained error handling in our application.
 /// Helps us debug errors in the code easier and gives the client a better idea of what went wrong.
@@ -22,58 +26,26 @@ pub enum AppError {
     ValidationError(Vec<AppValidationError>),
     AuthError(anyhow::Error),
     UserError((StatusCode, String)),
+    AccessDenied(String),
+    StepUpRequired,
+    TooManyUploads,
     Generic(anyhow::Error),
 }
 
-impl Serialize for AppError {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: serde::Serializer,
-    {
-        serializer.serialize_unit_struct(&self.r#type())
-    }
-}
-impl ToSchema for AppError {}
-impl PartialSchema for AppError {
-    fn schema() -> utoipa::openapi::RefOr<utoipa::openapi::schema::Schema> {
-        ObjectBuilder::new()
-            .schema_type(utoipa::openapi::Type::String)
-            .enum_values(Some([
-                "JsonRejection",
-                "SqlxError",
-                "SerdeError",
-                "ValidationError",
-                "AuthError",
-                "UserError",
-                "Generic",
-            ]))
-            .examples([serde_json::json!("UserError")])
-            .into()
-    }
-}
-
-/// A JSON response for errors that includes the error type and message
-/// Used in HTTP responses to notify the client of errors
-#[derive(Serialize, Debug, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub struct ErrorResponse {
-    #[schema(example = "UserError")]
-    pub r#type: AppError,
-    #[schema(example = "Something went wrong")]
-    pub message: String,
-}
-
 impl AppError {
-    /// Get the error type as a string to notify the client of what went wrong
-    pub fn r#type(&self) -> &'static str {
+    /// Get the error type to notify the client of what went wrong
+    pub fn r#type(&self) -> ErrorType {
         match self {
-            AppError::JsonRejection(_) => "JsonRejection",
-            AppError::ValidationError(_) => "ValidationError",
-            AppError::SerdeError(_) => "SerdeError",
-            AppError::AuthError(_) => "AuthError",
-            AppError::SqlxError(_) => "SqlxError",
-            AppError::Generic(_) => "Generic",
-            AppError::UserError(_) => "User",
+            AppError::JsonRejection(_) => ErrorType::JsonRejection,
+            AppError::ValidationError(_) => ErrorType::ValidationError,
+            AppError::SerdeError(_) => ErrorType::SerdeError,
+            AppError::AuthError(_) => ErrorType::AuthError,
+            AppError::SqlxError(_) => ErrorType::SqlxError,
+            AppError::Generic(_) => ErrorType::Generic,
+            AppError::UserError(_) => ErrorType::UserError,
+            AppError::AccessDenied(_) => ErrorType::AccessDenied,
+            AppError::StepUpRequired => ErrorType::StepUpRequired,
+            AppError::TooManyUploads => ErrorType::TooManyUploads,
         }
     }
 }
@@ -89,6 +61,12 @@ impl Display for AppError {
             AppError::SqlxError(e) => write!(f, "{}", e),
             AppError::Generic(err) => write!(f, "{}", err),
             AppError::UserError((_, err)) => write!(f, "{}", err),
+            AppError::AccessDenied(err) => write!(f, "{}", err),
+            AppError::StepUpRequired => write!(f, "Confirm your password to do this"),
+            AppError::TooManyUploads => write!(
+                f,
+                "Too many uploads in progress, wait for one to finish before starting another"
+            ),
         }
     }
 }
@@ -104,14 +82,15 @@ impl IntoResponse for AppError {
                 (StatusCode::BAD_REQUEST, sonic_rs::to_string(&e).unwrap())
             }
             AppError::AuthError(e) => {
-                headers.append(SET_COOKIE, "session=; HttpOnly; Max-Age=0".parse().unwrap());
-                headers.append(
-                    SET_COOKIE,
-                    "authenticated=; Path=/; Max-Age=0".parse().unwrap(),
-                );
+                for cookie in clear_session_cookies() {
+                    headers.append(SET_COOKIE, cookie.parse().unwrap());
+                }
                 (StatusCode::UNAUTHORIZED, e.to_string())
             }
             AppError::UserError((code, e)) => (*code, e.to_string()),
+            AppError::AccessDenied(e) => (StatusCode::FORBIDDEN, e.to_string()),
+            AppError::StepUpRequired => (StatusCode::FORBIDDEN, self.to_string()),
+            AppError::TooManyUploads => (StatusCode::TOO_MANY_REQUESTS, self.to_string()),
             AppError::SqlxError(_) | AppError::Generic(_) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
                 "Internal Server Error".to_owned(),
@@ -122,8 +101,8 @@ impl IntoResponse for AppError {
             status,
             headers,
             Json(ErrorResponse {
-                r#type: self,
-                message,
+                r#type: self.r#type(),
+                message: translate(message),
             }),
         )
             .into_response()
@@ -144,26 +123,17 @@ where
         // We don't need to add `AuthError` or `ValidationError` because we will handle those
         // explicitly in our application.
         if err.downcast_ref::<JsonRejection>().is_some() {
-            return Self::JsonRejection(err.downcast().unwrap());
+            Self::JsonRejection(err.downcast().unwrap())
         } else if err.downcast_ref::<sqlx::Error>().is_some() {
-            return Self::SqlxError(err.downcast().unwrap());
+            Self::SqlxError(err.downcast().unwrap())
         } else if err.downcast_ref::<sonic_rs::Error>().is_some() {
-            return Self::SerdeError(err.downcast().unwrap());
+            Self::SerdeError(err.downcast().unwrap())
         } else {
-            return Self::Generic(err);
+            Self::Generic(err)
         }
     }
 }
 
-/// A more descriptive error message for validation errors
-#[derive(Serialize, Debug)]
-pub struct AppValidationError {
-    /// The field that failed validation
-    field: String,
-    /// A detailed error message
-    message: String,
-}
-
 /// An error type for validation errors
 /// This is useful because we can return a JSON response with the error type and message
 /// to provide the client with a clearer error message than what the default `validator`
@@ -0,0 +1,80 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use axum::{
+    extract::Request,
+    http::{header::ACCEPT_LANGUAGE, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+
+/// Translations of the messages in `SuccessResponse` and `ErrorResponse`, keyed by the
+/// English message. Messages without a translation, including ones with values formatted
+/// into them, are sent in English. Clients that need to tell errors apart should use the
+/// error type instead of the message, which stays the same in every language.
+static CATALOGS: LazyLock<HashMap<&'static str, HashMap<String, String>>> = LazyLock::new(|| {
+    [
+        ("de", include_str!("../locales/de.json")),
+        ("es", include_str!("../locales/es.json")),
+    ]
+    .into_iter()
+    .map(|(language, catalog)| {
+        let catalog = serde_json::from_str(catalog)
+            .unwrap_or_else(|e| panic!("Invalid {} message catalog: {}", language, e));
+        (language, catalog)
+    })
+    .collect()
+});
+
+tokio::task_local! {
+    /// The language picked for the current request, if it isn't English
+    static LANGUAGE: Option<&'static str>;
+}
+
+/// Pick the language of the messages sent back for a request from its `Accept-Language` header
+pub async fn localize(request: Request, next: Next) -> Response {
+    let language = negotiate(request.headers());
+    LANGUAGE.scope(language, next.run(request)).await
+}
+
+/// Translate a message into the language of the current request
+pub fn translate(message: String) -> String {
+    LANGUAGE
+        .try_with(|language| *language)
+        .ok()
+        .flatten()
+        .and_then(|language| CATALOGS.get(language)?.get(&message).cloned())
+        .unwrap_or(message)
+}
+
+/// The supported language the client prefers most, or `None` for English.
+/// Only the primary subtag is used, so `es-MX` gets the `es` catalog.
+fn negotiate(headers: &HeaderMap) -> Option<&'static str> {
+    let header = headers.get(ACCEPT_LANGUAGE)?.to_str().ok()?;
+    let mut best: Option<(Option<&'static str>, f32)> = None;
+    for range in header.split(',') {
+        let mut parts = range.split(';');
+        let tag = parts.next().unwrap_or_default().trim();
+        let quality = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+        let Some(quality) = quality.filter(|q| *q > 0.0) else {
+            continue;
+        };
+        let primary = tag
+            .split('-')
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let language = if primary == "en" {
+            None
+        } else if let Some((language, _)) = CATALOGS.get_key_value(primary.as_str()) {
+            Some(*language)
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((language, quality));
+        }
+    }
+    best.and_then(|(language, _)| language)
+}
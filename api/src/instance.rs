@@ -0,0 +1,256 @@
+use std::{
+    fmt::Display,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use sqlx::{Executor, Sqlite};
+use tracing::instrument;
+
+pub use lokr_types::instance::{
+    Features, FeaturesUpdate, InstanceStats, MetadataVersions, ServerTime,
+};
+
+use crate::{
+    error::{AppError, ErrorResponse},
+    state::AppState,
+    upload::{METADATA_VERSION, SUPPORTED_METADATA_VERSIONS},
+    users::{TOTP_SKEW, TOTP_STEP},
+};
+
+#[utoipa::path(
+    get,
+    path = "/api/instance/features",
+    description = "Get which optional features are enabled on the instance, so clients can hide what isn't available.",
+    responses(
+        (status = OK, description = "Features found", body = Features),
+    ),
+    security(
+        ()
+    )
+)]
+#[instrument(skip(state))]
+pub async fn get_features(State(state): State<AppState>) -> Response {
+    (StatusCode::OK, Json(state.features())).into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/instance/metadata-versions",
+    description = "Get the encrypted metadata formats the instance understands. Clients should upload in the newest version they share with the instance, and read the version of each file before decrypting its metadata.",
+    responses(
+        (status = OK, description = "Metadata versions found", body = MetadataVersions),
+    ),
+    security(
+        ()
+    )
+)]
+#[instrument]
+pub async fn get_metadata_versions() -> Response {
+    (
+        StatusCode::OK,
+        Json(MetadataVersions {
+            current: METADATA_VERSION,
+            supported: SUPPORTED_METADATA_VERSIONS.to_vec(),
+        }),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/time",
+    description = "Get the current time on the server and the window TOTP codes are accepted in. Clients can compare it to the time on the device to warn the user before a clock that is off gets their TOTP codes rejected.",
+    responses(
+        (status = OK, description = "Server time found", body = ServerTime),
+    ),
+    security(
+        ()
+    )
+)]
+#[instrument]
+pub async fn get_time() -> Response {
+    let unix_time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    (
+        StatusCode::OK,
+        Json(ServerTime {
+            unix_time,
+            totp_step: TOTP_STEP,
+            totp_skew: TOTP_SKEW,
+        }),
+    )
+        .into_response()
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/instance/stats",
+    description = "Get coarse public numbers about the instance for status pages and directories of instances. The operator chooses which numbers are shared, the others are left out.",
+    responses(
+        (status = OK, description = "Statistics found", body = InstanceStats),
+        (status = NOT_FOUND, description = "The instance doesn't share any statistics", body = ErrorResponse),
+    ),
+    security(
+        ()
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_instance_stats(State(state): State<AppState>) -> Result<Response, AppError> {
+    let shared = state.config.public_stats;
+    if !shared.any() {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Route not found".into(),
+        )));
+    }
+    let mut stats = InstanceStats::default();
+    if shared.users {
+        let users = sqlx::query_scalar!("SELECT COUNT(*) FROM user")
+            .fetch_one(&state.pool)
+            .await?;
+        stats.users = Some(coarse(users));
+    }
+    if shared.files {
+        let files = sqlx::query_scalar!("SELECT COUNT(*) FROM file")
+            .fetch_one(&state.pool)
+            .await?;
+        stats.files = Some(coarse(files));
+    }
+    if shared.uptime {
+        let uptime = state.started_at.elapsed().as_secs();
+        stats.uptime = Some(uptime - uptime % 3600);
+    }
+    if shared.version {
+        stats.version = Some(env!("CARGO_PKG_VERSION").into());
+    }
+    Ok((StatusCode::OK, Json(stats)).into_response())
+}
+
+/// Round a count down to two significant digits, so the exact
+/// number of users or files can't be watched from outside
+fn coarse(count: i64) -> i64 {
+    let mut unit = 1;
+    while count / unit >= 100 {
+        unit *= 10;
+    }
+    count - count % unit
+}
+
+/// Which fields of [`InstanceStats`] are shared with everyone
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PublicStats {
+    pub users: bool,
+    pub files: bool,
+    pub uptime: bool,
+    pub version: bool,
+}
+
+impl PublicStats {
+    /// Whether anything is shared at all
+    pub fn any(&self) -> bool {
+        self.users || self.files || self.uptime || self.version
+    }
+
+    fn fields(&self) -> [(&'static str, bool); 4] {
+        [
+            ("users", self.users),
+            ("files", self.files),
+            ("uptime", self.uptime),
+            ("version", self.version),
+        ]
+    }
+}
+
+impl FromStr for PublicStats {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut stats = Self::default();
+        for field in s
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            match field.to_ascii_lowercase().as_str() {
+                "users" => stats.users = true,
+                "files" => stats.files = true,
+                "uptime" => stats.uptime = true,
+                "version" => stats.version = true,
+                "all" => {
+                    stats = Self {
+                        users: true,
+                        files: true,
+                        uptime: true,
+                        version: true,
+                    }
+                }
+                _ => {
+                    return Err(format!(
+                        "'{}' is not one of users, files, uptime, version, or all",
+                        field
+                    ))
+                }
+            }
+        }
+        Ok(stats)
+    }
+}
+
+impl Display for PublicStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let fields: Vec<_> = self
+            .fields()
+            .into_iter()
+            .filter_map(|(name, shared)| shared.then_some(name))
+            .collect();
+        write!(f, "{}", fields.join(","))
+    }
+}
+
+/// Read the features from the database, using the defaults for those that were never changed
+pub async fn load_features<'a, E: Executor<'a, Database = Sqlite>>(
+    db: E,
+) -> Result<Features, AppError> {
+    let mut features = Features::default();
+    for row in sqlx::query!("SELECT name, enabled FROM feature_flag")
+        .fetch_all(db)
+        .await?
+    {
+        match row.name.as_str() {
+            "registration" => features.registration = row.enabled,
+            "anonymous_uploads" => features.anonymous_uploads = row.enabled,
+            "public_profiles" => features.public_profiles = row.enabled,
+            // Left over from a feature that was removed
+            _ => {}
+        }
+    }
+    Ok(features)
+}
+
+/// The name of each feature in the `feature_flag` table along with its new value
+pub fn changed_features(update: &FeaturesUpdate) -> impl Iterator<Item = (&'static str, bool)> {
+    [
+        ("registration", update.registration),
+        ("anonymous_uploads", update.anonymous_uploads),
+        ("public_profiles", update.public_profiles),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| Some((name, enabled?)))
+}
+
+/// Reject the request if the feature is disabled
+pub fn require(enabled: bool, message: &str) -> Result<(), AppError> {
+    if enabled {
+        Ok(())
+    } else {
+        Err(AppError::UserError((StatusCode::FORBIDDEN, message.into())))
+    }
+}
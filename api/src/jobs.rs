@@ -0,0 +1,409 @@
+use std::{io::ErrorKind, time::Duration};
+
+use anyhow::anyhow;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::{Executor, Sqlite};
+use tracing::{error, instrument, warn};
+use uuid::Uuid;
+
+pub use lokr_types::jobs::{JobInfo, JobStatus};
+
+use crate::{
+    auth::SessionAuth,
+    error::{AppError, ErrorResponse},
+    state::AppState,
+    success, users, SuccessResponse,
+};
+
+/// The maximum number of times a job is attempted before it is marked as failed
+const MAX_ATTEMPTS: i64 = 5;
+/// How long the worker waits between checks for new jobs if it isn't notified
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// Jobs that finish changes that have already been committed. Cancelling one would
+/// leave data on disk that no file refers to or counts towards anyone's space.
+const UNCANCELLABLE_KINDS: &[&str] = &["deleteBlobs"];
+
+/// Work that is too slow or too fragile to be done inside of a request.
+/// Jobs are stored in the database so they survive restarts and are
+/// retried with a backoff if they fail.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum Job {
+    /// Delete the data of files that have already been removed from the database
+    DeleteBlobs { ids: Vec<Uuid> },
+    /// Recompute the used space of every user to fix any drift from the stored files
+    ReconcileUsedSpace,
+}
+
+/// Add a job to the queue.
+/// Pass in a transaction to make sure the job is only queued if the
+/// rest of the transaction commits. Call `Notify::notify_one` on the
+/// job notifier afterwards to have the worker pick it up immediately.
+///
+/// Jobs queued for a user are listed to them and kept after they complete so
+/// they can see how it went, jobs the server queues for itself are deleted.
+pub async fn enqueue<'a, E: Executor<'a, Database = Sqlite>>(
+    db: E,
+    user_id: Option<Uuid>,
+    job: &Job,
+) -> Result<(), AppError> {
+    let payload = serde_json::to_string(job)?;
+    sqlx::query!(
+        "INSERT INTO job (payload, user_id) VALUES (?, ?)",
+        payload,
+        user_id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Run jobs from the queue until the task is aborted.
+/// Jobs are run one at a time in the order they were queued.
+pub async fn run_worker(state: AppState) {
+    loop {
+        match run_next_job(&state).await {
+            // Immediately check for another job if one was just run
+            Ok(true) => continue,
+            Ok(false) => {}
+            Err(e) => error!("Error running job: {}", e),
+        }
+        // Wait until either a new job is queued or the poll interval passes,
+        // the latter is needed for jobs being retried
+        let _ = tokio::time::timeout(POLL_INTERVAL, state.job_notify.notified()).await;
+    }
+}
+
+/// Run the next job that is due, returning whether or not a job was found
+async fn run_next_job(state: &AppState) -> Result<bool, AppError> {
+    let pool = &state.pool;
+    let pending = JobStatus::Pending as i64;
+    let Some(row) = sqlx::query!(
+        r#"
+        SELECT id AS "id!", payload, attempts, user_id IS NOT NULL AS "has_user!: bool" FROM job
+        WHERE status = ? AND DATETIME(run_at) <= CURRENT_TIMESTAMP
+        ORDER BY id ASC
+        LIMIT 1
+        "#,
+        pending
+    )
+    .fetch_optional(pool)
+    .await?
+    else {
+        return Ok(false);
+    };
+
+    let result = match serde_json::from_str::<Job>(&row.payload) {
+        Ok(job) => run_job(job, state).await,
+        Err(e) => Err(anyhow!("Invalid job payload: {}", e)),
+    };
+
+    match result {
+        Ok(()) if row.has_user => {
+            let completed = JobStatus::Completed as i64;
+            sqlx::query!(
+                "UPDATE job SET status = ?, finished_at = CURRENT_TIMESTAMP WHERE id = ?",
+                completed,
+                row.id
+            )
+            .execute(pool)
+            .await?;
+        }
+        Ok(()) => {
+            sqlx::query!("DELETE FROM job WHERE id = ?", row.id)
+                .execute(pool)
+                .await?;
+        }
+        Err(e) => {
+            let attempts = row.attempts + 1;
+            let status = if attempts >= MAX_ATTEMPTS {
+                error!("Job {} failed permanently: {}", row.id, e);
+                JobStatus::Failed
+            } else {
+                warn!("Job {} failed on attempt {}: {}", row.id, attempts, e);
+                JobStatus::Pending
+            } as i64;
+            // Back off exponentially so a persistent problem doesn't get hammered
+            let delay = 30 * (1 << attempts);
+            let error = e.to_string();
+            let failed = JobStatus::Failed as i64;
+            // The job may have been cancelled while it was running, leave it cancelled
+            sqlx::query!(
+                r#"
+                UPDATE job SET status = ?, attempts = ?, last_error = ?,
+                run_at = DATETIME(CURRENT_TIMESTAMP, '+' || ? || ' seconds'),
+                finished_at = IIF(? = ?, CURRENT_TIMESTAMP, NULL)
+                WHERE id = ? AND status = ?
+                "#,
+                status,
+                attempts,
+                error,
+                delay,
+                status,
+                failed,
+                row.id,
+                pending
+            )
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(true)
+}
+
+async fn run_job(job: Job, state: &AppState) -> anyhow::Result<()> {
+    match job {
+        Job::DeleteBlobs { ids } => {
+            let mut failed = 0;
+            for id in ids {
+                match tokio::fs::remove_file(state.config.upload_dir().join(id.to_string())).await {
+                    // A not found error means that the file was already deleted,
+                    // which is what we want anyway
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        error!("Unable to delete file '{}': {}", id, e);
+                        failed += 1;
+                    }
+                    _ => {}
+                }
+            }
+            // Retrying the entire job is fine since already deleted files are skipped
+            if failed > 0 {
+                return Err(anyhow!("Unable to delete {} file(s)", failed));
+            }
+            Ok(())
+        }
+        Job::ReconcileUsedSpace => {
+            let drifted = users::reconcile_used_space(&state.pool)
+                .await
+                .map_err(|e| anyhow!("{}", e))?;
+            if drifted > 0 {
+                warn!("Corrected the used space of {} user(s)", drifted);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/jobs",
+    description = "Get the background jobs of the currently authenticated user, newest first. Completed and cancelled jobs are kept for a while so the user can see how they went.",
+    responses(
+        (status = OK, description = "Jobs found", body = [JobInfo]),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_jobs(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+) -> Result<Response, AppError> {
+    let jobs = sqlx::query_as!(
+        JobInfo,
+        r#"
+        SELECT id AS "id!", JSON_EXTRACT(payload, '$.type') AS "kind!: String",
+        status AS "status: JobStatus", attempts, last_error,
+        run_at AS "run_at: _", created_at AS "created_at: _",
+        modified_at AS "modified_at: _", finished_at AS "finished_at: _",
+        NULL AS "user_id?: Uuid"
+        FROM job WHERE user_id = ?
+        ORDER BY id DESC
+        "#,
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    Ok((StatusCode::OK, Json(jobs)).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/jobs/{id}",
+    description = "Get the status of one of the currently authenticated user's background jobs.",
+    params(
+        ("id" = i64, Path, description = "The id of the job"),
+    ),
+    responses(
+        (status = OK, description = "Job found", body = JobInfo),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = NOT_FOUND, description = "Job not found", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_job(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Path(id): Path<i64>,
+) -> Result<Response, AppError> {
+    let Some(job) = sqlx::query_as!(
+        JobInfo,
+        r#"
+        SELECT id AS "id!", JSON_EXTRACT(payload, '$.type') AS "kind!: String",
+        status AS "status: JobStatus", attempts, last_error,
+        run_at AS "run_at: _", created_at AS "created_at: _",
+        modified_at AS "modified_at: _", finished_at AS "finished_at: _",
+        NULL AS "user_id?: Uuid"
+        FROM job WHERE id = ? AND user_id = ?
+        "#,
+        id,
+        user.id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(job_not_found());
+    };
+    Ok((StatusCode::OK, Json(job)).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/jobs/{id}",
+    description = "Cancel one of the currently authenticated user's background jobs. Only jobs that are still pending can be cancelled, a job that is already running finishes its current attempt but isn't retried. Jobs that delete the data of deleted files can't be cancelled.",
+    params(
+        ("id" = i64, Path, description = "The id of the job"),
+    ),
+    responses(
+        (status = OK, description = "Job cancelled", body = SuccessResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = NOT_FOUND, description = "Job not found", body = ErrorResponse),
+        (status = CONFLICT, description = "The job isn't pending anymore or can't be cancelled", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn cancel_job(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Path(id): Path<i64>,
+) -> Result<Response, AppError> {
+    let pending = JobStatus::Pending as i64;
+    let cancelled = JobStatus::Cancelled as i64;
+    let Some(job) = sqlx::query!(
+        r#"
+        SELECT status AS "status: JobStatus", JSON_EXTRACT(payload, '$.type') AS "kind?: String"
+        FROM job WHERE id = ? AND user_id = ?
+        "#,
+        id,
+        user.id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(job_not_found());
+    };
+    if job.status != JobStatus::Pending {
+        return Err(AppError::UserError((
+            StatusCode::CONFLICT,
+            "Only pending jobs can be cancelled".into(),
+        )));
+    }
+    if job
+        .kind
+        .is_some_and(|kind| UNCANCELLABLE_KINDS.contains(&kind.as_str()))
+    {
+        return Err(AppError::UserError((
+            StatusCode::CONFLICT,
+            "Jobs that clean up deleted files can't be cancelled".into(),
+        )));
+    }
+    // The worker may have finished the job in the meantime
+    let result = sqlx::query!(
+        r#"
+        UPDATE job SET status = ?, finished_at = CURRENT_TIMESTAMP
+        WHERE id = ? AND status = ?
+        "#,
+        cancelled,
+        id,
+        pending
+    )
+    .execute(&state.pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        return Err(AppError::UserError((
+            StatusCode::CONFLICT,
+            "Only pending jobs can be cancelled".into(),
+        )));
+    }
+    Ok((StatusCode::OK, success!("Job cancelled successfully")).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/jobs/{id}/retry",
+    description = "Queue one of the currently authenticated user's failed or cancelled background jobs again. The job is run as soon as possible and gets a fresh set of attempts.",
+    params(
+        ("id" = i64, Path, description = "The id of the job"),
+    ),
+    responses(
+        (status = OK, description = "Job queued again", body = SuccessResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = NOT_FOUND, description = "Job not found", body = ErrorResponse),
+        (status = CONFLICT, description = "The job is still pending or has already completed", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn retry_job(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Path(id): Path<i64>,
+) -> Result<Response, AppError> {
+    let pending = JobStatus::Pending as i64;
+    let failed = JobStatus::Failed as i64;
+    let cancelled = JobStatus::Cancelled as i64;
+    let result = sqlx::query!(
+        r#"
+        UPDATE job SET status = ?, attempts = 0, last_error = NULL,
+        run_at = CURRENT_TIMESTAMP, finished_at = NULL
+        WHERE id = ? AND user_id = ? AND status IN (?, ?)
+        "#,
+        pending,
+        id,
+        user.id,
+        failed,
+        cancelled
+    )
+    .execute(&state.pool)
+    .await?;
+    if result.rows_affected() == 0 {
+        let exists = sqlx::query!(
+            "SELECT id FROM job WHERE id = ? AND user_id = ?",
+            id,
+            user.id
+        )
+        .fetch_optional(&state.pool)
+        .await?
+        .is_some();
+        return Err(if exists {
+            AppError::UserError((
+                StatusCode::CONFLICT,
+                "Only failed or cancelled jobs can be retried".into(),
+            ))
+        } else {
+            job_not_found()
+        });
+    }
+    state.job_notify.notify_one();
+    Ok((StatusCode::OK, success!("Job queued successfully")).into_response())
+}
+
+fn job_not_found() -> AppError {
+    AppError::UserError((StatusCode::NOT_FOUND, "Job not found".into()))
+}
@@ -1,31 +1,37 @@
 use anyhow::{anyhow, Result};
+use at_rest::AtRestKey;
+use chrono::Utc;
+use config::Config;
+use jobs::Job;
+use lokr_types::admin::{BuildInfo, CleanupStats};
 use regex::Regex;
-use serde::Serialize;
 use state::AppState;
 use std::{
     env::current_dir,
+    future::Future,
     net::SocketAddr,
     path::PathBuf,
     str::FromStr,
     sync::{Arc, LazyLock},
-    time::Duration,
+    time::{Duration, Instant},
 };
+use tokio::net::TcpListener;
 use tower::ServiceBuilder;
 use tower_governor::GovernorLayer;
 use tower_governor::{governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor};
 use tower_http::{
     cors::{AllowOrigin, CorsLayer},
-    services::{ServeDir, ServeFile},
-    timeout::TimeoutLayer,
+    services::ServeDir,
+    timeout::{RequestBodyTimeoutLayer, ResponseBodyTimeoutLayer, TimeoutLayer},
     trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
     LatencyUnit, ServiceBuilderExt,
 };
-use tracing::{info, warn, Level};
+use tracing::{error, info, warn, Level};
 use upload::serve_auth;
 use url::Url;
 use utoipa::{
     openapi::security::{ApiKey, ApiKeyValue, SecurityScheme},
-    Modify, OpenApi, ToSchema,
+    Modify, OpenApi,
 };
 use utoipa_axum::{router::OpenApiRouter, routes};
 use utoipa_swagger_ui::SwaggerUi;
@@ -35,7 +41,7 @@ use axum::{
     http::{
         header::{
             ACCEPT, AUTHORIZATION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, COOKIE,
-            SET_COOKIE,
+            RETRY_AFTER, SET_COOKIE,
         },
         HeaderValue,
     },
@@ -47,33 +53,47 @@ use sqlx::{
     SqlitePool,
 };
 
+pub mod admin;
+pub mod at_rest;
 pub mod auth;
+pub mod client;
+pub mod config;
+pub mod cookie;
+pub mod docs;
+pub mod doctor;
 pub mod error;
+pub mod i18n;
+pub mod instance;
+pub mod jobs;
+pub mod migrations;
+pub mod permissions;
+pub mod public;
+pub mod rate_limit;
+pub mod retry;
 pub mod session;
 pub mod share;
 pub mod state;
+pub mod transfer;
 pub mod upload;
 pub mod users;
 pub mod utils;
 
 pub const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 
-/// Path to the data directory for the application.
-/// Falls back to the current directory if the data directory cannot be determined.
-pub static DATA_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
-    let mut path = match dirs::data_dir() {
-        Some(dir) => dir,
-        None => {
-            warn!("Could not determine data directory. Attempting to use current directory.");
-            current_dir().unwrap()
-        }
-    };
-    path.push(PKG_NAME);
-    if !path.exists() {
-        std::fs::create_dir_all(&path).unwrap();
+/// What the server was built from. The git hash is set by the build script,
+/// or by `LOKR_GIT_HASH` when building outside of a git checkout.
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION").into(),
+        git_hash: option_env!("LOKR_GIT_HASH")
+            .filter(|hash| !hash.is_empty())
+            .map(String::from),
+        release: !cfg!(debug_assertions),
     }
-    path
-});
+}
+
+/// How often the used space of every user is recomputed from their files
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
 
 /// Path to the config directory for the application.
 /// Falls back to the current directory if the config directory cannot be determined.
@@ -92,23 +112,9 @@ pub static CONFIG_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
     path
 });
 
-/// Path to where user uploads are stored.
-pub static UPLOAD_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
-    let path = DATA_DIR.join("uploads");
-    if !path.exists() {
-        std::fs::create_dir_all(&path).unwrap();
-    }
-    path
-});
-
-/// Path to where user avatar/profile images are stored.
-pub static AVATAR_DIR: LazyLock<PathBuf> = LazyLock::new(|| {
-    let path = DATA_DIR.join("avatars");
-    if !path.exists() {
-        std::fs::create_dir_all(&path).unwrap();
-    }
-    path
-});
+/// Origins of the frontend dev server, which runs separately from the API
+pub static DEV_ORIGIN: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^https?://localhost:\d+/?$").unwrap());
 
 /// Website host
 pub static HOST: LazyLock<String> =
@@ -123,6 +129,7 @@ pub static HOST: LazyLock<String> =
             users::logout,
             users::check_usage,
             users::get_logged_in_user,
+            users::get_key_manifest,
             users::update_user,
             users::update_totp,
             users::search_users,
@@ -133,24 +140,59 @@ pub static HOST: LazyLock<String> =
             upload::upload_file,
             upload::delete_file,
             upload::update_file,
+            upload::create_alias,
             upload::get_file,
             upload::get_file_metadata,
+            upload::get_file_uploaders,
+            upload::find_fingerprints,
+            upload::rewrap_keys,
             share::share_file,
             share::get_user_shared_file,
             share::get_link_shared_file,
+            share::browse_link_shared_file,
+            share::clear_link_credentials,
+            share::claim_file,
             share::delete_share_permission,
+            share::get_revoked_shares,
+            share::restore_share,
             share::update_share_permission,
             share::get_shared_links,
             share::get_shared_users,
             share::get_link_info,
+            share::create_mount,
+            share::get_mounts,
+            share::delete_mount,
+            permissions::get_capabilities,
             session::get_sessions,
             session::delete_session,
+            session::verify_session,
+            admin::get_stats,
+            admin::update_features,
+            admin::get_jobs,
+            admin::get_config,
+            jobs::get_jobs,
+            jobs::get_job,
+            jobs::cancel_job,
+            jobs::retry_job,
+            instance::get_features,
+            instance::get_metadata_versions,
+            instance::get_time,
+            instance::get_instance_stats,
+            public::update_public_profile,
+            public::publish_link,
+            public::unpublish_link,
+            public::get_public_profile,
         ),
         tags(
             (name = "users", description = "User related operations"),
             (name = "upload", description = "File and directory uploading"),
             (name = "session", description = "User session management"),
             (name = "share", description = "File and directory sharing"),
+            (name = "permissions", description = "What the current credentials allow"),
+            (name = "admin", description = "Instance administration"),
+            (name = "jobs", description = "Background jobs of the user"),
+            (name = "instance", description = "Information about the instance"),
+            (name = "public", description = "Public profiles"),
         )
     )]
 struct ApiDoc;
@@ -168,31 +210,58 @@ impl Modify for SecurityAddon {
     }
 }
 
-#[derive(Serialize, ToSchema)]
-pub struct SuccessResponse {
-    #[schema(example = "Yay! It worked!")]
-    pub message: String,
+/// Serve the OpenAPI document of every API version along with a Swagger UI
+/// that can switch between them
+fn docs_ui(open_api: &utoipa::openapi::OpenApi) -> SwaggerUi {
+    let mut ui = SwaggerUi::new("/docs");
+    for (i, version) in docs::VERSIONS.iter().enumerate().rev() {
+        let doc = docs::version_doc(open_api, version);
+        // Keep the unversioned document around for existing generators, always
+        // pointing at the current version
+        if i == docs::VERSIONS.len() - 1 {
+            ui = ui.url("/api-docs/openapi.json", doc.clone());
+        }
+        ui = ui.url(format!("/api-docs/{}/openapi.json", version.name), doc);
+    }
+    ui
 }
 
+pub use lokr_types::{DryRunQuery, SuccessResponse};
+
 #[macro_export]
 macro_rules! success {
     ($message:expr) => {{
         ::axum::extract::Json($crate::SuccessResponse {
-            message: ($message).into(),
+            message: $crate::i18n::translate(($message).into()),
         })
     }};
 }
 
 /// Start up the HTTP server and listen for incoming requests
 /// on port 6969.
-pub async fn start_server(pool: SqlitePool) -> Result<()> {
-    let origin_regex = Regex::new(r"^https?://localhost:\d+/?$").unwrap();
+pub async fn start_server(pool: SqlitePool, config: Config) -> Result<()> {
+    // run our app with hyper, listening globally on port 6969
+    let listener = TcpListener::bind("0.0.0.0:6969").await?;
+    serve(pool, config, listener, async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install CTRL+C signal handler");
+    })
+    .await
+}
+
+/// Serve the API on `listener` until `shutdown` completes.
+/// Split out of [`start_server`] so tests can run the server on any port
+/// with their own configuration.
+pub async fn serve(
+    pool: SqlitePool,
+    config: Config,
+    listener: TcpListener,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> Result<()> {
     let cors = CorsLayer::very_permissive()
-        .allow_origin(AllowOrigin::predicate({
-            let origin_regex = origin_regex.clone();
-            move |origin: &HeaderValue, _: _| {
-                origin_regex.is_match(origin.to_str().unwrap_or_default())
-            }
+        .allow_origin(AllowOrigin::predicate(move |origin: &HeaderValue, _: _| {
+            DEV_ORIGIN.is_match(origin.to_str().unwrap_or_default())
         }))
         .allow_headers([
             AUTHORIZATION,
@@ -209,6 +278,10 @@ pub async fn start_server(pool: SqlitePool) -> Result<()> {
             CONTENT_LENGTH,
             ACCEPT,
             SET_COOKIE,
+            RETRY_AFTER,
+            rate_limit::RATELIMIT_LIMIT.clone(),
+            rate_limit::RATELIMIT_REMAINING.clone(),
+            rate_limit::RATELIMIT_RESET.clone(),
         ]);
 
     let sensitive_headers: Arc<[_]> = [AUTHORIZATION, COOKIE].into();
@@ -221,9 +294,10 @@ pub async fn start_server(pool: SqlitePool) -> Result<()> {
     // until a maximum of 30 requests are reached.
     let ip_governor_config = Arc::new(unsafe {
         GovernorConfigBuilder::default()
-            .const_period(Duration::from_millis(200))
+            .const_period(rate_limit::PERIOD)
             .key_extractor(SmartIpKeyExtractor)
-            .burst_size(30)
+            .burst_size(rate_limit::BURST_SIZE)
+            .use_headers()
             .finish()
             .unwrap_unchecked()
     });
@@ -264,8 +338,10 @@ pub async fn start_server(pool: SqlitePool) -> Result<()> {
         // within a given time period. This is used to prevent abuse/attacks on the server.
         // This is safe to use because the it is only none if the period or burst size is 0.
         // Neither of which are the case here.
-        // Set a timeout
-        .layer(TimeoutLayer::new(Duration::from_secs(15)))
+        // Time out bodies that stop sending data. JSON routes also get an overall
+        // timeout, but uploads and downloads are only limited by this.
+        .layer(RequestBodyTimeoutLayer::new(config.body_idle_timeout))
+        .layer(ResponseBodyTimeoutLayer::new(config.body_idle_timeout))
         // Compress responses
         .compression()
         // Set a `Content-Type` if there isn't one already.
@@ -274,105 +350,180 @@ pub async fn start_server(pool: SqlitePool) -> Result<()> {
             HeaderValue::from_static("application/octet-stream"),
         );
 
-    let state = AppState::new(pool.clone());
+    config.create_dirs()?;
+    let features = instance::load_features(&pool)
+        .await
+        .map_err(|e| anyhow!("Unable to load feature flags: {}", e))?;
+    let state = AppState::new(pool.clone(), config, features);
     // Make a separate upload router for handling auth using middleware
     let upload_router = OpenApiRouter::new()
-        .nest_service("/api/file/data/", ServeDir::new(&*UPLOAD_DIR))
+        .nest_service("/api/file/data/", ServeDir::new(state.config.upload_dir()))
         .layer(axum::middleware::from_fn_with_state(
             state.clone(),
             serve_auth,
         ));
+    // Routes that stream large bodies are kept separate so they don't get the
+    // overall timeout of the JSON routes. Each of them gets its own body limit.
+    let streaming_router = OpenApiRouter::new()
+        .routes(routes!(upload::upload_file))
+        .route_layer(DefaultBodyLimit::max(state.config.max_upload_size))
+        .routes(routes!(users::upload_avatar))
+        .route_layer(DefaultBodyLimit::max(state.config.max_avatar_size))
+        .route_layer(GovernorLayer {
+            config: ip_governor_config.clone(),
+        })
+        .route_layer(axum::middleware::from_fn(rate_limit::headers));
     // Setup the router along with the OpenApi documentation router
     // for easy docs generation.
     let (api_router, open_api): (Router, _) = OpenApiRouter::with_openapi(ApiDoc::openapi())
-        .routes(routes!(upload::upload_file))
-        .route_layer(DefaultBodyLimit::max(1_000_000_000))
         .routes(routes!(users::search_users))
         .routes(routes!(upload::get_file_metadata))
         .routes(routes!(share::get_user_shared_file))
         .routes(routes!(share::get_link_shared_file))
-        .routes(routes!(users::upload_avatar))
+        .routes(routes!(share::browse_link_shared_file))
+        .routes(routes!(share::claim_file))
         .routes(routes!(upload::delete_file))
         .routes(routes!(upload::update_file))
+        .routes(routes!(upload::create_alias))
+        .routes(routes!(session::verify_session))
         .route_layer(GovernorLayer {
             config: ip_governor_config,
         })
+        .route_layer(axum::middleware::from_fn(rate_limit::headers))
         // Routes above this line are rate limited by the `GovernorLayer`
         .routes(routes!(users::create_user))
         .routes(routes!(users::authenticate_user))
         .routes(routes!(users::logout))
         .routes(routes!(users::check_usage))
         .routes(routes!(users::get_logged_in_user))
+        .routes(routes!(users::get_key_manifest))
         .routes(routes!(users::update_user))
         .routes(routes!(users::update_totp))
         .routes(routes!(users::get_user))
         .routes(routes!(users::update_preferences))
+        .routes(routes!(users::get_avatar))
         .routes(routes!(share::share_file))
+        .routes(routes!(upload::get_file_uploaders))
+        .routes(routes!(upload::find_fingerprints))
+        .routes(routes!(upload::rewrap_keys))
         .routes(routes!(share::get_shared_links))
         .routes(routes!(share::get_shared_users))
         .routes(routes!(share::delete_share_permission))
+        .routes(routes!(share::get_revoked_shares))
+        .routes(routes!(share::restore_share))
         .routes(routes!(share::update_share_permission))
         .routes(routes!(share::get_link_info))
+        .routes(routes!(share::clear_link_credentials))
+        .routes(routes!(share::create_mount, share::get_mounts))
+        .routes(routes!(share::delete_mount))
+        .routes(routes!(permissions::get_capabilities))
         .routes(routes!(session::get_sessions))
         .routes(routes!(session::delete_session))
+        .routes(routes!(admin::get_stats))
+        .routes(routes!(admin::update_features))
+        .routes(routes!(admin::get_jobs))
+        .routes(routes!(admin::get_config))
+        .routes(routes!(jobs::get_jobs))
+        .routes(routes!(jobs::get_job, jobs::cancel_job))
+        .routes(routes!(jobs::retry_job))
+        .routes(routes!(instance::get_features))
+        .routes(routes!(instance::get_metadata_versions))
+        .routes(routes!(instance::get_time))
+        .routes(routes!(instance::get_instance_stats))
+        .routes(routes!(public::update_public_profile))
+        .routes(routes!(public::publish_link))
+        .routes(routes!(public::unpublish_link))
+        .routes(routes!(public::get_public_profile))
+        // Routes above this line only deal with small JSON bodies
+        .route_layer(DefaultBodyLimit::max(state.config.max_body_size))
+        .route_layer(TimeoutLayer::new(state.config.request_timeout))
+        .merge(streaming_router)
         // Serve uploaded files from the uploads directory
         // These files are eincrypted so they can't be accessed directly,
         // but they can be downloaded by the user who uploaded them.
-        .nest_service("/api/avatars/", ServeDir::new(&*AVATAR_DIR))
         .merge(upload_router)
+        .layer(axum::middleware::from_fn(auth::verify_origin))
         .layer(cors)
-        .with_state(state)
+        .with_state(state.clone())
         .split_for_parts();
 
     let app = Router::new()
         .merge(api_router)
-        .merge(SwaggerUi::new("/docs").url("/api-docs/openapi.json", open_api))
-        // Serve the client files from the `../client/dist` directory
-        // We use a fallback `ServeDir` for this because we send all the requests to the same file and
-        // react-router handles the routing on the client side.
-        //
-        // We need the first fallback to serve all of the static files for the server and we need
-        // the second fallback to redirect all other requests to the index.html file for
-        // react-router.
-        .fallback_service(
-            ServeDir::new("../client/dist").fallback(ServeFile::new("../client/dist/index.html")),
-        )
+        .merge(docs_ui(&open_api))
+        .merge(client::router(&state.config.client_dir))
+        .layer(axum::middleware::from_fn(i18n::localize))
         .layer(middleware);
 
-    // run our app with hyper, listening globally on port 6969
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:6969").await.unwrap();
+    // Finish or remove any uploads that were interrupted, e.g. by a crash or a restart
+    utils::clean_temp_files(
+        &state.pool,
+        &state.config.temp_dir(),
+        &state.config.upload_dir(),
+    )
+    .await;
 
     // Start the cleaner task
     let cleaner_task = tokio::task::spawn({
-        let pool = pool.clone();
+        let state = state.clone();
         async move {
+            let mut last_reconciled: Option<Instant> = None;
             loop {
+                // Fix any drift in the used space of users, starting with whatever
+                // built up while the server wasn't running
+                if last_reconciled.is_none_or(|time| time.elapsed() >= RECONCILE_INTERVAL) {
+                    match jobs::enqueue(&state.pool, None, &Job::ReconcileUsedSpace).await {
+                        Ok(()) => state.job_notify.notify_one(),
+                        Err(e) => error!("Unable to queue used space reconciliation: {}", e),
+                    }
+                    last_reconciled = Some(Instant::now());
+                }
                 tokio::time::sleep(Duration::from_secs(300)).await;
-                utils::clean_up(&pool).await;
+                let stats = utils::clean_up(&state.pool).await;
+                if stats != CleanupStats::default() {
+                    info!("Cleaned up {:?}", stats);
+                }
+                let mut totals = state.cleanup.lock().unwrap();
+                totals.runs += 1;
+                totals.last_run_at = Some(Utc::now());
+                totals.removed += stats;
             }
         }
     });
 
-    info!("Server listening on port 6969");
+    // Start the job worker
+    let job_task = tokio::task::spawn(jobs::run_worker(state.clone()));
+
+    // Log what the server is running with, so operators can check it in the logs
+    let address = listener.local_addr()?;
+    let build = build_info();
+    info!(
+        version = %build.version,
+        git_hash = build.git_hash.as_deref().unwrap_or("unknown"),
+        release = build.release,
+        %address,
+        "Server listening on {}",
+        address
+    );
+    info!(
+        config = %serde_json::to_string(&state.config.effective())?,
+        features = %serde_json::to_string(&state.features())?,
+        "Effective configuration"
+    );
     axum::serve(
         listener,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
-    .with_graceful_shutdown(async {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to install CTRL+C signal handler");
-    })
+    .with_graceful_shutdown(shutdown)
     .await?;
     pool.close().await;
     cleaner_task.abort();
+    job_task.abort();
     Ok(())
 }
 
-/// Initialize the database by creating the database file and running the migrations.
-/// Returns a connection pool to the database.
-pub async fn init_db(db_url: &Url) -> Result<SqlitePool> {
-    let pool: SqlitePool = SqlitePool::connect_lazy_with(
+/// Open a connection pool to the database, creating the database file if it doesn't exist
+pub fn connect_db(db_url: &Url) -> Result<SqlitePool> {
+    Ok(SqlitePool::connect_lazy_with(
         SqliteConnectOptions::from_str(db_url.as_str())?
             .foreign_keys(true)
             .create_if_missing(true)
@@ -381,19 +532,41 @@ pub async fn init_db(db_url: &Url) -> Result<SqlitePool> {
             // as it provides extra performance benefits
             // at the cost of durability
             .synchronous(SqliteSynchronous::Normal),
-    );
+    ))
+}
+
+/// Initialize the database by creating the database file and running the migrations.
+/// Returns a connection pool to the database.
+pub async fn init_db(db_url: &Url, at_rest_key: Option<&AtRestKey>) -> Result<SqlitePool> {
+    // A backup that was interrupted leaves an unencrypted copy of the database behind
+    match migrations::remove_stale_copies(db_url) {
+        Ok(0) => {}
+        Ok(count) => info!(
+            "Removed {} copies left behind by interrupted backups",
+            count
+        ),
+        Err(e) => warn!(
+            "Unable to remove copies left behind by interrupted backups: {}",
+            e
+        ),
+    }
+    let pool = connect_db(db_url)?;
     // Check if there is a version mismatch between the migrations and the database
     // If there is, delete the database file and run the migrations again
-    match sqlx::migrate!("./migrations").run(&pool).await {
-        Err(MigrateError::VersionMismatch(_)) => {
-            std::fs::remove_file(
-                db_url
-                    .to_file_path()
-                    .map_err(|_| anyhow!("Unable to convert db url to file path"))?,
-            )?;
+    match migrations::MIGRATOR.run(&pool).await {
+        Err(MigrateError::VersionMismatch(version)) => {
+            // Keep a copy of what is about to be deleted so it can be recovered
+            let backup = migrations::backup(db_url, at_rest_key).await?;
+            warn!(
+                "Migration {} changed since it was applied, recreating the database. The old database was backed up to {}",
+                version,
+                backup.display()
+            );
+            pool.close().await;
+            std::fs::remove_file(migrations::db_path(db_url)?)?;
             // Pin the future so we can call it recursively within the same async function
             // Will get a recursion error otherwise if we don't
-            Box::pin(init_db(db_url)).await?;
+            return Box::pin(init_db(db_url, at_rest_key)).await;
         }
         // We don't know how to deal with the other errors
         // but we can't continue so just return early with them
@@ -1,8 +1,28 @@
+use std::path::Path;
+
 use anyhow::{anyhow, Result};
-use lokr_api::{init_db, start_server, DATA_DIR};
+use lokr_api::{
+    at_rest,
+    config::Config,
+    doctor::{self, Severity},
+    init_db,
+    migrations::{self, MigrationState},
+    start_server,
+};
+use tracing::{error, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use url::Url;
 
+const USAGE: &str = "\
+Usage:
+    lokr-api                          Start the server, applying any pending migrations
+    lokr-api doctor                   Check the environment and config without starting the server
+    lokr-api migrate                  Apply pending migrations without starting the server
+    lokr-api migrate --backup-first   Back up the database before applying pending migrations
+    lokr-api migrate --dry-run        Show the SQL of pending migrations without applying them
+    lokr-api migrate status           List every migration and whether it has been applied
+    lokr-api decrypt <file>           Write a backup or avatar encrypted with LOKR_AT_REST_KEY to stdout";
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::registry()
@@ -13,9 +33,126 @@ async fn main() -> Result<()> {
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
-    let url = Url::from_file_path(&*DATA_DIR.join("api.db"))
-        .map_err(|_| anyhow!("Invalid database URL"))?;
-    let pool = init_db(&url).await?;
-    start_server(pool).await?;
+    let config = Config::from_env();
+    let url =
+        Url::from_file_path(config.database_path()).map_err(|_| anyhow!("Invalid database URL"))?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {
+            check_environment(&config).await?;
+            let pool = init_db(&url, config.at_rest_key.as_ref()).await?;
+            start_server(pool, config).await?;
+        }
+        Some("doctor") if args.len() == 1 => doctor(&config).await?,
+        Some("migrate") => {
+            config.create_dirs()?;
+            migrate(&config, &url, &args[1..]).await?
+        }
+        Some("decrypt") if args.len() == 2 => decrypt(&config, &args[1]).await?,
+        Some(_) => return Err(anyhow!("Unknown command\n\n{}", USAGE)),
+    }
     Ok(())
 }
+
+/// Log the problems found by the doctor before starting the server,
+/// refusing to start if any of them would break requests
+async fn check_environment(config: &Config) -> Result<()> {
+    let checks = doctor::run(config).await;
+    for check in &checks {
+        match check.severity {
+            Severity::Ok => {}
+            Severity::Warning => warn!("{}", check.message),
+            Severity::Error => error!("{}", check.message),
+        }
+    }
+    if doctor::has_errors(&checks) {
+        return Err(anyhow!(
+            "The server can't start until the errors above are fixed"
+        ));
+    }
+    Ok(())
+}
+
+/// Print the result of every check, failing if any of them found an error
+async fn doctor(config: &Config) -> Result<()> {
+    let checks = doctor::run(config).await;
+    for check in &checks {
+        println!("{}", check);
+    }
+    if doctor::has_errors(&checks) {
+        return Err(anyhow!("Found problems that stop the server from starting"));
+    }
+    Ok(())
+}
+
+async fn migrate(config: &Config, url: &Url, args: &[String]) -> Result<()> {
+    let mut show_status = false;
+    let mut dry_run = false;
+    let mut backup_first = false;
+    for arg in args {
+        match arg.as_str() {
+            "status" => show_status = true,
+            "--dry-run" => dry_run = true,
+            "--backup-first" => backup_first = true,
+            _ => return Err(anyhow!("Unknown argument `{}`\n\n{}", arg, USAGE)),
+        }
+    }
+    let statuses = migrations::status(url).await?;
+    if show_status {
+        for status in &statuses {
+            println!(
+                "{:04} {:<8} {}",
+                status.version,
+                format!("{:?}", status.state).to_lowercase(),
+                status.description
+            );
+        }
+        return Ok(());
+    }
+    let pending: Vec<_> = statuses
+        .iter()
+        .filter(|status| status.state == MigrationState::Pending)
+        .collect();
+    if dry_run {
+        for status in statuses.iter().filter(|status| {
+            matches!(
+                status.state,
+                MigrationState::Modified | MigrationState::Unknown
+            )
+        }) {
+            println!(
+                "-- {:04} {} is {:?} in the database, migrating will fail",
+                status.version, status.description, status.state
+            );
+        }
+        if pending.is_empty() {
+            println!("No pending migrations");
+        }
+        for status in pending {
+            println!("-- {:04} {}", status.version, status.description);
+            println!("{}", status.sql.as_deref().unwrap_or_default().trim_end());
+            println!();
+        }
+        return Ok(());
+    }
+    if let Some(backup) =
+        migrations::migrate(url, backup_first, config.at_rest_key.as_ref()).await?
+    {
+        println!("Backed up the database to {}", backup.display());
+    }
+    if pending.is_empty() {
+        println!("No pending migrations");
+    } else {
+        println!("Applied {} migrations", pending.len());
+    }
+    Ok(())
+}
+
+/// Decrypt a file the server encrypted at rest, like a database backup
+async fn decrypt(config: &Config, path: &str) -> Result<()> {
+    at_rest::open_file(
+        config.at_rest_key.as_ref(),
+        Path::new(path),
+        &mut std::io::stdout().lock(),
+    )
+}
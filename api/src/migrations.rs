@@ -0,0 +1,238 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use sqlx::{
+    migrate::{Migration, Migrator},
+    SqlitePool,
+};
+use url::Url;
+
+use crate::{
+    at_rest::{self, AtRestKey},
+    connect_db,
+};
+
+/// The migrations built into the binary
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Where a migration stands compared to the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied,
+    /// Not applied to the database yet, will be applied on the next start
+    Pending,
+    /// Applied, but the migration changed since then.
+    /// The server deletes the database on startup when it finds one of these.
+    Modified,
+    /// Applied to the database, but not part of this build,
+    /// usually because the database was used by a newer version
+    Unknown,
+}
+
+#[derive(Debug, Clone)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+    /// The SQL of the migration, if it is part of this build
+    pub sql: Option<String>,
+}
+
+impl MigrationStatus {
+    fn new(migration: &Migration, state: MigrationState) -> Self {
+        Self {
+            version: migration.version,
+            description: migration.description.to_string(),
+            state,
+            sql: Some(migration.sql.to_string()),
+        }
+    }
+}
+
+/// Compare the migrations of this build with the ones applied to the database
+/// without changing anything. A database that doesn't exist yet has every
+/// migration pending.
+pub async fn status(db_url: &Url) -> Result<Vec<MigrationStatus>> {
+    let mut applied = if db_path(db_url)?.exists() {
+        let pool = connect_db(db_url)?;
+        let applied = applied_migrations(&pool).await?;
+        pool.close().await;
+        applied
+    } else {
+        HashMap::new()
+    };
+    let mut statuses: Vec<_> = MIGRATOR
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .map(|migration| {
+            let state = match applied.remove(&migration.version) {
+                Some((_, checksum)) if checksum == *migration.checksum => MigrationState::Applied,
+                Some(_) => MigrationState::Modified,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus::new(migration, state)
+        })
+        .collect();
+    statuses.extend(
+        applied
+            .into_iter()
+            .map(|(version, (description, _))| MigrationStatus {
+                version,
+                description,
+                state: MigrationState::Unknown,
+                sql: None,
+            }),
+    );
+    statuses.sort_by_key(|status| status.version);
+    Ok(statuses)
+}
+
+/// Apply the pending migrations, refusing to touch a database with modified or
+/// unknown migrations instead of deleting it like the server does on startup.
+/// Returns the path of the backup if `backup_first` is set and there was anything
+/// to back up and migrate.
+pub async fn migrate(
+    db_url: &Url,
+    backup_first: bool,
+    key: Option<&AtRestKey>,
+) -> Result<Option<PathBuf>> {
+    let statuses = status(db_url).await?;
+    if let Some(status) = statuses.iter().find(|status| {
+        matches!(
+            status.state,
+            MigrationState::Modified | MigrationState::Unknown
+        )
+    }) {
+        return Err(anyhow!(
+            "Migration {} ({}) is {:?} in the database, refusing to migrate",
+            status.version,
+            status.description,
+            status.state
+        ));
+    }
+    if !statuses
+        .iter()
+        .any(|status| status.state == MigrationState::Pending)
+    {
+        return Ok(None);
+    }
+    let backup = if backup_first && db_path(db_url)?.exists() {
+        Some(backup(db_url, key).await?)
+    } else {
+        None
+    };
+    let pool = connect_db(db_url)?;
+    MIGRATOR.run(&pool).await?;
+    pool.close().await;
+    Ok(backup)
+}
+
+/// Copy the database next to itself with the current time in its name,
+/// encrypting it with `key` if there is one.
+/// `VACUUM INTO` is used instead of copying the file so that anything still
+/// in the WAL is included and the copy is consistent.
+pub async fn backup(db_url: &Url, key: Option<&AtRestKey>) -> Result<PathBuf> {
+    let db_path = db_path(db_url)?;
+    let file_name = db_path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid database path"))?
+        .to_string_lossy();
+    let backup_path = db_path.with_file_name(format!(
+        "{}.{}.bak",
+        file_name,
+        Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+    if backup_path.exists() {
+        return Err(anyhow!("{} already exists", backup_path.display()));
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Backups hold everything in the database, so don't let other users read them
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    // SQLite can only write the copy to a file, so it is encrypted afterwards.
+    // It accepts an empty file, which lets the copy be created with the same permissions.
+    let copy_path = backup_path.with_extension("tmp");
+    options.open(&copy_path)?;
+    let pool = connect_db(db_url)?;
+    let copied = sqlx::query("VACUUM INTO ?")
+        .bind(copy_path.to_string_lossy())
+        .execute(&pool)
+        .await;
+    pool.close().await;
+    let sealed = match (copied, key) {
+        (Err(e), _) => Err(e.into()),
+        (Ok(_), None) => std::fs::rename(&copy_path, &backup_path).map_err(Into::into),
+        (Ok(_), Some(key)) => {
+            let (key, copy_path, backup_path) =
+                (key.clone(), copy_path.clone(), backup_path.clone());
+            tokio::task::spawn_blocking(move || {
+                at_rest::seal_file(&key, &copy_path, &backup_path, &options)
+            })
+            .await?
+        }
+    };
+    if copy_path.exists() {
+        tokio::fs::remove_file(&copy_path).await?;
+    }
+    // Don't leave a backup behind that can't be restored
+    if sealed.is_err() && backup_path.exists() {
+        tokio::fs::remove_file(&backup_path).await?;
+    }
+    sealed?;
+    Ok(backup_path)
+}
+
+/// Remove unencrypted copies left behind by backups that were interrupted,
+/// returning how many were removed
+pub fn remove_stale_copies(db_url: &Url) -> Result<usize> {
+    let db_path = db_path(db_url)?;
+    let (Some(dir), Some(file_name)) = (db_path.parent(), db_path.file_name()) else {
+        return Err(anyhow!("Invalid database path"));
+    };
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let prefix = format!("{}.", file_name.to_string_lossy());
+    let mut removed = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_copy = path.file_name().is_some_and(|name| {
+            let name = name.to_string_lossy();
+            name.starts_with(&prefix) && name.ends_with(".tmp")
+        });
+        if is_copy {
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+pub(crate) fn db_path(db_url: &Url) -> Result<PathBuf> {
+    db_url
+        .to_file_path()
+        .map_err(|_| anyhow!("Unable to convert db url to file path"))
+}
+
+/// The version, description and checksum of every migration applied to the database
+async fn applied_migrations(pool: &SqlitePool) -> Result<HashMap<i64, (String, Vec<u8>)>> {
+    let has_table: bool = sqlx::query_scalar(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(pool)
+    .await?;
+    if !has_table {
+        return Ok(HashMap::new());
+    }
+    let rows: Vec<(i64, String, Vec<u8>)> = sqlx::query_as(
+        "SELECT version, description, checksum FROM _sqlx_migrations WHERE success ORDER BY version",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(version, description, checksum)| (version, (description, checksum)))
+        .collect())
+}
@@ -0,0 +1,328 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::{headers::Cookie, TypedHeader};
+use sqlx::{Executor, Sqlite, SqlitePool};
+use tracing::instrument;
+use uuid::Uuid;
+
+pub use lokr_types::permissions::{AccessLevel, Capabilities, CapabilityQuery};
+
+use crate::{
+    auth::SessionAuth,
+    error::{AppError, ErrorResponse},
+    share::{stored_audience, LinkAudience},
+    state::AppState,
+};
+
+/// Who is trying to access a file.
+/// A request can come from a logged in user, through a share link, or both.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Accessor<'a> {
+    pub user_id: Option<Uuid>,
+    pub link_id: Option<Uuid>,
+    /// The password hash of the link, taken from the cookie set when the link was unlocked
+    pub link_password: Option<&'a str>,
+}
+
+/// What an accessor is allowed to do with a file, ordered from least to most access
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    View,
+    Edit,
+    Owner,
+}
+
+/// Get the highest access the accessor has to a file, or `None` if the file
+/// doesn't exist or the accessor can't access it.
+/// Sharing a directory shares everything inside of it, so shares of any of the
+/// file's ancestors count as well.
+pub async fn file_access<'a, E: Executor<'a, Database = Sqlite>>(
+    db: E,
+    file_id: Uuid,
+    accessor: &Accessor<'_>,
+) -> Result<Option<Access>, AppError> {
+    let row = sqlx::query!(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id, owner_id
+            FROM file
+            WHERE id = ?  -- the file we're checking
+            UNION ALL
+            SELECT f.id, f.parent_id, f.owner_id
+            FROM file f
+            JOIN ancestors a ON f.id = a.parent_id
+        )
+        SELECT
+            COALESCE(MAX(a.owner_id = ?), FALSE) AS "owner!: bool",
+            COALESCE(MAX(su.edit_permission OR (sl.edit_permission AND NOT sl.add_only)), FALSE) AS "edit!: bool",
+            COUNT(su.file_id) + COUNT(sl.file_id) AS "shares!: i64"
+        FROM ancestors a
+        LEFT JOIN share_user AS su
+        ON su.file_id = a.id AND su.user_id = ?
+        LEFT JOIN share_link AS sl
+        ON sl.file_id = a.id AND sl.id = ? AND (sl.expires_at IS NULL OR DATETIME(sl.expires_at) >= CURRENT_TIMESTAMP)
+        AND (sl.password_hash IS NULL OR sl.password_hash = ?)
+        "#,
+        file_id,
+        accessor.user_id,
+        accessor.user_id,
+        accessor.link_id,
+        accessor.link_password,
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(if row.owner {
+        Some(Access::Owner)
+    } else if row.edit {
+        Some(Access::Edit)
+    } else if row.shares > 0 {
+        Some(Access::View)
+    } else {
+        None
+    })
+}
+
+impl From<Access> for AccessLevel {
+    fn from(access: Access) -> Self {
+        match access {
+            Access::View => Self::View,
+            Access::Edit => Self::Edit,
+            Access::Owner => Self::Owner,
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/capabilities",
+    description = "Get what the current credentials allow on a file, either as the logged in user, through a share link, or both. Files that can't be accessed at all are not found, like everywhere else.",
+    params(CapabilityQuery),
+    responses(
+        (status = OK, description = "Capabilities found", body = Capabilities),
+        (status = NOT_FOUND, description = "File not found", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The owner of the file shared files with the user before and chose to tell them why they were denied", body = ErrorResponse),
+    ),
+    security(
+        (),
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_capabilities(
+    State(state): State<AppState>,
+    user: Option<SessionAuth>,
+    TypedHeader(cookies): TypedHeader<Cookie>,
+    Query(query): Query<CapabilityQuery>,
+) -> Result<Response, AppError> {
+    let link_password = query
+        .link_id
+        .and_then(|l_id| cookies.get(&l_id.to_string()))
+        .and_then(|password_hash| urlencoding::decode(password_hash).ok());
+    let user_id = user.map(|user| user.0.id);
+    check_link_audience(&state, query.link_id, user_id).await?;
+    let accessor = Accessor {
+        user_id,
+        link_id: query.link_id,
+        link_password: link_password.as_deref(),
+    };
+    match file_capabilities(&state.pool, query.file_id, &accessor).await? {
+        Some(capabilities) => Ok((StatusCode::OK, Json(capabilities)).into_response()),
+        None => Err(denied(&state, query.file_id, &accessor).await),
+    }
+}
+
+/// Work out everything the accessor can do with a file, or `None` if they can't
+/// access it at all. This has to agree with the checks of the routes themselves.
+pub async fn file_capabilities(
+    pool: &SqlitePool,
+    file_id: Uuid,
+    accessor: &Accessor<'_>,
+) -> Result<Option<Capabilities>, AppError> {
+    let Some(access) = file_access(pool, file_id, accessor).await? else {
+        return Ok(None);
+    };
+    let row = sqlx::query!(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id
+            FROM file
+            WHERE id = ?  -- the file we're checking
+            UNION ALL
+            SELECT f.id, f.parent_id
+            FROM file f
+            JOIN ancestors a ON f.id = a.parent_id
+        )
+        SELECT
+            (SELECT is_directory FROM file WHERE id = ?) AS "is_directory!: bool",
+            (SELECT owner_id IS NULL AND parent_id IS NULL FROM file WHERE id = ?) AS "anonymous!: bool",
+            -- Add only links can upload too
+            COALESCE(MAX(su.edit_permission OR sl.edit_permission), FALSE) AS "upload!: bool",
+            -- Shares only allow changing what is inside of the shared file, not the file itself
+            COALESCE(MAX(
+                (su.edit_permission AND su.file_id != ?) OR
+                (sl.edit_permission AND NOT sl.add_only AND sl.file_id != ?)
+            ), FALSE) AS "change!: bool",
+            COALESCE(MAX(sl.file_id = ?), FALSE) AS "linked!: bool"
+        FROM ancestors a
+        LEFT JOIN share_user AS su
+        ON su.file_id = a.id AND su.user_id = ?
+        LEFT JOIN share_link AS sl
+        ON sl.file_id = a.id AND sl.id = ? AND (sl.expires_at IS NULL OR DATETIME(sl.expires_at) >= CURRENT_TIMESTAMP)
+        AND (sl.password_hash IS NULL OR sl.password_hash = ?)
+        "#,
+        file_id,
+        file_id,
+        file_id,
+        file_id,
+        file_id,
+        file_id,
+        accessor.user_id,
+        accessor.link_id,
+        accessor.link_password,
+    )
+    .fetch_one(pool)
+    .await?;
+    let owner = access == Access::Owner;
+    let upload = row.is_directory && (owner || row.upload);
+    // Same as the limit checked when uploading through the link
+    let upload_limit = match accessor.link_id {
+        Some(link_id) if upload && !owner => sqlx::query!(
+            r#"
+            WITH RECURSIVE descendants AS (
+                SELECT id, size FROM file
+                WHERE id = (SELECT file_id FROM share_link WHERE id = ?)
+                UNION ALL
+                SELECT f.id, f.size FROM file f
+                JOIN descendants d ON f.parent_id = d.id
+            )
+            SELECT max_size AS "max_size!",
+            (SELECT COALESCE(SUM(size), 0) FROM descendants) AS "used_size!: i64"
+            FROM share_link WHERE id = ? AND max_size IS NOT NULL
+            "#,
+            link_id,
+            link_id
+        )
+        .fetch_optional(pool)
+        .await?
+        .map(|link| (link.max_size - link.used_size).max(0)),
+        _ => None,
+    };
+    Ok(Some(Capabilities {
+        file_id,
+        access: access.into(),
+        view: true,
+        upload,
+        edit: owner || row.change,
+        delete: owner || row.change,
+        share: owner,
+        claim: accessor.user_id.is_some() && row.anonymous && row.linked,
+        upload_limit,
+    }))
+}
+
+/// Make sure the user is someone the link works for.
+/// Owners can always use their own links. Links that don't exist are let
+/// through so that the caller reports them the same way as before.
+pub async fn check_link_audience(
+    state: &AppState,
+    link_id: Option<Uuid>,
+    user_id: Option<Uuid>,
+) -> Result<(), AppError> {
+    let Some(link_id) = link_id else {
+        return Ok(());
+    };
+    let Some(link) = sqlx::query!(
+        r#"
+        SELECT sl.audience, f.owner_id AS "owner_id: Uuid"
+        FROM share_link sl
+        JOIN file f ON f.id = sl.file_id
+        WHERE sl.id = ?
+        "#,
+        link_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Ok(());
+    };
+    let audience = stored_audience(link.audience.as_deref());
+    if audience == LinkAudience::Anyone {
+        return Ok(());
+    }
+    let Some(user_id) = user_id else {
+        return Err(AppError::UserError((
+            StatusCode::UNAUTHORIZED,
+            "Log in to open this link".into(),
+        )));
+    };
+    let allowed = link.owner_id == Some(user_id)
+        || match audience {
+            LinkAudience::Anyone | LinkAudience::Accounts => true,
+            // Email addresses aren't verified, so nobody can be trusted to be in a domain.
+            // Links created before these were refused only work for their owner.
+            LinkAudience::EmailDomains { .. } => false,
+            LinkAudience::Users { user_ids } => user_ids.contains(&user_id),
+        };
+    if !allowed {
+        return Err(AppError::UserError((
+            StatusCode::FORBIDDEN,
+            "This link doesn't work for your account".into(),
+        )));
+    }
+    Ok(())
+}
+
+/// The error for an accessor that isn't allowed to do what they asked with a file.
+/// This is a 404 by default so that nobody can tell which files exist. Owners can
+/// opt in to giving a 403 with the reason instead to logged in users they have shared
+/// files with, including users whose shares were revoked, which makes a lot of
+/// confusion within teams easier to sort out.
+pub async fn denied(state: &AppState, file_id: Uuid, accessor: &Accessor<'_>) -> AppError {
+    match denial_reason(state, file_id, accessor).await {
+        Ok(Some(reason)) => AppError::AccessDenied(reason.into()),
+        Ok(None) => AppError::UserError((StatusCode::NOT_FOUND, "File not found".into())),
+        Err(e) => e,
+    }
+}
+
+/// Why the accessor was denied access to the file, if they are allowed to know
+async fn denial_reason(
+    state: &AppState,
+    file_id: Uuid,
+    accessor: &Accessor<'_>,
+) -> Result<Option<&'static str>, AppError> {
+    let Some(user_id) = accessor.user_id else {
+        return Ok(None);
+    };
+    let Some(owner) = sqlx::query!(
+        r#"
+        SELECT u.explain_denials,
+        EXISTS(
+            SELECT 1 FROM share_contact sc WHERE sc.owner_id = f.owner_id AND sc.user_id = ?
+        ) AS "known!: bool"
+        FROM file f
+        JOIN user u ON u.id = f.owner_id
+        WHERE f.id = ?
+        "#,
+        user_id,
+        file_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Ok(None);
+    };
+    if !owner.known || !(owner.explain_denials || state.config.explain_denials) {
+        return Ok(None);
+    }
+    Ok(Some(
+        match file_access(&state.pool, file_id, accessor).await? {
+            Some(_) => "You don't have permission to change this file",
+            None => "This file is not shared with you",
+        },
+    ))
+}
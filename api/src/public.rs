@@ -0,0 +1,245 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use tracing::instrument;
+use uuid::Uuid;
+
+pub use lokr_types::public::{
+    PublicFile, PublicProfile, PublicProfileUpdate, PublishRequest, MAX_PUBLIC_TITLE_LENGTH,
+};
+
+use crate::{
+    auth::SessionAuth,
+    error::{AppError, ErrorResponse},
+    instance,
+    state::AppState,
+    success, SuccessResponse,
+};
+
+#[utoipa::path(
+    put,
+    path = "/api/profile/public",
+    description = "Turn the public profile of the currently authenticated user on or off. Turning it off hides the profile without forgetting which files are listed on it.",
+    request_body(content = PublicProfileUpdate, description = "Whether the public profile should be shown"),
+    responses(
+        (status = OK, description = "Public profile successfully updated", body = SuccessResponse),
+        (status = FORBIDDEN, description = "Public profiles are disabled on this instance", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn update_public_profile(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Json(req): Json<PublicProfileUpdate>,
+) -> Result<Response, AppError> {
+    instance::require(
+        state.features().public_profiles,
+        "Public profiles are disabled on this instance",
+    )?;
+    sqlx::query!(
+        "UPDATE user SET public_profile = ? WHERE id = ?",
+        req.enabled,
+        user.id
+    )
+    .execute(&state.pool)
+    .await?;
+    Ok((
+        StatusCode::OK,
+        success!("Successfully updated public profile"),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/profile/public/files",
+    description = "List a share link on the public profile of the currently authenticated user, or change its title if it is already listed. The key of the link is stored on the server so that visitors of the profile can decrypt the file, so only publish files that are meant to be public.",
+    request_body(content = PublishRequest, description = "The link to publish"),
+    responses(
+        (status = OK, description = "Link successfully published", body = SuccessResponse),
+        (status = BAD_REQUEST, description = "Invalid title or key", body = ErrorResponse),
+        (status = FORBIDDEN, description = "Public profiles are disabled on this instance", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = NOT_FOUND, description = "The link does not exist or the user does not own its file", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state, req))]
+pub async fn publish_link(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Json(req): Json<PublishRequest>,
+) -> Result<Response, AppError> {
+    instance::require(
+        state.features().public_profiles,
+        "Public profiles are disabled on this instance",
+    )?;
+    let title = req.title.trim();
+    if title.is_empty() || title.chars().count() > MAX_PUBLIC_TITLE_LENGTH {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Title must be between 1 and {} characters",
+                MAX_PUBLIC_TITLE_LENGTH
+            ),
+        )));
+    }
+    // Raw AES-256 keys are 44 characters once base64 encoded, anything much
+    // longer than that isn't a key
+    if req.key.is_empty() || req.key.len() > 64 {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Invalid key".into(),
+        )));
+    }
+    // Only the owner of a file can publish links to it, as editors
+    // don't get to decide who the file is shared with
+    let published = sqlx::query!(
+        r#"
+        INSERT INTO public_file (link_id, user_id, title, link_key)
+        SELECT sl.id, ?, ?, ?
+        FROM share_link sl
+        JOIN file f ON f.id = sl.file_id
+        WHERE sl.id = ? AND f.owner_id = ?
+        ON CONFLICT(link_id) DO UPDATE SET title = excluded.title, link_key = excluded.link_key
+        "#,
+        user.id,
+        title,
+        req.key,
+        req.link_id,
+        user.id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if published == 0 {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Link not found".into(),
+        )));
+    }
+    Ok((StatusCode::OK, success!("Successfully published link")).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/profile/public/files/{link_id}",
+    description = "Remove a share link from the public profile of the currently authenticated user. The link itself keeps working.",
+    params(
+        ("link_id" = Uuid, Path, description = "The id of the published link")
+    ),
+    responses(
+        (status = OK, description = "Link successfully removed from the profile", body = SuccessResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = NOT_FOUND, description = "The link is not on the user's profile", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn unpublish_link(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Path(link_id): Path<Uuid>,
+) -> Result<Response, AppError> {
+    let removed = sqlx::query!(
+        "DELETE FROM public_file WHERE link_id = ? AND user_id = ?",
+        link_id,
+        user.id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if removed == 0 {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Link not found".into(),
+        )));
+    }
+    Ok((StatusCode::OK, success!("Successfully unpublished link")).into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/user/{username}/public",
+    description = "Get the public profile of a user and the files they listed on it. Expired links are left out.",
+    params(
+        ("username" = String, Path, description = "The username of the user")
+    ),
+    responses(
+        (status = OK, description = "Public profile found", body = PublicProfile),
+        (status = NOT_FOUND, description = "The user does not exist, does not have a public profile, or public profiles are disabled", body = ErrorResponse)
+    ),
+    security(
+        ()
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_public_profile(
+    State(state): State<AppState>,
+    Path(username): Path<String>,
+) -> Result<Response, AppError> {
+    // Hide every profile while the feature is disabled instead of
+    // deleting anything, so they come back if it is turned on again
+    if !state.features().public_profiles {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User not found".into(),
+        )));
+    }
+    let Some(user) = sqlx::query!(
+        r#"
+        SELECT id AS "id: Uuid", username, avatar AS avatar_extension, avatar_version
+        FROM user
+        WHERE username = ? AND public_profile
+        "#,
+        username
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "User not found".into(),
+        )));
+    };
+    let files = sqlx::query_as!(
+        PublicFile,
+        r#"
+        SELECT pf.link_id AS "link_id: Uuid", pf.title, pf.link_key AS key,
+        f.is_directory, f.size, sl.password_hash IS NOT NULL AS "password_protected!: bool",
+        sl.expires_at AS "expires_at: DateTime<Utc>", pf.created_at AS "published_at: DateTime<Utc>"
+        FROM public_file pf
+        JOIN share_link sl ON sl.id = pf.link_id
+        JOIN file f ON f.id = sl.file_id
+        WHERE pf.user_id = ?
+        AND (sl.expires_at IS NULL OR DATETIME(sl.expires_at) >= CURRENT_TIMESTAMP)
+        ORDER BY pf.created_at DESC
+        "#,
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Json(PublicProfile {
+            id: user.id,
+            username: user.username,
+            avatar_extension: user.avatar_extension,
+            avatar_version: user.avatar_version,
+            files,
+        }),
+    )
+        .into_response())
+}
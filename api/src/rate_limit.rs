@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderName},
+    middleware::Next,
+    response::Response,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use uuid::Uuid;
+
+use crate::error::AppError;
+
+/// How often a rate limited IP gets another request
+pub const PERIOD: Duration = Duration::from_millis(200);
+
+/// How many requests an IP can make at once before being rate limited
+pub const BURST_SIZE: u32 = 30;
+
+pub static RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+pub static RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+pub static RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+
+/// Replace the `x-ratelimit-*` headers added by the `GovernorLayer` with the
+/// standard `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers,
+/// so clients can pace themselves instead of running into 429s.
+/// `RateLimit-Reset` is the number of seconds until the full burst is available again,
+/// or until the next request is allowed if there are none left.
+pub async fn headers(request: Request, next: Next) -> Response {
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    let limit = take_number(headers, "x-ratelimit-limit");
+    let remaining = take_number(headers, "x-ratelimit-remaining");
+    let after = take_number(headers, "x-ratelimit-after");
+    headers.remove("x-ratelimit-whitelisted");
+    let (Some(limit), Some(remaining)) = (limit, remaining) else {
+        return response;
+    };
+    let reset = match after {
+        Some(after) => after,
+        None => (PERIOD * (limit.saturating_sub(remaining)) as u32)
+            .as_secs_f64()
+            .ceil() as u64,
+    };
+    headers.insert(RATELIMIT_LIMIT.clone(), limit.into());
+    headers.insert(RATELIMIT_REMAINING.clone(), remaining.into());
+    headers.insert(RATELIMIT_RESET.clone(), reset.into());
+    response
+}
+
+fn take_number(headers: &mut HeaderMap, name: &str) -> Option<u64> {
+    headers
+        .remove(name)
+        .and_then(|value| value.to_str().ok()?.parse().ok())
+}
+
+/// Limits how many uploads each user can have in progress at once.
+/// Users get a semaphore while they have uploads in progress, which is
+/// dropped again once all of them have finished.
+#[derive(Debug, Default)]
+pub struct UploadLimiter {
+    users: Mutex<HashMap<Uuid, Arc<Semaphore>>>,
+}
+
+impl UploadLimiter {
+    /// Start an upload for the user, which lasts until the returned permit is dropped.
+    /// A `max` of 0 means there is no limit.
+    pub fn acquire(
+        &self,
+        user_id: Uuid,
+        max: u32,
+    ) -> Result<Option<OwnedSemaphorePermit>, AppError> {
+        if max == 0 {
+            return Ok(None);
+        }
+        let mut users = self.users.lock().unwrap();
+        // Permits hold a reference to their semaphore, so a semaphore nothing
+        // else references has no uploads in progress
+        users.retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+        users
+            .entry(user_id)
+            .or_insert_with(|| Arc::new(Semaphore::new(max as usize)))
+            .clone()
+            .try_acquire_owned()
+            .map(Some)
+            .map_err(|_| AppError::TooManyUploads)
+    }
+}
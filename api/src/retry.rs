@@ -0,0 +1,98 @@
+use std::{
+    future::Future,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use lokr_types::admin::RetryStats;
+use tracing::warn;
+
+use crate::{error::AppError, state::AppState};
+
+/// How writes are retried when SQLite is busy with another writer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a write is attempted in total, including the first attempt
+    pub max_attempts: u32,
+    /// The delay before the first retry, which doubles with each retry.
+    /// Up to this much random jitter is added on top so that writers
+    /// that collided don't collide again.
+    pub base_delay: Duration,
+}
+
+impl RetryPolicy {
+    fn delay(&self, retry: u32) -> Duration {
+        let jitter = fastrand::u64(0..=self.base_delay.as_millis() as u64);
+        self.base_delay.saturating_mul(1 << retry.min(16)) + Duration::from_millis(jitter)
+    }
+}
+
+/// Counts of retried writes since the server started
+#[derive(Debug, Default)]
+pub struct RetryMetrics {
+    retries: AtomicU64,
+    exhausted: AtomicU64,
+}
+
+impl RetryMetrics {
+    pub fn stats(&self) -> RetryStats {
+        RetryStats {
+            retries: self.retries.load(Ordering::Relaxed),
+            exhausted: self.exhausted.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Whether the error is SQLite being busy, either `SQLITE_BUSY` itself or one
+/// of its extended codes like `SQLITE_BUSY_SNAPSHOT` (517).
+/// Reference: https://www.sqlite.org/rescode.html#busy
+pub fn is_busy(error: &AppError) -> bool {
+    let AppError::SqlxError(error) = error else {
+        return false;
+    };
+    error
+        .as_database_error()
+        .and_then(|e| e.code())
+        .and_then(|code| code.parse::<i32>().ok())
+        .is_some_and(|code| code & 0xff == 5)
+}
+
+/// Run a write, running it again with exponential backoff if SQLite is busy.
+/// Anything else is returned right away, as is the busy error once the
+/// attempts run out. `write` should start its own transaction so that a
+/// retry starts from scratch.
+pub async fn retry_transaction<T, F, Fut>(
+    state: &AppState,
+    operation: &'static str,
+    mut write: F,
+) -> Result<T, AppError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AppError>>,
+{
+    let policy = state.config.retry_policy();
+    let mut attempt = 1;
+    loop {
+        match write().await {
+            Err(e) if is_busy(&e) => {
+                if attempt >= policy.max_attempts {
+                    state.retries.exhausted.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        "Database still busy after {} attempts at {}",
+                        attempt, operation
+                    );
+                    return Err(e);
+                }
+                state.retries.retries.fetch_add(1, Ordering::Relaxed);
+                let delay = policy.delay(attempt);
+                warn!(
+                    "Database busy during {}, retrying in {:?} (attempt {} of {})",
+                    operation, delay, attempt, policy.max_attempts
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
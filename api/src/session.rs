@@ -4,28 +4,21 @@ use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use chrono::{DateTime, Utc};
-use serde::Serialize;
 use tracing::instrument;
-use utoipa::ToSchema;
+use uuid::Uuid;
+
+pub use lokr_types::session::{Session, StepUpRequest};
 
 use crate::{
-    auth::SessionAuth,
+    auth::{SessionAuth, User},
+    cookie::set_session_cookies,
     error::{AppError, ErrorResponse},
     state::AppState,
-    success, SuccessResponse,
+    success,
+    users::{verify_password, verify_totp},
+    SuccessResponse,
 };
 
-#[derive(Serialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub struct Session {
-    /// The session number. This is unique on a per-user basis.
-    number: i64,
-    created_at: DateTime<Utc>,
-    last_used_at: DateTime<Utc>,
-    user_agent: Option<String>,
-}
-
 #[utoipa::path(
     get,
     path = "/api/sessions",
@@ -94,3 +87,78 @@ pub async fn delete_session(
     };
     Ok((StatusCode::OK, success!("Session successfully deleted")).into_response())
 }
+
+#[utoipa::path(
+    post,
+    path = "/api/session/verify",
+    description = "Confirm that the currently authenticated user is still the one using the session by entering their password or, if they have TOTP enabled, a TOTP code. Sensitive operations are only allowed for a few minutes afterwards.",
+    request_body(content = StepUpRequest, description = "The password or TOTP code of the user"),
+    responses(
+        (status = OK, description = "Session successfully verified", body = SuccessResponse),
+        (status = BAD_REQUEST, description = "Neither a password nor a TOTP code was given, or the user doesn't have TOTP enabled", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated, or the password or TOTP code is wrong", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state, req))]
+pub async fn verify_session(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Json(req): Json<StepUpRequest>,
+) -> Result<Response, AppError> {
+    let db_user = sqlx::query!(
+        "SELECT password_hash, totp_enabled, totp_secret FROM user WHERE id = ?",
+        user.id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    match (req.totp_code, req.password) {
+        (Some(code), _) => match db_user.totp_secret {
+            Some(secret) if db_user.totp_enabled => verify_totp(&user, secret, &code)?,
+            _ => {
+                return Err(AppError::UserError((
+                    StatusCode::BAD_REQUEST,
+                    "TOTP is not enabled".into(),
+                )))
+            }
+        },
+        (None, Some(password)) => verify_password(&state, &password, &db_user.password_hash)?,
+        (None, None) => {
+            return Err(AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                "A password or TOTP code is required".into(),
+            )))
+        }
+    }
+    sqlx::query!(
+        "UPDATE session SET verified_at = CURRENT_TIMESTAMP WHERE user_id = ? AND number = ?",
+        user.id,
+        user.session_number
+    )
+    .execute(&state.pool)
+    .await?;
+    Ok((StatusCode::OK, success!("Session successfully verified")).into_response())
+}
+
+/// Replace the id of the user's current session with a new one and give it a new number,
+/// so a session id that was stolen before a sensitive change to the account can't be
+/// used afterwards. Returns the cookies that need to be set to keep the user logged in.
+pub async fn rotate_session(state: &AppState, user: &User) -> Result<[String; 2], AppError> {
+    let new_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+        UPDATE session SET id = ?,
+        number = (SELECT MAX(number) FROM session WHERE user_id = ?) + 1
+        WHERE user_id = ? AND number = ?
+        "#,
+        new_id,
+        user.id,
+        user.id,
+        user.session_number
+    )
+    .execute(&state.pool)
+    .await?;
+    Ok(set_session_cookies(&state.config, new_id))
+}
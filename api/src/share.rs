@@ -6,77 +6,42 @@ use argon2::{
 };
 use axum::{
     extract::{Path, Query, State},
-    http::{header::SET_COOKIE, StatusCode},
+    http::{
+        header::{CACHE_CONTROL, SET_COOKIE, VARY},
+        HeaderValue, StatusCode,
+    },
     response::{AppendHeaders, IntoResponse, Response},
     Json,
 };
 use axum_extra::{headers::Cookie, TypedHeader};
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
-use sqlx::{Executor, Sqlite};
+use sqlx::{Executor, Sqlite, SqlitePool};
 use tracing::instrument;
-use utoipa::ToSchema;
 use uuid::Uuid;
 
+pub use lokr_types::share::{
+    ClaimRequest, LinkAudience, Mount, MountRequest, RevokedShare, RevokedShareQuery,
+    ShareIdentifier, ShareRequest, ShareRequestType, ShareResponse, ShareResponseType,
+    ShareUpdateRequest, SharedFileQuery, UserShareResponse,
+};
+
 use crate::{
     auth::SessionAuth,
+    cookie::{link_cookie, SESSION_MAX_AGE},
     error::{AppError, ErrorResponse},
+    permissions::check_link_audience,
+    retry::retry_transaction,
     state::AppState,
     success,
-    upload::{is_owner, FileMetadata, FileQuery, FileResponse, UploadMetadata},
+    upload::{
+        check_wrapped_key, is_owner, FileMetadata, FileQuery, FileResponse, KeyAlgorithm,
+        LinkParams, UploadMetadata,
+    },
     users::PublicUser,
     utils::{get_file_users, Normalize},
     SuccessResponse,
 };
 
-/// An enum representing the type of sharing
-#[derive(Deserialize, ToSchema, Debug)]
-#[serde(rename_all = "camelCase", tag = "type")]
-pub enum ShareRequestType {
-    #[serde(rename_all = "camelCase")]
-    User {
-        user_id: Uuid,
-        encrypted_key: String,
-    },
-    Link {
-        expires: u64,
-        password: Option<String>,
-    },
-}
-
-/// A request to share a file with a user or generate a link
-#[derive(Deserialize, ToSchema, Debug)]
-pub struct ShareRequest {
-    #[serde(flatten)]
-    type_: ShareRequestType,
-    id: Uuid,
-    /// Whether the user/link should have editing permissions
-    edit: bool,
-}
-
-#[derive(Serialize, ToSchema)]
-#[serde(rename_all = "camelCase", tag = "type")]
-pub enum ShareResponseType {
-    #[serde(rename_all = "camelCase")]
-    User { user_id: Uuid },
-    #[serde(rename_all = "camelCase")]
-    Link {
-        link_id: Uuid,
-        expires_at: Option<DateTime<Utc>>,
-        password_protected: bool,
-    },
-}
-
-#[derive(Serialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub struct ShareResponse {
-    #[serde(flatten)]
-    pub type_: ShareResponseType,
-    edit_permission: bool,
-    created_at: DateTime<Utc>,
-    modified_at: DateTime<Utc>,
-}
-
 #[utoipa::path(
     post,
     path = "/api/share",
@@ -102,23 +67,36 @@ pub async fn share_file(
         } => Ok((
             StatusCode::OK,
             Json(
-                share_with_user(&state, body.id, &encrypted_key, user.id, user_id, body.edit)
-                    .await?,
+                retry_transaction(&state, "share with user", || {
+                    share_with_user(&state, body.id, &encrypted_key, user.id, user_id, body.edit)
+                })
+                .await?,
             ),
         )
             .into_response()),
-        ShareRequestType::Link { expires, password } => Ok((
+        ShareRequestType::Link {
+            expires,
+            password,
+            audience,
+        } => Ok((
             StatusCode::CREATED,
             Json(
-                share_with_link(
-                    &state,
-                    &state.pool,
-                    body.id,
-                    Some(user.id),
-                    expires,
-                    password,
-                    body.edit,
-                )
+                retry_transaction(&state, "share with link", || {
+                    share_with_link(
+                        &state,
+                        &state.pool,
+                        body.id,
+                        Some(user.id),
+                        expires,
+                        password.clone(),
+                        if body.edit {
+                            LinkPermission::Edit
+                        } else {
+                            LinkPermission::View
+                        },
+                        audience.clone(),
+                    )
+                })
                 .await?,
             ),
         )
@@ -126,7 +104,21 @@ pub async fn share_file(
     }
 }
 
+/// What a share link allows its users to do
+#[derive(Debug, Clone, Copy)]
+pub enum LinkPermission {
+    View,
+    Edit,
+    /// Files can be uploaded through the link, but nothing can be
+    /// moved, renamed, or deleted. The total size of the shared files
+    /// can optionally be capped.
+    AddOnly {
+        max_size: Option<i64>,
+    },
+}
+
 /// Helper function for sharing a file with using a link
+#[allow(clippy::too_many_arguments)]
 pub async fn share_with_link<'a, E: Executor<'a, Database = Sqlite>>(
     state: &AppState,
     db: E,
@@ -134,9 +126,20 @@ pub async fn share_with_link<'a, E: Executor<'a, Database = Sqlite>>(
     user: Option<Uuid>,
     expires: u64,
     password: Option<String>,
-    edit: bool,
+    permission: LinkPermission,
+    audience: LinkAudience,
 ) -> Result<ShareResponse, AppError> {
     let link = Uuid::new_v4();
+    let audience = normalize_audience(audience)?;
+    let stored_audience = match audience {
+        LinkAudience::Anyone => None,
+        _ => Some(serde_json::to_string(&audience)?),
+    };
+    let (edit, add_only, max_size) = match permission {
+        LinkPermission::View => (false, false, None),
+        LinkPermission::Edit => (true, false, None),
+        LinkPermission::AddOnly { max_size } => (true, true, max_size),
+    };
     let expires = (expires > 0).then(|| Utc::now() + Duration::from_secs(expires));
     // Check if the user owns the file
     // If the no user is provided, the file must be an anonymous file
@@ -180,14 +183,18 @@ pub async fn share_with_link<'a, E: Executor<'a, Database = Sqlite>>(
     // Everything is good so insert the link
     let row = sqlx::query!(
         r#"
-        INSERT INTO share_link (id, file_id, expires_at, password_hash, edit_permission) VALUES (?, ?, ?, ?, ?)
+        INSERT INTO share_link (id, file_id, expires_at, password_hash, edit_permission, add_only, max_size, audience)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
         RETURNING created_at AS "created_at!", modified_at AS "modified_at!"
         "#,
         link,
         file_id,
         expires,
         password_hash,
-        edit
+        edit,
+        add_only,
+        max_size,
+        stored_audience
     )
     .fetch_one(db)
     .await?;
@@ -197,6 +204,9 @@ pub async fn share_with_link<'a, E: Executor<'a, Database = Sqlite>>(
             link_id: link,
             expires_at: expires,
             password_protected: password_hash.is_some(),
+            add_only,
+            max_size,
+            audience,
         },
         edit_permission: edit,
         created_at: row.created_at.and_utc(),
@@ -204,6 +214,35 @@ pub async fn share_with_link<'a, E: Executor<'a, Database = Sqlite>>(
     })
 }
 
+/// Check that an audience can match anyone at all
+fn normalize_audience(audience: LinkAudience) -> Result<LinkAudience, AppError> {
+    let invalid = |message: &str| AppError::UserError((StatusCode::BAD_REQUEST, message.into()));
+    Ok(match audience {
+        // Users can set their email to anything, so a domain doesn't say who they are
+        LinkAudience::EmailDomains { .. } => {
+            return Err(invalid(
+                "Links can't be limited to email domains until email addresses are verified",
+            ))
+        }
+        LinkAudience::Users { mut user_ids } => {
+            user_ids.sort();
+            user_ids.dedup();
+            if user_ids.is_empty() {
+                return Err(invalid("At least one user is required"));
+            }
+            LinkAudience::Users { user_ids }
+        }
+        audience => audience,
+    })
+}
+
+/// Parse the audience stored with a link
+pub fn stored_audience(audience: Option<&str>) -> LinkAudience {
+    audience
+        .and_then(|audience| serde_json::from_str(audience).ok())
+        .unwrap_or_default()
+}
+
 /// Helper function for sharing a file directly with a user
 pub async fn share_with_user(
     state: &AppState,
@@ -269,7 +308,7 @@ pub async fn share_with_user(
     get,
     path = "/api/shared",
     description = "Get files shared with the user",
-    params(FileQuery),
+    params(FileQuery, SharedFileQuery),
     responses(
         (status = OK, description = "File successfully retrieved", body = FileResponse),
         (status = BAD_REQUEST, description = "Invalid query params", body = ErrorResponse),
@@ -284,8 +323,8 @@ pub async fn get_user_shared_file(
     State(state): State<AppState>,
     SessionAuth(user): SessionAuth,
     Query(params): Query<FileQuery>,
+    Query(filter): Query<SharedFileQuery>,
 ) -> Result<Response, AppError> {
-    let depth = params.depth.min(20);
     // Check if the user has access to the file
     if params.id.is_some() {
         let access_query = sqlx::query_scalar!(
@@ -321,6 +360,45 @@ pub async fn get_user_shared_file(
             )));
         }
     }
+    let (files, root) = shared_tree(&state.pool, user.id, &params, filter.owner_id).await?;
+    if params.id.is_some() && files.is_empty() {
+        Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "File not found".into(),
+        )))
+    } else {
+        let owners = filter.group_by_owner.then(|| {
+            let mut owners: HashMap<Uuid, Vec<Uuid>> = HashMap::new();
+            for id in &root {
+                if let Some(owner_id) = files.get(id).and_then(|file| file.owner_id) {
+                    owners.entry(owner_id).or_default().push(*id);
+                }
+            }
+            owners
+        });
+        Ok((
+            StatusCode::OK,
+            Json(FileResponse {
+                users: get_file_users(&state.pool, &files).await?,
+                files,
+                root,
+                owners,
+            }),
+        )
+            .into_response())
+    }
+}
+
+/// Get the files shared with the user as a tree, starting at the files directly
+/// shared with them or at `params.id`. The caller has to check that the user
+/// has access to the file first.
+pub async fn shared_tree(
+    pool: &SqlitePool,
+    user_id: Uuid,
+    params: &FileQuery,
+    owner_id: Option<Uuid>,
+) -> Result<(HashMap<Uuid, FileMetadata>, Vec<Uuid>), AppError> {
+    let depth = params.depth.min(20);
     // The query to get the shared files
     let query = sqlx::query!(
         r#"
@@ -342,11 +420,17 @@ pub async fn get_user_shared_file(
                     key_nonce, 
                     name_nonce, 
                     mime_type_nonce, 
+                    metadata_version,
+                    -- Keys shared with a user are wrapped with their public key
+                    IIF(share_user.encrypted_key IS NULL, key_algorithm, 1) AS key_algorithm,
                     owner_id,
                     uploader_id,
                     is_directory,
                     mime,
-                    size,
+                    IIF(blob_id IS NULL, size, (SELECT MAX(b.size) FROM file b
+                        WHERE b.id = file.blob_id OR b.blob_id = file.blob_id)) AS size,
+                    plaintext_size,
+                    blob_id,
                     file.created_at,
                     file.modified_at,
                     edit_permission
@@ -357,6 +441,7 @@ pub async fn get_user_shared_file(
                     (user_id IS NULL OR user_id = ?) AND 
                     -- Don't show files owned by the user, as they aren't shared
                     owner_id != ? AND
+                    owner_id = COALESCE(?, owner_id) AND
                     -- If no file id is provided, then show the root directory
                     -- We need to use COALESCE to ensure that only files in root directory
                     -- are shown if the file id is NULL. We can idenfify shared files in the root directory
@@ -375,11 +460,16 @@ pub async fn get_user_shared_file(
                     f.key_nonce, 
                     f.name_nonce, 
                     f.mime_type_nonce, 
+                    f.metadata_version,
+                    f.key_algorithm,
                     f.owner_id,
                     f.uploader_id,
                     f.is_directory,
                     f.mime,
-                    f.size,
+                    IIF(f.blob_id IS NULL, f.size, (SELECT MAX(b.size) FROM file b
+                        WHERE b.id = f.blob_id OR b.blob_id = f.blob_id)),
+                    f.plaintext_size,
+                    f.blob_id,
                     f.created_at,
                     f.modified_at,
                     NULL as "edit_permission"
@@ -400,24 +490,28 @@ pub async fn get_user_shared_file(
                 key_nonce, 
                 name_nonce, 
                 mime_type_nonce, 
+                metadata_version,
+                key_algorithm AS "key_algorithm!: KeyAlgorithm",
                 owner_id AS "owner_id: Uuid",
                 uploader_id AS "uploader_id: Uuid",
                 is_directory,
                 mime,
                 edit_permission AS "edit_permission?",
-                IIF(size - 16 < 0, 0, size - 16) AS "size!: i64",
+                plaintext_size AS "size!: i64",
+                size AS "ciphertext_size!: i64",
                 created_at,
                 modified_at
             FROM children ORDER BY depth ASC LIMIT ? OFFSET ?
     "#,
-        user.id,
-        user.id,
+        user_id,
+        user_id,
+        owner_id,
         params.id,
         depth,
         params.limit,
         params.offset
     )
-    .fetch_all(&state.pool);
+    .fetch_all(pool);
 
     // If the user has requested to include ancestors, we need to run a second query
     // We want to speed up computation, so if the user requests ancestors
@@ -438,6 +532,8 @@ pub async fn get_user_shared_file(
                 f.key_nonce, 
                 f.name_nonce, 
                 f.mime_type_nonce, 
+                f.metadata_version,
+                IIF(su.encrypted_key IS NULL, f.key_algorithm, 1) AS key_algorithm,
                 f.owner_id,
                 f.uploader_id,
                 f.is_directory,
@@ -462,11 +558,14 @@ pub async fn get_user_shared_file(
                 f.id,
                 IIF(su.file_id IS NOT NULL, NULL, f.parent_id) AS parent_id,
                 f.encrypted_name,
-                f.encrypted_key,
+                -- The ancestor that is directly shared has to be decrypted with the user's own key
+                COALESCE(su.encrypted_key, f.encrypted_key) AS encrypted_key,
                 f.file_nonce, 
                 f.key_nonce, 
                 f.name_nonce, 
                 f.mime_type_nonce, 
+                f.metadata_version,
+                IIF(su.encrypted_key IS NULL, f.key_algorithm, 1) AS key_algorithm,
                 f.owner_id,
                 f.uploader_id,
                 f.is_directory,
@@ -486,18 +585,21 @@ pub async fn get_user_shared_file(
                 id AS "id: Uuid",
                 parent_id AS "parent_id: Uuid", 
                 encrypted_name, 
-                encrypted_key, 
+                encrypted_key AS "encrypted_key!: String", 
                 owner_id AS "owner_id: Uuid",
                 uploader_id AS "uploader_id: Uuid",
                 file_nonce, 
                 key_nonce, 
                 name_nonce, 
                 mime_type_nonce, 
+                metadata_version,
+                key_algorithm AS "key_algorithm!: KeyAlgorithm",
                 is_directory AS "is_directory!",
                 mime,
                 -- Ancestors are always directories so their size must
                 -- be always be 0
                 0 AS "size!: i64",
+                0 AS "ciphertext_size!: i64",
                 edit_permission AS "edit_permission?",
                 created_at,
                 modified_at
@@ -505,13 +607,13 @@ pub async fn get_user_shared_file(
             WHERE depth > 0
             ORDER BY depth DESC
         "#,
-            user.id,
+            user_id,
             params.id,
-            user.id,
-            user.id,
-            user.id
+            user_id,
+            user_id,
+            user_id
         )
-        .fetch_all(&state.pool);
+        .fetch_all(pool);
         // Run both database queries concurrently
         let (query, ancestor_query) = tokio::try_join!(query, ancestor_query)?;
         let ancestors = ancestor_query.into_iter().map(|row| FileMetadata {
@@ -530,10 +632,20 @@ pub async fn get_user_shared_file(
                 name_nonce: row.name_nonce,
                 key_nonce: row.key_nonce,
                 mime_type_nonce: row.mime_type_nonce,
+                metadata_version: row.metadata_version,
+                key_algorithm: Some(row.key_algorithm),
+                plaintext_size: None,
             },
             size: row.size,
+            ciphertext_size: row.ciphertext_size,
             children: Vec::new(),
             edit_permission: row.edit_permission,
+            shared_user_count: None,
+            active_link_count: None,
+            shared_via_ancestor: None,
+            key_epoch: None,
+            mount_id: None,
+            alias_of: None,
         });
         (query, Some(ancestors))
     } else {
@@ -541,7 +653,7 @@ pub async fn get_user_shared_file(
     };
 
     // Convert the query result into a tree structure
-    let (files, root) = ancestors
+    Ok(ancestors
         .into_iter()
         .flatten()
         .chain(query.into_iter().map(|row| FileMetadata {
@@ -560,28 +672,22 @@ pub async fn get_user_shared_file(
                 name_nonce: row.name_nonce,
                 key_nonce: row.key_nonce,
                 mime_type_nonce: row.mime_type_nonce,
+                metadata_version: row.metadata_version,
+                key_algorithm: Some(row.key_algorithm),
+                plaintext_size: None,
             },
             size: row.size,
+            ciphertext_size: row.ciphertext_size,
             children: Vec::new(),
             edit_permission: row.edit_permission,
+            shared_user_count: None,
+            active_link_count: None,
+            shared_via_ancestor: None,
+            key_epoch: None,
+            mount_id: None,
+            alias_of: None,
         }))
-        .normalize();
-    if params.id.is_some() && files.is_empty() {
-        Err(AppError::UserError((
-            StatusCode::NOT_FOUND,
-            "File not found".into(),
-        )))
-    } else {
-        Ok((
-            StatusCode::OK,
-            Json(FileResponse {
-                users: get_file_users(&state.pool, &files).await?,
-                files,
-                root,
-            }),
-        )
-            .into_response())
-    }
+        .normalize())
 }
 
 #[utoipa::path(
@@ -594,19 +700,75 @@ pub async fn get_user_shared_file(
         (status = OK, description = "Files successfully retrieved", body = FileResponse),
         (status = BAD_REQUEST, description = "Invalid query params", body = ErrorResponse),
         (status = NOT_FOUND, description = "File not found", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "The link only works for logged in users", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The link doesn't work for the logged in user", body = ErrorResponse),
     ),
     security(
-        ()
+        (),
+        ("lokr_session_cookie" = [])
     )
 )]
 #[instrument(err, skip(state, link_request))]
 pub async fn get_link_shared_file(
     State(state): State<AppState>,
+    user: Option<SessionAuth>,
     Query(params): Query<FileQuery>,
     TypedHeader(cookie): TypedHeader<Cookie>,
     Path(link_id): Path<Uuid>,
     Json(link_request): Json<Option<String>>,
 ) -> Result<Response, AppError> {
+    link_shared_files(&state, user, params, Some(&cookie), link_id, link_request).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/shared/{link_id}/browse",
+    description = "Get files shared through a link without sending the password again. Once the password has been checked by `POST /api/shared/{link_id}`, the cookie it sets is enough to browse the link with GET requests, which the browser can cache. Links without a password work right away.",
+    params(FileQuery, ("link_id" = Uuid, Path, description = "The id of the share link")),
+    responses(
+        (status = OK, description = "Files successfully retrieved", body = FileResponse),
+        (status = BAD_REQUEST, description = "Invalid query params", body = ErrorResponse),
+        (status = NOT_FOUND, description = "File not found", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "The password hasn't been checked yet or has changed since, or the link only works for logged in users", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The link doesn't work for the logged in user", body = ErrorResponse),
+    ),
+    security(
+        (),
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn browse_link_shared_file(
+    State(state): State<AppState>,
+    user: Option<SessionAuth>,
+    Query(params): Query<FileQuery>,
+    cookie: Option<TypedHeader<Cookie>>,
+    Path(link_id): Path<Uuid>,
+) -> Result<Response, AppError> {
+    let cookie = cookie.as_ref().map(|TypedHeader(cookie)| cookie);
+    let response = link_shared_files(&state, user, params, cookie, link_id, None).await?;
+    // The files depend on the password cookie, so only the browser that sent it may cache them
+    Ok((
+        AppendHeaders([
+            (CACHE_CONTROL, HeaderValue::from_static("private")),
+            (VARY, HeaderValue::from_static("cookie")),
+        ]),
+        response,
+    )
+        .into_response())
+}
+
+/// Get the files shared through a link, checking the password from the body
+/// or, if there is none, the password hash remembered in the cookie
+async fn link_shared_files(
+    state: &AppState,
+    user: Option<SessionAuth>,
+    params: FileQuery,
+    cookie: Option<&Cookie>,
+    link_id: Uuid,
+    link_request: Option<String>,
+) -> Result<Response, AppError> {
+    check_link_audience(state, Some(link_id), user.map(|user| user.0.id)).await?;
     let depth = params.depth.min(20);
     // Check if the user has access to the file
     if params.id.is_some() {
@@ -646,54 +808,32 @@ pub async fn get_link_shared_file(
     }
 
     // Check if the password is correct
-    let password = if let Some(stored_hash) =
-        sqlx::query_scalar!("SELECT password_hash FROM share_link WHERE id = ?", link_id)
-            .fetch_optional(&state.pool)
-            .await?
-            .ok_or(AppError::UserError((
-                StatusCode::NOT_FOUND,
-                "Invalid share link".into(),
-            )))? {
-        // Attempt to read the password from the request body.
-        // If the password is not provided, then check the cookie to see if
-        // the user has already provided the correct password in the past.
-        // If neither, then reject the request.
-        match (link_request, cookie.get(&link_id.to_string())) {
-            (Some(password), _) if !password.is_empty() => {
-                tokio::task::block_in_place(|| {
-                    state
-                        .argon2
-                        .verify_password(
-                            password.as_bytes(),
-                            &PasswordHash::new(&stored_hash)
-                                .expect("Password hash should be valid"),
-                        )
-                        .map_err(|_| {
-                            AppError::UserError((
-                                StatusCode::UNAUTHORIZED,
-                                "Invalid password".into(),
-                            ))
-                        })
-                })?;
-            }
-            (_, Some(password_hash)) => {
-                let password_hash = urlencoding::decode(password_hash)?.to_string();
-                if password_hash != stored_hash {
-                    return Err(AppError::UserError((
-                        StatusCode::UNAUTHORIZED,
-                        "Invalid password".into(),
-                    )));
-                }
-            }
-            (_, _) => return Err(AppError::UserError((
-                StatusCode::UNAUTHORIZED,
-                "This link requires a password. Please provide a password inside the request body"
-                    .into(),
-            ))),
-        };
-        Some(stored_hash)
-    } else {
-        None
+    let from_cookie = link_request.as_deref().is_none_or(str::is_empty);
+    let password = match check_link_password(state, link_id, link_request, cookie).await {
+        Ok(password) => password,
+        // The link was deleted or its password was changed, so the remembered
+        // password hash is useless now and can be cleared
+        Err(e)
+            if from_cookie
+                && cookie.is_some_and(|cookie| cookie.get(&link_id.to_string()).is_some()) =>
+        {
+            let removal = link_cookie(&link_id.to_string(), "").removal();
+            return Ok((AppendHeaders([(SET_COOKIE, removal)]), e).into_response());
+        }
+        Err(e) => return Err(e),
+    };
+    // Remember the password hash until the link expires
+    let cookie_max_age = match password {
+        Some(_) => sqlx::query_scalar!(
+            r#"SELECT expires_at AS "expires_at: DateTime<Utc>" FROM share_link WHERE id = ?"#,
+            link_id
+        )
+        .fetch_one(&state.pool)
+        .await?
+        .map_or(SESSION_MAX_AGE, |expires_at| {
+            (expires_at - Utc::now()).num_seconds().max(0) as u64
+        }),
+        None => 0,
     };
     // The query to get the shared files
     let query = sqlx::query!(
@@ -713,11 +853,16 @@ pub async fn get_link_shared_file(
                     key_nonce,
                     name_nonce,
                     mime_type_nonce,
+                    metadata_version,
+                    key_algorithm,
                     owner_id,
                     uploader_id,
                     is_directory,
                     mime,
-                    size,
+                    IIF(blob_id IS NULL, size, (SELECT MAX(b.size) FROM file b
+                        WHERE b.id = file.blob_id OR b.blob_id = file.blob_id)) AS size,
+                    plaintext_size,
+                    blob_id,
                     file.created_at,
                     file.modified_at,
                     edit_permission
@@ -745,11 +890,16 @@ pub async fn get_link_shared_file(
                     f.key_nonce,
                     f.name_nonce,
                     f.mime_type_nonce,
+                    f.metadata_version,
+                    f.key_algorithm,
                     f.owner_id,
                     f.uploader_id,
                     f.is_directory,
                     f.mime,
-                    f.size,
+                    IIF(f.blob_id IS NULL, f.size, (SELECT MAX(b.size) FROM file b
+                        WHERE b.id = f.blob_id OR b.blob_id = f.blob_id)),
+                    f.plaintext_size,
+                    f.blob_id,
                     f.created_at,
                     f.modified_at,
                     NULL AS edit_permission
@@ -770,12 +920,15 @@ pub async fn get_link_shared_file(
                 key_nonce,
                 name_nonce,
                 mime_type_nonce,
+                metadata_version,
+                key_algorithm AS "key_algorithm!: KeyAlgorithm",
                 owner_id AS "owner_id: Uuid",
                 uploader_id AS "uploader_id: Uuid",
                 is_directory,
                 mime,
                 edit_permission AS "edit_permission?",
-                IIF(size - 16 < 0, 0, size - 16) AS "size!: i64",
+                plaintext_size AS "size!: i64",
+                size AS "ciphertext_size!: i64",
                 created_at,
                 modified_at
             FROM children ORDER BY depth ASC LIMIT ? OFFSET ?
@@ -807,6 +960,8 @@ pub async fn get_link_shared_file(
                     f.key_nonce,
                     f.name_nonce,
                     f.mime_type_nonce,
+                    f.metadata_version,
+                    f.key_algorithm,
                     f.owner_id,
                     f.uploader_id,
                     f.is_directory,
@@ -835,6 +990,8 @@ pub async fn get_link_shared_file(
                     f.key_nonce,
                     f.name_nonce,
                     f.mime_type_nonce,
+                    f.metadata_version,
+                    f.key_algorithm,
                     f.owner_id,
                     f.uploader_id,
                     f.is_directory,
@@ -863,11 +1020,14 @@ pub async fn get_link_shared_file(
                 key_nonce,
                 name_nonce,
                 mime_type_nonce,
+                metadata_version,
+                key_algorithm AS "key_algorithm!: KeyAlgorithm",
                 is_directory AS "is_directory!",
                 mime,
                 -- Ancestors are always directories so their size must
                 -- be always be 0
                 0 AS "size!: i64",
+                0 AS "ciphertext_size!: i64",
                 edit_permission AS "edit_permission?",
                 created_at,
                 modified_at
@@ -898,10 +1058,20 @@ pub async fn get_link_shared_file(
                 name_nonce: row.name_nonce,
                 key_nonce: row.key_nonce,
                 mime_type_nonce: row.mime_type_nonce,
+                metadata_version: row.metadata_version,
+                key_algorithm: Some(row.key_algorithm),
+                plaintext_size: None,
             },
             size: row.size,
+            ciphertext_size: row.ciphertext_size,
             children: Vec::new(),
             edit_permission: row.edit_permission,
+            shared_user_count: None,
+            active_link_count: None,
+            shared_via_ancestor: None,
+            key_epoch: None,
+            mount_id: None,
+            alias_of: None,
         });
         (query, Some(ancestors))
     } else {
@@ -928,10 +1098,20 @@ pub async fn get_link_shared_file(
                 name_nonce: row.name_nonce,
                 key_nonce: row.key_nonce,
                 mime_type_nonce: row.mime_type_nonce,
+                metadata_version: row.metadata_version,
+                key_algorithm: Some(row.key_algorithm),
+                plaintext_size: None,
             },
             size: row.size,
+            ciphertext_size: row.ciphertext_size,
             children: Vec::new(),
             edit_permission: row.edit_permission,
+            shared_user_count: None,
+            active_link_count: None,
+            shared_via_ancestor: None,
+            key_epoch: None,
+            mount_id: None,
+            alias_of: None,
         }))
         .normalize();
 
@@ -940,10 +1120,9 @@ pub async fn get_link_shared_file(
         if let Some(password) = password {
             AppendHeaders(vec![(
                 SET_COOKIE,
-                format!(
-                    "{link_id}={}; HttpOnly; Path=/api",
-                    urlencoding::encode(&password)
-                ),
+                link_cookie(&link_id.to_string(), &urlencoding::encode(&password))
+                    .max_age(cookie_max_age)
+                    .build(&state.config),
             )])
         } else {
             AppendHeaders(vec![])
@@ -952,11 +1131,223 @@ pub async fn get_link_shared_file(
             users: get_file_users(&state.pool, &files).await?,
             files,
             root,
+            owners: None,
         }),
     )
         .into_response())
 }
 
+#[utoipa::path(
+    delete,
+    path = "/api/shared/credentials",
+    description = "Forget the password hashes of share links remembered in the cookies of the request, or only the one of the given link. Links with a password have to be unlocked again afterwards.",
+    params(LinkParams),
+    responses(
+        (status = OK, description = "Link credentials successfully cleared", body = SuccessResponse, headers(("Set-Cookie" = String, description = "Deletes the cookies holding the password hashes of the links"))),
+    ),
+    security(
+        ()
+    )
+)]
+pub async fn clear_link_credentials(
+    cookie: Option<TypedHeader<Cookie>>,
+    Query(params): Query<LinkParams>,
+) -> Result<Response, AppError> {
+    // Link cookies are named after the id of the link, which no other cookie is
+    let removals: Vec<_> = cookie
+        .iter()
+        .flat_map(|TypedHeader(cookie)| cookie.iter())
+        .filter_map(|(name, _)| Uuid::try_parse(name).ok())
+        .filter(|link_id| params.link_id.is_none_or(|id| id == *link_id))
+        .map(|link_id| (SET_COOKIE, link_cookie(&link_id.to_string(), "").removal()))
+        .collect();
+    Ok((
+        StatusCode::OK,
+        AppendHeaders(removals),
+        success!("Successfully cleared link credentials"),
+    )
+        .into_response())
+}
+
+/// Check that the password for a share link is correct if it has one.
+/// The password can either be provided directly or through a cookie containing
+/// the password hash from a previous request.
+/// Returns the stored password hash so it can be remembered by the client.
+pub async fn check_link_password(
+    state: &AppState,
+    link_id: Uuid,
+    password: Option<String>,
+    cookie: Option<&Cookie>,
+) -> Result<Option<String>, AppError> {
+    let Some(stored_hash) =
+        sqlx::query_scalar!("SELECT password_hash FROM share_link WHERE id = ?", link_id)
+            .fetch_optional(&state.pool)
+            .await?
+            .ok_or(AppError::UserError((
+                StatusCode::NOT_FOUND,
+                "Invalid share link".into(),
+            )))?
+    else {
+        return Ok(None);
+    };
+    // Attempt to read the password from the request body.
+    // If the password is not provided, then check the cookie to see if
+    // the user has already provided the correct password in the past.
+    // If neither, then reject the request.
+    match (
+        password,
+        cookie.and_then(|cookie| cookie.get(&link_id.to_string())),
+    ) {
+        (Some(password), _) if !password.is_empty() => {
+            tokio::task::block_in_place(|| {
+                state
+                    .argon2
+                    .verify_password(
+                        password.as_bytes(),
+                        &PasswordHash::new(&stored_hash).expect("Password hash should be valid"),
+                    )
+                    .map_err(|_| {
+                        AppError::UserError((StatusCode::UNAUTHORIZED, "Invalid password".into()))
+                    })
+            })?;
+        }
+        (_, Some(password_hash)) => {
+            let password_hash = urlencoding::decode(password_hash)?.to_string();
+            if password_hash != stored_hash {
+                return Err(AppError::UserError((
+                    StatusCode::UNAUTHORIZED,
+                    "Invalid password".into(),
+                )));
+            }
+        }
+        (_, _) => {
+            return Err(AppError::UserError((
+                StatusCode::UNAUTHORIZED,
+                "This link requires a password. Please provide a password inside the request body"
+                    .into(),
+            )))
+        }
+    };
+    Ok(Some(stored_hash))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/shared/{link_id}/claim",
+    description = "Claim a file that was uploaded anonymously, moving it into the user's account. The share link that was generated for the upload is deleted, so the file will no longer expire.",
+    params(("link_id" = Uuid, Path, description = "The id of the share link generated for the upload")),
+    request_body(content = ClaimRequest, description = "The rewrapped file key"),
+    responses(
+        (status = OK, description = "File successfully claimed", body = SuccessResponse),
+        (status = BAD_REQUEST, description = "Invalid request body", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "Invalid link password", body = ErrorResponse),
+        (status = PAYMENT_REQUIRED, description = "User does not have enough free space", body = ErrorResponse),
+        (status = NOT_FOUND, description = "Link not found or file cannot be claimed", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state, req))]
+pub async fn claim_file(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    TypedHeader(cookie): TypedHeader<Cookie>,
+    Path(link_id): Path<Uuid>,
+    Json(req): Json<ClaimRequest>,
+) -> Result<Response, AppError> {
+    // The key of a root file is wrapped with the owner's public key, without a nonce
+    check_wrapped_key(None, &req.encrypted_key, None)
+        .map_err(|e| AppError::UserError((StatusCode::BAD_REQUEST, e)))?;
+    check_link_password(&state, link_id, req.password, Some(&cookie)).await?;
+
+    let mut tx = state.pool.begin().await?;
+    // Only root files without an owner can be claimed, anything else
+    // was not uploaded anonymously.
+    let Some(file_id) = sqlx::query_scalar!(
+        r#"
+        SELECT file.id AS "id: Uuid" FROM share_link
+        JOIN file ON file.id = share_link.file_id
+        WHERE share_link.id = ? AND file.owner_id IS NULL AND file.parent_id IS NULL
+        AND (expires_at IS NULL OR DATETIME(expires_at) >= CURRENT_TIMESTAMP)
+        "#,
+        link_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Link not found or file cannot be claimed".into(),
+        )));
+    };
+
+    let owner = sqlx::query!(
+        "SELECT total_space, used_space FROM user WHERE id = ?",
+        user.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    // Delete the links before changing the owner, otherwise the share link
+    // triggers would take the space of the links away from the new owner.
+    // Removing them also stops the file from being cleaned up once they expire.
+    sqlx::query!(
+        r#"
+        WITH RECURSIVE descendants AS (
+            SELECT id FROM file WHERE id = ?
+            UNION ALL
+            SELECT f.id FROM file f
+            JOIN descendants d ON f.parent_id = d.id
+        )
+        DELETE FROM share_link WHERE file_id IN (SELECT id FROM descendants)
+        "#,
+        file_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query!(
+        r#"
+        WITH RECURSIVE descendants AS (
+            SELECT id FROM file WHERE id = ?
+            UNION ALL
+            SELECT f.id FROM file f
+            JOIN descendants d ON f.parent_id = d.id
+        )
+        UPDATE file SET owner_id = ?, uploader_id = COALESCE(uploader_id, ?)
+        WHERE id IN (SELECT id FROM descendants)
+        "#,
+        file_id,
+        user.id,
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+    // Root files owned by a user have their key encrypted with the user's
+    // public key, which doesn't use a nonce
+    sqlx::query!(
+        "UPDATE file SET encrypted_key = ?, key_nonce = NULL, key_algorithm = 1 WHERE id = ?",
+        req.encrypted_key,
+        file_id
+    )
+    .execute(&mut *tx)
+    .await?;
+    // The triggers on the file table have added the claimed files to the used space,
+    // dropping the transaction undoes that if they don't fit
+    let used_space = sqlx::query_scalar!("SELECT used_space FROM user WHERE id = ?", user.id)
+        .fetch_one(&mut *tx)
+        .await?;
+    if used_space > owner.total_space + state.config.grace_space(owner.total_space) {
+        return Err(AppError::UserError((
+            StatusCode::PAYMENT_REQUIRED,
+            "You do not have enough free space to claim this file".into(),
+        )));
+    }
+    tx.commit().await?;
+
+    Ok((StatusCode::OK, success!("File successfully claimed")).into_response())
+}
+
 #[utoipa::path(
     get,
     path = "/api/shared/{file_id}/links",
@@ -989,6 +1380,7 @@ pub async fn get_shared_links(
         expires_at AS "expires_at",
         edit_permission,
         (password_hash IS NOT NULL) AS "password_protected!: bool",
+        add_only, max_size, audience,
         created_at AS "created_at!", modified_at AS "modified_at!"
         FROM share_link 
         WHERE file_id = ? AND
@@ -1005,6 +1397,9 @@ pub async fn get_shared_links(
             link_id: row.link_id,
             expires_at: row.expires_at.map(|e| e.and_utc()),
             password_protected: row.password_protected,
+            add_only: row.add_only,
+            max_size: row.max_size,
+            audience: stored_audience(row.audience.as_deref()),
         },
         edit_permission: row.edit_permission,
         created_at: row.created_at.and_utc(),
@@ -1014,12 +1409,6 @@ pub async fn get_shared_links(
     Ok((StatusCode::OK, Json(query)).into_response())
 }
 
-#[derive(Serialize, ToSchema)]
-struct UserShareResponse {
-    access: Vec<ShareResponse>,
-    users: HashMap<Uuid, PublicUser>,
-}
-
 #[utoipa::path(
     get,
     path = "/api/shared/{file_id}/users",
@@ -1053,7 +1442,7 @@ pub async fn get_shared_users(
         su.modified_at AS "su_modified_at!",
         username, email, public_key,
         NULL AS "password_salt?: String", 
-        avatar AS "avatar_extension"
+        avatar AS "avatar_extension", avatar_version
         FROM share_user su
         JOIN user u ON u.id = su.user_id
         WHERE file_id = ?
@@ -1082,6 +1471,7 @@ pub async fn get_shared_users(
                     email: row.email,
                     public_key: row.public_key,
                     avatar_extension: row.avatar_extension,
+                    avatar_version: row.avatar_version,
                     password_salt: row.password_salt,
                 },
             );
@@ -1091,24 +1481,10 @@ pub async fn get_shared_users(
     Ok((StatusCode::OK, Json(UserShareResponse { access, users })).into_response())
 }
 
-#[derive(Debug, Deserialize, ToSchema)]
-#[serde(tag = "type", rename_all = "camelCase")]
-pub enum ShareIdentifier {
-    #[serde(rename_all = "camelCase")]
-    User { user_id: Uuid, file_id: Uuid },
-    #[serde(rename_all = "camelCase")]
-    Link {
-        link_id: Uuid,
-        /// If this is NULL, this is assumed to not be changing.
-        /// An empty string means remove the password
-        password: Option<String>,
-    },
-}
-
 #[utoipa::path(
     delete,
     path = "/api/shared",
-    description = "Delete an active share link or revoke user permissions for a file",
+    description = "Delete an active share link or revoke user permissions for a file. The share can be restored for a while afterwards.",
     request_body(content = ShareIdentifier, description = "The type of file sharing being used"),
     responses(
         (status = OK, description = " Successfully deleted/revoked file permissions", body = SuccessResponse),
@@ -1125,20 +1501,46 @@ pub async fn delete_share_permission(
     SessionAuth(user): SessionAuth,
     Json(req): Json<ShareIdentifier>,
 ) -> Result<Response, AppError> {
+    let window = state.config.share_restore_window.as_secs() as i64;
     match req {
         ShareIdentifier::User { user_id, file_id } => {
-            let rows = sqlx::query!(
-                "
-                DELETE FROM share_user WHERE user_id = ? AND
-                file_id IN (SELECT id FROM file WHERE id = ? AND owner_id = ?)
-                ",
-                user_id,
-                file_id,
-                user.id
-            )
-            .execute(&state.pool)
-            .await?
-            .rows_affected();
+            let rows = retry_transaction(&state, "delete user share", || async {
+                let mut tx = state.pool.begin().await?;
+                // Keep the share around so the owner can undo revoking it
+                if window > 0 {
+                    sqlx::query!(
+                        r#"
+                        INSERT OR REPLACE INTO revoked_share_user
+                        (file_id, user_id, encrypted_key, edit_permission, created_at, restorable_until)
+                        SELECT file_id, user_id, encrypted_key, edit_permission, created_at,
+                        DATETIME(CURRENT_TIMESTAMP, '+' || ? || ' seconds')
+                        FROM share_user WHERE user_id = ? AND
+                        file_id IN (SELECT id FROM file WHERE id = ? AND owner_id = ?)
+                        "#,
+                        window,
+                        user_id,
+                        file_id,
+                        user.id
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                let rows = sqlx::query!(
+                    "
+                    DELETE FROM share_user WHERE user_id = ? AND
+                    file_id IN (SELECT id FROM file WHERE id = ? AND owner_id = ?)
+                    ",
+                    user_id,
+                    file_id,
+                    user.id
+                )
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                tx.commit().await?;
+                Ok(rows)
+            })
+            .await?;
             if rows == 0 {
                 return Err(AppError::UserError((
                     StatusCode::NOT_FOUND,
@@ -1152,21 +1554,49 @@ pub async fn delete_share_permission(
                 .into_response())
         }
         ShareIdentifier::Link { link_id, .. } => {
-            let rows = sqlx::query!(
-                r#"
-                DELETE FROM share_link
-                WHERE id IN (
-                    SELECT share_link.id FROM share_link
-                    JOIN file ON file.id = share_link.file_id
-                    WHERE share_link.id = ? AND owner_id = ?
+            let rows = retry_transaction(&state, "delete link", || async {
+                let mut tx = state.pool.begin().await?;
+                // Expired links are left for the cleanup, there is nothing to restore
+                if window > 0 {
+                    sqlx::query!(
+                        r#"
+                        INSERT OR REPLACE INTO revoked_share_link
+                        (id, file_id, created_at, expires_at, password_hash, edit_permission,
+                        add_only, max_size, audience, restorable_until)
+                        SELECT share_link.id, file_id, share_link.created_at, expires_at, password_hash,
+                        edit_permission, add_only, max_size, audience,
+                        DATETIME(CURRENT_TIMESTAMP, '+' || ? || ' seconds')
+                        FROM share_link
+                        JOIN file ON file.id = share_link.file_id
+                        WHERE share_link.id = ? AND owner_id = ? AND
+                        (expires_at IS NULL OR DATETIME(expires_at) >= CURRENT_TIMESTAMP)
+                        "#,
+                        window,
+                        link_id,
+                        user.id
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                let rows = sqlx::query!(
+                    r#"
+                    DELETE FROM share_link
+                    WHERE id IN (
+                        SELECT share_link.id FROM share_link
+                        JOIN file ON file.id = share_link.file_id
+                        WHERE share_link.id = ? AND owner_id = ?
+                    )
+                    "#,
+                    link_id,
+                    user.id
                 )
-                "#,
-                link_id,
-                user.id
-            )
-            .execute(&state.pool)
-            .await?
-            .rows_affected();
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                tx.commit().await?;
+                Ok(rows)
+            })
+            .await?;
             if rows == 0 {
                 return Err(AppError::UserError((
                     StatusCode::NOT_FOUND,
@@ -1178,11 +1608,209 @@ pub async fn delete_share_permission(
     }
 }
 
-#[derive(Debug, Deserialize, ToSchema)]
-pub struct ShareUpdateRequest {
-    #[serde(flatten)]
-    type_: ShareIdentifier,
-    edit: bool,
+#[utoipa::path(
+    get,
+    path = "/api/shared/revoked",
+    description = "Get the shares of the currently authenticated user's files that were revoked recently enough to be restored, most recently revoked first.",
+    params(RevokedShareQuery),
+    responses(
+        (status = OK, description = "Revoked shares successfully retrieved", body = [RevokedShare]),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_revoked_shares(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Query(query): Query<RevokedShareQuery>,
+) -> Result<Response, AppError> {
+    let users = sqlx::query!(
+        r#"
+        SELECT rsu.file_id AS "file_id: Uuid", rsu.user_id AS "user_id: Uuid",
+        rsu.edit_permission, rsu.created_at AS "created_at!",
+        rsu.revoked_at, rsu.restorable_until
+        FROM revoked_share_user rsu
+        JOIN file ON file.id = rsu.file_id
+        WHERE owner_id = ? AND rsu.file_id = COALESCE(?, rsu.file_id)
+        AND DATETIME(restorable_until) >= CURRENT_TIMESTAMP
+        "#,
+        user.id,
+        query.file_id
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .map(|row| RevokedShare {
+        type_: ShareResponseType::User {
+            user_id: row.user_id,
+        },
+        file_id: row.file_id,
+        edit_permission: row.edit_permission,
+        created_at: row.created_at.and_utc(),
+        revoked_at: row.revoked_at.and_utc(),
+        restorable_until: row.restorable_until.and_utc(),
+    });
+    let links = sqlx::query!(
+        r#"
+        SELECT rsl.id AS "link_id: Uuid", rsl.file_id AS "file_id: Uuid",
+        expires_at, (password_hash IS NOT NULL) AS "password_protected!: bool",
+        edit_permission, add_only, max_size, audience, rsl.created_at AS "created_at!",
+        rsl.revoked_at, rsl.restorable_until
+        FROM revoked_share_link rsl
+        JOIN file ON file.id = rsl.file_id
+        WHERE owner_id = ? AND rsl.file_id = COALESCE(?, rsl.file_id)
+        AND DATETIME(restorable_until) >= CURRENT_TIMESTAMP
+        "#,
+        user.id,
+        query.file_id
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .map(|row| RevokedShare {
+        type_: ShareResponseType::Link {
+            link_id: row.link_id,
+            expires_at: row.expires_at.map(|e| e.and_utc()),
+            password_protected: row.password_protected,
+            add_only: row.add_only,
+            max_size: row.max_size,
+            audience: stored_audience(row.audience.as_deref()),
+        },
+        file_id: row.file_id,
+        edit_permission: row.edit_permission,
+        created_at: row.created_at.and_utc(),
+        revoked_at: row.revoked_at.and_utc(),
+        restorable_until: row.restorable_until.and_utc(),
+    });
+    let mut shares: Vec<RevokedShare> = users.chain(links).collect();
+    shares.sort_by_key(|share| std::cmp::Reverse(share.revoked_at));
+    Ok((StatusCode::OK, Json(shares)).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/shared/restore",
+    description = "Restore a share that was revoked, with the same permissions and, for users, the same wrapped file key. Links keep their id and password, so the old link works again.",
+    request_body(content = ShareIdentifier, description = "The share to restore, the password of links is ignored"),
+    responses(
+        (status = OK, description = "Share successfully restored", body = SuccessResponse),
+        (status = NOT_FOUND, description = "There is no revoked share to restore, or it was revoked too long ago", body = ErrorResponse),
+        (status = CONFLICT, description = "The file is shared with the user again already, or the link has expired", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn restore_share(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Json(req): Json<ShareIdentifier>,
+) -> Result<Response, AppError> {
+    let not_found = || {
+        AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "There is no revoked share to restore".into(),
+        ))
+    };
+    match req {
+        ShareIdentifier::User { user_id, file_id } => {
+            retry_transaction(&state, "restore user share", || async {
+                let mut tx = state.pool.begin().await?;
+                let Some(share) = sqlx::query!(
+                    r#"
+                    DELETE FROM revoked_share_user
+                    WHERE user_id = ? AND file_id IN (SELECT id FROM file WHERE id = ? AND owner_id = ?)
+                    AND DATETIME(restorable_until) >= CURRENT_TIMESTAMP
+                    RETURNING encrypted_key, edit_permission, created_at
+                    "#,
+                    user_id,
+                    file_id,
+                    user.id
+                )
+                .fetch_optional(&mut *tx)
+                .await?
+                else {
+                    return Err(not_found());
+                };
+                let restored = sqlx::query!(
+                    r#"
+                    INSERT OR IGNORE INTO share_user (file_id, user_id, encrypted_key, edit_permission, created_at)
+                    VALUES (?, ?, ?, ?, ?)
+                    "#,
+                    file_id,
+                    user_id,
+                    share.encrypted_key,
+                    share.edit_permission,
+                    share.created_at
+                )
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+                if restored == 0 {
+                    return Err(AppError::UserError((
+                        StatusCode::CONFLICT,
+                        "File is already shared with user".into(),
+                    )));
+                }
+                tx.commit().await?;
+                Ok(())
+            })
+            .await?;
+        }
+        ShareIdentifier::Link { link_id, .. } => {
+            retry_transaction(&state, "restore link", || async {
+                let mut tx = state.pool.begin().await?;
+                let Some(link) = sqlx::query!(
+                    r#"
+                    DELETE FROM revoked_share_link
+                    WHERE id = ? AND file_id IN (SELECT id FROM file WHERE owner_id = ?)
+                    AND DATETIME(restorable_until) >= CURRENT_TIMESTAMP
+                    RETURNING file_id AS "file_id: Uuid", created_at, expires_at,
+                    password_hash, edit_permission, add_only, max_size, audience,
+                    (expires_at IS NOT NULL AND DATETIME(expires_at) < CURRENT_TIMESTAMP) AS "expired!: bool"
+                    "#,
+                    link_id,
+                    user.id
+                )
+                .fetch_optional(&mut *tx)
+                .await?
+                else {
+                    return Err(not_found());
+                };
+                if link.expired {
+                    return Err(AppError::UserError((
+                        StatusCode::CONFLICT,
+                        "The link has expired since it was deleted".into(),
+                    )));
+                }
+                sqlx::query!(
+                    r#"
+                    INSERT INTO share_link (id, file_id, created_at, expires_at, password_hash, edit_permission, add_only, max_size, audience)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                    "#,
+                    link_id,
+                    link.file_id,
+                    link.created_at,
+                    link.expires_at,
+                    link.password_hash,
+                    link.edit_permission,
+                    link.add_only,
+                    link.max_size,
+                    link.audience
+                )
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                Ok(())
+            })
+            .await?;
+        }
+    }
+    Ok((StatusCode::OK, success!("Share successfully restored")).into_response())
 }
 
 #[utoipa::path(
@@ -1209,20 +1837,23 @@ pub async fn update_share_permission(
         // Using nested queries in both cases to avoid
         // call overhead of multiple queries
         ShareIdentifier::User { user_id, file_id } => {
-            let rows = sqlx::query!(
-                "
-                UPDATE share_user SET edit_permission = ? FROM
-                (SELECT id FROM file WHERE owner_id = ? AND id = ?) AS s
-                WHERE user_id = ? AND file_id = s.id
-                ",
-                req.edit,
-                user.id,
-                file_id,
-                user_id,
-            )
-            .execute(&state.pool)
-            .await?
-            .rows_affected();
+            let rows = retry_transaction(&state, "update user share", || async {
+                Ok(sqlx::query!(
+                    "
+                    UPDATE share_user SET edit_permission = ? FROM
+                    (SELECT id FROM file WHERE owner_id = ? AND id = ?) AS s
+                    WHERE user_id = ? AND file_id = s.id
+                    ",
+                    req.edit,
+                    user.id,
+                    file_id,
+                    user_id,
+                )
+                .execute(&state.pool)
+                .await?
+                .rows_affected())
+            })
+            .await?;
             if rows == 0 {
                 return Err(AppError::UserError((
                     StatusCode::FORBIDDEN,
@@ -1255,28 +1886,31 @@ pub async fn update_share_permission(
                 }
                 p => p,
             };
-            let rows = sqlx::query!(
-                "UPDATE share_link SET edit_permission = ?,
-                password_hash =  
-                CASE ?
-                    WHEN NULL THEN password_hash
-                    WHEN '' THEN NULL
-                    ELSE ?
-                END
-                FROM
-                (SELECT share_link.id FROM file
-                JOIN share_link ON share_link.file_id = file.id
-                WHERE owner_id = ? AND share_link.id = ?) AS f
-                WHERE share_link.id = f.id",
-                req.edit,
-                password_hash,
-                password_hash,
-                user.id,
-                link_id
-            )
-            .execute(&state.pool)
-            .await?
-            .rows_affected();
+            let rows = retry_transaction(&state, "update link", || async {
+                Ok(sqlx::query!(
+                    "UPDATE share_link SET edit_permission = ?,
+                    password_hash =  
+                    CASE ?
+                        WHEN NULL THEN password_hash
+                        WHEN '' THEN NULL
+                        ELSE ?
+                    END
+                    FROM
+                    (SELECT share_link.id FROM file
+                    JOIN share_link ON share_link.file_id = file.id
+                    WHERE owner_id = ? AND share_link.id = ?) AS f
+                    WHERE share_link.id = f.id",
+                    req.edit,
+                    password_hash,
+                    password_hash,
+                    user.id,
+                    link_id
+                )
+                .execute(&state.pool)
+                .await?
+                .rows_affected())
+            })
+            .await?;
             if rows == 0 {
                 return Err(AppError::UserError((
                     StatusCode::FORBIDDEN,
@@ -1309,7 +1943,8 @@ pub async fn get_link_info(
         r#"
         SELECT id AS "id: Uuid", expires_at,
         password_hash IS NOT NULL AS "password_protected!: bool",
-        edit_permission, created_at AS "created_at!", modified_at AS "modified_at!"
+        edit_permission, add_only, max_size, audience,
+        created_at AS "created_at!", modified_at AS "modified_at!"
         FROM share_link WHERE id = ?
         "#,
         link_id
@@ -1329,6 +1964,9 @@ pub async fn get_link_info(
                 link_id: link.id,
                 expires_at: link.expires_at.map(|time| time.and_utc()),
                 password_protected: link.password_protected,
+                add_only: link.add_only,
+                max_size: link.max_size,
+                audience: stored_audience(link.audience.as_deref()),
             },
             edit_permission: link.edit_permission,
             created_at: link.created_at.and_utc(),
@@ -1337,3 +1975,206 @@ pub async fn get_link_info(
     )
         .into_response())
 }
+
+#[utoipa::path(
+    post,
+    path = "/api/mount",
+    description = "Mount a directory that is directly shared with the user into their own root. Mounted directories are listed along with the user's own files, and can be browsed through the same endpoint.",
+    request_body = MountRequest,
+    responses(
+        (status = CREATED, description = "The directory was mounted", body = Mount),
+        (status = BAD_REQUEST, description = "The file is not a directory", body = ErrorResponse),
+        (status = NOT_FOUND, description = "The directory is not directly shared with the user", body = ErrorResponse),
+        (status = CONFLICT, description = "The directory is already mounted", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn create_mount(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Json(req): Json<MountRequest>,
+) -> Result<Response, AppError> {
+    // Only the top of a share can be mounted, since its key is the only one
+    // wrapped for the user. Anything below it is reached through the mount.
+    let Some(is_directory) = sqlx::query_scalar!(
+        r#"
+        SELECT f.is_directory FROM file f
+        JOIN share_user su ON su.file_id = f.id
+        WHERE f.id = ? AND su.user_id = ?
+        "#,
+        req.file_id,
+        user.id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Directory not found".into(),
+        )));
+    };
+    if !is_directory {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "Only directories can be mounted".into(),
+        )));
+    }
+    let id = Uuid::new_v4();
+    let Some(created_at) = sqlx::query_scalar!(
+        r#"
+        INSERT INTO mount (id, user_id, file_id) VALUES (?, ?, ?)
+        ON CONFLICT DO NOTHING
+        RETURNING created_at
+        "#,
+        id,
+        user.id,
+        req.file_id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    else {
+        return Err(AppError::UserError((
+            StatusCode::CONFLICT,
+            "Directory is already mounted".into(),
+        )));
+    };
+    Ok((
+        StatusCode::CREATED,
+        Json(Mount {
+            id,
+            file_id: req.file_id,
+            active: true,
+            created_at: created_at.and_utc(),
+        }),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/mount",
+    description = "Get the directories the user mounted into their root, including the ones whose share was revoked",
+    responses(
+        (status = OK, description = "Mounts retrieved successfully", body = [Mount]),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_mounts(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+) -> Result<Response, AppError> {
+    let mounts = sqlx::query!(
+        r#"
+        SELECT m.id AS "id: Uuid", m.file_id AS "file_id: Uuid", m.created_at,
+        su.file_id IS NOT NULL AS "active!: bool"
+        FROM mount m
+        LEFT JOIN share_user su ON su.file_id = m.file_id AND su.user_id = m.user_id
+        WHERE m.user_id = ?
+        ORDER BY m.created_at ASC
+        "#,
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .map(|row| Mount {
+        id: row.id,
+        file_id: row.file_id,
+        active: row.active,
+        created_at: row.created_at.and_utc(),
+    })
+    .collect::<Vec<_>>();
+    Ok((StatusCode::OK, Json(mounts)).into_response())
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/mount/{id}",
+    description = "Remove a mount from the user's root. The directory stays shared with the user.",
+    params(
+        ("id" = Uuid, Path, description = "The id of the mount")
+    ),
+    responses(
+        (status = OK, description = "The mount was removed", body = SuccessResponse),
+        (status = NOT_FOUND, description = "Mount not found", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn delete_mount(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Path(id): Path<Uuid>,
+) -> Result<Response, AppError> {
+    let rows = sqlx::query!(
+        "DELETE FROM mount WHERE id = ? AND user_id = ?",
+        id,
+        user.id
+    )
+    .execute(&state.pool)
+    .await?
+    .rows_affected();
+    if rows == 0 {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "Mount not found".into(),
+        )));
+    }
+    Ok((StatusCode::OK, success!("Mount removed")).into_response())
+}
+
+/// The active mounts of the user, as pairs of the mount id and the mounted directory
+pub async fn active_mounts<'a, E: Executor<'a, Database = Sqlite>>(
+    db: E,
+    user_id: Uuid,
+) -> Result<Vec<(Uuid, Uuid)>, AppError> {
+    Ok(sqlx::query!(
+        r#"
+        SELECT m.id AS "id: Uuid", m.file_id AS "file_id: Uuid" FROM mount m
+        JOIN share_user su ON su.file_id = m.file_id AND su.user_id = m.user_id
+        WHERE m.user_id = ?
+        ORDER BY m.created_at ASC
+        "#,
+        user_id
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| (row.id, row.file_id))
+    .collect())
+}
+
+/// Whether the file is a directory the user mounted or is inside of one,
+/// meaning it can be browsed as if it was one of the user's own files
+pub async fn is_mounted<'a, E: Executor<'a, Database = Sqlite>>(
+    db: E,
+    user_id: Uuid,
+    file_id: Uuid,
+) -> Result<bool, AppError> {
+    Ok(sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id FROM file WHERE id = ?
+            UNION ALL
+            SELECT f.id, f.parent_id FROM file f
+            JOIN ancestors a ON f.id = a.parent_id
+        )
+        SELECT COUNT(*) AS "count!: i64" FROM mount m
+        JOIN share_user su ON su.file_id = m.file_id AND su.user_id = m.user_id
+        WHERE m.user_id = ? AND m.file_id IN (SELECT id FROM ancestors)
+        "#,
+        file_id,
+        user_id
+    )
+    .fetch_one(db)
+    .await?
+        > 0)
+}
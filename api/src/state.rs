@@ -1,22 +1,61 @@
-use std::sync::Arc;
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    time::Instant,
+};
 
 use argon2::Argon2;
 use axum::extract::FromRef;
+use chrono::{DateTime, Utc};
+use lokr_types::{admin::CleanupStats, instance::Features};
 use sqlx::SqlitePool;
+use tokio::sync::Notify;
+
+use crate::{config::Config, rate_limit::UploadLimiter, retry::RetryMetrics};
 
 #[derive(Clone, Debug)]
 pub struct AppState {
     pub pool: SqlitePool,
     pub argon2: Arc<Argon2<'static>>,
+    /// Used to wake up the job worker after queueing a job
+    pub job_notify: Arc<Notify>,
+    pub config: Arc<Config>,
+    /// What the cleaner task has removed since the server started
+    pub cleanup: Arc<Mutex<CleanupTotals>>,
+    /// Cached copy of the `feature_flag` table, updated whenever an admin changes it
+    pub features: Arc<RwLock<Features>>,
+    pub started_at: Instant,
+    /// How often writes were retried because the database was busy
+    pub retries: Arc<RetryMetrics>,
+    /// The uploads each user has in progress
+    pub uploads: Arc<UploadLimiter>,
+}
+
+#[derive(Debug, Default)]
+pub struct CleanupTotals {
+    pub runs: u64,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub removed: CleanupStats,
 }
 
 impl AppState {
-    pub fn new(pool: SqlitePool) -> Self {
+    pub fn new(pool: SqlitePool, config: Config, features: Features) -> Self {
         Self {
             pool,
-            argon2: Argon2::default().into(),
+            argon2: config.argon2().into(),
+            config: Arc::new(config),
+            job_notify: Arc::new(Notify::new()),
+            cleanup: Arc::default(),
+            features: Arc::new(RwLock::new(features)),
+            started_at: Instant::now(),
+            retries: Arc::default(),
+            uploads: Arc::default(),
         }
     }
+
+    /// The features that are currently enabled
+    pub fn features(&self) -> Features {
+        *self.features.read().unwrap()
+    }
 }
 
 impl FromRef<AppState> for SqlitePool {
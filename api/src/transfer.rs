@@ -0,0 +1,56 @@
+use axum::http::StatusCode;
+use sqlx::{Executor, Sqlite};
+use uuid::Uuid;
+
+use crate::{error::AppError, state::AppState};
+
+/// Add to the bytes transferred to and from a user's files this month
+pub async fn record<'a, E: Executor<'a, Database = Sqlite>>(
+    db: E,
+    user_id: Uuid,
+    uploaded: i64,
+    downloaded: i64,
+) -> Result<(), AppError> {
+    sqlx::query!(
+        r#"
+        INSERT INTO transfer (user_id, month, uploaded, downloaded)
+        VALUES (?, strftime('%Y-%m', 'now'), ?, ?)
+        ON CONFLICT(user_id, month) DO UPDATE SET
+        uploaded = uploaded + excluded.uploaded,
+        downloaded = downloaded + excluded.downloaded
+        "#,
+        user_id,
+        uploaded,
+        downloaded
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Reject downloads of a user's files once as much has been downloaded this month
+/// as their transfer cap allows. The download that goes over the cap is still let
+/// through, as its size isn't known until it has been served.
+pub async fn check_download_cap(state: &AppState, owner_id: Uuid) -> Result<(), AppError> {
+    let default_cap = state.config.monthly_transfer_cap as i64;
+    let row = sqlx::query!(
+        r#"
+        SELECT NULLIF(COALESCE(u.transfer_cap, ?), 0) AS "cap?: i64",
+        COALESCE(t.downloaded, 0) AS "downloaded!: i64"
+        FROM user u
+        LEFT JOIN transfer t ON t.user_id = u.id AND t.month = strftime('%Y-%m', 'now')
+        WHERE u.id = ?
+        "#,
+        default_cap,
+        owner_id
+    )
+    .fetch_optional(&state.pool)
+    .await?;
+    if row.is_some_and(|row| row.cap.is_some_and(|cap| row.downloaded >= cap)) {
+        return Err(AppError::UserError((
+            StatusCode::TOO_MANY_REQUESTS,
+            "The owner of this file has used up their transfer for this month".into(),
+        )));
+    }
+    Ok(())
+}
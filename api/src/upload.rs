@@ -2,88 +2,60 @@ use std::{collections::HashMap, io::ErrorKind, path::PathBuf};
 
 use axum::{
     extract::{Multipart, Path, Query, Request, State},
-    http::{StatusCode, Uri},
+    http::{header::CONTENT_LENGTH, Method, StatusCode, Uri},
     middleware::Next,
     response::{IntoResponse, Response},
     Json,
 };
 use axum_extra::{headers::Cookie, TypedHeader};
-use chrono::{DateTime, TimeZone, Utc};
-use serde::{Deserialize, Serialize};
-use serde_inline_default::serde_inline_default;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use serde::Serialize;
 use sqlx::{Executor, Sqlite};
 use tokio::{fs::File, io::AsyncWriteExt};
-use tracing::{error, instrument};
-use utoipa::{IntoParams, ToSchema};
+use tracing::{error, instrument, warn};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
+pub use lokr_types::upload::{
+    AliasRequest, DeletePreview, FileMetadata, FileQuery, FileResponse, FingerprintQuery,
+    FingerprintResponse, KeyAlgorithm, LinkParams, QuotaWarning, RewrapKey, RewrapRequest,
+    RewrapResponse, RewrapResult, UpdateFile, UploadMetadata, UploadResponse, UploaderResponse,
+    UploaderSummary, MAX_FINGERPRINT_LOOKUP, MAX_REWRAP_BATCH, METADATA_VERSION,
+    SUPPORTED_METADATA_VERSIONS,
+};
+
 use crate::{
-    auth::SessionAuth,
+    auth::{SessionAuth, StepUpAuth},
     error::{AppError, ErrorResponse},
-    share::{share_with_link, ShareResponse},
+    instance,
+    jobs::{self, Job},
+    permissions::{check_link_audience, denied, file_access, Accessor},
+    retry::retry_transaction,
+    share::{
+        active_mounts, is_mounted, share_with_link, shared_tree, LinkAudience, LinkPermission,
+        ShareResponse,
+    },
     state::AppState,
-    success,
+    success, transfer,
     users::PublicUser,
     utils::{get_file_users, Normalize},
-    SuccessResponse, UPLOAD_DIR,
+    DryRunQuery, SuccessResponse,
 };
 
-/// All data for the uploaded file.
-/// All encrypted fields are expected to be encrypted
-/// by the provided key, except for the key itself
-/// which is expected to be encrypted by the user's public key
-#[derive(Deserialize, Debug, Serialize, ToSchema, Clone)]
-#[serde(rename_all = "camelCase")]
-pub struct UploadMetadata {
-    /// The encrypted name of the file to be uploaded
-    #[schema(content_encoding = "base64")]
-    pub encrypted_file_name: String,
-    /// The encrypted mime type of the file to be uploaded
-    /// Optional in case the mime type is not known
-    #[schema(content_encoding = "base64")]
-    pub encrypted_mime_type: Option<String>,
-    /// The key used to encrypt the file
-    /// Should be encrypted by the user's public key
-    #[schema(content_encoding = "base64")]
-    pub encrypted_key: String,
-    /// We need to use a diffent nonce for each
-    /// piece of data that we encrypt for security reasons
-    /// The nonce for the file (not encrypted) can be null
-    /// if the file is a directory
-    #[schema(content_encoding = "base64")]
-    pub file_nonce: Option<String>,
-    /// The nonce for the encryption key (not encrypted)
-    /// Not neeeded if the file is in the root directory
-    #[schema(content_encoding = "base64")]
-    pub key_nonce: Option<String>,
-    /// The nonce for the file name (not encrypted)
-    #[schema(content_encoding = "base64")]
-    pub name_nonce: String,
-    /// The nonce for the file mime type(not encrypted)
-    /// can be null if the file does not have a mime type
-    #[schema(content_encoding = "base64")]
-    pub mime_type_nonce: Option<String>,
-    /// Whether the file is a directory
-    #[serde(default)]
-    pub is_directory: bool,
-    /// The direct parent id of the file
-    /// Should be null if in the root directory
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub parent_id: Option<Uuid>,
-}
+/// The maximum length of a fingerprint, enough for a hex encoded SHA-512 HMAC
+const MAX_FINGERPRINT_LENGTH: usize = 128;
 
-/// The size and id of the uploaded file
-/// Also has a flag to indicate if the file is a directory
+/// The size of the authentication tag AES-GCM adds to the data it encrypts
+const AES_GCM_TAG_LENGTH: i64 = 16;
+
+/// What deleting a file responds with, depending on whether it was a dry run
+// Only used for documentation, like `UploadRequest`
 #[derive(Serialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub struct UploadResponse {
-    id: Uuid,
-    size: i64,
-    is_directory: bool,
-    /// Used to handle the case where the file is uploaded
-    /// by an anonymous user.
-    #[serde(skip_serializing_if = "Option::is_none")]
-    link: Option<ShareResponse>,
+#[allow(unused)]
+#[serde(untagged)]
+pub enum DeleteResponse {
+    Deleted(SuccessResponse),
+    DryRun(DeletePreview),
 }
 
 /// A request to upload a file
@@ -101,6 +73,18 @@ pub struct UploadRequest {
     file: String,
     #[schema(example = "", content_media_type = "text/plain")]
     link_id: Option<String>,
+    /// A password to protect the share link generated for anonymous uploads with.
+    /// This is ignored for any other upload.
+    #[schema(example = "amogus", content_media_type = "text/plain")]
+    password: Option<String>,
+    /// A keyed hash of the plaintext of the file, used by sync clients to find
+    /// files they already uploaded. Hash it with a key the server never sees,
+    /// such as an HMAC keyed with something derived from the user's private key.
+    #[schema(
+        example = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+        content_media_type = "text/plain"
+    )]
+    fingerprint: Option<String>,
 }
 
 #[utoipa::path(
@@ -112,7 +96,10 @@ pub struct UploadRequest {
     ),
     responses(
         (status = OK, description = "The file was uploaded successfully", body = UploadResponse),
-        (status = BAD_REQUEST, description = "The file metadata or file data was not provided or provided incorrectly", body = ErrorResponse),
+        (status = BAD_REQUEST, description = "The file metadata or file data was not provided or provided incorrectly, or the metadata version isn't supported", body = ErrorResponse),
+        (status = PAYMENT_REQUIRED, description = "The owner of the file does not have enough free space, including the grace space", body = ErrorResponse),
+        (status = FORBIDDEN, description = "Anonymous uploads are disabled on this instance", body = ErrorResponse),
+        (status = TOO_MANY_REQUESTS, description = "The user already has as many uploads in progress as the instance allows", body = ErrorResponse),
     ),
     security(
         (),
@@ -129,8 +116,19 @@ pub async fn upload_file(
 ) -> Result<Response, AppError> {
     let mut metadata: Option<UploadMetadata> = None;
     let uuid = user.map(|user| user.0.id);
+    check_link_audience(&state, params.link_id, uuid).await?;
+    // Held until the upload has been written to disk
+    let _permit = uuid
+        .map(|uuid| {
+            state
+                .uploads
+                .acquire(uuid, state.config.max_concurrent_uploads)
+        })
+        .transpose()?;
     let file_id = Uuid::now_v7();
-    let mut file_path: Option<PathBuf> = None;
+    let mut has_file = false;
+    let mut share_password: Option<String> = None;
+    let mut fingerprint: Option<String> = None;
     // Allocate a megabyte buffer
     let mut file_data: Vec<u8> = Vec::with_capacity(1024 * 1024);
     let link_password = params
@@ -143,9 +141,14 @@ pub async fn upload_file(
             Some("metadata") => {
                 metadata = Some(serde_json::from_slice(&field.bytes().await?)?);
             }
+            Some("password") => {
+                share_password = Some(field.text().await?);
+            }
+            Some("fingerprint") => {
+                fingerprint = Some(field.text().await?);
+            }
             Some("file") => {
-                file_path = Some(UPLOAD_DIR.join(file_id.to_string()));
-
+                has_file = true;
                 while let Some(chunk) = field.chunk().await? {
                     file_data.extend_from_slice(&chunk);
                 }
@@ -154,13 +157,21 @@ pub async fn upload_file(
         }
     }
 
-    let Some(metadata) = metadata else {
+    let Some(mut metadata) = metadata else {
         return Err(AppError::UserError((
             StatusCode::BAD_REQUEST,
             "Missing file metadata".into(),
         )));
     };
 
+    // Uploads without an account or a parent directory to put them in are anonymous
+    if uuid.is_none() && metadata.parent_id.is_none() {
+        instance::require(
+            state.features().anonymous_uploads,
+            "Anonymous uploads are disabled on this instance",
+        )?;
+    }
+
     if metadata.mime_type_nonce.is_some() != metadata.encrypted_mime_type.is_some() {
         return Err(AppError::UserError((
             StatusCode::BAD_REQUEST,
@@ -175,56 +186,102 @@ pub async fn upload_file(
         )));
     }
 
-    // Implement retry mechanism for the entire transaction
-    const MAX_RETRIES: usize = 5;
-    const BASE_RETRY_DELAY_MS: u64 = 50;
+    if fingerprint
+        .as_ref()
+        .is_some_and(|f| f.is_empty() || f.len() > MAX_FINGERPRINT_LENGTH)
+    {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Fingerprint must be between 1 and {} characters",
+                MAX_FINGERPRINT_LENGTH
+            ),
+        )));
+    }
 
-    let mut retries = 0;
-    let link;
+    if !SUPPORTED_METADATA_VERSIONS.contains(&metadata.metadata_version) {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Unsupported metadata version {}, the supported versions are {:?}",
+                metadata.metadata_version, SUPPORTED_METADATA_VERSIONS
+            ),
+        )));
+    }
+
+    metadata.key_algorithm = Some(
+        check_wrapped_key(
+            metadata.key_algorithm,
+            &metadata.encrypted_key,
+            metadata.key_nonce.as_deref(),
+        )
+        .map_err(|e| AppError::UserError((StatusCode::BAD_REQUEST, e)))?,
+    );
 
-    loop {
-        // Begin a new transaction for each attempt
-        match process_upload_transaction(
+    // The declared size can't be checked without the key, but encryption
+    // never makes data smaller so it can't be more than what was uploaded
+    let data_size = file_data.len() as i64;
+    metadata.plaintext_size = Some(match metadata.plaintext_size {
+        Some(size) if !(0..=data_size).contains(&size) => {
+            return Err(AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                "The plaintext size can't be more than the size of the uploaded data".into(),
+            )))
+        }
+        Some(size) => size,
+        None => (data_size - AES_GCM_TAG_LENGTH).max(0),
+    });
+
+    // Write the file to a temporary location before touching the database
+    // so that a partially written file is never visible in the upload directory.
+    // It only gets moved into place once the transaction below has committed.
+    let temp_path = if has_file && !metadata.is_directory && !file_data.is_empty() {
+        Some(write_temp_blob(&state.config.temp_dir(), file_id, &file_data).await?)
+    } else {
+        None
+    };
+
+    // Each attempt runs the whole transaction again
+    let (link, quota_warning) = match retry_transaction(&state, "upload", || {
+        process_upload_transaction(
             &state,
             &uuid,
             &params,
             &metadata,
             link_password.as_deref(),
+            share_password.as_deref(),
+            fingerprint.as_deref(),
             file_id,
             file_data.len() as i64,
         )
-        .await
-        {
-            Ok(link_result) => {
-                link = link_result;
-                break;
-            }
-            Err(e) => {
-                // Check if it's an SQLITE_BUSY error because if it is
-                // then we need to retry. 517 is SQLITE_BUSY_SNAPSHOT
-                // Reference: https://www.sqlite.org/rescode.html#busy_snapshot
-                if let AppError::SqlxError(db_err) = &e {
-                    if let Some(code) = db_err.as_database_error().and_then(|e| e.code()) {
-                        if (code == "5" || code == "517") && retries < MAX_RETRIES {
-                            retries += 1;
-                            // Exponential backoff with jitter
-                            let jitter = fastrand::u64(1..=50);
-                            let delay = BASE_RETRY_DELAY_MS * (1 << retries) + jitter;
-                            tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
-                            continue;
-                        }
-                    }
-                }
-                return Err(e);
+    })
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => {
+            // The file was never committed to the database, so get rid of the data
+            if let Some(temp_path) = &temp_path {
+                remove_temp_blob(temp_path).await;
             }
+            return Err(e);
         }
-    }
+    };
 
-    // If everything succeeds, write the file to disk (only if it's not a directory)
-    if let Some(file_path) = file_path {
-        if !metadata.is_directory && !file_data.is_empty() {
-            let mut file = File::create(file_path).await?;
-            file.write_all(&file_data).await?;
+    // The transaction committed, so move the file into the upload directory
+    if let Some(temp_path) = temp_path {
+        if let Err(e) = tokio::fs::rename(
+            &temp_path,
+            state.config.upload_dir().join(file_id.to_string()),
+        )
+        .await
+        {
+            error!("Unable to move file '{}' into place: {}", file_id, e);
+            remove_temp_blob(&temp_path).await;
+            // Remove the row again as it would otherwise point at data that doesn't exist
+            sqlx::query!("DELETE FROM file WHERE id = ?", file_id)
+                .execute(&state.pool)
+                .await?;
+            return Err(e.into());
         }
     }
 
@@ -232,24 +289,95 @@ pub async fn upload_file(
         StatusCode::OK,
         Json(UploadResponse {
             id: file_id,
-            size: file_data.len() as i64,
+            size: data_size,
+            plaintext_size: metadata.plaintext_size.unwrap_or_default(),
             is_directory: metadata.is_directory,
             link,
+            quota_warning,
         }),
     )
         .into_response())
 }
 
+/// Check that a wrapped key and its nonce have the lengths the algorithm produces,
+/// returning the algorithm, which is inferred from the nonce if not given
+pub(crate) fn check_wrapped_key(
+    algorithm: Option<KeyAlgorithm>,
+    encrypted_key: &str,
+    key_nonce: Option<&str>,
+) -> Result<KeyAlgorithm, String> {
+    let algorithm = algorithm.unwrap_or_else(|| KeyAlgorithm::infer(key_nonce));
+    let decoded_length = |value: &str| BASE64_STANDARD.decode(value).ok().map(|v| v.len());
+    let lengths = algorithm.wrapped_key_lengths();
+    if !decoded_length(encrypted_key).is_some_and(|length| lengths.contains(&length)) {
+        let lengths: Vec<_> = lengths.iter().map(ToString::to_string).collect();
+        return Err(format!(
+            "Keys wrapped with {:?} must be {} bytes",
+            algorithm,
+            lengths.join(" or ")
+        ));
+    }
+    match (algorithm.nonce_length(), key_nonce) {
+        (None, None) => Ok(algorithm),
+        (None, Some(_)) => Err(format!("{:?} doesn't use a key nonce", algorithm)),
+        (Some(length), Some(nonce)) if decoded_length(nonce) == Some(length) => Ok(algorithm),
+        (Some(length), _) => Err(format!(
+            "Keys wrapped with {:?} need a {} byte nonce",
+            algorithm, length
+        )),
+    }
+}
+
+/// Write file data to a temporary file named after the file id, returning its path.
+/// The data is synced to disk before returning so that a rename afterwards can't
+/// expose a partially written file.
+async fn write_temp_blob(
+    temp_dir: &std::path::Path,
+    file_id: Uuid,
+    data: &[u8],
+) -> Result<PathBuf, AppError> {
+    let temp_path = temp_dir.join(file_id.to_string());
+    let result = async {
+        let mut file = File::create(&temp_path).await?;
+        file.write_all(data).await?;
+        file.sync_all().await
+    }
+    .await;
+    if let Err(e) = result {
+        remove_temp_blob(&temp_path).await;
+        return Err(e.into());
+    }
+    Ok(temp_path)
+}
+
+/// Remove a temporary file, logging instead of returning errors since this
+/// is only ever called while cleaning up after another failure.
+async fn remove_temp_blob(temp_path: &std::path::Path) {
+    match tokio::fs::remove_file(temp_path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            error!(
+                "Unable to remove temporary file '{}': {}",
+                temp_path.display(),
+                e
+            );
+        }
+        _ => {}
+    }
+}
+
 // Extract the transaction logic into a separate function to enable proper retries
+#[allow(clippy::too_many_arguments)]
 async fn process_upload_transaction(
     state: &AppState,
     uuid: &Option<Uuid>,
     params: &LinkParams,
     metadata: &UploadMetadata,
     link_password: Option<&str>,
+    share_password: Option<&str>,
+    fingerprint: Option<&str>,
     file_id: Uuid,
     file_size: i64,
-) -> Result<Option<ShareResponse>, AppError> {
+) -> Result<(Option<ShareResponse>, Option<QuotaWarning>), AppError> {
     // Begin a transaction to prevent a race condition across threads
     // that could allow a user to upload more than they are allowed to
     let mut tx = state.pool.begin().await?;
@@ -322,7 +450,36 @@ async fn process_upload_transaction(
         None => *uuid
     };
 
+    // Check if uploading through the link would go over its size limit
+    if let Some(link) = sqlx::query!(
+        r#"
+        WITH RECURSIVE descendants AS (
+            SELECT id, size FROM file
+            WHERE id = (SELECT file_id FROM share_link WHERE id = ?)
+            UNION ALL
+            SELECT f.id, f.size FROM file f
+            JOIN descendants d ON f.parent_id = d.id
+        )
+        SELECT max_size AS "max_size!",
+        (SELECT COALESCE(SUM(size), 0) FROM descendants) AS "used_size!: i64"
+        FROM share_link WHERE id = ? AND max_size IS NOT NULL
+        "#,
+        params.link_id,
+        params.link_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    {
+        if link.used_size + file_size > link.max_size {
+            return Err(AppError::UserError((
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "This folder is full".into(),
+            )));
+        }
+    }
+
     // Check if the owner has enough space to upload the file
+    let mut over_quota = false;
     if let Some(owner_id) = owner_id {
         let owner = sqlx::query!(
             "SELECT total_space, used_space FROM user WHERE id = ?",
@@ -345,20 +502,26 @@ async fn process_upload_transaction(
                 .as_ref()
                 .map(|e| e.len())
                 .unwrap_or(1);
-        if owner.used_space + row_space as i64 + file_size > owner.total_space {
+        let used_space = owner.used_space + row_space as i64 + file_size;
+        let grace_space = state.config.grace_space(owner.total_space);
+        if used_space > owner.total_space + grace_space {
             return Err(AppError::UserError((
                 StatusCode::PAYMENT_REQUIRED,
                 "File owner does not have enough free space".into(),
             )));
         }
+        over_quota = used_space > owner.total_space;
     }
 
+    let key_algorithm = metadata.key_algorithm.map(|algorithm| algorithm as i64);
     match sqlx::query!(
         r#"
         INSERT INTO file (id, owner_id, uploader_id, parent_id,
         encrypted_key, encrypted_name, mime, file_nonce,
-        key_nonce, mime_type_nonce, name_nonce, is_directory, size)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        key_nonce, mime_type_nonce, name_nonce, is_directory, size, fingerprint, key_epoch,
+        metadata_version, key_algorithm, plaintext_size)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?,
+        COALESCE((SELECT key_epoch FROM user WHERE id = ?), 0), ?, ?, ?)
         "#,
         file_id,
         owner_id,
@@ -373,6 +536,11 @@ async fn process_upload_transaction(
         metadata.name_nonce,
         metadata.is_directory,
         file_size,
+        fingerprint,
+        owner_id,
+        metadata.metadata_version,
+        key_algorithm,
+        metadata.plaintext_size,
     )
     .execute(&mut *tx)
     .await
@@ -392,46 +560,88 @@ async fn process_upload_transaction(
         Err(e) => return Err(e.into()),
         _ => {}
     }
+    if let Some(owner_id) = owner_id {
+        transfer::record(&mut *tx, owner_id, file_size, 0).await?;
+    }
 
     // If the owner is None, then that means the owner is anonymous
     // in this case we should generate a share link instead of checking
     // for space.
     let link: Option<ShareResponse> = if owner_id.is_none() && metadata.parent_id.is_none() {
-        // Create a share link without edit permissions so we don't have to deal with
-        // anonymous users filling up a bunch of space.
-        // Might add ability to password protect in the future, keeping things simple for now.
+        // Files get a share link without edit permissions so we don't have to deal with
+        // anonymous users filling up a bunch of space. Directories become drop folders
+        // that anyone with the link can add to, up to a configured size.
         // Will probably prevent abuse in the future using some kind of captcha or cloudflare
-        Some(share_with_link(state, &mut *tx, file_id, *uuid, 60 * 60 * 24, None, false).await?)
+        let permission = if metadata.is_directory {
+            LinkPermission::AddOnly {
+                max_size: Some(state.config.anonymous_folder_max_size as i64),
+            }
+        } else {
+            LinkPermission::View
+        };
+        Some(
+            share_with_link(
+                state,
+                &mut *tx,
+                file_id,
+                *uuid,
+                60 * 60 * 24,
+                share_password.map(String::from),
+                permission,
+                LinkAudience::Anyone,
+            )
+            .await?,
+        )
     } else {
         None
     };
 
+    // Let the uploader know that the owner is using their grace space,
+    // the used space is read back to include everything the triggers counted
+    let quota_warning = match owner_id {
+        Some(owner_id) if over_quota => {
+            let owner = sqlx::query!(
+                "SELECT total_space, used_space FROM user WHERE id = ?",
+                owner_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            warn!(
+                "User {} is over their total space ({} of {} bytes)",
+                owner_id, owner.used_space, owner.total_space
+            );
+            Some(QuotaWarning {
+                used_space: owner.used_space,
+                total_space: owner.total_space,
+                grace_space: state.config.grace_space(owner.total_space),
+            })
+        }
+        _ => None,
+    };
+
     // Everything went well, commit the transaction
     tx.commit().await?;
 
-    Ok(link)
-}
-
-#[derive(Debug, Deserialize, IntoParams)]
-#[serde(rename_all = "camelCase")]
-pub struct LinkParams {
-    link_id: Option<Uuid>,
+    Ok((link, quota_warning))
 }
 
 #[utoipa::path(
     delete,
     path = "/api/file/{id}",
-    description = "Delete a file. Recursively deletes all children if the file is a directory",
+    description = "Delete a file. Recursively deletes all children if the file is a directory. With `dryRun` set, nothing is deleted and what would be deleted is returned instead.",
     params(
             LinkParams,
+            DryRunQuery,
             ("id" = Uuid, Path, description = "The id of the file to delete"),
         ),
     responses(
-        (status = OK, description = "The file was deleted successfully", body = SuccessResponse),
+        (status = OK, description = "The file was deleted successfully, or what would be deleted on a dry run", body = DeleteResponse),
         (status = BAD_REQUEST, description = "File id was not provided", body = ErrorResponse),
         (status = NOT_FOUND, description = "File was not found", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The owner of the file shared files with the user before and chose to tell them why they were denied", body = ErrorResponse),
     ),
     security(
+        (),
         ("lokr_session_cookie" = [])
     )
 )]
@@ -441,16 +651,44 @@ pub async fn delete_file(
     user: Option<SessionAuth>,
     TypedHeader(cookies): TypedHeader<Cookie>,
     Query(params): Query<LinkParams>,
+    Query(dry_run): Query<DryRunQuery>,
     Path(id): Path<Uuid>,
 ) -> Result<Response, AppError> {
     // Check if the user owns the file or has edit
     // access to the file. In the case of edit access,
     // only children are able to be deleted
     let uuid = user.map(|user| user.0.id);
+    check_link_audience(&state, params.link_id, uuid).await?;
     let link_password = params
         .link_id
         .and_then(|l_id| cookies.get(&l_id.to_string()))
         .and_then(|password_hash| urlencoding::decode(password_hash).ok());
+    // Each attempt runs the whole transaction again
+    retry_transaction(&state, "delete file", || {
+        process_delete_transaction(
+            &state,
+            uuid,
+            params.link_id,
+            link_password.as_deref(),
+            dry_run.dry_run,
+            id,
+        )
+    })
+    .await
+}
+
+// Extract the transaction logic into a separate function to enable proper retries
+async fn process_delete_transaction(
+    state: &AppState,
+    uuid: Option<Uuid>,
+    link_id: Option<Uuid>,
+    link_password: Option<&str>,
+    dry_run: bool,
+    id: Uuid,
+) -> Result<Response, AppError> {
+    // Run everything in a transaction so the blobs queued for deletion
+    // always match the rows that were actually deleted
+    let mut tx = state.pool.begin().await?;
     if sqlx::query!(
         r#"
         WITH RECURSIVE ancestors AS (
@@ -480,112 +718,144 @@ pub async fn delete_file(
                 -- if it is a child of a directory being shared with them, not
                 -- the file itself
                 (su.edit_permission AND su.file_id != ?) OR 
-                (sl.edit_permission AND sl.file_id != ?)
+                (sl.edit_permission AND NOT sl.add_only AND sl.file_id != ?)
             )
         )
         LIMIT 1
         "#,
         id,
         uuid,
-        params.link_id,
+        link_id,
         link_password,
         uuid,
         id,
         id
     )
-    .fetch_optional(&state.pool)
+    .fetch_optional(&mut *tx)
     .await?
     .is_none() {
         // Return an error if the user have permission to delete the file
         // or the file doesn't exist
         // This is to prevent users from deleting files they don't own
         // or attempting to snoop on files they don't have access to
-        return Err(AppError::UserError((
-            StatusCode::NOT_FOUND,
-            "File not found".into(),
-        )));
+        let accessor = Accessor {
+            user_id: uuid,
+            link_id,
+            link_password,
+        };
+        return Err(denied(state, id, &accessor).await);
     };
 
     // Get the children of the file for local deletion
     let descendant_files = sqlx::query!(
         r#"
         WITH RECURSIVE descendants AS (
-            SELECT id, is_directory FROM file WHERE id = ?
+            SELECT id, is_directory, size, blob_id FROM file WHERE id = ?
             UNION ALL
-            SELECT f.id, f.is_directory
+            SELECT f.id, f.is_directory, f.size, f.blob_id
             FROM file f
             JOIN descendants d ON f.parent_id = d.id
         )
-        SELECT id AS "id: Uuid", is_directory AS "is_directory!" FROM descendants;
+        SELECT id AS "id: Uuid", is_directory AS "is_directory!", size AS "size!: i64",
+        COALESCE(blob_id, id) AS "blob_id!: Uuid"
+        FROM descendants;
         "#,
         id
     )
-    .fetch_all(&state.pool)
+    .fetch_all(&mut *tx)
     .await?;
+    // Data shared with aliases that are left is kept, and one of them takes
+    // over counting its size if the file that counted it was deleted.
+    // Directories aren't stored on the file system, so skip them.
+    let mut blobs: HashMap<Uuid, (i64, i64)> = HashMap::new();
+    for file in descendant_files.iter().filter(|file| !file.is_directory) {
+        let (size, references) = blobs.entry(file.blob_id).or_default();
+        *size += file.size;
+        *references += 1;
+    }
+
+    if dry_run {
+        let shares = sqlx::query!(
+            r#"
+            WITH RECURSIVE descendants AS (
+                SELECT id FROM file WHERE id = ?
+                UNION ALL
+                SELECT f.id FROM file f
+                JOIN descendants d ON f.parent_id = d.id
+            )
+            SELECT
+                (SELECT COUNT(*) FROM share_user
+                 WHERE file_id IN (SELECT id FROM descendants)) AS "users!: i64",
+                (SELECT COUNT(*) FROM share_link
+                 WHERE file_id IN (SELECT id FROM descendants) AND
+                 (expires_at IS NULL OR DATETIME(expires_at) >= CURRENT_TIMESTAMP)) AS "links!: i64"
+            "#,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        // Only data that no file outside of the deleted ones refers to is freed
+        let mut bytes = 0;
+        for (blob_id, (size, references)) in &blobs {
+            let total = sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!: i64" FROM file WHERE id = ? OR blob_id = ?"#,
+                blob_id,
+                blob_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            if total == *references {
+                bytes += size;
+            }
+        }
+        let (directories, files): (Vec<_>, Vec<_>) =
+            descendant_files.iter().partition(|file| file.is_directory);
+        let preview = DeletePreview {
+            ids: descendant_files.iter().map(|file| file.id).collect(),
+            files: files.len() as i64,
+            directories: directories.len() as i64,
+            bytes,
+            shared_users: shares.users,
+            share_links: shares.links,
+        };
+        return Ok((StatusCode::OK, Json(preview)).into_response());
+    }
 
     // The user has permission to delete the file, so delete it and all of its
     // children recursively
     sqlx::query!(r#"DELETE FROM file WHERE id = ?"#, id)
-        .execute(&state.pool)
+        .execute(&mut *tx)
         .await?;
 
-    for file in descendant_files {
-        // Only delete the file on the local file system if it is not a directory
-        // This is because we don't actually store created directories on the file system
-        if !file.is_directory {
-            // If the file exists, delete it
-            match std::fs::remove_file(&*UPLOAD_DIR.join(file.id.to_string())) {
-                // A not found error likely means that the file was already deleted
-                // so just ignore it.
-                // Any other error likely means that there actually is a file
-                // system error so log it. We don't want to return the error
-                // because we want to try deleting all of the files locally
-                // instead of short-circuiting. Either way, the files are deleted
-                // in the database, so they are inaccessible to the user
-                Err(e) if e.kind() != ErrorKind::NotFound => {
-                    error!("Unable to delete file '{}': {}", file.id, e);
-                }
-                _ => {}
-            }
+    let mut ids = Vec::new();
+    for (blob_id, (size, _)) in blobs {
+        let kept = sqlx::query_scalar!(
+            r#"
+            UPDATE file SET size = size + ?
+            WHERE id = (SELECT id FROM file WHERE id = ? OR blob_id = ? ORDER BY id LIMIT 1)
+            RETURNING id AS "id: Uuid"
+            "#,
+            size,
+            blob_id,
+            blob_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if kept.is_none() {
+            ids.push(blob_id);
         }
     }
+    // Deleting the data of a large directory can take a while, so leave it
+    // to the job worker instead of making the client wait for it.
+    if !ids.is_empty() {
+        jobs::enqueue(&mut *tx, uuid, &Job::DeleteBlobs { ids }).await?;
+    }
+    tx.commit().await?;
+    state.job_notify.notify_one();
 
     Ok((StatusCode::OK, success!("File deleted successfully")).into_response())
 }
 
-#[derive(Deserialize, ToSchema, Debug)]
-#[serde(rename_all = "camelCase", tag = "type")]
-pub enum UpdateFile {
-    /// Move the file to a new parent
-    #[serde(rename_all = "camelCase")]
-    Move {
-        /// The new parent id of the file
-        parent_id: Option<Uuid>,
-        #[schema(
-            example = "38ZP4XEKLikREzyy9ttdaKLZ8WiWCd2i8ptTCwRwMlc=",
-            content_encoding = "base64"
-        )]
-        encrypted_key: String,
-        /// The new nonce for the encryption key
-        #[schema(example = "nonce", content_encoding = "base64")]
-        key_nonce: Option<String>,
-    },
-    /// Rename the file
-    #[serde(rename_all = "camelCase")]
-    Rename {
-        /// The new encrypted name of the file
-        #[schema(
-            example = "38ZP4XEKLikREzyy9ttdaKLZ8WiWCd2i8ptTCwRwMlc=",
-            content_encoding = "base64"
-        )]
-        encrypted_name: String,
-        /// The new nonce for the file name
-        /// We use a new one for each name for security reasons
-        #[schema(example = "nonce", content_encoding = "base64")]
-        name_nonce: String,
-    },
-}
-
 #[utoipa::path(
     put,
     path = "/api/file/{id}",
@@ -597,10 +867,12 @@ pub enum UpdateFile {
         ),
     responses(
         (status = OK, description = "The file was updated successfully", body = SuccessResponse),
-        (status = BAD_REQUEST, description = "File id was not provided or the new parent is not a directory", body = ErrorResponse),
+        (status = BAD_REQUEST, description = "File id was not provided, the new parent is not a directory or the new key doesn't match its algorithm", body = ErrorResponse),
         (status = NOT_FOUND, description = "File was not found", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The owner of the file shared files with the user before and chose to tell them why they were denied", body = ErrorResponse),
     ),
     security(
+        (),
         ("lokr_session_cookie" = [])
     )
 )]
@@ -614,6 +886,7 @@ pub async fn update_file(
     Json(body): Json<UpdateFile>,
 ) -> Result<Response, AppError> {
     let uuid = user.map(|user| user.0.id);
+    check_link_audience(&state, params.link_id, uuid).await?;
     let link_password = params
         .link_id
         .and_then(|l_id| cookies.get(&l_id.to_string()))
@@ -649,7 +922,7 @@ pub async fn update_file(
                 -- if it is a child of a directory being shared with them, not
                 -- the file itself
                 (su.edit_permission AND su.file_id != ?) OR 
-                (sl.edit_permission AND sl.file_id != ?)
+                (sl.edit_permission AND NOT sl.add_only AND sl.file_id != ?)
             )
         )
         LIMIT 1
@@ -668,10 +941,12 @@ pub async fn update_file(
         // or the file doesn't exist
         // This is to prevent users from updating files they don't own
         // or attempting to snoop on files they don't have access to
-        return Err(AppError::UserError((
-            StatusCode::NOT_FOUND,
-            "Unable to find file to update".into(),
-        )));
+        let accessor = Accessor {
+            user_id: uuid,
+            link_id: params.link_id,
+            link_password: link_password.as_deref(),
+        };
+        return Err(denied(&state, id, &accessor).await);
     };
 
     match body {
@@ -679,6 +954,7 @@ pub async fn update_file(
             parent_id,
             encrypted_key,
             key_nonce,
+            key_algorithm,
         } => {
             if parent_id.is_some() != key_nonce.is_some() {
                 return Err(AppError::UserError((
@@ -686,6 +962,10 @@ pub async fn update_file(
                     "A new nonce is only needed if the file does not have a parent id".into(),
                 )));
             }
+            let key_algorithm =
+                check_wrapped_key(key_algorithm, &encrypted_key, key_nonce.as_deref())
+                    .map_err(|e| AppError::UserError((StatusCode::BAD_REQUEST, e)))?
+                    as i64;
             // Make sure that the target parent file being has the same owner to
             // prevent tampering with the source file.
             // We also include a children query here to ensure that the
@@ -738,7 +1018,7 @@ pub async fn update_file(
                         )
                         AND 
                             -- Ensure that the user has permission to edit the file
-                            (owner_id = ? OR su.edit_permission OR sl.edit_permission)
+                            (owner_id = ? OR su.edit_permission OR (sl.edit_permission AND NOT sl.add_only))
                         LIMIT 1
                         "#,
                         parent_id,
@@ -781,10 +1061,11 @@ pub async fn update_file(
 
             // Update the parent id of the file
             sqlx::query!(
-                "UPDATE file SET parent_id = ?, encrypted_key = ?, key_nonce = ? WHERE id = ?",
+                "UPDATE file SET parent_id = ?, encrypted_key = ?, key_nonce = ?, key_algorithm = ? WHERE id = ?",
                 parent_id,
                 encrypted_key,
                 key_nonce,
+                key_algorithm,
                 id
             )
             .execute(&state.pool)
@@ -809,6 +1090,241 @@ pub async fn update_file(
     Ok((StatusCode::OK, success!("File updated successfully")).into_response())
 }
 
+#[utoipa::path(
+    post,
+    path = "/api/file/{id}/alias",
+    description = "Make a file appear in another directory as well without storing its data again. The alias shares the data of the file, and the data is only deleted once the file and all of its aliases are. The data only counts towards the owner's space once. Only the owner of a file can alias it, and only into their own directories.",
+    request_body(content = AliasRequest, content_type = "application/json"),
+    params(
+            ("id" = Uuid, Path, description = "The id of the file to alias"),
+        ),
+    responses(
+        (status = OK, description = "The alias was created", body = UploadResponse),
+        (status = BAD_REQUEST, description = "The file is a directory, the parent is not a directory or the key doesn't match its algorithm", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = PAYMENT_REQUIRED, description = "The user is out of space", body = ErrorResponse),
+        (status = NOT_FOUND, description = "The file or the parent was not found", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state, body))]
+pub async fn create_alias(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Path(id): Path<Uuid>,
+    Json(body): Json<AliasRequest>,
+) -> Result<Response, AppError> {
+    if body.parent_id.is_some() != body.key_nonce.is_some() {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            "A new nonce is only needed if the file does not have a parent id".into(),
+        )));
+    }
+    let key_algorithm = check_wrapped_key(
+        body.key_algorithm,
+        &body.encrypted_key,
+        body.key_nonce.as_deref(),
+    )
+    .map_err(|e| AppError::UserError((StatusCode::BAD_REQUEST, e)))? as i64;
+
+    let alias_id = Uuid::now_v7();
+    let plaintext_size = retry_transaction(&state, "create alias", || async {
+        let mut tx = state.pool.begin().await?;
+        let Some(is_directory) = sqlx::query_scalar!(
+            r#"SELECT is_directory AS "is_directory!" FROM file WHERE id = ? AND owner_id = ?"#,
+            id,
+            user.id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Err(AppError::UserError((
+                StatusCode::NOT_FOUND,
+                "File not found".into(),
+            )));
+        };
+        // An alias of a directory could end up inside of itself
+        if is_directory {
+            return Err(AppError::UserError((
+                StatusCode::BAD_REQUEST,
+                "Directories can't be aliased".into(),
+            )));
+        }
+        if let Some(parent_id) = body.parent_id {
+            let Some(parent_is_directory) = sqlx::query_scalar!(
+                r#"SELECT is_directory AS "is_directory!" FROM file WHERE id = ? AND owner_id = ?"#,
+                parent_id,
+                user.id
+            )
+            .fetch_optional(&mut *tx)
+            .await?
+            else {
+                return Err(AppError::UserError((
+                    StatusCode::NOT_FOUND,
+                    "Parent directory not found".into(),
+                )));
+            };
+            if !parent_is_directory {
+                return Err(AppError::UserError((
+                    StatusCode::BAD_REQUEST,
+                    "Cannot set file parent to non-directories".into(),
+                )));
+            }
+        }
+
+        // The alias only takes up space for its own row, so only turn it down
+        // if the owner is already past their grace space
+        let owner = sqlx::query!(
+            "SELECT total_space, used_space FROM user WHERE id = ?",
+            user.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if owner.used_space > owner.total_space + state.config.grace_space(owner.total_space) {
+            return Err(AppError::UserError((
+                StatusCode::PAYMENT_REQUIRED,
+                "File owner does not have enough free space".into(),
+            )));
+        }
+
+        // Aliases of aliases share the data of the original file directly,
+        // so there is never more than one hop to the data
+        let plaintext_size = sqlx::query_scalar!(
+            r#"
+            INSERT INTO file (id, owner_id, uploader_id, parent_id,
+            encrypted_key, encrypted_name, mime, file_nonce,
+            key_nonce, mime_type_nonce, name_nonce, is_directory, size, fingerprint, key_epoch,
+            metadata_version, key_algorithm, plaintext_size, blob_id)
+            SELECT ?, owner_id, ?, ?, ?, encrypted_name, mime, file_nonce,
+            ?, mime_type_nonce, name_nonce, FALSE, 0, fingerprint,
+            (SELECT key_epoch FROM user WHERE user.id = file.owner_id),
+            metadata_version, ?, plaintext_size, COALESCE(blob_id, id)
+            FROM file WHERE id = ?
+            RETURNING plaintext_size
+            "#,
+            alias_id,
+            user.id,
+            body.parent_id,
+            body.encrypted_key,
+            body.key_nonce,
+            key_algorithm,
+            id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(plaintext_size)
+    })
+    .await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(UploadResponse {
+            id: alias_id,
+            size: 0,
+            plaintext_size,
+            is_directory: false,
+            link: None,
+            quota_warning: None,
+        }),
+    )
+        .into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/files/rewrap",
+    description = "Replace the encrypted keys of many files at once, such as after moving a large directory. All of the keys are updated in a single transaction, and a key that can't be updated doesn't stop the others from being updated. Only the owner of a file can re-wrap its key, and the password or a TOTP code has to have been entered recently. Every call bumps the user's key epoch, and the updated files are given the new epoch.",
+    request_body(content = RewrapRequest, description = "The new encrypted keys"),
+    responses(
+        (status = OK, description = "The keys were processed, see the result of each key", body = RewrapResponse),
+        (status = BAD_REQUEST, description = "Too many keys were sent", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The password or a TOTP code has to be entered again first", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state, req))]
+pub async fn rewrap_keys(
+    State(state): State<AppState>,
+    StepUpAuth(user): StepUpAuth,
+    Json(req): Json<RewrapRequest>,
+) -> Result<Response, AppError> {
+    if req.files.len() > MAX_REWRAP_BATCH {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!("Cannot re-wrap more than {} keys at once", MAX_REWRAP_BATCH),
+        )));
+    }
+
+    let (results, key_epoch) = retry_transaction(&state, "rewrap keys", || async {
+        let mut tx = state.pool.begin().await?;
+        // Re-wrapping keys is a key rotation, so the files get a new epoch that
+        // clients holding the old keys can notice
+        let key_epoch = sqlx::query_scalar!(
+            "UPDATE user SET key_epoch = key_epoch + 1 WHERE id = ? RETURNING key_epoch",
+            user.id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        let mut results = Vec::with_capacity(req.files.len());
+        for RewrapKey {
+            file_id,
+            encrypted_key,
+            key_nonce,
+            key_algorithm,
+        } in &req.files
+        {
+            let file = sqlx::query!(
+                r#"SELECT parent_id AS "parent_id: Uuid" FROM file WHERE id = ? AND owner_id = ?"#,
+                file_id,
+                user.id
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            let key_algorithm = check_wrapped_key(*key_algorithm, encrypted_key, key_nonce.as_deref());
+            let error = match (file, key_algorithm) {
+                (None, _) => Some("File not found".into()),
+                (Some(_), _) if encrypted_key.is_empty() => Some("Missing encrypted key".into()),
+                // Keys of files in the root directory are encrypted with the
+                // owner's public key, which doesn't use a nonce
+                (Some(file), _) if file.parent_id.is_some() != key_nonce.is_some() => {
+                    Some("A nonce is only needed if the file has a parent".into())
+                }
+                (Some(_), Err(e)) => Some(e),
+                (Some(_), Ok(key_algorithm)) => {
+                    let key_algorithm = key_algorithm as i64;
+                    sqlx::query!(
+                        "UPDATE file SET encrypted_key = ?, key_nonce = ?, key_algorithm = ?, key_epoch = ? WHERE id = ?",
+                        encrypted_key,
+                        key_nonce,
+                        key_algorithm,
+                        key_epoch,
+                        file_id
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    None
+                }
+            };
+            results.push(RewrapResult {
+                file_id: *file_id,
+                updated: error.is_none(),
+                error,
+            });
+        }
+        tx.commit().await?;
+        Ok((results, key_epoch))
+    })
+    .await?;
+
+    Ok((StatusCode::OK, Json(RewrapResponse { results, key_epoch })).into_response())
+}
+
 /// Check if a user owns a file
 pub async fn is_owner<'a, E: Executor<'a, Database = Sqlite>>(
     db: E,
@@ -825,125 +1341,10 @@ pub async fn is_owner<'a, E: Executor<'a, Database = Sqlite>>(
     .is_some())
 }
 
-/// Metadata of a file or directory
-#[derive(Serialize, ToSchema, Clone, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct FileMetadata {
-    /// The id of the file or directory
-    pub id: Uuid,
-    #[serde(flatten)]
-    pub upload: UploadMetadata,
-    pub created_at: DateTime<Utc>,
-    pub modified_at: DateTime<Utc>,
-    pub owner_id: Option<Uuid>,
-    pub uploader_id: Option<Uuid>,
-    pub size: i64,
-    /// Whether or not the user has edit permission to this file
-    /// if this is not set then the file should inherit the edit permissions
-    /// of the parent. This will not be sent when a user is querying their
-    /// own files, as they will always have edit permissions.
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub edit_permission: Option<bool>,
-    /// The children of the directory.
-    /// Only present if the file is a directory.
-    #[serde(skip_serializing_if = "Vec::is_empty")]
-    pub children: Vec<Uuid>,
-}
-
-#[derive(Deserialize, IntoParams, Debug)]
-#[serde(rename_all = "camelCase")]
-#[serde_inline_default]
-pub struct FileQuery {
-    /// The id of the file or directory to get.
-    /// If not provided, the root of the currently
-    /// authorized user directory is returned
-    pub id: Option<Uuid>,
-    /// The maximum depth to return children for
-    #[serde_inline_default(1)]
-    #[param(maximum = 20, default = 1)]
-    pub depth: u32,
-    /// The offset to start returning children from
-    #[param(default = 0)]
-    #[serde_inline_default(0)]
-    pub offset: u32,
-    /// The maximum number of children to return
-    #[param(default = 50)]
-    #[serde_inline_default(50)]
-    pub limit: u32,
-    /// Whether to include the ancestors of the
-    /// chain of the file in the response
-    #[serde(default)]
-    pub include_ancestors: bool,
-}
-
-impl FileMetadata {
-    fn example() -> HashMap<Uuid, Self> {
-        let parent_uuid = Uuid::try_parse_ascii(b"123e4567-e89b-12d3-a456-426614174000").unwrap();
-        let child_uuid = Uuid::try_parse_ascii(b"21f981a7-d21f-4aa5-9f6b-09005235236a").unwrap();
-        let user_id = Uuid::try_parse_ascii(b"dae2b0f0-d84b-42c8-aebd-58a71ee1fb86").unwrap();
-
-        let date = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
-        let first = FileMetadata {
-            id: parent_uuid,
-            upload: UploadMetadata {
-                encrypted_file_name: "encryptedFileName".into(),
-                encrypted_mime_type: Some("encryptedMimeType".into()),
-                encrypted_key: "encryptedKey".into(),
-                file_nonce: Some("exampleNonce".into()),
-                key_nonce: Some("exampleNonce".into()),
-                name_nonce: "exampleNonce".into(),
-                mime_type_nonce: Some("exampleNonce".into()),
-                is_directory: true,
-                parent_id: None,
-            },
-            size: 0,
-            edit_permission: None,
-            created_at: date,
-            modified_at: date,
-            owner_id: Some(user_id),
-            uploader_id: Some(user_id),
-            children: vec![child_uuid],
-        };
-        let child = FileMetadata {
-            id: child_uuid,
-            upload: UploadMetadata {
-                encrypted_file_name: "encryptedFileName".into(),
-                encrypted_mime_type: Some("encryptedMimeType".into()),
-                encrypted_key: "encryptedKey".into(),
-                file_nonce: Some("exampleNonce".into()),
-                key_nonce: Some("exampleNonce".into()),
-                name_nonce: "exampleNonce".into(),
-                mime_type_nonce: Some("exampleNonce".into()),
-                is_directory: false,
-                parent_id: Some(parent_uuid),
-            },
-            size: 32,
-            created_at: date,
-            modified_at: date,
-            owner_id: Some(user_id),
-            uploader_id: Some(user_id),
-            children: vec![],
-            edit_permission: None,
-        };
-        HashMap::from([(parent_uuid, first), (child_uuid, child)])
-    }
-}
-
-#[derive(Serialize, ToSchema)]
-pub struct FileResponse {
-    #[schema(
-        example = FileMetadata::example
-    )]
-    pub files: HashMap<Uuid, FileMetadata>,
-    #[schema(example = "same kind of thing as files, but with `PublicUser` schema...")]
-    pub users: HashMap<Uuid, PublicUser>,
-    #[schema(example = "123e4567-e89b-12d3-a456-426614174000")]
-    pub root: Vec<Uuid>,
-}
 #[utoipa::path(
     get,
     path = "/api/file",
-    description = "Get the metadata of a file or directory. Also returns the children of a directory.",
+    description = "Get the metadata of a file or directory. Also returns the children of a directory. Directories the user mounted are listed in their root and can be browsed like their own, with the keys of the files shared with them.",
     params(
         FileQuery
     ),
@@ -951,9 +1352,9 @@ pub struct FileResponse {
         (status = OK, description = "The file or directory metadata was retrieved successfully", body = FileResponse),
         (status = BAD_REQUEST, description = "No file id or user authoziation provided", body = ErrorResponse),
         (status = NOT_FOUND, description = "File was not found"),
+        (status = FORBIDDEN, description = "The owner of the file shared files with the user before and chose to tell them why they were denied", body = ErrorResponse),
     ),
     security(
-        (),
         ("lokr_session_cookie" = [])
     )
 )]
@@ -967,7 +1368,14 @@ pub async fn get_file_metadata(
     let depth = params.depth.min(20);
     let query = sqlx::query!(
         r#"
-            WITH RECURSIVE children AS (
+            WITH RECURSIVE anchor_ancestors AS (
+                -- Ancestors of the specified node, not including itself
+                SELECT parent_id AS id FROM file WHERE id = ?
+                UNION ALL
+                SELECT f.parent_id FROM file f
+                JOIN anchor_ancestors a ON f.id = a.id
+            ),
+            children AS (
                 -- Anchor member (root or specified node)
                 SELECT 
                     0 AS depth,
@@ -981,11 +1389,25 @@ pub async fn get_file_metadata(
                     key_nonce, 
                     name_nonce, 
                     mime_type_nonce, 
+                    metadata_version,
+                    key_algorithm,
                     is_directory, 
                     mime,
-                    size,
+                    IIF(blob_id IS NULL, size, (SELECT MAX(b.size) FROM file b
+                        WHERE b.id = file.blob_id OR b.blob_id = file.blob_id)) AS size,
+                    plaintext_size,
+                    blob_id,
                     created_at,
-                    modified_at
+                    modified_at,
+                    key_epoch,
+                    (SELECT COUNT(*) FROM share_user WHERE file_id = file.id) AS shared_user_count,
+                    (SELECT COUNT(*) FROM share_link WHERE file_id = file.id AND
+                    (expires_at IS NULL OR DATETIME(expires_at) >= CURRENT_TIMESTAMP)) AS active_link_count,
+                    (
+                        EXISTS (SELECT 1 FROM share_user WHERE file_id IN (SELECT id FROM anchor_ancestors)) OR
+                        EXISTS (SELECT 1 FROM share_link WHERE file_id IN (SELECT id FROM anchor_ancestors) AND
+                        (expires_at IS NULL OR DATETIME(expires_at) >= CURRENT_TIMESTAMP))
+                    ) AS shared_via_ancestor
                 FROM file
                 WHERE 
                 owner_id = COALESCE(?, owner_id) AND
@@ -1005,11 +1427,22 @@ pub async fn get_file_metadata(
                     f.key_nonce, 
                     f.name_nonce, 
                     f.mime_type_nonce, 
+                    f.metadata_version,
+                    f.key_algorithm,
                     f.is_directory, 
                     f.mime,
-                    f.size,
+                    IIF(f.blob_id IS NULL, f.size, (SELECT MAX(b.size) FROM file b
+                        WHERE b.id = f.blob_id OR b.blob_id = f.blob_id)),
+                    f.plaintext_size,
+                    f.blob_id,
                     f.created_at,
-                    f.modified_at
+                    f.modified_at,
+                    f.key_epoch,
+                    (SELECT COUNT(*) FROM share_user WHERE file_id = f.id),
+                    (SELECT COUNT(*) FROM share_link WHERE file_id = f.id AND
+                    (expires_at IS NULL OR DATETIME(expires_at) >= CURRENT_TIMESTAMP)),
+                    -- A file is shared through its ancestors if its parent is
+                    c.shared_via_ancestor OR c.shared_user_count > 0 OR c.active_link_count > 0
                 FROM file f
                 JOIN children c ON f.parent_id = c.id
                 WHERE 
@@ -1029,13 +1462,22 @@ pub async fn get_file_metadata(
                 key_nonce, 
                 name_nonce, 
                 mime_type_nonce AS "mime_type_nonce?", 
+                metadata_version,
+                key_algorithm AS "key_algorithm!: KeyAlgorithm",
                 is_directory AS "is_directory!",
                 mime,
-                IIF(size - 16 < 0, 0, size - 16) AS "size!: i64",
+                plaintext_size AS "size!: i64",
+                size AS "ciphertext_size!: i64",
+                blob_id AS "alias_of: Uuid",
                 created_at,
-                modified_at
+                modified_at,
+                key_epoch,
+                shared_user_count AS "shared_user_count!: i64",
+                active_link_count AS "active_link_count!: i64",
+                shared_via_ancestor AS "shared_via_ancestor!: bool"
             FROM children ORDER BY depth ASC LIMIT ? OFFSET ?
             "#,
+        params.id,
         user.id,
         params.id,
         params.id,
@@ -1064,10 +1506,13 @@ pub async fn get_file_metadata(
                     key_nonce, 
                     name_nonce, 
                     mime_type_nonce, 
+                    metadata_version,
+                    key_algorithm,
                     is_directory, 
                     mime,
                     created_at,
-                    modified_at
+                    modified_at,
+                    key_epoch
                 FROM file
                 WHERE 
                 owner_id = ? AND
@@ -1087,10 +1532,13 @@ pub async fn get_file_metadata(
                     f.key_nonce, 
                     f.name_nonce, 
                     f.mime_type_nonce, 
+                    f.metadata_version,
+                    f.key_algorithm,
                     f.is_directory, 
                     f.mime,
                     f.created_at,
-                    f.modified_at
+                    f.modified_at,
+                    f.key_epoch
                 FROM file f
                 JOIN ancestors a ON f.id = a.parent_id
             )
@@ -1106,13 +1554,17 @@ pub async fn get_file_metadata(
                 key_nonce, 
                 name_nonce, 
                 mime_type_nonce AS "mime_type_nonce?", 
+                metadata_version,
+                key_algorithm AS "key_algorithm!: KeyAlgorithm",
                 is_directory AS "is_directory!",
                 mime,
                 -- Ancestors are always directories so their size must
                 -- be always be 0
                 0 AS "size!: i64",
+                0 AS "ciphertext_size!: i64",
                 created_at,
-                modified_at
+                modified_at,
+                key_epoch
             FROM ancestors
             WHERE depth > 0
             ORDER BY depth DESC
@@ -1139,17 +1591,27 @@ pub async fn get_file_metadata(
                 key_nonce: row.key_nonce,
                 name_nonce: row.name_nonce,
                 mime_type_nonce: row.mime_type_nonce,
+                metadata_version: row.metadata_version,
+                key_algorithm: Some(row.key_algorithm),
+                plaintext_size: None,
             },
             size: row.size,
+            ciphertext_size: row.ciphertext_size,
             children: Vec::new(),
             edit_permission: None,
+            shared_user_count: None,
+            active_link_count: None,
+            shared_via_ancestor: None,
+            key_epoch: Some(row.key_epoch),
+            mount_id: None,
+            alias_of: None,
         });
         (query, Some(ancestors))
     } else {
         (query.await?, None)
     };
     // Convert the query result into a tree structure
-    let (files, root) = ancestors
+    let (mut files, mut root) = ancestors
         .into_iter()
         .flatten()
         .chain(query.into_iter().map(|row| FileMetadata {
@@ -1168,17 +1630,53 @@ pub async fn get_file_metadata(
                 key_nonce: row.key_nonce,
                 name_nonce: row.name_nonce,
                 mime_type_nonce: row.mime_type_nonce,
+                metadata_version: row.metadata_version,
+                key_algorithm: Some(row.key_algorithm),
+                plaintext_size: None,
             },
             size: row.size,
+            ciphertext_size: row.ciphertext_size,
             children: Vec::new(),
             edit_permission: None,
+            shared_user_count: Some(row.shared_user_count),
+            active_link_count: Some(row.active_link_count),
+            shared_via_ancestor: Some(row.shared_via_ancestor),
+            key_epoch: Some(row.key_epoch),
+            mount_id: None,
+            alias_of: row.alias_of,
         }))
         .normalize();
-    if params.id.is_some() && files.is_empty() {
-        Err(AppError::UserError((
-            StatusCode::NOT_FOUND,
-            "File not found".into(),
-        )))
+    let mounts = match params.id {
+        // Mounted directories are listed after the user's own files on the first page
+        None if params.offset == 0 => active_mounts(&state.pool, user.id).await?,
+        // Files that aren't the user's own may be inside of a directory they mounted
+        Some(id) if files.is_empty() && is_mounted(&state.pool, user.id, id).await? => {
+            (files, root) = shared_tree(&state.pool, user.id, &params, None).await?;
+            active_mounts(&state.pool, user.id).await?
+        }
+        _ => Vec::new(),
+    };
+    for (mount_id, file_id) in mounts {
+        if params.id.is_none() {
+            let query = FileQuery {
+                id: Some(file_id),
+                include_ancestors: false,
+                ..params.clone()
+            };
+            let (mounted, mounted_root) = shared_tree(&state.pool, user.id, &query, None).await?;
+            files.extend(mounted);
+            root.extend(mounted_root);
+        }
+        if let Some(file) = files.get_mut(&file_id) {
+            file.mount_id = Some(mount_id);
+        }
+    }
+    if let (Some(id), true) = (params.id, files.is_empty()) {
+        let accessor = Accessor {
+            user_id: Some(user.id),
+            ..Default::default()
+        };
+        Err(denied(&state, id, &accessor).await)
     } else {
         Ok((
             StatusCode::OK,
@@ -1186,12 +1684,195 @@ pub async fn get_file_metadata(
                 users: get_file_users(&state.pool, &files).await?,
                 files,
                 root,
+                owners: None,
             }),
         )
             .into_response())
     }
 }
 
+#[utoipa::path(
+    get,
+    path = "/api/file/{id}/uploaders",
+    description = "Get the files in a directory grouped by the user that uploaded them. Only available to the owner of the directory or users with edit permission to it.",
+    params(
+        ("id" = Uuid, Path, description = "The id of the directory")
+    ),
+    responses(
+        (status = OK, description = "The uploaders were retrieved successfully", body = UploaderResponse),
+        (status = NOT_FOUND, description = "File was not found", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_file_uploaders(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Path(id): Path<Uuid>,
+) -> Result<Response, AppError> {
+    if sqlx::query!(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT
+                id,
+                parent_id
+            FROM file
+            WHERE id = ?  -- the file we're checking
+            UNION ALL
+            SELECT
+                f.id,
+                f.parent_id
+            FROM file f
+            JOIN ancestors a ON f.id = a.parent_id
+        )
+        SELECT file.id
+        FROM file
+        LEFT JOIN share_user AS su
+        ON su.file_id = file.id AND su.user_id = ?
+        WHERE file.id IN (SELECT id FROM ancestors) AND (owner_id = ? OR su.edit_permission)
+        LIMIT 1
+        "#,
+        id,
+        user.id,
+        user.id
+    )
+    .fetch_optional(&state.pool)
+    .await?
+    .is_none()
+    {
+        return Err(AppError::UserError((
+            StatusCode::NOT_FOUND,
+            "File not found".into(),
+        )));
+    }
+
+    let rows = sqlx::query!(
+        r#"
+        WITH RECURSIVE children AS (
+            SELECT id, uploader_id, is_directory, plaintext_size FROM file WHERE id = ?
+            UNION ALL
+            SELECT f.id, f.uploader_id, f.is_directory, f.plaintext_size FROM file f
+            JOIN children c ON f.parent_id = c.id
+        )
+        SELECT id AS "id: Uuid", uploader_id AS "uploader_id: Uuid",
+        plaintext_size AS "size!: i64"
+        FROM children
+        WHERE NOT is_directory
+        ORDER BY uploader_id
+        "#,
+        id
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut uploaders: Vec<UploaderSummary> = Vec::new();
+    for row in rows {
+        match uploaders.last_mut() {
+            Some(last) if last.uploader_id == row.uploader_id => {
+                last.total_size += row.size;
+                last.files.push(row.id);
+            }
+            _ => uploaders.push(UploaderSummary {
+                uploader_id: row.uploader_id,
+                total_size: row.size,
+                files: vec![row.id],
+            }),
+        }
+    }
+    // Show the biggest contributors first
+    uploaders.sort_by_key(|uploader| std::cmp::Reverse(uploader.total_size));
+
+    let users = sqlx::query!(
+        r#"
+        WITH RECURSIVE children AS (
+            SELECT id, uploader_id FROM file WHERE id = ?
+            UNION ALL
+            SELECT f.id, f.uploader_id FROM file f
+            JOIN children c ON f.parent_id = c.id
+        )
+        SELECT id AS "id: Uuid", username, email, public_key, avatar AS avatar_extension, avatar_version
+        FROM user WHERE id IN (SELECT uploader_id FROM children)
+        "#,
+        id
+    )
+    .fetch_all(&state.pool)
+    .await?
+    .into_iter()
+    .map(|row| {
+        (
+            row.id,
+            PublicUser {
+                id: row.id,
+                username: row.username,
+                email: row.email,
+                public_key: row.public_key,
+                avatar_extension: row.avatar_extension,
+                avatar_version: row.avatar_version,
+                password_salt: None,
+            },
+        )
+    })
+    .collect();
+
+    Ok((StatusCode::OK, Json(UploaderResponse { uploaders, users })).into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/file/fingerprints",
+    description = "Find the files of the currently authenticated user that were uploaded with any of the given fingerprints, so that sync clients can skip uploading files that haven't changed. Only files owned by the user are searched.",
+    request_body(content = FingerprintQuery, description = "The fingerprints to look for"),
+    responses(
+        (status = OK, description = "The matching files were retrieved successfully", body = FingerprintResponse),
+        (status = BAD_REQUEST, description = "Too many fingerprints were requested", body = ErrorResponse),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse),
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state, query))]
+pub async fn find_fingerprints(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+    Json(query): Json<FingerprintQuery>,
+) -> Result<Response, AppError> {
+    if query.fingerprints.len() > MAX_FINGERPRINT_LOOKUP {
+        return Err(AppError::UserError((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Cannot look up more than {} fingerprints at once",
+                MAX_FINGERPRINT_LOOKUP
+            ),
+        )));
+    }
+    let fingerprints = serde_json::to_string(&query.fingerprints)?;
+    let rows = sqlx::query!(
+        r#"
+        SELECT id AS "id: Uuid", fingerprint AS "fingerprint!"
+        FROM file
+        WHERE owner_id = ? AND fingerprint IN (SELECT value FROM json_each(?))
+        ORDER BY id
+        "#,
+        user.id,
+        fingerprints
+    )
+    .fetch_all(&state.pool)
+    .await?;
+
+    let mut response = FingerprintResponse::default();
+    for row in rows {
+        response
+            .files
+            .entry(row.fingerprint)
+            .or_default()
+            .push(row.id);
+    }
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
 #[utoipa::path(
     get,
     path = "/api/file/data/{id}",
@@ -1203,6 +1884,9 @@ pub async fn get_file_metadata(
     responses(
         (status = OK, description = "The file was retrieved successfully", content_type = "application/octet-stream"),
         (status = NOT_FOUND, description = "File was not found"),
+        (status = UNAUTHORIZED, description = "The link only works for logged in users", body = ErrorResponse),
+        (status = FORBIDDEN, description = "The owner of the file shared files with the user before and chose to tell them why they were denied, or the link doesn't work for the logged in user", body = ErrorResponse),
+        (status = TOO_MANY_REQUESTS, description = "The owner of the file has used up their transfer for this month", body = ErrorResponse),
     ),
 )]
 // Dummy function to avoid generate documentation for this path
@@ -1216,7 +1900,7 @@ pub async fn serve_auth(
     TypedHeader(cookies): TypedHeader<Cookie>,
     uri: Uri,
     Query(params): Query<LinkParams>,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> Result<Response, AppError> {
     if auth.is_none() && params.link_id.is_none() {
@@ -1237,48 +1921,53 @@ pub async fn serve_auth(
         )));
     };
     let uuid = auth.map(|user| user.0.id);
+    check_link_audience(&state, params.link_id, uuid).await?;
     let link_password = params
         .link_id
         .and_then(|l_id| cookies.get(&l_id.to_string()))
         .and_then(|password_hash| urlencoding::decode(password_hash).ok());
-    let query = sqlx::query!(r#"
-        WITH RECURSIVE ancestors AS (
-            SELECT
-                id,
-                parent_id
-            FROM file
-            WHERE id = ?  -- the file we're checking
-            UNION ALL
-            SELECT
-                f.id,
-                f.parent_id
-            FROM file f
-            JOIN ancestors a ON f.id = a.parent_id
-        )
-        SELECT is_directory
-        FROM file 
-        LEFT JOIN share_user AS su
-        ON su.file_id = file.id AND su.user_id = ?
-        LEFT JOIN share_link AS sl
-        ON sl.file_id = file.id AND sl.id = ? AND (expires_at IS NULL OR DATETIME(expires_at) >= CURRENT_TIMESTAMP)
-        AND (sl.password_hash IS NULL OR sl.password_hash = ?)
-        WHERE file.id IN (SELECT id FROM ancestors) AND
-        owner_id = ? OR su.file_id IS NOT NULL OR sl.file_id IS NOT NULL
-        LIMIT 1
-        "#,
-        id,
-        uuid,
-        params.link_id,
-        link_password,
-        uuid,
-    ).fetch_optional(&state.pool).await?;
-
-    if query.is_none() {
-        return Err(AppError::UserError((
-            StatusCode::NOT_FOUND,
-            "File not found".into(),
-        )));
+    let accessor = Accessor {
+        user_id: uuid,
+        link_id: params.link_id,
+        link_password: link_password.as_deref(),
+    };
+    if file_access(&state.pool, id, &accessor).await?.is_none() {
+        return Err(denied(&state, id, &accessor).await);
     }
+    let file = sqlx::query!(
+        r#"SELECT owner_id AS "owner_id: Uuid", blob_id AS "blob_id: Uuid" FROM file WHERE id = ?"#,
+        id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    // Aliases are served from the data they share with the file they were made of
+    if let Some(blob_id) = file.blob_id {
+        let mut path_and_query = format!("{}{}", &path[..path.len() - last_segment.len()], blob_id);
+        if let Some(query) = uri.query() {
+            path_and_query = format!("{}?{}", path_and_query, query);
+        }
+        *request.uri_mut() = path_and_query
+            .parse()
+            .map_err(|e| AppError::Generic(anyhow::Error::new(e)))?;
+    }
+    // Downloads count towards the transfer of the owner, whoever downloads them
+    let owner_id = file.owner_id;
+    if let Some(owner_id) = owner_id {
+        transfer::check_download_cap(&state, owner_id).await?;
+    }
+    let is_head = request.method() == Method::HEAD;
     let response = next.run(request).await;
+    let downloaded = response
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<i64>().ok());
+    if let (Some(owner_id), Some(downloaded)) = (owner_id, downloaded) {
+        if !is_head && response.status().is_success() {
+            // The file has already been served, so don't fail the request over this
+            if let Err(e) = transfer::record(&state.pool, owner_id, 0, downloaded).await {
+                error!("Unable to record download of file {}: {}", id, e);
+            }
+        }
+    }
     Ok(response)
 }
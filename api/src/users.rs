@@ -1,160 +1,58 @@
-use std::{cmp::Ordering, fs::File, io::BufWriter, marker::PhantomData, ops::ControlFlow};
+use std::{cmp::Ordering, collections::HashSet, io::Cursor, marker::PhantomData};
 
 use anyhow::anyhow;
 use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, Salt, SaltString},
-    PasswordHash, PasswordVerifier,
+    password_hash::{rand_core::OsRng, ParamsString, PasswordHasher, Salt, SaltString},
+    Algorithm as Argon2Algorithm, Params, PasswordHash, PasswordVerifier,
 };
 use axum::{
-    body::{Body, HttpBody},
+    body::Bytes,
     extract::{Path, Query, State},
-    http::{header::SET_COOKIE, StatusCode},
+    http::{
+        header::{CACHE_CONTROL, CONTENT_TYPE, ETAG, IF_NONE_MATCH, SET_COOKIE},
+        HeaderMap, StatusCode,
+    },
     response::{AppendHeaders, IntoResponse, Response},
     Json,
 };
 use axum_extra::{headers::UserAgent, TypedHeader};
 use base64::{engine::general_purpose, Engine};
-use futures_util::StreamExt;
-use image::{imageops::FilterType, DynamicImage, GenericImageView};
-use serde::{Deserialize, Serialize};
-use serde_inline_default::serde_inline_default;
-use sqlx::{prelude::FromRow, Decode, Sqlite};
+use chrono::Utc;
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat, Rgb, RgbImage};
+use sqlx::SqlitePool;
 use totp_rs::{Algorithm, Secret, TOTP};
-use tracing::instrument;
-use utoipa::{IntoParams, ToSchema};
+use tracing::{info, instrument, warn};
+use utoipa::ToSchema;
 use uuid::Uuid;
-use validator::{Validate, ValidateEmail, ValidationError};
+use validator::{ValidateEmail, ValidationError};
+
+pub use lokr_types::users::{
+    validate_username, AvatarParams, AvatarResponse, CheckUsage, CreateUser, FileSortOrder,
+    KeyManifest, LoginResponse, LoginUser, ManifestFile, Preferences, PublicUser, SessionUser,
+    SortOrder, TOTPRequest, TOTPResponse, Theme, UserSearch, UserUpdate, UserUpdateField,
+    MAX_PASSWORD_LENGTH, MAX_USERNAME_LENGTH, MIN_PASSWORD_LENGTH, MIN_USERNAME_LENGTH,
+    PUBLIC_KEY_LENGTH,
+};
 
 use crate::{
-    auth::SessionAuth,
-    error::{AppError, AppValidate, ErrorResponse},
+    at_rest,
+    auth::{SessionAuth, User},
+    cookie::{clear_session_cookies, set_session_cookies},
+    error::{AppError, AppValidate, ErrorResponse, ErrorType},
+    i18n, instance,
+    retry::retry_transaction,
+    session::rotate_session,
     state::AppState,
     success,
     utils::levenshtien,
-    SuccessResponse, AVATAR_DIR, HOST,
+    SuccessResponse, HOST,
 };
 
-pub const MIN_PASSWORD_LENGTH: u64 = 8;
-pub const MAX_PASSWORD_LENGTH: u64 = 256;
-pub const MIN_USERNAME_LENGTH: u64 = 3;
-pub const MAX_USERNAME_LENGTH: u64 = 20;
-pub const PUBLIC_KEY_LENGTH: usize = 550; // Length I ended up with after encoding the public key
-
-/// A struct representing a new user to be created
-#[derive(Deserialize, ToSchema, Validate, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct CreateUser {
-    /// The name of the user to create
-    #[validate(length(min = MIN_USERNAME_LENGTH, max = MAX_USERNAME_LENGTH), custom(function = "validate_username"))]
-    // I would use the max and min constants here, but they are not allowed in the attribute
-    #[schema(min_length = 3, max_length = 20, example = "sussyman")]
-    username: String,
-    /// The new user's password
-    /// Should be hashed using Argon2 before being sent to the backend
-    #[validate(length(min = MIN_PASSWORD_LENGTH, max = MAX_PASSWORD_LENGTH))]
-    #[schema(
-        min_length = 8,
-        max_length = 64,
-        example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
-    )]
-    password: String,
-    /// Optional email for the user
-    #[validate(email)]
-    #[schema(example = "sussyman@amogus.com")]
-    email: Option<String>,
-    /// The initialization vector for the AES encrypted user's private key
-    #[schema(content_encoding = "base64", example = "l+EEL/mHKlkxlEG0")]
-    iv: String,
-    /// The user's public key
-    #[schema(
-        content_encoding = "base64",
-        example = "d4Ogp+CI5mkdCCfXxDmmxor9FKMTQ5dq4gAvCECgcFs="
-    )]
-    public_key: String,
-    /// The user's private key encrypted using their password
-    #[schema(
-        content_encoding = "base64",
-        example = "38ZP4XEKLikREzyy9ttdaKLZ8WiWCd2i8ptTCwRwMlc="
-    )]
-    encrypted_private_key: String,
-    /// The salt for the PBKDF2 key derivation function
-    #[schema(content_encoding = "base64", example = "iKJcRJf7fwtO6est")]
-    salt: String,
-}
-
-/// A struct representing a user logging in
-#[derive(Serialize, Deserialize, ToSchema, Validate, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct LoginUser {
-    #[validate(length(min = MIN_USERNAME_LENGTH, max = MAX_USERNAME_LENGTH), custom(function = "validate_username"))]
-    #[schema(example = "sussyman")]
-    username: String,
-    #[validate(length(min = MIN_PASSWORD_LENGTH, max = MAX_PASSWORD_LENGTH))]
-    #[schema(
-        example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
-    )]
-    password: String,
-    /// The totp code provided by the user. Should always be exactly 6 digits
-    #[serde(skip_serializing_if = "Option::is_none")]
-    #[validate(length(min = 6, max = 6))]
-    #[schema(example = "696969")]
-    totp_code: Option<String>,
-}
-
-/// A successful login response
-#[derive(Serialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub struct LoginResponse {
-    /// The initialization vector for the AES encrypted user's private key
-    #[schema(content_encoding = "base64", example = "BukSfO6yaQ")]
-    iv: String,
-    /// The user's public key
-    #[schema(
-        content_encoding = "base64",
-        example = "QQe22k5wy-88PUFIW1P7MkgxoyMyalmjnffAuUNgMuE"
-    )]
-    public_key: String,
-    /// The user's private key encrypted using their password
-    #[schema(
-        content_encoding = "base64",
-        example = "9WNx5GS9CSaqesguryWS-jiY8Vb0VMMjMtV5JJECk9A"
-    )]
-    encrypted_private_key: String,
-    /// The salt for the PBKDF2 key derivation function
-    #[schema(content_encoding = "base64", example = "iKJcRJf7fwtO6est")]
-    salt: String,
-    /// The user's preferred theme
-    theme: Theme,
-}
-
-/// Verify that the username only contains alphanumeric characters and underscores
-pub fn validate_username(username: &str) -> Result<(), ValidationError> {
-    match username
-        .chars()
-        .try_fold((0, 0), |(alphanumeric, underscore), c| {
-            if c.is_alphanumeric() {
-                ControlFlow::Continue((alphanumeric + 1, underscore))
-            } else if c == '_' {
-                ControlFlow::Continue((alphanumeric, underscore + 1))
-            } else {
-                ControlFlow::Break(ValidationError::new(
-                    r#"must only contain alphanumeric characters and _"#,
-                ))
-            }
-        }) {
-        ControlFlow::Continue((a, u)) => {
-            if a > u {
-                Ok(())
-            } else {
-                // So we don't end up with usernames like "_a_" or "______"
-                Err(ValidationError::new(
-                    r#"must contain more alphanumeric characters than underscores"#,
-                ))
-            }
-        }
-        ControlFlow::Break(e) => Err(e),
-    }
-}
+/// How many seconds each TOTP code is valid for
+pub(crate) const TOTP_STEP: u64 = 30;
+/// How many steps before or after the current one a TOTP code is still accepted from,
+/// to allow for the clock of the user's device being a little off
+pub(crate) const TOTP_SKEW: u8 = 1;
 
 fn validate_password(password: &str) -> Result<Option<Salt<'_>>, ValidationError> {
     if let Ok(hashed_password) = PasswordHash::new(password) {
@@ -177,7 +75,8 @@ fn validate_password(password: &str) -> Result<Option<Salt<'_>>, ValidationError
     responses(
         (status = CREATED, description = "User successfully created", body = SuccessResponse),
         (status = CONFLICT, description = "Username or email already in use", body = ErrorResponse),
-        (status = BAD_REQUEST, description = "Invalid username, email, or password", body = ErrorResponse)
+        (status = BAD_REQUEST, description = "Invalid username, email, or password", body = ErrorResponse),
+        (status = FORBIDDEN, description = "Registration is disabled on this instance", body = ErrorResponse)
     )
 )]
 #[instrument(err, skip(state))]
@@ -185,6 +84,10 @@ pub async fn create_user(
     State(state): State<AppState>,
     Json(new_user): Json<CreateUser>,
 ) -> Result<Response, AppError> {
+    instance::require(
+        state.features().registration,
+        "Registration is disabled on this instance",
+    )?;
     // New user has a valid email, username, and password
     new_user.app_validate()?;
 
@@ -225,7 +128,7 @@ pub async fn create_user(
     if decoded_public_key.len() != PUBLIC_KEY_LENGTH {
         return Err(AppError::UserError((
             StatusCode::BAD_REQUEST,
-            format!("Public key must be {} bytes", PUBLIC_KEY_LENGTH).into(),
+            format!("Public key must be {} bytes", PUBLIC_KEY_LENGTH),
         )));
     }
     let decoded_iv = general_purpose::STANDARD
@@ -266,22 +169,26 @@ pub async fn create_user(
     })?
     .to_string();
     let uuid = Uuid::new_v4();
-    sqlx::query!(
-        r#"
-        INSERT INTO user (id, username, password_hash, email, iv, encrypted_private_key, public_key, salt, password_salt, theme, grid_view, sort_order)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 0, true, 0)
-        "#,
-        uuid,
-        new_user.username,
-        password_hash,
-        new_user.email,
-        new_user.iv,
-        new_user.encrypted_private_key,
-        new_user.public_key,
-        new_user.salt,
-        password_salt
-    )
-    .execute(&state.pool)
+    retry_transaction(&state, "register", || async {
+        sqlx::query!(
+            r#"
+            INSERT INTO user (id, username, password_hash, email, iv, encrypted_private_key, public_key, salt, password_salt, theme, grid_view, sort_order)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, 0, true, 0)
+            "#,
+            uuid,
+            new_user.username,
+            password_hash,
+            new_user.email,
+            new_user.iv,
+            new_user.encrypted_private_key,
+            new_user.public_key,
+            new_user.salt,
+            password_salt
+        )
+        .execute(&state.pool)
+        .await?;
+        Ok(())
+    })
     .await?;
     Ok((StatusCode::CREATED, success!("User successfully created!")).into_response())
 }
@@ -306,7 +213,7 @@ pub async fn authenticate_user(
 ) -> Result<Response, AppError> {
     user.app_validate()?;
     let Some(db_user) = sqlx::query!(
-        "SELECT id, email, password_hash, totp_enabled, totp_secret FROM user WHERE username = ?",
+        r#"SELECT id AS "id: Uuid", email, password_hash, totp_enabled, totp_secret FROM user WHERE username = ?"#,
         user.username
     )
     .fetch_optional(&state.pool)
@@ -335,8 +242,8 @@ pub async fn authenticate_user(
         let totp = TOTP::new_unchecked(
             Algorithm::SHA1,
             6,
-            1,
-            30,
+            TOTP_SKEW,
+            TOTP_STEP,
             secret.to_bytes()?,
             Some("Lokr".to_string()),
             db_user
@@ -352,11 +259,20 @@ pub async fn authenticate_user(
         }
     }
 
+    // The password is only available in plaintext on login, so this is the only chance
+    // to rehash it if the hashing parameters have changed since it was last hashed.
+    // Failing to do so shouldn't stop the user from logging in.
+    if is_legacy_hash(&state, &db_user.password_hash) {
+        if let Err(e) = rehash_password(&state, &db_user.id, &user.password).await {
+            warn!("Unable to rehash password of user {}: {}", db_user.id, e);
+        }
+    }
+
     let uuid = Uuid::new_v4();
     let user_agent = user_agent.as_str();
     sqlx::query!(
-        "INSERT INTO session (id, user_id, number, user_agent)
-        VALUES (?, ?, COALESCE((SELECT MAX(number) FROM session WHERE user_id = ?), 0) + 1, ?) RETURNING id",
+        "INSERT INTO session (id, user_id, number, user_agent, verified_at)
+        VALUES (?, ?, COALESCE((SELECT MAX(number) FROM session WHERE user_id = ?), 0) + 1, ?, CURRENT_TIMESTAMP) RETURNING id",
         uuid,
         db_user.id,
         db_user.id,
@@ -378,14 +294,7 @@ pub async fn authenticate_user(
     .await?;
     Ok((
         StatusCode::OK,
-        [(
-            SET_COOKIE,
-            format!("session={uuid}; HttpOnly; Max-Age=34560000"),
-        )],
-        AppendHeaders([(
-            SET_COOKIE,
-            "authenticated=true; Max-Age=34560000; Path=/".to_string(),
-        )]),
+        AppendHeaders(set_session_cookies(&state.config, uuid).map(|cookie| (SET_COOKIE, cookie))),
         Json(login_body),
     )
         .into_response())
@@ -422,22 +331,12 @@ pub async fn logout(
     }
     Ok((
         StatusCode::OK,
-        [(SET_COOKIE, "session=; HttpOnly; Max-Age=0")],
-        AppendHeaders([(SET_COOKIE, "authenticated=; Path=/; Max-Age=0".to_string())]),
+        AppendHeaders(clear_session_cookies().map(|cookie| (SET_COOKIE, cookie))),
         success!("User successfully logged out"),
     )
         .into_response())
 }
 
-#[derive(Deserialize, Validate, IntoParams, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct CheckUsage {
-    #[validate(length(min = 3, max = 20), custom(function = "validate_username"))]
-    username: Option<String>,
-    #[validate(email)]
-    email: Option<String>,
-}
-
 #[utoipa::path(
     get,
     path = "/api/check",
@@ -476,8 +375,8 @@ pub async fn check_usage(
                 .is_some()
         {
             errors.push(ErrorResponse {
-                r#type: AppError::UserError((StatusCode::CONFLICT, "".into())),
-                message: "Username already in use".into(),
+                r#type: ErrorType::UserError,
+                message: i18n::translate("Username already in use".into()),
             });
         }
     }
@@ -493,8 +392,8 @@ pub async fn check_usage(
                 .is_some()
         {
             errors.push(ErrorResponse {
-                r#type: AppError::UserError((StatusCode::CONFLICT, "".into())),
-                message: "Email already in use".into(),
+                r#type: ErrorType::UserError,
+                message: i18n::translate("Email already in use".into()),
             });
         }
     }
@@ -507,125 +406,6 @@ pub async fn check_usage(
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub enum Theme {
-    System = 0,
-    Dark = 1,
-    Light = 2,
-}
-impl TryFrom<i64> for Theme {
-    type Error = anyhow::Error;
-    fn try_from(value: i64) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(Self::System),
-            1 => Ok(Self::Dark),
-            2 => Ok(Self::Light),
-            _ => Err(anyhow!("Invalid theme value")),
-        }
-    }
-}
-
-impl<'r> Decode<'r, Sqlite> for Theme {
-    fn decode(
-        value: <Sqlite as sqlx::Database>::ValueRef<'r>,
-    ) -> Result<Self, sqlx::error::BoxDynError> {
-        let value: i64 = <i64 as Decode<Sqlite>>::decode(value)?;
-        Ok(value.try_into()?)
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub enum FileSortOrder {
-    Name = 0,
-    Modified = 1,
-    Created = 2,
-    Size = 3,
-    Uploader = 4,
-    Owner = 5,
-    Extension = 6,
-}
-
-impl TryFrom<i64> for FileSortOrder {
-    type Error = anyhow::Error;
-
-    fn try_from(value: i64) -> Result<Self, Self::Error> {
-        match value {
-            0 => Ok(Self::Name),
-            1 => Ok(Self::Modified),
-            2 => Ok(Self::Created),
-            3 => Ok(Self::Size),
-            4 => Ok(Self::Uploader),
-            5 => Ok(Self::Owner),
-            6 => Ok(Self::Extension),
-            _ => Err(anyhow!("Invalid file sort order value")),
-        }
-    }
-}
-
-impl<'r> Decode<'r, Sqlite> for FileSortOrder {
-    fn decode(
-        value: <Sqlite as sqlx::Database>::ValueRef<'r>,
-    ) -> Result<Self, sqlx::error::BoxDynError> {
-        let value: i64 = <i64 as Decode<Sqlite>>::decode(value)?;
-        Ok(value.try_into()?)
-    }
-}
-
-#[derive(Serialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-/// A struct representing the currently logged in user
-pub struct SessionUser {
-    id: Uuid,
-    /// The name of the user
-    #[schema(example = "sussyman")]
-    username: String,
-    /// Optional email for the user
-    #[schema(example = "sussyman@amogus.com")]
-    email: Option<String>,
-    /// The initialization vector for the AES encrypted user's private key
-    #[schema(content_encoding = "base64", example = "BukSfO6yaQ")]
-    iv: String,
-    /// The user's public key
-    #[schema(
-        content_encoding = "base64",
-        example = "QQe22k5wy-88PUFIW1P7MkgxoyMyalmjnffAuUNgMuE"
-    )]
-    public_key: String,
-    /// The user's private key encrypted using their password
-    #[schema(
-        content_encoding = "base64",
-        example = "9WNx5GS9CSaqesguryWS-jiY8Vb0VMMjMtV5JJECk9A"
-    )]
-    encrypted_private_key: String,
-    /// The salt for the PBKDF2 key derivation function
-    #[schema(content_encoding = "base64", example = "iKJcRJf7fwtO6est")]
-    salt: String,
-    /// The salt for the user's hashed password if applicable
-    password_salt: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    /// The file extension for the user's avatar
-    avatar_extension: Option<String>,
-    /// Whether the user has TOTP enabled
-    totp_enabled: bool,
-    /// Whether the user has verified their TOTP key
-    totp_verified: bool,
-    /// The theme preference of the user
-    theme: Theme,
-    /// Default sort order for files
-    sort_order: FileSortOrder,
-    /// Whether the user prefers a grid view for files
-    #[schema(example = true)]
-    grid_view: bool,
-    /// The total amount of space available to the user
-    #[schema(example = 1_000_000_000)]
-    total_space: i64,
-    /// The amount of space used by the user
-    #[schema(example = 0)]
-    used_space: i64,
-}
-
 #[utoipa::path(
     get,
     path = "/api/profile",
@@ -643,15 +423,23 @@ pub async fn get_logged_in_user(
     State(state): State<AppState>,
     SessionAuth(user): SessionAuth,
 ) -> Result<Response, AppError> {
+    let default_transfer_cap = state.config.monthly_transfer_cap as i64;
     let query = sqlx::query_as!(
         SessionUser,
         r#"SELECT id AS "id: _", username, email,
             iv, public_key, encrypted_private_key, salt,
-            avatar AS avatar_extension, totp_enabled, totp_verified,
+            avatar AS avatar_extension, avatar_version, totp_enabled, totp_verified,
             password_salt, theme AS "theme: Theme",
             sort_order AS "sort_order: FileSortOrder", grid_view,
-            total_space, used_space
-            FROM user WHERE id = ?"#,
+            explain_denials, total_space, used_space,
+            COALESCE(t.uploaded, 0) AS "uploaded_this_month!: i64",
+            COALESCE(t.downloaded, 0) AS "downloaded_this_month!: i64",
+            NULLIF(COALESCE(transfer_cap, ?), 0) AS "transfer_cap?: i64",
+            key_epoch
+            FROM user
+            LEFT JOIN transfer t ON t.user_id = user.id AND t.month = strftime('%Y-%m', 'now')
+            WHERE id = ?"#,
+        default_transfer_cap,
         user.id
     )
     .fetch_one(&state.pool)
@@ -659,39 +447,95 @@ pub async fn get_logged_in_user(
     Ok(Json(query).into_response())
 }
 
-/// Update the currently authenticated user's profile
-#[derive(Deserialize, ToSchema, Debug)]
-#[serde(rename_all = "camelCase")]
-pub struct UserUpdate {
-    /// The field to update
-    #[serde(flatten)]
-    field: UserUpdateField,
-    /// The new value for the field
-    #[schema(example = "sussyman2")]
-    new_value: String,
-    /// The user's current password to prevent accidental or
-    /// malicious updates
-    #[schema(
-        example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
-    )]
-    password: String,
-}
+#[utoipa::path(
+    get,
+    path = "/api/profile/keys/manifest",
+    description = "Get the keys and nonces of every file owned by or shared with the currently authenticated user, along with their encrypted private key. Storing this offline allows files to be decrypted from their data alone, even if the database is lost. Files that are shared directly with the user have no `parentId` and their key is encrypted with the user's public key.",
+    responses(
+        (status = OK, description = "Manifest successfully generated", body = KeyManifest),
+        (status = UNAUTHORIZED, description = "No user is currently authenticated", body = ErrorResponse)
+    ),
+    security(
+        ("lokr_session_cookie" = [])
+    )
+)]
+#[instrument(err, skip(state))]
+pub async fn get_key_manifest(
+    State(state): State<AppState>,
+    SessionAuth(user): SessionAuth,
+) -> Result<Response, AppError> {
+    let keys = sqlx::query!(
+        "SELECT iv, encrypted_private_key, salt FROM user WHERE id = ?",
+        user.id
+    )
+    .fetch_one(&state.pool)
+    .await?;
+    let rows = sqlx::query!(
+        r#"
+        WITH RECURSIVE shared AS (
+            -- Files shared directly with the user have their key encrypted
+            -- with the user's public key instead of the key of their parent
+            SELECT f.id, NULL AS parent_id, f.is_directory, su.encrypted_key,
+            NULL AS key_nonce, f.file_nonce, f.encrypted_name, f.name_nonce,
+            f.metadata_version, 0 AS depth
+            FROM share_user su
+            JOIN file f ON f.id = su.file_id
+            WHERE su.user_id = ?
+            UNION ALL
+            SELECT f.id, f.parent_id, f.is_directory, f.encrypted_key,
+            f.key_nonce, f.file_nonce, f.encrypted_name, f.name_nonce,
+            f.metadata_version, s.depth + 1
+            FROM file f
+            JOIN shared s ON f.parent_id = s.id
+        )
+        SELECT id AS "id!: Uuid", parent_id AS "parent_id: Uuid",
+        is_directory AS "is_directory!: bool", TRUE AS "owned!: bool",
+        encrypted_key AS "encrypted_key!: String", key_nonce, file_nonce,
+        encrypted_name AS "encrypted_name!", name_nonce AS "name_nonce!",
+        metadata_version AS "metadata_version!: i64", 0 AS depth
+        FROM file
+        WHERE owner_id = ?
+        UNION ALL
+        SELECT id, parent_id, is_directory, FALSE, encrypted_key,
+        key_nonce, file_nonce, encrypted_name, name_nonce, metadata_version, depth
+        FROM shared
+        ORDER BY depth
+        "#,
+        user.id,
+        user.id
+    )
+    .fetch_all(&state.pool)
+    .await?;
 
-#[derive(Deserialize, ToSchema, Debug)]
-#[serde(rename_all = "camelCase", tag = "type")]
-pub enum UserUpdateField {
-    Username,
-    Email,
-    /// Update the user's password
-    /// Requires a new encrypted private key to be provided since
-    /// the password is used to derive the key for the AES encryption.
-    /// This also requires a new salt and iv for security
-    #[serde(rename_all = "camelCase")]
-    Password {
-        encrypted_private_key: String,
-        salt: String,
-        iv: String,
-    },
+    // A file can be reached through more than one share if a directory and
+    // something inside of it are both shared with the user. Files shared
+    // directly come first, so keep those since they don't depend on a parent.
+    let mut seen = HashSet::new();
+    let files = rows
+        .into_iter()
+        .filter(|row| seen.insert(row.id))
+        .map(|row| ManifestFile {
+            id: row.id,
+            parent_id: row.parent_id,
+            is_directory: row.is_directory,
+            owned: row.owned,
+            encrypted_key: row.encrypted_key,
+            key_nonce: row.key_nonce,
+            file_nonce: row.file_nonce,
+            encrypted_name: row.encrypted_name,
+            name_nonce: row.name_nonce,
+            metadata_version: row.metadata_version,
+        })
+        .collect();
+    Ok(Json(KeyManifest {
+        user_id: user.id,
+        created_at: Utc::now(),
+        iv: keys.iv,
+        encrypted_private_key: keys.encrypted_private_key,
+        salt: keys.salt,
+        files,
+    })
+    .into_response())
 }
 
 #[utoipa::path(
@@ -700,7 +544,7 @@ pub enum UserUpdateField {
     description = "Update the currently authenticated user",
     request_body(content = UserUpdate, description = "The user data to update"),
     responses(
-        (status = OK, description = "User successfully updated", body = SuccessResponse),
+        (status = OK, description = "User successfully updated. When the email or password is changed, the session is rotated and the new session cookies are returned.", body = SuccessResponse, headers(("Set-Cookie" = String, description = "New `session` and `authenticated` cookies, only sent when the session is rotated"))),
         (status = BAD_REQUEST, description = "Invalid username or email", body = ErrorResponse),
         (status = UNAUTHORIZED, description = "No user is currently authenticated or incorrect password", body = ErrorResponse)
     ),
@@ -720,6 +564,7 @@ pub async fn update_user(
         .password_hash;
     verify_password(&state, &update.password, &password_hash)?;
 
+    let rotate = !matches!(update.field, UserUpdateField::Username);
     match update.field {
         UserUpdateField::Username => {
             if update.new_value.len() < MIN_USERNAME_LENGTH as usize
@@ -730,8 +575,7 @@ pub async fn update_user(
                     format!(
                         "Username must be between {} and {} characters",
                         MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
-                    )
-                    .into(),
+                    ),
                 )));
             }
             if validate_username(&update.new_value).is_err() {
@@ -755,12 +599,16 @@ pub async fn update_user(
                 )));
             }
 
-            sqlx::query!(
-                "UPDATE user SET username = ? WHERE id = ?",
-                update.new_value,
-                user.id
-            )
-            .execute(&state.pool)
+            retry_transaction(&state, "update username", || async {
+                sqlx::query!(
+                    "UPDATE user SET username = ? WHERE id = ?",
+                    update.new_value,
+                    user.id
+                )
+                .execute(&state.pool)
+                .await?;
+                Ok(())
+            })
             .await?;
         }
         UserUpdateField::Email => {
@@ -776,6 +624,7 @@ pub async fn update_user(
             // with different case
             if !user
                 .email
+                .as_ref()
                 .is_some_and(|email| email.eq_ignore_ascii_case(&update.new_value))
                 && sqlx::query!("SELECT id FROM user WHERE email = ?", update.new_value)
                     .fetch_optional(&state.pool)
@@ -788,12 +637,16 @@ pub async fn update_user(
                 )));
             }
 
-            sqlx::query!(
-                "UPDATE user SET email = ? WHERE id = ?",
-                update.new_value,
-                user.id
-            )
-            .execute(&state.pool)
+            retry_transaction(&state, "update email", || async {
+                sqlx::query!(
+                    "UPDATE user SET email = ? WHERE id = ?",
+                    update.new_value,
+                    user.id
+                )
+                .execute(&state.pool)
+                .await?;
+                Ok(())
+            })
             .await?;
         }
         UserUpdateField::Password {
@@ -809,8 +662,7 @@ pub async fn update_user(
                     format!(
                         "Password must be between {} and {} characters",
                         MIN_PASSWORD_LENGTH, MAX_PASSWORD_LENGTH
-                    )
-                    .into(),
+                    ),
                 )));
             }
 
@@ -848,75 +700,59 @@ pub async fn update_user(
             })?
             .to_string();
 
-            sqlx::query!(
-                r#"
-                UPDATE user SET password_hash = ?,
-                encrypted_private_key = ?, password_salt = ?,
-                salt = ?, iv = ?
-                WHERE id = ?
-                "#,
-                password_hash,
-                encrypted_private_key,
-                password_salt,
-                salt,
-                iv,
-                user.id
-            )
-            .execute(&state.pool)
+            retry_transaction(&state, "update password", || async {
+                let mut tx = state.pool.begin().await?;
+                sqlx::query!(
+                    r#"
+                    UPDATE user SET password_hash = ?,
+                    encrypted_private_key = ?, password_salt = ?,
+                    salt = ?, iv = ?, key_epoch = key_epoch + 1
+                    WHERE id = ?
+                    "#,
+                    password_hash,
+                    encrypted_private_key,
+                    password_salt,
+                    salt,
+                    iv,
+                    user.id
+                )
+                .execute(&mut *tx)
+                .await?;
+
+                // Anyone else logged in with the old password shouldn't stay logged in
+                sqlx::query!(
+                    "DELETE FROM session WHERE user_id = ? AND number != ?",
+                    user.id,
+                    user.session_number
+                )
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                Ok(())
+            })
             .await?;
         }
     }
 
+    if rotate {
+        let cookies = rotate_session(&state, &user).await?;
+        return Ok((
+            StatusCode::OK,
+            AppendHeaders(cookies.map(|cookie| (SET_COOKIE, cookie))),
+            success!("User updated successfully"),
+        )
+            .into_response());
+    }
     Ok((StatusCode::OK, success!("User updated successfully")).into_response())
 }
 
-#[derive(Deserialize, ToSchema, Debug)]
-#[serde(rename_all = "camelCase", tag = "type")]
-/// Request an update to the currently authenticated user's TOTP settings
-pub enum TOTPRequest {
-    /// Enable or disable TOTP for the currently authenticated user
-    Enable {
-        enable: bool,
-
-        #[schema(
-            example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
-        )]
-        password: String,
-    },
-    /// Regenerate the currently authenticated user's TOTP secret
-    Regenerate {
-        #[schema(
-            example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
-        )]
-        password: String,
-    },
-    /// Verify the currently authenticated user's TOTP
-    /// using the provided TOTP code
-    Verify {
-        #[schema(example = "696969")]
-        code: String,
-    },
-}
-
-#[derive(Serialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub struct TOTPResponse {
-    /// The base64 encoded QR code for the TOTP secret.
-    /// Encoded as a PNG image to allow for easy presentation to the user.
-    #[schema(
-        content_encoding = "base64",
-        example = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAABQAAAAUCAYAAACNiR0N"
-    )]
-    qr_code: String,
-}
-
 #[utoipa::path(
     put,
     path = "/api/totp",
     description = "Update the currently authenticated user's TOTP settings",
     request_body(content = TOTPRequest, description = "TOTP settings to update"),
     responses(
-        (status = OK, description = "TOTP settings successfully updated. Returned when successfully enabling, disabling, or, verifing TOTP.", body = SuccessResponse),
+        (status = OK, description = "TOTP settings successfully updated. Returned when successfully enabling, disabling, or, verifing TOTP. Enabling or disabling TOTP rotates the session.", body = SuccessResponse, headers(("Set-Cookie" = String, description = "New `session` and `authenticated` cookies, only sent when the session is rotated"))),
         (status = CREATED, description = "A new TOTP has been regenerated. Returned upon a successful regeneration request", body = TOTPResponse), 
         (status = BAD_REQUEST, description = "Invalid TOTP request", body = ErrorResponse)
     ),
@@ -964,8 +800,10 @@ pub async fn update_totp(
             .fetch_one(&state.pool)
             .await?;
 
+            let cookies = rotate_session(&state, &user).await?;
             Ok((
                 StatusCode::OK,
+                AppendHeaders(cookies.map(|cookie| (SET_COOKIE, cookie))),
                 success!(format!(
                     "TOTP {} successfully!",
                     if enable { "enabled" } else { "disabled" }
@@ -988,8 +826,8 @@ pub async fn update_totp(
             let totp = TOTP::new_unchecked(
                 Algorithm::SHA1,
                 6,
-                1,
-                30,
+                TOTP_SKEW,
+                TOTP_STEP,
                 secret.to_bytes()?,
                 Some("Lokr".to_string()),
                 user.email
@@ -1027,8 +865,8 @@ pub async fn update_totp(
             let totp = TOTP::new_unchecked(
                 Algorithm::SHA1,
                 6,
-                1,
-                30,
+                TOTP_SKEW,
+                TOTP_STEP,
                 secret.to_bytes()?,
                 Some("Lokr".to_string()),
                 user.email
@@ -1050,55 +888,6 @@ pub async fn update_totp(
     }
 }
 
-#[derive(Deserialize, IntoParams, Debug)]
-#[into_params(
-    parameter_in = Query
-)]
-#[serde_inline_default]
-pub struct UserSearch {
-    #[serde(default)]
-    #[param(inline)]
-    sort: SortOrder,
-    #[serde_inline_default(10)]
-    limit: u32,
-    #[serde_inline_default(0)]
-    offset: u32,
-}
-
-#[derive(Deserialize, ToSchema, Default, Debug)]
-#[serde(rename_all = "camelCase")]
-pub enum SortOrder {
-    #[default]
-    BestMatch,
-    Alphabetical,
-    Shortest,
-}
-
-#[derive(Serialize, ToSchema, FromRow)]
-#[serde(rename_all = "camelCase")]
-pub struct PublicUser {
-    /// The id of the user
-    pub id: Uuid,
-    /// The name of the user
-    #[schema(example = "sussyman")]
-    pub username: String,
-    /// Optional email for the user
-    #[schema(example = "sussyman@amogus.com")]
-    pub email: Option<String>,
-    /// The user's public key
-    #[schema(
-        content_encoding = "base64",
-        example = "QQe22k5wy-88PUFIW1P7MkgxoyMyalmjnffAuUNgMuE"
-    )]
-    pub public_key: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    /// The file extension for the user's avatar
-    pub avatar_extension: Option<String>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    /// The password salt used when registering
-    pub password_salt: Option<String>,
-}
-
 #[utoipa::path(
     get,
     path = "/api/users/search/{query}",
@@ -1120,17 +909,17 @@ pub async fn search_users(
     if query.len() < MIN_USERNAME_LENGTH as usize {
         return Err(AppError::UserError((
             StatusCode::BAD_REQUEST,
-            format!("Query must be at least {} characters", MIN_USERNAME_LENGTH).into(),
+            format!("Query must be at least {} characters", MIN_USERNAME_LENGTH),
         )));
     } else if query.len() > MAX_USERNAME_LENGTH as usize {
         return Err(AppError::UserError((
             StatusCode::BAD_REQUEST,
-            format!("Query must be at most {} characters", MAX_USERNAME_LENGTH).into(),
+            format!("Query must be at most {} characters", MAX_USERNAME_LENGTH),
         )));
     }
     let mut all_users = sqlx::query_as!(
         PublicUser,
-        r#"SELECT id AS "id: _", username, email, public_key, avatar AS avatar_extension, password_salt FROM user"#
+        r#"SELECT id AS "id: _", username, email, public_key, avatar AS avatar_extension, avatar_version, password_salt FROM user"#
     )
     .fetch_all(&state.pool)
     .await?;
@@ -1176,7 +965,7 @@ pub async fn get_user(
 ) -> Result<Response, AppError> {
     let Some(query) = sqlx::query_as!(
         PublicUser,
-        r#"SELECT id AS "id: _", username, email, public_key, avatar AS avatar_extension, password_salt FROM user WHERE id = ?"#,
+        r#"SELECT id AS "id: _", username, email, public_key, avatar AS avatar_extension, avatar_version, password_salt FROM user WHERE id = ?"#,
         id
     )
     .fetch_optional(&state.pool)
@@ -1190,11 +979,6 @@ pub async fn get_user(
     Ok((StatusCode::OK, Json(query)).into_response())
 }
 
-#[derive(Serialize, ToSchema)]
-pub struct AvatarResponse {
-    extension: String,
-}
-
 #[derive(ToSchema)]
 #[schema(value_type = String, format = Binary, content_media_type = "application/octet-stream")]
 struct BinaryFile(PhantomData<Vec<u8>>);
@@ -1216,13 +1000,8 @@ struct BinaryFile(PhantomData<Vec<u8>>);
 pub async fn upload_avatar(
     State(state): State<AppState>,
     SessionAuth(user): SessionAuth,
-    image_body: Body,
+    image_data: Bytes,
 ) -> Result<Response, AppError> {
-    let mut image_stream = image_body.into_data_stream();
-    let mut image_data = Vec::with_capacity(image_stream.size_hint().lower() as usize);
-    while let Some(chunk) = image_stream.next().await {
-        image_data.extend_from_slice(&chunk?);
-    }
     let image_type = image::guess_format(&image_data).map_err(|e| {
         AppError::UserError((StatusCode::BAD_REQUEST, format!("Invalid file data: {}", e)))
     })?;
@@ -1236,22 +1015,29 @@ pub async fn upload_avatar(
     let original_image = image::load_from_memory_with_format(&image_data, image_type)?;
     let cropped_image = crop_square(&original_image).resize(256, 256, FilterType::Lanczos3);
     tokio::task::block_in_place(|| -> Result<(), AppError> {
-        let mut file = File::create(&*AVATAR_DIR.join(format!("{}.{}", user.id, file_extension)))?;
-        let mut writer = BufWriter::new(&mut file);
-        cropped_image.write_to(&mut writer, image_type)?;
+        let mut data = Vec::new();
+        cropped_image.write_to(&mut Cursor::new(&mut data), image_type)?;
+        std::fs::write(
+            state
+                .config
+                .avatar_dir()
+                .join(format!("{}.{}", user.id, file_extension)),
+            at_rest::seal(state.config.at_rest_key.as_ref(), data)?,
+        )?;
         Ok(())
     })?;
-    sqlx::query!(
-        "UPDATE user SET avatar = ? WHERE id = ?",
+    let version = sqlx::query_scalar!(
+        "UPDATE user SET avatar = ?, avatar_version = avatar_version + 1 WHERE id = ? RETURNING avatar_version",
         file_extension,
         user.id
     )
-    .execute(&state.pool)
+    .fetch_one(&state.pool)
     .await?;
     Ok((
         StatusCode::CREATED,
         Json(AvatarResponse {
             extension: (*file_extension).into(),
+            version,
         }),
     )
         .into_response())
@@ -1271,8 +1057,63 @@ fn crop_square(image: &DynamicImage) -> DynamicImage {
     image.crop_imm(x, y, min_dim, min_dim)
 }
 
+/// Generate a 256x256 identicon for a user, a symmetric 5x5 grid of cells in a
+/// single color. The same id always gets the same image.
+fn identicon(id: &Uuid) -> RgbImage {
+    const SIZE: u32 = 256;
+    const CELL: u32 = 40;
+    const MARGIN: u32 = (SIZE - 5 * CELL) / 2;
+    // Mix the bits of the id so similar ids don't get similar images.
+    // This is the finalizer of SplitMix64, which unlike the hasher of the
+    // standard library is guaranteed to never change.
+    let (high, low) = id.as_u64_pair();
+    let mut hash = high ^ low.rotate_left(32);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+
+    let color = hsl_to_rgb((hash >> 48) as f32 / u16::MAX as f32 * 360.0, 0.55, 0.55);
+    let mut image = RgbImage::from_pixel(SIZE, SIZE, Rgb([240, 240, 240]));
+    for row in 0..5 {
+        // The left three columns are picked from the hash and mirrored onto the right
+        for column in 0..3 {
+            if (hash >> (row * 3 + column)) & 1 == 0 {
+                continue;
+            }
+            for column in [column, 4 - column] {
+                let (x, y) = (MARGIN + column as u32 * CELL, MARGIN + row as u32 * CELL);
+                for px in x..x + CELL {
+                    for py in y..y + CELL {
+                        image.put_pixel(px, py, color);
+                    }
+                }
+            }
+        }
+    }
+    image
+}
+
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> Rgb<u8> {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    Rgb([r, g, b].map(|channel| ((channel + m) * 255.0).round() as u8))
+}
+
 // Verify the password against the hash in the database
-fn verify_password(state: &AppState, password: &str, password_hash: &str) -> Result<(), AppError> {
+pub(crate) fn verify_password(
+    state: &AppState,
+    password: &str,
+    password_hash: &str,
+) -> Result<(), AppError> {
     // Alert the tokio runtime that there will be a computationally expensive
     // blocking operation. This will allow the runtime to schedule other tasks
     // while waiting for this operation to complete
@@ -1289,29 +1130,213 @@ fn verify_password(state: &AppState, password: &str, password_hash: &str) -> Res
     })
 }
 
+/// Check a TOTP code against the user's TOTP secret
+pub(crate) fn verify_totp(user: &User, secret: Vec<u8>, code: &str) -> Result<(), AppError> {
+    let totp = TOTP::new_unchecked(
+        Algorithm::SHA1,
+        6,
+        TOTP_SKEW,
+        TOTP_STEP,
+        Secret::Raw(secret).to_bytes()?,
+        Some("Lokr".to_string()),
+        user.email
+            .clone()
+            .unwrap_or_else(|| format!("{}@{}", user.username, *HOST)),
+    );
+    if !totp.check_current(code)? {
+        return Err(AppError::UserError((
+            StatusCode::UNAUTHORIZED,
+            "Invalid TOTP code".into(),
+        )));
+    }
+    Ok(())
+}
+
+/// Whether the hash was created with different parameters than the ones currently configured
+fn is_legacy_hash(state: &AppState, password_hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(password_hash) else {
+        return false;
+    };
+    let current = state.argon2.params();
+    match Params::try_from(&hash) {
+        Ok(params) => {
+            hash.algorithm != Argon2Algorithm::Argon2id.ident()
+                || params.m_cost() != current.m_cost()
+                || params.t_cost() != current.t_cost()
+                || params.p_cost() != current.p_cost()
+        }
+        Err(_) => true,
+    }
+}
+
+/// Hash the password with the current parameters and replace the user's stored hash
+async fn rehash_password(state: &AppState, user_id: &Uuid, password: &str) -> Result<(), AppError> {
+    let salt = SaltString::generate(&mut OsRng);
+    let password_hash = tokio::task::block_in_place(|| {
+        state
+            .argon2
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| anyhow!("Unable to hash password: {}", e))
+    })?;
+    sqlx::query!(
+        "UPDATE user SET password_hash = ? WHERE id = ?",
+        password_hash,
+        user_id
+    )
+    .execute(&state.pool)
+    .await?;
+    info!("Upgraded password hash of user {}", user_id);
+    Ok(())
+}
+
+/// Count the password hashes that weren't created with the given parameters.
+/// These are upgraded as users log in, so once this reaches zero it's safe to
+/// assume that every stored hash meets the current requirements.
+pub async fn count_legacy_hashes(pool: &SqlitePool, params: &Params) -> Result<i64, AppError> {
+    let params = ParamsString::try_from(params).map_err(|e| anyhow!("{}", e))?;
+    let prefix = format!("$argon2id$v=19${}$%", params);
+    Ok(sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM user WHERE password_hash NOT LIKE ?",
+        prefix
+    )
+    .fetch_one(pool)
+    .await?)
+}
+
+/// Recompute the used space of every user from the files and shares they own,
+/// returning the number of users whose used space had drifted.
+/// The space of each row comes from the `file_space`, `share_user_space` and
+/// `share_link_space` views, which the triggers that normally keep
+/// `used_space` up to date use as well.
+pub async fn reconcile_used_space(pool: &SqlitePool) -> Result<u64, AppError> {
+    Ok(sqlx::query!(
+        r#"
+        WITH usage AS (
+            SELECT u.id, (
+                SELECT COALESCE(SUM(fs.space), 0)
+                FROM file_space fs WHERE fs.owner_id = u.id
+            ) + (
+                SELECT COALESCE(SUM(sus.space), 0)
+                FROM share_user_space sus
+                JOIN file f ON f.id = sus.file_id
+                WHERE f.owner_id = u.id
+            ) + (
+                SELECT COALESCE(SUM(sls.space), 0)
+                FROM share_link_space sls
+                JOIN file f ON f.id = sls.file_id
+                WHERE f.owner_id = u.id
+            ) AS used_space
+            FROM user u
+        )
+        UPDATE user SET used_space = usage.used_space
+        FROM usage
+        WHERE usage.id = user.id AND user.used_space != usage.used_space
+        "#
+    )
+    .execute(pool)
+    .await?
+    .rows_affected())
+}
+
 #[utoipa::path(
     get,
-    path = "/api/avatars/{id}.{ext}",
-    description = "Get the avatar of a user from their id. For now, all uploaded images are converted into 256x256.",
+    path = "/api/avatars/{file}",
+    description = "Get the avatar of a user from their id. For now, all uploaded images are converted into 256x256. Users without an avatar get a generated identicon PNG, so clients don't need a fallback of their own.",
     params(
-            ("id" = Uuid, Path, description = "The id of the file to get"),
-            ("ext" = String, Path, description = "The file's extension"),
+            ("file" = String, Path, description = "The id of the user, optionally followed by the avatar's file extension", example = "dae2b0f0-d84b-42c8-aebd-58a71ee1fb86.png"),
+            AvatarParams,
         ),
     responses(
         (status = OK, description = "The file was retrieved successfully", content_type = "application/octet-stream"),
-        (status = NOT_FOUND, description = "File was not found"),
+        (status = NOT_MODIFIED, description = "The avatar has not changed"),
+        (status = NOT_FOUND, description = "File was not found", body = ErrorResponse),
     ),
+    security(
+        ()
+    )
 )]
-// Dummy function to avoid generate documentation for this path
-#[allow(unused)]
-async fn get_avatar() {}
+#[instrument(err, skip(state, headers))]
+pub async fn get_avatar(
+    State(state): State<AppState>,
+    Path(file): Path<String>,
+    Query(params): Query<AvatarParams>,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let not_found = || AppError::UserError((StatusCode::NOT_FOUND, "Avatar not found".into()));
+    let (id, extension) = match file.split_once('.') {
+        Some((id, extension)) => (id, Some(extension)),
+        None => (file.as_str(), None),
+    };
+    let id = Uuid::try_parse(id).map_err(|_| not_found())?;
+    let Some(user) = sqlx::query!("SELECT avatar, avatar_version FROM user WHERE id = ?", id)
+        .fetch_optional(&state.pool)
+        .await?
+    else {
+        return Err(not_found());
+    };
+    let version = user.avatar_version;
+    // Users without an avatar get an identicon, which is only available as a PNG
+    let (file, extension) = match (user.avatar, extension) {
+        (Some(avatar), None) => (format!("{}.{}", id, avatar), avatar),
+        (Some(avatar), Some(extension)) if avatar == extension => (file.clone(), avatar),
+        (None, None | Some("png")) => (format!("{}.identicon.png", id), "png".into()),
+        _ => return Err(not_found()),
+    };
+
+    // The version is bumped every time the avatar is rewritten,
+    // so it can be used as a strong validator
+    let etag = format!("\"{}-{}\"", id.simple(), version);
+    // Requests for the current version can be cached forever as a new
+    // upload changes the url. Anything else has to be revalidated.
+    let cache_control = if params.v == Some(version) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    };
+    let cache_headers =
+        AppendHeaders([(ETAG, etag.clone()), (CACHE_CONTROL, cache_control.into())]);
+
+    if headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value
+                .split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        })
+    {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
 
-#[derive(Debug, Deserialize, ToSchema)]
-#[serde(rename_all = "camelCase")]
-pub struct Preferences {
-    theme: Theme,
-    grid_view: bool,
-    sort_order: FileSortOrder,
+    let path = state.config.avatar_dir().join(&file);
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => at_rest::open(state.config.at_rest_key.as_ref(), data)?,
+        // Identicons are generated the first time they are requested
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && file.ends_with(".identicon.png") => {
+            tokio::task::block_in_place(|| -> Result<Vec<u8>, AppError> {
+                let mut data = Vec::new();
+                identicon(&id).write_to(&mut Cursor::new(&mut data), ImageFormat::Png)?;
+                std::fs::write(
+                    &path,
+                    at_rest::seal(state.config.at_rest_key.as_ref(), data.clone())?,
+                )?;
+                Ok(data)
+            })?
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(not_found()),
+        Err(e) => return Err(e.into()),
+    };
+    let content_type = ImageFormat::from_extension(&extension)
+        .map(|format| format.to_mime_type())
+        .unwrap_or("application/octet-stream");
+    Ok((
+        StatusCode::OK,
+        cache_headers,
+        [(CONTENT_TYPE, content_type)],
+        data,
+    )
+        .into_response())
 }
 
 #[utoipa::path(
@@ -1332,14 +1357,23 @@ pub async fn update_preferences(
     let theme = req.theme as u8;
     let grid_view = req.grid_view as u8;
     let sort_order = req.sort_order as u8;
-    sqlx::query!(
-        "UPDATE user SET theme = ?, grid_view = ?, sort_order = ? WHERE id = ?",
-        theme,
-        grid_view,
-        sort_order,
-        user.id
-    )
-    .execute(&state.pool)
+    retry_transaction(&state, "update preferences", || async {
+        sqlx::query!(
+            r#"
+            UPDATE user SET theme = ?, grid_view = ?, sort_order = ?,
+            explain_denials = COALESCE(?, explain_denials)
+            WHERE id = ?
+            "#,
+            theme,
+            grid_view,
+            sort_order,
+            req.explain_denials,
+            user.id
+        )
+        .execute(&state.pool)
+        .await?;
+        Ok(())
+    })
     .await?;
     Ok((StatusCode::OK, success!("Successfully updated preferences")).into_response())
 }
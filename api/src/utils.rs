@@ -1,10 +1,20 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 
 use anyhow::Result;
+use lokr_types::admin::CleanupStats;
 use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use tracing::{error, info};
 use uuid::Uuid;
 
-use crate::{upload::FileMetadata, users::PublicUser, UPLOAD_DIR};
+use crate::{
+    error::AppError,
+    jobs::{self, Job, JobStatus},
+    upload::FileMetadata,
+    users::PublicUser,
+};
 
 macro_rules! log_err {
     ($inner:expr) => {{
@@ -34,12 +44,8 @@ pub fn levenshtien(a: &str, b: &str) -> usize {
 
     let mut pre;
     let mut tmp;
-    let mut cur = vec![0; len_b];
-
     // initialize string b
-    for i in 1..len_b {
-        cur[i] = i;
-    }
+    let mut cur: Vec<usize> = (0..len_b).collect();
 
     // calculate edit distance
     for (i, ca) in a.chars().enumerate() {
@@ -103,18 +109,55 @@ impl<T: Iterator<Item = FileMetadata>> Normalize for T {
     }
 }
 
-/// Clean up the database by removing expired sessions and share links
-pub async fn clean_up(pool: &SqlitePool) {
+/// Clean up the database by removing expired sessions and share links,
+/// returning what was removed.
+/// The used space of file owners is kept up to date by the database triggers.
+pub async fn clean_up(pool: &SqlitePool) -> CleanupStats {
+    let mut stats = CleanupStats::default();
     // Use log_err! to log errors without returning them to the caller
     log_err!(
     sqlx::query!("DELETE FROM session WHERE DATETIME(last_used_at, '+' || idle_duration || ' seconds' ) < CURRENT_TIMESTAMP")
         .execute(pool)
-        .await);
+        .await
+        .map(|result| stats.sessions = result.rows_affected()));
     log_err!(
         sqlx::query!("DELETE FROM share_link WHERE DATETIME(expires_at) < CURRENT_TIMESTAMP")
             .execute(pool)
             .await
+            .map(|result| stats.share_links = result.rows_affected())
     );
+    // Revoked shares can't be restored anymore
+    log_err!(sqlx::query!(
+        "DELETE FROM revoked_share_user WHERE DATETIME(restorable_until) < CURRENT_TIMESTAMP"
+    )
+    .execute(pool)
+    .await
+    .map(|result| stats.revoked_shares += result.rows_affected()));
+    log_err!(sqlx::query!(
+        "DELETE FROM revoked_share_link WHERE DATETIME(restorable_until) < CURRENT_TIMESTAMP"
+    )
+    .execute(pool)
+    .await
+    .map(|result| stats.revoked_shares += result.rows_affected()));
+    // Mounts are kept while their share can still be restored
+    log_err!(sqlx::query!(
+        r#"
+        DELETE FROM mount WHERE
+        NOT EXISTS (SELECT 1 FROM share_user su WHERE su.file_id = mount.file_id AND su.user_id = mount.user_id) AND
+        NOT EXISTS (SELECT 1 FROM revoked_share_user rsu WHERE rsu.file_id = mount.file_id AND rsu.user_id = mount.user_id)
+        "#
+    )
+    .execute(pool)
+    .await);
+    // Users only need to see how their jobs went for a while after they finish
+    let pending = JobStatus::Pending as i64;
+    log_err!(sqlx::query!(
+        "DELETE FROM job WHERE status != ? AND DATETIME(finished_at, '+30 days') < CURRENT_TIMESTAMP",
+        pending
+    )
+    .execute(pool)
+    .await
+    .map(|result| stats.jobs = result.rows_affected()));
     // Delete all files that are not owned by a user and are not shared
     log_err!('e: {
         let deleted_files = match sqlx::query!(
@@ -123,20 +166,85 @@ pub async fn clean_up(pool: &SqlitePool) {
         WHERE owner_id IS NULL
         AND id NOT IN
         (SELECT file_id FROM share_link WHERE DATETIME(expires_at) >= CURRENT_TIMESTAMP)
-        RETURNING id AS "id: Uuid"
+        RETURNING id AS "id: Uuid", size
         "#
         )
         .fetch_all(pool)
         .await
         {
             Ok(k) => k,
-            Err(e) => break 'e Err(e),
+            Err(e) => break 'e Err(AppError::from(e)),
         };
-        for file in deleted_files {
-            log_err!(std::fs::remove_file(&*UPLOAD_DIR.join(file.id.to_string())));
+        if deleted_files.is_empty() {
+            break 'e Ok(());
         }
-        Ok(())
+        stats.files = deleted_files.len() as u64;
+        stats.reclaimed_bytes = deleted_files.iter().map(|file| file.size as u64).sum();
+        jobs::enqueue(
+            pool,
+            None,
+            &Job::DeleteBlobs {
+                ids: deleted_files.into_iter().map(|file| file.id).collect(),
+            },
+        )
+        .await
     });
+    stats
+}
+
+/// Clear out the temporary upload directory, which should only be done on startup.
+/// Uploads are written there first and only moved into the upload directory once
+/// their row is committed, so a file whose row exists was committed right before the
+/// server stopped and is moved into place. Anything else belongs to an upload that
+/// was never committed and is removed.
+pub async fn clean_temp_files(pool: &SqlitePool, temp_dir: &Path, upload_dir: &Path) {
+    let mut entries = match tokio::fs::read_dir(temp_dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Unable to read temporary upload directory: {}", e);
+            return;
+        }
+    };
+    loop {
+        let path = match entries.next_entry().await {
+            Ok(Some(entry)) => entry.path(),
+            Ok(None) => break,
+            Err(e) => {
+                error!("Unable to read temporary upload directory: {}", e);
+                break;
+            }
+        };
+        let id = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| Uuid::try_parse(name).ok());
+        let committed = match id {
+            Some(id) => match sqlx::query!("SELECT id FROM file WHERE id = ?", id)
+                .fetch_optional(pool)
+                .await
+            {
+                Ok(row) => row.is_some(),
+                // Leave the file alone rather than risk deleting committed data
+                Err(e) => {
+                    error!("Unable to check temporary file '{}': {}", path.display(), e);
+                    continue;
+                }
+            },
+            None => false,
+        };
+        if let (true, Some(id)) = (committed, id) {
+            match tokio::fs::rename(&path, upload_dir.join(id.to_string())).await {
+                Ok(()) => info!("Moved the data of file {} into place", id),
+                Err(e) => error!("Unable to move file '{}' into place: {}", id, e),
+            }
+        } else if let Err(e) = tokio::fs::remove_file(&path).await {
+            error!(
+                "Unable to remove temporary file '{}': {}",
+                path.display(),
+                e
+            );
+        }
+    }
 }
 
 /// Get the user ids referenced by a map of files
@@ -156,7 +264,7 @@ pub async fn get_file_users(
     let mut builder: QueryBuilder<'_, Sqlite> = QueryBuilder::new(
         r#"
         SELECT id, username, email, public_key,
-        avatar AS avatar_extension, NULL AS password_salt
+        avatar AS avatar_extension, avatar_version, NULL AS password_salt
         FROM user WHERE id IN ("#,
     );
     let mut separated = builder.separated(", ");
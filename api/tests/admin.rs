@@ -0,0 +1,187 @@
+use lokr_api::{instance::PublicStats, utils::clean_up};
+use lokr_client::types::{
+    instance::FeaturesUpdate,
+    share::{ShareRequest, ShareRequestType},
+};
+
+mod common;
+
+use common::*;
+
+async fn expire_links(server: &TestServer) {
+    sqlx::query("UPDATE share_link SET expires_at = DATETIME(CURRENT_TIMESTAMP, '-1 minute')")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stats_require_admin() {
+    let server = TestServer::start().await;
+    let client = server.user("admin").await;
+    upload(&client, None, b"counted").await;
+    assert_eq!(status(client.admin_stats().await), 403);
+
+    sqlx::query("UPDATE user SET is_admin = TRUE WHERE username = 'admin'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    let stats = client.admin_stats().await.unwrap();
+    assert_eq!(stats.users, 1);
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.used_space, client.profile().await.unwrap().used_space);
+    assert_eq!(stats.cleanup_runs, 0);
+
+    assert_eq!(status(server.client().admin_stats().await), 401);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clean_up_expired_links() {
+    let server = TestServer::start().await;
+    let client = server.user("cleanup_owner").await;
+    let file = upload(&client, None, b"linked").await;
+    let used_space = client.profile().await.unwrap().used_space;
+    client
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: None,
+                audience: Default::default(),
+            },
+            id: file.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    assert!(client.profile().await.unwrap().used_space > used_space);
+
+    expire_links(&server).await;
+    let stats = clean_up(&server.pool).await;
+    assert_eq!(stats.share_links, 1);
+    // Owned files stay around after their links expire
+    assert_eq!(stats.files, 0);
+    // The link no longer counts against its owner
+    assert_eq!(client.profile().await.unwrap().used_space, used_space);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clean_up_anonymous_files() {
+    let server = TestServer::start().await;
+    let anonymous = server.client();
+    let data = b"anonymous upload";
+    let file = upload(&anonymous, None, data).await;
+    assert!(file.link.is_some());
+
+    // Nothing is removed while the link is still valid
+    assert_eq!(clean_up(&server.pool).await.files, 0);
+
+    expire_links(&server).await;
+    let stats = clean_up(&server.pool).await;
+    assert_eq!(stats.share_links, 1);
+    assert_eq!(stats.files, 1);
+    assert_eq!(stats.reclaimed_bytes, data.len() as u64);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn feature_flags() {
+    let server = TestServer::start().await;
+    let admin = server.user("flags_admin").await;
+    let anonymous = server.client();
+    assert!(anonymous.features().await.unwrap().registration);
+    let update = FeaturesUpdate {
+        registration: Some(false),
+        anonymous_uploads: Some(false),
+        ..Default::default()
+    };
+    assert_eq!(status(admin.update_features(&update).await), 403);
+
+    sqlx::query("UPDATE user SET is_admin = TRUE WHERE username = 'flags_admin'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    let features = admin.update_features(&update).await.unwrap();
+    assert!(!features.registration);
+    assert!(!features.anonymous_uploads);
+    // Features that weren't part of the update are left alone
+    assert!(features.public_profiles);
+    assert_eq!(anonymous.features().await.unwrap(), features);
+
+    assert_eq!(
+        status(anonymous.register(&new_user("flags_new")).await),
+        403
+    );
+    let result = anonymous
+        .upload(&metadata(None, false), Some(b"anonymous".to_vec()))
+        .await;
+    assert_eq!(status(result), 403);
+    // Users with an account can still upload
+    upload(&admin, None, b"not anonymous").await;
+
+    admin
+        .update_features(&FeaturesUpdate {
+            registration: Some(true),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    anonymous.register(&new_user("flags_new")).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn public_instance_stats() {
+    let server = TestServer::start().await;
+    assert_eq!(status(server.client().instance_stats().await), 404);
+
+    let server = TestServer::start_with(|config| {
+        config.public_stats = "users, version".parse().unwrap();
+    })
+    .await;
+    let client = server.user("public_stats").await;
+    upload(&client, None, b"not counted").await;
+    let stats = server.client().instance_stats().await.unwrap();
+    assert_eq!(stats.users, Some(1));
+    assert_eq!(stats.version.as_deref(), Some(env!("CARGO_PKG_VERSION")));
+    // Only what the operator chose is shared
+    assert!(stats.files.is_none());
+    assert!(stats.uptime.is_none());
+    assert!("users,passwords".parse::<PublicStats>().is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn effective_config() {
+    let server = TestServer::start_with(|config| {
+        config.quota_grace_percent = 25;
+        config.public_stats = "users".parse().unwrap();
+        config.at_rest_key = Some(
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFE="
+                .parse()
+                .unwrap(),
+        );
+    })
+    .await;
+    let client = server.user("config_admin").await;
+    assert_eq!(status(client.admin_config().await), 403);
+    assert_eq!(status(server.client().admin_config().await), 401);
+
+    sqlx::query("UPDATE user SET is_admin = TRUE WHERE username = 'config_admin'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    client
+        .update_features(&FeaturesUpdate {
+            public_profiles: Some(false),
+            ..FeaturesUpdate::default()
+        })
+        .await
+        .unwrap();
+    let response = client.admin_config().await.unwrap();
+    assert_eq!(response.build.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(response.config.quota_grace_percent, 25);
+    assert_eq!(response.config.public_stats, "users");
+    assert_eq!(response.config.request_timeout, 15);
+    // Only whether the key is set is shown
+    assert!(response.config.at_rest_key);
+    // Changes made by admins at runtime are included
+    assert!(!response.features.public_profiles);
+    assert!(response.features.registration);
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use common::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn serves_client() {
+    let dist = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dist.path().join("assets")).unwrap();
+    std::fs::write(dist.path().join("index.html"), "<html></html>").unwrap();
+    std::fs::write(dist.path().join("assets/index-a1b2c3.js"), "app").unwrap();
+    std::fs::write(dist.path().join("lokr.png"), "logo").unwrap();
+    let dir = dist.path().to_owned();
+    let server = TestServer::start_with(|config| config.client_dir = dir).await;
+    let get = |path: &str| reqwest::get(server.url(path));
+    let cache_control = |response: &reqwest::Response| {
+        response
+            .headers()
+            .get("cache-control")
+            .map(|value| value.to_str().unwrap().to_owned())
+    };
+
+    let response = get("/assets/index-a1b2c3.js").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        cache_control(&response).as_deref(),
+        Some("public, max-age=31536000, immutable")
+    );
+    let response = get("/assets/index-d4e5f6.js").await.unwrap();
+    assert_eq!(response.status(), 404);
+    assert!(cache_control(&response).is_none());
+
+    // Client routes get the app, which has to be revalidated after every deploy
+    for path in ["/", "/files", "/profile/settings"] {
+        let response = get(path).await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(cache_control(&response).as_deref(), Some("no-cache"));
+        assert_eq!(response.text().await.unwrap(), "<html></html>");
+    }
+
+    assert_eq!(
+        get("/lokr.png").await.unwrap().text().await.unwrap(),
+        "logo"
+    );
+    assert_eq!(get("/missing.png").await.unwrap().status(), 404);
+    let response = get("/api/not-a-route").await.unwrap();
+    assert_eq!(response.status(), 404);
+    let error: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(error["message"], "Route not found");
+}
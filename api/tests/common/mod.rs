@@ -0,0 +1,157 @@
+//! Shared setup for the integration tests.
+//!
+//! Every test boots its own server on a random port, with the database
+//! and uploads in a temporary directory that is removed when the test ends.
+
+#![allow(dead_code)]
+
+use base64::{prelude::BASE64_STANDARD, Engine};
+use lokr_api::{config::Config, init_db, serve};
+use lokr_client::{
+    types::{
+        upload::{UploadMetadata, UploadResponse, METADATA_VERSION},
+        users::{CreateUser, LoginUser, PUBLIC_KEY_LENGTH},
+    },
+    Client, Error,
+};
+use sqlx::SqlitePool;
+use std::path::Path;
+use tempfile::TempDir;
+use tokio::net::TcpListener;
+use url::Url;
+use uuid::Uuid;
+
+pub const PASSWORD: &str = "correct horse battery staple";
+
+pub struct TestServer {
+    url: String,
+    /// For setting up state that can't be reached through the API
+    pub pool: SqlitePool,
+    _data_dir: TempDir,
+}
+
+impl TestServer {
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Start a server with changes to the default test configuration
+    pub async fn start_with(configure: impl FnOnce(&mut Config)) -> Self {
+        let data_dir = tempfile::tempdir().unwrap();
+        let mut config = Config {
+            data_dir: data_dir.path().into(),
+            // Hashing with the default parameters is slow in debug builds
+            argon2_memory_cost: 1024,
+            argon2_iterations: 1,
+            ..Config::default()
+        };
+        configure(&mut config);
+        let db_url = Url::from_file_path(config.database_path()).unwrap();
+        let pool = init_db(&db_url, config.at_rest_key.as_ref()).await.unwrap();
+        // Bind before returning so requests made while the server
+        // is still starting up wait instead of failing
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        // The server is stopped along with the test's runtime
+        tokio::spawn({
+            let pool = pool.clone();
+            async move {
+                serve(pool, config, listener, std::future::pending())
+                    .await
+                    .unwrap();
+            }
+        });
+        Self {
+            url,
+            pool,
+            _data_dir: data_dir,
+        }
+    }
+
+    /// Where the server keeps its database, uploads, and avatars
+    pub fn data_dir(&self) -> &Path {
+        self._data_dir.path()
+    }
+
+    /// The full URL of a path on the server, for requests the client doesn't cover
+    pub fn url(&self, path: &str) -> String {
+        format!("{}{}", self.url, path)
+    }
+
+    pub fn client(&self) -> Client {
+        Client::new(&self.url).unwrap()
+    }
+
+    /// Register a user and return a client that is logged in as them
+    pub async fn user(&self, username: &str) -> Client {
+        let client = self.client();
+        client.register(&new_user(username)).await.unwrap();
+        client
+            .login(&LoginUser {
+                username: username.into(),
+                password: PASSWORD.into(),
+                totp_code: None,
+            })
+            .await
+            .unwrap();
+        client
+    }
+}
+
+/// Random base64 data standing in for the encrypted fields,
+/// which the server never looks inside of
+pub fn fake(len: usize) -> String {
+    BASE64_STANDARD.encode((0..len).map(|_| fastrand::u8(..)).collect::<Vec<u8>>())
+}
+
+pub fn new_user(username: &str) -> CreateUser {
+    CreateUser {
+        username: username.into(),
+        password: PASSWORD.into(),
+        email: None,
+        iv: fake(12),
+        public_key: fake(PUBLIC_KEY_LENGTH),
+        encrypted_private_key: fake(64),
+        salt: fake(16),
+    }
+}
+
+pub fn metadata(parent_id: Option<Uuid>, is_directory: bool) -> UploadMetadata {
+    UploadMetadata {
+        encrypted_file_name: fake(16),
+        encrypted_mime_type: None,
+        // Keys of root files are wrapped with RSA-OAEP, the rest with AES-GCM
+        encrypted_key: fake(parent_id.map_or(512, |_| 48)),
+        file_nonce: (!is_directory).then(|| fake(12)),
+        key_nonce: parent_id.map(|_| fake(12)),
+        name_nonce: fake(12),
+        mime_type_nonce: None,
+        metadata_version: METADATA_VERSION,
+        key_algorithm: None,
+        plaintext_size: None,
+        is_directory,
+        parent_id,
+    }
+}
+
+pub async fn upload(client: &Client, parent_id: Option<Uuid>, data: &[u8]) -> UploadResponse {
+    client
+        .upload(&metadata(parent_id, false), Some(data.to_vec()))
+        .await
+        .unwrap()
+}
+
+pub async fn mkdir(client: &Client, parent_id: Option<Uuid>) -> UploadResponse {
+    client
+        .upload(&metadata(parent_id, true), None)
+        .await
+        .unwrap()
+}
+
+/// The status code of a failed request
+pub fn status<T: std::fmt::Debug>(result: Result<T, Error>) -> u16 {
+    match result {
+        Err(Error::Api { status, .. }) => status.as_u16(),
+        other => panic!("Expected an error response, got {:?}", other),
+    }
+}
@@ -0,0 +1,99 @@
+use lokr_api::docs::{version_doc, ApiVersion, DeprecatedRoute};
+use serde_json::Value;
+use utoipa::openapi::{
+    path::{HttpMethod, OperationBuilder, PathItem, PathsBuilder},
+    OpenApiBuilder,
+};
+
+mod common;
+
+use common::*;
+
+async fn spec(server: &TestServer, path: &str) -> Value {
+    let response = reqwest::get(server.url(path)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    response.json().await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn versioned_specs() {
+    let server = TestServer::start().await;
+    let v1 = spec(&server, "/api-docs/v1/openapi.json").await;
+    assert_eq!(v1["info"]["version"], "v1");
+    // The unversioned document is the current version
+    assert_eq!(spec(&server, "/api-docs/openapi.json").await, v1);
+    // Nothing has been deprecated yet
+    assert!(v1["paths"]
+        .as_object()
+        .unwrap()
+        .values()
+        .flat_map(|item| item.as_object().unwrap().values())
+        .all(|operation| operation.get("deprecated").is_none()));
+}
+
+#[test]
+fn deprecated_routes_point_to_their_replacement() {
+    let operation = |id: &str| {
+        OperationBuilder::new()
+            .operation_id(Some(id))
+            .description(Some("Upload a thing"))
+            .build()
+    };
+    let doc = OpenApiBuilder::new()
+        .paths(
+            PathsBuilder::new()
+                .path(
+                    "/api/test/old",
+                    PathItem::new(HttpMethod::Put, operation("old")),
+                )
+                .path(
+                    "/api/test/new",
+                    PathItem::new(HttpMethod::Put, operation("new")),
+                ),
+        )
+        .build();
+    let version = ApiVersion {
+        name: "test",
+        deprecated: &[DeprecatedRoute {
+            method: HttpMethod::Put,
+            path: "/api/test/old",
+            replaced_by: (HttpMethod::Put, "/api/test/new"),
+        }],
+    };
+    let doc = serde_json::to_value(version_doc(&doc, &version)).unwrap();
+    assert_eq!(doc["info"]["version"], "test");
+
+    let old = &doc["paths"]["/api/test/old"]["put"];
+    assert_eq!(old["deprecated"], true);
+    assert_eq!(
+        old["description"],
+        "Upload a thing\n\nDeprecated, use `PUT /api/test/new` instead."
+    );
+    let replacement = &old["x-replaced-by"];
+    assert_eq!(replacement["method"], "PUT");
+    assert_eq!(replacement["path"], "/api/test/new");
+    assert_eq!(replacement["operationId"], "new");
+    assert_eq!(replacement["link"], "#/paths/~1api~1test~1new/put");
+    assert!(doc["paths"]["/api/test/new"]["put"]
+        .get("deprecated")
+        .is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn link_routes_allow_anonymous_access() {
+    let server = TestServer::start().await;
+    let v1 = spec(&server, "/api-docs/v1/openapi.json").await;
+    // An empty requirement means the route can be used without a session
+    let anonymous = |path: &str, method: &str| {
+        v1["paths"][path][method]["security"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|requirement| requirement.as_object().unwrap().is_empty())
+    };
+    assert!(anonymous("/api/upload", "post"));
+    assert!(anonymous("/api/file/{id}", "delete"));
+    assert!(anonymous("/api/file/{id}", "put"));
+    assert!(anonymous("/api/capabilities", "get"));
+    assert!(!anonymous("/api/file", "get"));
+}
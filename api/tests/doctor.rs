@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use lokr_api::{
+    config::Config,
+    cookie::SameSite,
+    doctor::{self, Check, Severity},
+    init_db,
+};
+use tempfile::TempDir;
+use url::Url;
+
+fn config(data_dir: &TempDir) -> Config {
+    // Stand in for the built web client
+    std::fs::write(data_dir.path().join("index.html"), b"").unwrap();
+    Config {
+        data_dir: data_dir.path().into(),
+        client_dir: data_dir.path().into(),
+        ..Config::default()
+    }
+}
+
+fn find<'a>(checks: &'a [Check], name: &str) -> Vec<&'a Check> {
+    checks.iter().filter(|check| check.name == name).collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn healthy_environment() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let checks = doctor::run(&config(&data_dir)).await;
+    assert!(!doctor::has_errors(&checks), "{:?}", checks);
+    for name in ["directories", "database", "clock", "config"] {
+        assert_eq!(find(&checks, name)[0].severity, Severity::Ok);
+    }
+    // The data directories are created along the way
+    assert!(data_dir.path().join("uploads").is_dir());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unwritable_data_dir() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let file = data_dir.path().join("file");
+    std::fs::write(&file, b"not a directory").unwrap();
+    let config = Config {
+        data_dir: file.join("lokr"),
+        ..config(&data_dir)
+    };
+    let checks = doctor::run(&config).await;
+    assert!(doctor::has_errors(&checks));
+    // Nothing else is checked without somewhere to write to
+    assert!(checks.iter().all(|check| check.name == "directories"));
+    assert!(checks[0].message.contains("LOKR_DATA_DIR"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn inconsistent_config() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        cookie_same_site: SameSite::None,
+        cookie_secure: false,
+        request_timeout: Duration::ZERO,
+        argon2_memory_cost: 1,
+        ..config(&data_dir)
+    };
+    let checks = doctor::run(&config).await;
+    assert!(doctor::has_errors(&checks));
+    let severities: Vec<_> = find(&checks, "config")
+        .iter()
+        .map(|check| check.severity)
+        .collect();
+    // Secure is forced for SameSite=None, so that works, just only over HTTPS
+    assert_eq!(
+        severities,
+        [Severity::Warning, Severity::Error, Severity::Warning]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clock_behind_database() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = config(&data_dir);
+    let url = Url::from_file_path(config.database_path()).unwrap();
+    let pool = init_db(&url, None).await.unwrap();
+    sqlx::query("UPDATE _sqlx_migrations SET installed_on = DATETIME('now', '+1 hour')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+
+    let checks = doctor::run(&config).await;
+    let clock = find(&checks, "clock")[0];
+    assert_eq!(clock.severity, Severity::Warning);
+    assert!(clock.message.contains("behind"));
+    // The server can still start, TOTP is only needed by some users
+    assert!(!doctor::has_errors(&checks));
+}
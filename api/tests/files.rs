@@ -0,0 +1,649 @@
+use lokr_api::utils::clean_temp_files;
+use lokr_client::{
+    types::{
+        share::{ShareRequest, ShareRequestType, ShareResponseType},
+        upload::{
+            AliasRequest, FileQuery, KeyAlgorithm, RewrapKey, UpdateFile, UploadMetadata,
+            METADATA_VERSION,
+        },
+    },
+    Error,
+};
+use uuid::Uuid;
+
+mod common;
+
+use common::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn upload_download_delete() {
+    let server = TestServer::start().await;
+    let client = server.user("files_flow").await;
+    let data = b"encrypted file contents";
+    let file = upload(&client, None, data).await;
+    assert!(!file.is_directory);
+
+    let root = client.files(&FileQuery::default()).await.unwrap();
+    assert_eq!(root.root, [file.id]);
+    assert_eq!(client.download(file.id).await.unwrap(), data);
+
+    client.delete_file(file.id).await.unwrap();
+    assert!(client
+        .files(&FileQuery::default())
+        .await
+        .unwrap()
+        .root
+        .is_empty());
+    assert_eq!(status(client.download(file.id).await), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn download_range() {
+    let server = TestServer::start().await;
+    let client = server.user("files_range").await;
+    let data: Vec<u8> = (0..=255).collect();
+    let file = upload(&client, None, &data).await;
+    assert_eq!(
+        client.download_range(file.id, 16..32).await.unwrap(),
+        &data[16..32]
+    );
+    assert!(matches!(
+        client.download_range(file.id, 0..0).await,
+        Err(Error::EmptyRange)
+    ));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nested_directories() {
+    let server = TestServer::start().await;
+    let client = server.user("files_nested").await;
+    let dir = mkdir(&client, None).await;
+    let sub = mkdir(&client, Some(dir.id)).await;
+    let file = upload(&client, Some(sub.id), b"nested").await;
+
+    let response = client
+        .files(&FileQuery {
+            id: Some(dir.id),
+            depth: 2,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.files[&dir.id].children, [sub.id]);
+    assert_eq!(response.files[&sub.id].children, [file.id]);
+
+    // Ancestors are needed to decrypt the key of a nested file
+    let response = client
+        .files(&FileQuery {
+            id: Some(file.id),
+            include_ancestors: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert!(response.files.contains_key(&dir.id));
+    assert!(response.files.contains_key(&sub.id));
+
+    // Deleting a directory deletes everything in it
+    client.delete_file(dir.id).await.unwrap();
+    assert_eq!(status(client.download(file.id).await), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn files_are_private() {
+    let server = TestServer::start().await;
+    let owner = server.user("files_owner").await;
+    let other = server.user("files_other").await;
+    let file = upload(&owner, None, b"private").await;
+
+    let query = FileQuery {
+        id: Some(file.id),
+        ..Default::default()
+    };
+    assert_eq!(status(other.files(&query).await), 404);
+    assert_eq!(status(other.download(file.id).await), 404);
+    assert_eq!(status(other.delete_file(file.id).await), 404);
+    assert_eq!(owner.download(file.id).await.unwrap(), b"private");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delete_releases_used_space() {
+    let server = TestServer::start().await;
+    let owner = server.user("files_space").await;
+    let other = server.user("files_space_other").await;
+    let other_id = other.profile().await.unwrap().id;
+    let dir = mkdir(&owner, None).await;
+    let sub = mkdir(&owner, Some(dir.id)).await;
+    let file = upload(&owner, Some(sub.id), b"nested").await;
+    owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::User {
+                user_id: other_id,
+                encrypted_key: fake(32),
+            },
+            id: file.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: None,
+                audience: Default::default(),
+            },
+            id: sub.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+
+    // A dry run reports what would be deleted without deleting it
+    let used_space = owner.profile().await.unwrap().used_space;
+    let preview = owner.preview_delete_file(dir.id).await.unwrap();
+    assert_eq!(preview.ids, [dir.id, sub.id, file.id]);
+    assert_eq!((preview.files, preview.directories), (1, 2));
+    assert_eq!(preview.bytes, b"nested".len() as i64);
+    assert_eq!((preview.shared_users, preview.share_links), (1, 1));
+    assert_eq!(owner.profile().await.unwrap().used_space, used_space);
+    assert_eq!(owner.download(file.id).await.unwrap(), b"nested");
+    assert_eq!(status(other.preview_delete_file(dir.id).await), 404);
+
+    // Everything under the directory is deleted along with it,
+    // including the shares of its children
+    owner.delete_file(dir.id).await.unwrap();
+    assert_eq!(owner.profile().await.unwrap().used_space, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn quota_grace_space() {
+    let server = TestServer::start_with(|config| config.quota_grace_percent = 50).await;
+    let client = server.user("files_quota").await;
+    sqlx::query("UPDATE user SET total_space = 3000 WHERE username = 'files_quota'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    let data = [0; 1000];
+    assert!(upload(&client, None, &data).await.quota_warning.is_none());
+
+    // Going over the total space still works, but comes with a warning
+    let file = upload(&client, None, &data).await;
+    let warning = file.quota_warning.unwrap();
+    assert_eq!(warning.total_space, 3000);
+    assert_eq!(warning.grace_space, 1500);
+    assert!(warning.used_space > 3000);
+    assert_eq!(
+        warning.used_space,
+        client.profile().await.unwrap().used_space
+    );
+
+    // Nothing past the grace space is accepted
+    let result = client
+        .upload(&metadata(None, false), Some(data.to_vec()))
+        .await;
+    assert_eq!(status(result), 402);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn find_by_fingerprint() {
+    let server = TestServer::start().await;
+    let client = server.user("files_fp").await;
+    let other = server.user("files_fp_other").await;
+    let fingerprint = "a".repeat(64);
+    let first = client
+        .upload_with_fingerprint(
+            &metadata(None, false),
+            Some(b"same".to_vec()),
+            Some(&fingerprint),
+        )
+        .await
+        .unwrap();
+    let second = client
+        .upload_with_fingerprint(
+            &metadata(None, false),
+            Some(b"same".to_vec()),
+            Some(&fingerprint),
+        )
+        .await
+        .unwrap();
+    upload(&client, None, b"no fingerprint").await;
+
+    let response = client
+        .find_fingerprints(&[fingerprint.clone(), "b".repeat(64)])
+        .await
+        .unwrap();
+    assert_eq!(response.files.len(), 1);
+    assert_eq!(response.files[&fingerprint], [first.id, second.id]);
+
+    // Only the user's own files are searched
+    assert!(other
+        .find_fingerprints(std::slice::from_ref(&fingerprint))
+        .await
+        .unwrap()
+        .files
+        .is_empty());
+
+    let result = client
+        .upload_with_fingerprint(&metadata(None, false), Some(b"x".to_vec()), Some(""))
+        .await;
+    assert_eq!(status(result), 400);
+    let too_many = vec![String::from("a"); 1001];
+    assert_eq!(status(client.find_fingerprints(&too_many).await), 400);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rewrap_keys() {
+    let server = TestServer::start().await;
+    let client = server.user("files_rewrap").await;
+    let other = server.user("files_rewrap_other").await;
+    let dir = mkdir(&client, None).await;
+    let file = upload(&client, Some(dir.id), b"rewrapped").await;
+    let not_mine = upload(&other, None, b"not mine").await;
+
+    let root_key = fake(512);
+    let child_key = fake(48);
+    let response = client
+        .rewrap_keys(vec![
+            RewrapKey {
+                file_id: dir.id,
+                encrypted_key: root_key.clone(),
+                key_nonce: None,
+                key_algorithm: None,
+            },
+            RewrapKey {
+                file_id: file.id,
+                encrypted_key: child_key.clone(),
+                key_nonce: Some(fake(12)),
+                key_algorithm: None,
+            },
+            // Files in the root directory don't have a key nonce
+            RewrapKey {
+                file_id: dir.id,
+                encrypted_key: fake(48),
+                key_nonce: Some(fake(12)),
+                key_algorithm: None,
+            },
+            RewrapKey {
+                file_id: not_mine.id,
+                encrypted_key: fake(512),
+                key_nonce: None,
+                key_algorithm: None,
+            },
+        ])
+        .await
+        .unwrap();
+    let updated: Vec<_> = response.results.iter().map(|r| r.updated).collect();
+    assert_eq!(updated, [true, true, false, false]);
+    assert!(response.results[3].error.is_some());
+    assert_eq!(response.key_epoch, 1);
+    assert_eq!(client.profile().await.unwrap().key_epoch, 1);
+
+    // The failed items don't undo the others
+    let files = client
+        .files(&FileQuery {
+            id: Some(dir.id),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .files;
+    assert_eq!(files[&dir.id].upload.encrypted_key, root_key);
+    assert_eq!(files[&file.id].upload.encrypted_key, child_key);
+    assert_eq!(files[&file.id].key_epoch, Some(1));
+    // New files are wrapped with the current keys
+    let new_file = upload(&client, None, b"after rotation").await;
+    let files = client
+        .files(&FileQuery {
+            id: Some(new_file.id),
+            ..Default::default()
+        })
+        .await
+        .unwrap()
+        .files;
+    assert_eq!(files[&new_file.id].key_epoch, Some(1));
+    assert_eq!(other.download(not_mine.id).await.unwrap(), b"not mine");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn monthly_transfer() {
+    let server = TestServer::start_with(|config| config.monthly_transfer_cap = 150).await;
+    let owner = server.user("files_transfer").await;
+    let data = [1; 100];
+    let file = upload(&owner, None, &data).await;
+    let profile = owner.profile().await.unwrap();
+    assert_eq!(profile.uploaded_this_month, 100);
+    assert_eq!(profile.downloaded_this_month, 0);
+    assert_eq!(profile.transfer_cap, Some(150));
+
+    // Downloads through links count towards the owner, and ranges only count what was sent
+    let link_id = match owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: None,
+                audience: Default::default(),
+            },
+            id: file.id,
+            edit: false,
+        })
+        .await
+        .unwrap()
+        .type_
+    {
+        ShareResponseType::Link { link_id, .. } => link_id,
+        _ => panic!("Expected a link"),
+    };
+    let url = server.url(&format!("/api/file/data/{}?linkId={}", file.id, link_id));
+    let response = reqwest::get(&url).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.bytes().await.unwrap().len(), 100);
+    owner.download_range(file.id, 0..10).await.unwrap();
+    assert_eq!(owner.profile().await.unwrap().downloaded_this_month, 110);
+
+    // The download that goes over the cap still works, but nothing after it
+    owner.download(file.id).await.unwrap();
+    assert_eq!(status(owner.download(file.id).await), 429);
+    assert_eq!(reqwest::get(&url).await.unwrap().status(), 429);
+
+    // Admins can lift the cap for a single user
+    sqlx::query("UPDATE user SET transfer_cap = 0 WHERE username = 'files_transfer'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    assert!(owner.profile().await.unwrap().transfer_cap.is_none());
+    assert_eq!(owner.download(file.id).await.unwrap(), data);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn metadata_versions() {
+    let server = TestServer::start().await;
+    let client = server.user("files_versions").await;
+    let versions = server.client().metadata_versions().await.unwrap();
+    assert_eq!(versions.current, METADATA_VERSION);
+    assert!(versions.supported.contains(&versions.current));
+
+    let mut unsupported = metadata(None, false);
+    unsupported.metadata_version = versions.supported.iter().max().unwrap() + 1;
+    assert_eq!(
+        status(
+            client
+                .upload(&unsupported, Some(b"from the future".to_vec()))
+                .await
+        ),
+        400
+    );
+
+    // Files keep the version they were written in, so older rows can be told apart
+    let old = upload(&client, None, b"old format").await;
+    let new = upload(&client, None, b"new format").await;
+    sqlx::query("UPDATE file SET metadata_version = 2 WHERE id = ?")
+        .bind(new.id)
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    let files = client.files(&FileQuery::default()).await.unwrap().files;
+    assert_eq!(files[&old.id].upload.metadata_version, 1);
+    assert_eq!(files[&new.id].upload.metadata_version, 2);
+    let manifest = client.key_manifest().await.unwrap();
+    let manifest_version = |id| {
+        manifest
+            .files
+            .iter()
+            .find(|file| file.id == id)
+            .unwrap()
+            .metadata_version
+    };
+    assert_eq!(manifest_version(old.id), 1);
+    assert_eq!(manifest_version(new.id), 2);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn key_algorithms() {
+    let server = TestServer::start().await;
+    let client = server.user("files_algorithms").await;
+    let dir = mkdir(&client, None).await;
+
+    // The algorithm is inferred from the nonce, so a 32 byte key fits neither
+    let mut short = metadata(None, true);
+    short.encrypted_key = fake(32);
+    assert_eq!(status(client.upload(&short, None).await), 400);
+    let mut short = metadata(Some(dir.id), true);
+    short.encrypted_key = fake(32);
+    assert_eq!(status(client.upload(&short, None).await), 400);
+
+    // XChaCha20-Poly1305 needs a longer nonce than AES-GCM
+    let mut xchacha = metadata(Some(dir.id), false);
+    xchacha.key_algorithm = Some(KeyAlgorithm::XChaCha20Poly1305);
+    assert_eq!(
+        status(client.upload(&xchacha, Some(b"xchacha".to_vec())).await),
+        400
+    );
+    xchacha.key_nonce = Some(fake(24));
+    let file = client
+        .upload(&xchacha, Some(b"xchacha".to_vec()))
+        .await
+        .unwrap();
+    // RSA-OAEP doesn't use a nonce, but works with other key sizes
+    let mut rsa = metadata(None, true);
+    rsa.key_algorithm = Some(KeyAlgorithm::RsaOaep);
+    rsa.key_nonce = Some(fake(12));
+    assert_eq!(status(client.upload(&rsa, None).await), 400);
+    rsa.key_nonce = None;
+    rsa.encrypted_key = fake(256);
+    let rsa_2048 = client.upload(&rsa, None).await.unwrap();
+
+    let query = FileQuery {
+        id: Some(dir.id),
+        ..Default::default()
+    };
+    let files = client.files(&query).await.unwrap().files;
+    assert_eq!(
+        files[&dir.id].upload.key_algorithm,
+        Some(KeyAlgorithm::RsaOaep)
+    );
+    assert_eq!(
+        files[&file.id].upload.key_algorithm,
+        Some(KeyAlgorithm::XChaCha20Poly1305)
+    );
+    let files = client.files(&FileQuery::default()).await.unwrap().files;
+    assert_eq!(
+        files[&rsa_2048.id].upload.key_algorithm,
+        Some(KeyAlgorithm::RsaOaep)
+    );
+
+    // Moving the file to the root directory wraps its key with RSA-OAEP instead
+    let to_root = |encrypted_key| UpdateFile::Move {
+        parent_id: None,
+        encrypted_key,
+        key_nonce: None,
+        key_algorithm: None,
+    };
+    assert_eq!(
+        status(client.update_file(file.id, &to_root(fake(48))).await),
+        400
+    );
+    client
+        .update_file(file.id, &to_root(fake(512)))
+        .await
+        .unwrap();
+    let files = client.files(&FileQuery::default()).await.unwrap().files;
+    assert_eq!(
+        files[&file.id].upload.key_algorithm,
+        Some(KeyAlgorithm::RsaOaep)
+    );
+
+    // Files shared with a user have their key wrapped with the user's public key
+    let other = server.user("files_algo_other").await;
+    let other_id = other.profile().await.unwrap().id;
+    let child = upload(&client, Some(dir.id), b"shared").await;
+    client
+        .share(&ShareRequest {
+            type_: ShareRequestType::User {
+                user_id: other_id,
+                encrypted_key: fake(512),
+            },
+            id: child.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    let shared = other
+        .shared_files(&FileQuery::default(), &Default::default())
+        .await
+        .unwrap()
+        .files;
+    assert_eq!(
+        shared[&child.id].upload.key_algorithm,
+        Some(KeyAlgorithm::RsaOaep)
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn plaintext_and_ciphertext_sizes() {
+    let server = TestServer::start().await;
+    let client = server.user("files_sizes").await;
+    let dir = mkdir(&client, None).await;
+
+    // Without a declared size the AES-GCM tag is assumed to be the only overhead
+    let single = upload(&client, Some(dir.id), &[0; 116]).await;
+    assert_eq!((single.size, single.plaintext_size), (116, 100));
+    // Data encrypted in chunks has a tag and nonce per chunk
+    let chunked = client
+        .upload(
+            &UploadMetadata {
+                plaintext_size: Some(64),
+                ..metadata(Some(dir.id), false)
+            },
+            Some(vec![0; 120]),
+        )
+        .await
+        .unwrap();
+    assert_eq!((chunked.size, chunked.plaintext_size), (120, 64));
+    for plaintext_size in [-1, 121] {
+        let metadata = UploadMetadata {
+            plaintext_size: Some(plaintext_size),
+            ..metadata(Some(dir.id), false)
+        };
+        assert_eq!(
+            status(client.upload(&metadata, Some(vec![0; 120])).await),
+            400
+        );
+    }
+
+    let response = client
+        .files(&FileQuery {
+            id: Some(dir.id),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let sizes = |id| {
+        let file = &response.files[&id];
+        (file.size, file.ciphertext_size)
+    };
+    assert_eq!(sizes(single.id), (100, 116));
+    assert_eq!(sizes(chunked.id), (64, 120));
+    assert_eq!(sizes(dir.id), (0, 0));
+    // The stored data is what counts towards the used space
+    let preview = client.preview_delete_file(dir.id).await.unwrap();
+    assert_eq!(preview.bytes, 236);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn aliases_share_data() {
+    let server = TestServer::start().await;
+    let owner = server.user("files_alias").await;
+    let other = server.user("files_alias_other").await;
+    let first = mkdir(&owner, None).await;
+    let second = mkdir(&owner, None).await;
+    let data = vec![7; 4096];
+    let file = upload(&owner, Some(first.id), &data).await;
+    let into = |parent_id: Option<Uuid>| AliasRequest {
+        parent_id,
+        encrypted_key: fake(parent_id.map_or(512, |_| 48)),
+        key_nonce: parent_id.map(|_| fake(12)),
+        key_algorithm: None,
+    };
+
+    // Only the owner can alias a file, and only files can be aliased
+    assert_eq!(status(other.alias(file.id, &into(None)).await), 404);
+    assert_eq!(status(owner.alias(first.id, &into(None)).await), 400);
+    assert_eq!(
+        status(owner.alias(file.id, &into(Some(file.id))).await),
+        400
+    );
+
+    // The alias doesn't take up the space of the data again
+    let used_space = owner.profile().await.unwrap().used_space;
+    let alias = owner.alias(file.id, &into(Some(second.id))).await.unwrap();
+    assert_eq!(alias.plaintext_size, 4096 - 16);
+    let alias_space = owner.profile().await.unwrap().used_space - used_space;
+    assert!(alias_space < 1024);
+    let listing = owner
+        .files(&FileQuery {
+            id: Some(second.id),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let listed = &listing.files[&alias.id];
+    assert_eq!(listed.alias_of, Some(file.id));
+    assert_eq!(listed.ciphertext_size, 4096);
+    assert_eq!(owner.download(alias.id).await.unwrap(), data);
+
+    // An alias of an alias shares the data of the original file
+    let nested = owner.alias(alias.id, &into(None)).await.unwrap();
+    let root = owner.files(&FileQuery::default()).await.unwrap();
+    assert_eq!(root.files[&nested.id].alias_of, Some(file.id));
+
+    // The data is kept, and still counted once, while an alias is left
+    assert_eq!(owner.preview_delete_file(file.id).await.unwrap().bytes, 0);
+    assert_eq!(owner.preview_delete_file(first.id).await.unwrap().bytes, 0);
+    owner.delete_file(file.id).await.unwrap();
+    assert!(owner.profile().await.unwrap().used_space > 4096);
+    assert_eq!(owner.download(alias.id).await.unwrap(), data);
+    owner.delete_file(second.id).await.unwrap();
+    assert_eq!(owner.download(nested.id).await.unwrap(), data);
+    let root = owner.files(&FileQuery::default()).await.unwrap();
+    assert_eq!(root.files[&nested.id].ciphertext_size, 4096);
+
+    // Deleting the last alias deletes the data
+    assert_eq!(
+        owner.preview_delete_file(nested.id).await.unwrap().bytes,
+        4096
+    );
+    owner.delete_file(nested.id).await.unwrap();
+    let blob = server.data_dir().join("uploads").join(file.id.to_string());
+    for _ in 0..100 {
+        if !blob.exists() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert!(!blob.exists());
+    owner.delete_file(first.id).await.unwrap();
+    assert_eq!(owner.profile().await.unwrap().used_space, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn temp_files_are_recovered_or_removed() {
+    let server = TestServer::start().await;
+    let owner = server.user("files_temp").await;
+    let file = upload(&owner, None, b"committed").await;
+    let temp_dir = server.data_dir().join("tmp");
+    let upload_dir = server.data_dir().join("uploads");
+    // The server stopped after the upload committed but before its data was moved into place
+    std::fs::rename(
+        upload_dir.join(file.id.to_string()),
+        temp_dir.join(file.id.to_string()),
+    )
+    .unwrap();
+    let orphan = temp_dir.join(Uuid::now_v7().to_string());
+    std::fs::write(&orphan, b"never committed").unwrap();
+
+    clean_temp_files(&server.pool, &temp_dir, &upload_dir).await;
+    assert_eq!(owner.download(file.id).await.unwrap(), b"committed");
+    assert!(!orphan.exists());
+    assert_eq!(std::fs::read_dir(&temp_dir).unwrap().count(), 0);
+}
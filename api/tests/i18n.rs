@@ -0,0 +1,51 @@
+use serde_json::Value;
+
+mod common;
+
+use common::*;
+
+async fn login_error(server: &TestServer, accept_language: Option<&str>) -> Value {
+    let mut request = reqwest::Client::new()
+        .post(server.url("/api/login"))
+        .header("user-agent", "i18n-test")
+        .json(&serde_json::json!({ "username": "i18n_nobody", "password": PASSWORD }));
+    if let Some(accept_language) = accept_language {
+        request = request.header("accept-language", accept_language);
+    }
+    let response = request.send().await.unwrap();
+    let status = response.status();
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(status, 401, "{}", body);
+    body
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn localized_messages() {
+    let server = TestServer::start().await;
+    let english = login_error(&server, None).await;
+    assert_eq!(english["message"], "Invalid username or password");
+
+    let spanish = login_error(&server, Some("es-MX,es;q=0.9,en;q=0.8")).await;
+    assert_eq!(
+        spanish["message"],
+        "Nombre de usuario o contraseña incorrectos"
+    );
+    // The type stays the same so clients can still tell errors apart
+    assert_eq!(spanish["type"], english["type"]);
+    let german = login_error(&server, Some("fr;q=0.9, de;q=0.8, es;q=0.1")).await;
+    assert_eq!(german["message"], "Benutzername oder Passwort ist falsch");
+    for accept_language in ["en, es;q=0.5", "fr", "es;q=0", "*"] {
+        assert_eq!(login_error(&server, Some(accept_language)).await, english);
+    }
+
+    // Success messages are translated too
+    let response = reqwest::Client::new()
+        .get(server.url("/api/check"))
+        .query(&[("username", "i18n_free")])
+        .header("accept-language", "de")
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["message"], "Keine Konflikte gefunden");
+}
@@ -0,0 +1,164 @@
+use std::time::Duration;
+
+use lokr_api::utils::clean_up;
+use lokr_client::{
+    types::jobs::{JobInfo, JobQuery, JobStatus},
+    Client,
+};
+
+mod common;
+
+use common::*;
+
+/// Wait for the worker to get the job out of the pending state
+async fn wait_for_job(client: &Client, id: i64) -> JobInfo {
+    for _ in 0..100 {
+        let job = client.job(id).await.unwrap();
+        if job.status != JobStatus::Pending || job.attempts > 0 {
+            return job;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Job {} was never run", id);
+}
+
+/// Queue a job for the user that can never succeed, so it stays around
+async fn queue_broken_job(server: &TestServer, username: &str, delay: &str) -> i64 {
+    sqlx::query_scalar(
+        r#"
+        INSERT INTO job (payload, user_id, run_at)
+        SELECT '{"type":"broken"}', id, DATETIME(CURRENT_TIMESTAMP, ?)
+        FROM user WHERE username = ?
+        RETURNING id
+        "#,
+    )
+    .bind(delay)
+    .bind(username)
+    .fetch_one(&server.pool)
+    .await
+    .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deleting_files_queues_a_job_for_the_user() {
+    let server = TestServer::start().await;
+    let client = server.user("job_owner").await;
+    let file = upload(&client, None, b"short lived").await;
+    client.delete_file(file.id).await.unwrap();
+
+    let jobs = client.jobs().await.unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].kind, "deleteBlobs");
+    // Users don't need to be told who they are
+    assert_eq!(jobs[0].user_id, None);
+
+    let job = wait_for_job(&client, jobs[0].id).await;
+    assert_eq!(job.status, JobStatus::Completed);
+    assert!(job.finished_at.is_some());
+    // Finished jobs can't be cancelled or retried
+    assert_eq!(status(client.cancel_job(job.id).await), 409);
+    assert_eq!(status(client.retry_job(job.id).await), 409);
+
+    // Other users can't see the job
+    let other = server.user("job_snoop").await;
+    assert!(other.jobs().await.unwrap().is_empty());
+    assert_eq!(status(other.job(job.id).await), 404);
+    assert_eq!(status(other.cancel_job(job.id).await), 404);
+    assert_eq!(status(other.retry_job(job.id).await), 404);
+    assert_eq!(status(server.client().jobs().await), 401);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cancel_and_retry_jobs() {
+    let server = TestServer::start().await;
+    let client = server.user("job_canceller").await;
+    let id = queue_broken_job(&server, "job_canceller", "+1 hour").await;
+
+    client.cancel_job(id).await.unwrap();
+    let job = client.job(id).await.unwrap();
+    assert_eq!(job.status, JobStatus::Cancelled);
+    assert!(job.finished_at.is_some());
+    assert_eq!(status(client.cancel_job(id).await), 409);
+
+    // Retrying runs the job right away
+    client.retry_job(id).await.unwrap();
+    assert_eq!(status(client.retry_job(id).await), 409);
+    let job = wait_for_job(&client, id).await;
+    assert_eq!(job.attempts, 1);
+    assert!(job.last_error.unwrap().contains("Invalid job payload"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deleting_data_cant_be_cancelled() {
+    let server = TestServer::start().await;
+    let client = server.user("job_hoarder").await;
+    // Keep the job pending so there is something to cancel
+    let id: i64 = sqlx::query_scalar(
+        r#"
+        INSERT INTO job (payload, user_id, run_at)
+        SELECT '{"type":"deleteBlobs","ids":[]}', id, DATETIME(CURRENT_TIMESTAMP, '+1 hour')
+        FROM user WHERE username = 'job_hoarder'
+        RETURNING id
+        "#,
+    )
+    .fetch_one(&server.pool)
+    .await
+    .unwrap();
+    assert_eq!(status(client.cancel_job(id).await), 409);
+    assert_eq!(client.job(id).await.unwrap().status, JobStatus::Pending);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn admins_see_every_job() {
+    let server = TestServer::start().await;
+    let client = server.user("job_admin").await;
+    let id = queue_broken_job(&server, "job_admin", "+1 hour").await;
+    sqlx::query(
+        "UPDATE job SET status = 1, attempts = 5, last_error = 'Disk on fire' WHERE id = ?",
+    )
+    .bind(id)
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    let failed = JobQuery {
+        status: Some(JobStatus::Failed),
+        ..JobQuery::default()
+    };
+    assert_eq!(status(client.admin_jobs(&failed).await), 403);
+
+    sqlx::query("UPDATE user SET is_admin = TRUE WHERE username = 'job_admin'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    let jobs = client.admin_jobs(&failed).await.unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].last_error.as_deref(), Some("Disk on fire"));
+    let user_id = client.profile().await.unwrap().id;
+    assert_eq!(jobs[0].user_id, Some(user_id));
+    let pending = JobQuery {
+        status: Some(JobStatus::Pending),
+        user_id: Some(user_id),
+    };
+    assert!(client.admin_jobs(&pending).await.unwrap().is_empty());
+    let mine = JobQuery {
+        user_id: Some(user_id),
+        ..JobQuery::default()
+    };
+    assert_eq!(client.admin_jobs(&mine).await.unwrap().len(), 1);
+
+    // A failed job gets a fresh set of attempts when it is retried
+    client.retry_job(id).await.unwrap();
+    let job = wait_for_job(&client, id).await;
+    assert_eq!(job.attempts, 1);
+
+    // Finished jobs are kept for 30 days
+    sqlx::query(
+        "UPDATE job SET status = 2, finished_at = DATETIME(CURRENT_TIMESTAMP, '-31 days') WHERE id = ?",
+    )
+    .bind(id)
+    .execute(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(clean_up(&server.pool).await.jobs, 1);
+    assert!(client.jobs().await.unwrap().is_empty());
+}
@@ -0,0 +1,176 @@
+use lokr_api::{
+    at_rest::{self, AtRestKey},
+    init_db,
+    migrations::{self, MigrationState},
+};
+use url::Url;
+
+fn states(statuses: &[migrations::MigrationStatus]) -> Vec<MigrationState> {
+    statuses.iter().map(|status| status.state).collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn migration_status() {
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("api.db");
+    let url = Url::from_file_path(&db_path).unwrap();
+
+    // Checking the status doesn't create the database
+    let statuses = migrations::status(&url).await.unwrap();
+    assert!(!statuses.is_empty());
+    assert!(states(&statuses)
+        .iter()
+        .all(|state| *state == MigrationState::Pending));
+    assert!(statuses.iter().all(|status| status.sql.is_some()));
+    assert!(!db_path.exists());
+
+    // There is nothing to back up yet
+    assert!(migrations::migrate(&url, true, None)
+        .await
+        .unwrap()
+        .is_none());
+    let statuses = migrations::status(&url).await.unwrap();
+    assert!(states(&statuses)
+        .iter()
+        .all(|state| *state == MigrationState::Applied));
+
+    let pool = init_db(&url, None).await.unwrap();
+    sqlx::query("INSERT INTO user (id, username, password_hash, iv, encrypted_private_key, public_key, salt) VALUES (X'00', 'kept', '', X'00', X'00', X'00', X'00')")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE _sqlx_migrations SET checksum = X'00' WHERE version = 1")
+        .execute(&pool)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (9999, 'from the future', TRUE, X'00', 0)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+    let statuses = migrations::status(&url).await.unwrap();
+    assert_eq!(statuses[0].state, MigrationState::Modified);
+    let last = statuses.last().unwrap();
+    assert_eq!(last.version, 9999);
+    assert_eq!(last.state, MigrationState::Unknown);
+    assert!(last.sql.is_none());
+    // Migrating by hand never deletes the database
+    assert!(migrations::migrate(&url, true, None).await.is_err());
+
+    // A database used by a newer version stops the server from starting
+    assert!(init_db(&url, None).await.is_err());
+    let pool = lokr_api::connect_db(&url).unwrap();
+    sqlx::query("DELETE FROM _sqlx_migrations WHERE version = 9999")
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool.close().await;
+
+    // Starting the server recreates the database, but keeps a copy of the old one
+    let pool = init_db(&url, None).await.unwrap();
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM user")
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(users, 0);
+    let backup = std::fs::read_dir(dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension().is_some_and(|ext| ext == "bak"))
+        .unwrap();
+    let backup = lokr_api::connect_db(&Url::from_file_path(backup).unwrap()).unwrap();
+    let username: String = sqlx::query_scalar("SELECT username FROM user")
+        .fetch_one(&backup)
+        .await
+        .unwrap();
+    assert_eq!(username, "kept");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn encrypted_backup() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = Url::from_file_path(dir.path().join("api.db")).unwrap();
+    init_db(&url, None).await.unwrap().close().await;
+    let key: AtRestKey = "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFE="
+        .parse()
+        .unwrap();
+
+    let backup = migrations::backup(&url, Some(&key)).await.unwrap();
+    let data = std::fs::read(&backup).unwrap();
+    assert!(!data.starts_with(b"SQLite format 3"));
+    let mut decrypted = Vec::new();
+    at_rest::open_file(Some(&key), &backup, &mut decrypted).unwrap();
+    assert!(decrypted.starts_with(b"SQLite format 3"));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&backup).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    // Nothing unencrypted is left behind
+    assert_eq!(
+        std::fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| entry
+                .as_ref()
+                .unwrap()
+                .path()
+                .extension()
+                .is_some_and(|ext| ext == "tmp"))
+            .count(),
+        0
+    );
+}
+
+#[test]
+fn sealed_files_are_read_back_in_chunks() {
+    let dir = tempfile::tempdir().unwrap();
+    let key: AtRestKey = "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFE="
+        .parse()
+        .unwrap();
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // Empty, a single chunk, exactly one chunk, and several chunks
+    for (i, len) in [0, 10, 64 * 1024, 200 * 1024 + 7].into_iter().enumerate() {
+        let data: Vec<u8> = (0..len).map(|_| fastrand::u8(..)).collect();
+        let plain = dir.path().join(format!("{}.plain", i));
+        let sealed = dir.path().join(format!("{}.sealed", i));
+        std::fs::write(&plain, &data).unwrap();
+        at_rest::seal_file(&key, &plain, &sealed, &options).unwrap();
+        let mut opened = Vec::new();
+        at_rest::open_file(Some(&key), &sealed, &mut opened).unwrap();
+        assert_eq!(opened, data);
+
+        // Dropping the last chunk has to be noticed
+        let on_disk = std::fs::read(&sealed).unwrap();
+        if len > 64 * 1024 {
+            std::fs::write(
+                &sealed,
+                &on_disk[..on_disk.len() - (len % (64 * 1024)) - 16],
+            )
+            .unwrap();
+            assert!(at_rest::open_file(Some(&key), &sealed, &mut Vec::new()).is_err());
+        }
+    }
+    let wrong: AtRestKey = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+        .parse()
+        .unwrap();
+    assert!(
+        at_rest::open_file(Some(&wrong), &dir.path().join("1.sealed"), &mut Vec::new()).is_err()
+    );
+    assert!(at_rest::open_file(None, &dir.path().join("1.sealed"), &mut Vec::new()).is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn interrupted_backups_are_removed_on_startup() {
+    let dir = tempfile::tempdir().unwrap();
+    let url = Url::from_file_path(dir.path().join("api.db")).unwrap();
+    let copy = dir.path().join("api.db.20250101T000000.tmp");
+    let unrelated = dir.path().join("notes.tmp");
+    std::fs::write(&copy, b"SQLite format 3").unwrap();
+    std::fs::write(&unrelated, b"kept").unwrap();
+
+    init_db(&url, None).await.unwrap().close().await;
+    assert!(!copy.exists());
+    assert!(unrelated.exists());
+}
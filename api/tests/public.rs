@@ -0,0 +1,107 @@
+use lokr_client::{
+    types::{
+        public::PublishRequest,
+        share::{ShareRequest, ShareRequestType, ShareResponseType},
+    },
+    Client,
+};
+use uuid::Uuid;
+
+mod common;
+
+use common::*;
+
+async fn link(client: &Client, file_id: Uuid) -> Uuid {
+    let response = client
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: None,
+                audience: Default::default(),
+            },
+            id: file_id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    let ShareResponseType::Link { link_id, .. } = response.type_ else {
+        panic!("Expected a link");
+    };
+    link_id
+}
+
+fn publish(link_id: Uuid) -> PublishRequest {
+    PublishRequest {
+        link_id,
+        title: "Release notes".into(),
+        key: fake(32),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn public_profile() {
+    let server = TestServer::start().await;
+    let owner = server.user("public_owner").await;
+    let visitor = server.client();
+    let file = upload(&owner, None, b"for everyone").await;
+    let link_id = link(&owner, file.id).await;
+    let request = publish(link_id);
+    owner.publish_link(&request).await.unwrap();
+
+    // Profiles are hidden until the user opts in
+    assert_eq!(status(visitor.public_profile("public_owner").await), 404);
+    owner.set_public_profile(true).await.unwrap();
+    let profile = visitor.public_profile("public_owner").await.unwrap();
+    assert_eq!(profile.files.len(), 1);
+    assert_eq!(profile.files[0].link_id, link_id);
+    assert_eq!(profile.files[0].title, request.title);
+    assert_eq!(profile.files[0].key, request.key);
+
+    owner.unpublish_link(link_id).await.unwrap();
+    let profile = visitor.public_profile("public_owner").await.unwrap();
+    assert!(profile.files.is_empty());
+    assert_eq!(status(owner.unpublish_link(link_id).await), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_owner_can_publish() {
+    let server = TestServer::start().await;
+    let owner = server.user("public_link_owner").await;
+    let other = server.user("public_other").await;
+    let file = upload(&owner, None, b"not yours").await;
+    let link_id = link(&owner, file.id).await;
+    assert_eq!(status(other.publish_link(&publish(link_id)).await), 404);
+    assert_eq!(
+        status(
+            owner
+                .publish_link(&PublishRequest {
+                    title: " ".into(),
+                    ..publish(link_id)
+                })
+                .await
+        ),
+        400
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn expired_links_are_hidden() {
+    let server = TestServer::start().await;
+    let owner = server.user("public_expired").await;
+    owner.set_public_profile(true).await.unwrap();
+    let file = upload(&owner, None, b"old news").await;
+    owner
+        .publish_link(&publish(link(&owner, file.id).await))
+        .await
+        .unwrap();
+    sqlx::query("UPDATE share_link SET expires_at = DATETIME(CURRENT_TIMESTAMP, '-1 minute')")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    let profile = server
+        .client()
+        .public_profile("public_expired")
+        .await
+        .unwrap();
+    assert!(profile.files.is_empty());
+}
@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use lokr_client::{types::error::ErrorType, Error};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+mod common;
+
+use common::*;
+
+fn header(response: &reqwest::Response, name: &str) -> Option<u64> {
+    response.headers().get(name)?.to_str().ok()?.parse().ok()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn rate_limit_headers() {
+    let server = TestServer::start().await;
+    let http = reqwest::Client::new();
+    let url = server.url("/api/file");
+
+    let response = http.get(&url).send().await.unwrap();
+    assert_eq!(header(&response, "ratelimit-limit"), Some(30));
+    let remaining = header(&response, "ratelimit-remaining").unwrap();
+    assert!(remaining < 30);
+    assert!(header(&response, "ratelimit-reset").is_some());
+    assert!(response.headers().get("x-ratelimit-limit").is_none());
+
+    // Use up the rest of the burst, after which requests are rejected
+    // along with how long to wait for the next one
+    let mut response = http.get(&url).send().await.unwrap();
+    for _ in 0..remaining + 10 {
+        if response.status() == 429 {
+            break;
+        }
+        response = http.get(&url).send().await.unwrap();
+    }
+    assert_eq!(response.status(), 429);
+    assert_eq!(header(&response, "ratelimit-remaining"), Some(0));
+    assert!(header(&response, "ratelimit-reset").is_some());
+    assert!(response.headers().get("retry-after").is_some());
+
+    // Routes without a rate limit don't pretend to have one
+    let response = http.get(server.url("/api/profile")).send().await.unwrap();
+    assert!(response.headers().get("ratelimit-limit").is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn concurrent_upload_limit() {
+    let server = TestServer::start_with(|config| config.max_concurrent_uploads = 1).await;
+    let client = server.user("upload_limit").await;
+    let other = server.user("upload_limit_other").await;
+
+    // Start an upload that stops sending data halfway through, keeping it in progress
+    let address = server.url("").trim_start_matches("http://").to_string();
+    let mut stalled = TcpStream::connect(&address).await.unwrap();
+    let request = format!(
+        "POST /api/upload HTTP/1.1\r\nHost: {}\r\nCookie: session={}\r\n\
+        Content-Type: multipart/form-data; boundary=stall\r\nContent-Length: 100000\r\n\r\n\
+        --stall\r\nContent-Disposition: form-data; name=\"file\"\r\n\r\npartial",
+        address,
+        client.session().unwrap()
+    );
+    stalled.write_all(request.as_bytes()).await.unwrap();
+    stalled.flush().await.unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    match client
+        .upload(&metadata(None, false), Some(fake(32).into()))
+        .await
+    {
+        Err(Error::Api { status, kind, .. }) => {
+            assert_eq!(status, 429);
+            assert_eq!(kind, Some(ErrorType::TooManyUploads));
+        }
+        response => panic!("Expected the upload to be rejected, got {:?}", response),
+    }
+    // Other users have their own limit
+    upload(&other, None, b"not affected").await;
+
+    // The slot is freed once the stalled upload fails
+    drop(stalled);
+    for _ in 0..50 {
+        if client
+            .upload(&metadata(None, false), Some(fake(32).into()))
+            .await
+            .is_ok()
+        {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("The upload slot was never freed");
+}
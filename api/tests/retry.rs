@@ -0,0 +1,77 @@
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use lokr_api::{
+    config::Config,
+    error::AppError,
+    init_db,
+    instance::Features,
+    retry::{is_busy, retry_transaction},
+    state::AppState,
+};
+use lokr_client::types::admin::RetryStats;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use url::Url;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retries_only_busy_writes() {
+    let data_dir = tempfile::tempdir().unwrap();
+    let config = Config {
+        data_dir: data_dir.path().into(),
+        db_retry_attempts: 3,
+        db_retry_delay: Duration::from_millis(1),
+        ..Config::default()
+    };
+    let url = Url::from_file_path(config.database_path()).unwrap();
+    let pool = init_db(&url, None).await.unwrap();
+    let state = AppState::new(pool, config, Features::default());
+    // A connection that gives up right away instead of waiting for the lock
+    let options = SqliteConnectOptions::from_str(url.as_str())
+        .unwrap()
+        .busy_timeout(Duration::ZERO);
+    let writer = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect_with(options)
+        .await
+        .unwrap();
+    let mut locker = state.pool.acquire().await.unwrap();
+    sqlx::query("BEGIN IMMEDIATE")
+        .execute(&mut *locker)
+        .await
+        .unwrap();
+
+    let attempts = AtomicU32::new(0);
+    let write = |name: &'static str| {
+        attempts.fetch_add(1, Ordering::Relaxed);
+        let query = sqlx::query("INSERT INTO feature_flag (name, enabled) VALUES (?, TRUE)")
+            .bind(name)
+            .execute(&writer);
+        async move {
+            query.await?;
+            Ok::<_, AppError>(())
+        }
+    };
+    let result = retry_transaction(&state, "test", || write("retry")).await;
+    assert!(is_busy(&result.unwrap_err()));
+    assert_eq!(attempts.swap(0, Ordering::Relaxed), 3);
+    assert_eq!(
+        state.retries.stats(),
+        RetryStats {
+            retries: 2,
+            exhausted: 1
+        }
+    );
+
+    sqlx::query("ROLLBACK").execute(&mut *locker).await.unwrap();
+    retry_transaction(&state, "test", || write("released"))
+        .await
+        .unwrap();
+    assert_eq!(attempts.swap(0, Ordering::Relaxed), 1);
+    // Other errors aren't retried
+    let result = retry_transaction(&state, "test", || write("released")).await;
+    assert!(!is_busy(&result.unwrap_err()));
+    assert_eq!(attempts.load(Ordering::Relaxed), 1);
+}
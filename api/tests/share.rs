@@ -0,0 +1,970 @@
+use std::time::Duration;
+
+use lokr_api::utils::clean_up;
+use lokr_client::types::{
+    error::ErrorType,
+    permissions::{AccessLevel, CapabilityQuery},
+    share::{
+        ClaimRequest, LinkAudience, RevokedShareQuery, ShareIdentifier, ShareRequest,
+        ShareRequestType, ShareResponse, ShareResponseType, ShareUpdateRequest, SharedFileQuery,
+    },
+    upload::{FileQuery, FileResponse},
+    users::{Preferences, UserSearch, UserUpdate, UserUpdateField},
+};
+use uuid::Uuid;
+
+mod common;
+
+use common::*;
+
+async fn user_id(client: &lokr_client::Client) -> Uuid {
+    client.profile().await.unwrap().id
+}
+
+fn share_with(user_id: Uuid, file_id: Uuid, edit: bool) -> ShareRequest {
+    ShareRequest {
+        type_: ShareRequestType::User {
+            user_id,
+            encrypted_key: fake(32),
+        },
+        id: file_id,
+        edit,
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn share_with_user() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_owner").await;
+    let viewer = server.user("share_viewer").await;
+    let viewer_id = user_id(&viewer).await;
+    let dir = mkdir(&owner, None).await;
+    let file = upload(&owner, Some(dir.id), b"shared data").await;
+
+    let request = share_with(viewer_id, dir.id, false);
+    owner.share(&request).await.unwrap();
+    let shared = owner.share_users(dir.id).await.unwrap();
+    assert!(shared.users.contains_key(&viewer_id));
+
+    // The shared directory shows up for the viewer along with its children
+    let response = viewer
+        .shared_files(&FileQuery::default(), &SharedFileQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(response.root, [dir.id]);
+    let response = viewer
+        .shared_files(
+            &FileQuery {
+                id: Some(file.id),
+                include_ancestors: true,
+                ..Default::default()
+            },
+            &SharedFileQuery::default(),
+        )
+        .await
+        .unwrap();
+    // The shared directory has to be decryptable with the viewer's own key
+    let ShareRequestType::User { encrypted_key, .. } = request.type_ else {
+        unreachable!()
+    };
+    assert_eq!(response.files[&dir.id].upload.encrypted_key, encrypted_key);
+    assert!(response.files[&dir.id].upload.parent_id.is_none());
+    assert_eq!(viewer.download(file.id).await.unwrap(), b"shared data");
+
+    // Viewers can't change anything
+    assert_eq!(status(viewer.delete_file(file.id).await), 404);
+    assert!(viewer
+        .upload(&metadata(Some(dir.id), false), Some(b"nope".to_vec()))
+        .await
+        .is_err());
+
+    owner
+        .delete_share(&ShareIdentifier::User {
+            user_id: viewer_id,
+            file_id: dir.id,
+        })
+        .await
+        .unwrap();
+    assert!(viewer
+        .shared_files(&FileQuery::default(), &SharedFileQuery::default())
+        .await
+        .unwrap()
+        .root
+        .is_empty());
+    assert_eq!(status(viewer.download(file.id).await), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn share_with_editor() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_edit_owner").await;
+    let editor = server.user("share_editor").await;
+    let editor_id = user_id(&editor).await;
+    let dir = mkdir(&owner, None).await;
+
+    owner
+        .share(&share_with(editor_id, dir.id, false))
+        .await
+        .unwrap();
+    owner
+        .update_share(&ShareUpdateRequest {
+            type_: ShareIdentifier::User {
+                user_id: editor_id,
+                file_id: dir.id,
+            },
+            edit: true,
+        })
+        .await
+        .unwrap();
+
+    // Files uploaded by an editor still belong to the owner of the directory
+    let file = upload(&editor, Some(dir.id), b"from the editor").await;
+    assert_eq!(owner.download(file.id).await.unwrap(), b"from the editor");
+    let response = owner
+        .files(&FileQuery {
+            id: Some(file.id),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    assert_eq!(response.files[&file.id].uploader_id, Some(editor_id));
+    editor.delete_file(file.id).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn share_links() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_link_owner").await;
+    let file = upload(&owner, None, b"linked").await;
+    let response = owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: Some("link password".into()),
+                audience: Default::default(),
+            },
+            id: file.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    let ShareResponseType::Link {
+        link_id,
+        password_protected,
+        expires_at,
+        ..
+    } = response.type_
+    else {
+        panic!("Expected a link");
+    };
+    assert!(password_protected);
+    assert!(expires_at.is_some());
+
+    let links = owner.share_links(file.id).await.unwrap();
+    assert_eq!(links.len(), 1);
+
+    owner
+        .delete_share(&ShareIdentifier::Link {
+            link_id,
+            password: None,
+        })
+        .await
+        .unwrap();
+    assert!(owner.share_links(file.id).await.unwrap().is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_owner_can_share() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_only_owner").await;
+    let other = server.user("share_not_owner").await;
+    let other_id = user_id(&other).await;
+    let owner_id = user_id(&owner).await;
+    let file = upload(&owner, None, b"mine").await;
+    assert_eq!(
+        status(other.share(&share_with(owner_id, file.id, true)).await),
+        404
+    );
+    // Users can be found to share with by their username
+    let users = owner
+        .search_users("share_not_owner", &UserSearch::default())
+        .await
+        .unwrap();
+    assert_eq!(users[0].id, other_id);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shared_directory_data() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_data_owner").await;
+    let viewer = server.user("share_data_viewer").await;
+    let viewer_id = user_id(&viewer).await;
+    let dir = mkdir(&owner, None).await;
+    let sub = mkdir(&owner, Some(dir.id)).await;
+    let nested = upload(&owner, Some(sub.id), b"deeply nested").await;
+    let outside = upload(&owner, None, b"not shared").await;
+
+    // Sharing the top directory is enough to download anything below it
+    owner
+        .share(&share_with(viewer_id, dir.id, false))
+        .await
+        .unwrap();
+    assert_eq!(viewer.download(nested.id).await.unwrap(), b"deeply nested");
+    // Having a share doesn't give access to the owner's other files
+    assert_eq!(status(viewer.download(outside.id).await), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn explain_denials() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_deny_owner").await;
+    let revoked = server.user("share_deny_revoked").await;
+    let stranger = server.user("share_deny_stranger").await;
+    let revoked_id = user_id(&revoked).await;
+    let file = upload(&owner, None, b"no longer shared").await;
+    owner
+        .share(&share_with(revoked_id, file.id, false))
+        .await
+        .unwrap();
+    owner
+        .delete_share(&ShareIdentifier::User {
+            user_id: revoked_id,
+            file_id: file.id,
+        })
+        .await
+        .unwrap();
+
+    // Nobody is told anything until the owner opts in
+    assert_eq!(status(revoked.download(file.id).await), 404);
+    let profile = owner.profile().await.unwrap();
+    assert!(!profile.explain_denials);
+    owner
+        .update_preferences(&Preferences {
+            theme: profile.theme,
+            grid_view: profile.grid_view,
+            sort_order: profile.sort_order,
+            explain_denials: Some(true),
+        })
+        .await
+        .unwrap();
+    assert!(owner.profile().await.unwrap().explain_denials);
+
+    match revoked.download(file.id).await {
+        Err(lokr_client::Error::Api { status, kind, .. }) => {
+            assert_eq!(status, 403);
+            assert_eq!(kind, Some(ErrorType::AccessDenied));
+        }
+        other => panic!("Expected an error, got {:?}", other.map(|_| ())),
+    }
+    assert_eq!(status(revoked.delete_file(file.id).await), 403);
+    // Users the owner never shared anything with still can't tell the file exists
+    assert_eq!(status(stranger.download(file.id).await), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn explain_denials_instance_wide() {
+    let server = TestServer::start_with(|config| config.explain_denials = true).await;
+    let owner = server.user("share_all_owner").await;
+    let viewer = server.user("share_all_viewer").await;
+    let viewer_id = user_id(&viewer).await;
+    let dir = mkdir(&owner, None).await;
+    let file = upload(&owner, None, b"not in the directory").await;
+    owner
+        .share(&share_with(viewer_id, dir.id, false))
+        .await
+        .unwrap();
+    assert_eq!(status(viewer.download(file.id).await), 403);
+    // Viewers are told they can't change what is shared with them
+    let child = upload(&owner, Some(dir.id), b"read only").await;
+    assert_eq!(status(viewer.delete_file(child.id).await), 403);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn link_password_cookie() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_cookie_owner").await;
+    let file = upload(&owner, None, b"locked").await;
+    let response = owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: Some("link password".into()),
+                audience: Default::default(),
+            },
+            id: file.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    let ShareResponseType::Link { link_id, .. } = response.type_ else {
+        panic!("Expected a link");
+    };
+    let http = reqwest::Client::new();
+    let url = server.url(&format!("/api/shared/{}", link_id));
+
+    // The password hash is remembered until the link expires
+    let response = http
+        .post(&url)
+        .query(&FileQuery::default())
+        .json("link password")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let set_cookie = response.headers()["set-cookie"].to_str().unwrap();
+    assert!(set_cookie.starts_with(&format!("{}=", link_id)));
+    assert!(set_cookie.contains("Path=/api;"));
+    let max_age: u64 = set_cookie
+        .split("; ")
+        .find_map(|part| part.strip_prefix("Max-Age="))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!((3500..=3600).contains(&max_age));
+    let cookie = set_cookie.split(';').next().unwrap().to_owned();
+
+    // The credentials can be cleared like logging out
+    let response = http
+        .delete(server.url("/api/shared/credentials"))
+        .header("cookie", format!("other=value; {}", cookie))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let removals: Vec<_> = response.headers().get_all("set-cookie").iter().collect();
+    assert_eq!(removals.len(), 1);
+    assert!(removals[0]
+        .to_str()
+        .unwrap()
+        .starts_with(&format!("{}=;", link_id)));
+
+    // Credentials for a deleted link are cleared when they are used
+    owner
+        .delete_share(&ShareIdentifier::Link {
+            link_id,
+            password: None,
+        })
+        .await
+        .unwrap();
+    let response = http
+        .post(&url)
+        .query(&FileQuery::default())
+        .header("cookie", &cookie)
+        .json(&None::<String>)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+    assert!(response.headers()["set-cookie"]
+        .to_str()
+        .unwrap()
+        .contains("Max-Age=0"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn browse_link_with_cookie() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_browse_owner").await;
+    let dir = mkdir(&owner, None).await;
+    let sub = mkdir(&owner, Some(dir.id)).await;
+    let file = upload(&owner, Some(sub.id), b"browsed").await;
+    let response = owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: Some("link password".into()),
+                audience: Default::default(),
+            },
+            id: dir.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    let ShareResponseType::Link { link_id, .. } = response.type_ else {
+        panic!("Expected a link");
+    };
+    let http = reqwest::Client::new();
+    let browse = server.url(&format!("/api/shared/{}/browse", link_id));
+
+    // The password has to be checked before the link can be browsed
+    let response = http
+        .get(&browse)
+        .query(&FileQuery::default())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    let response = http
+        .post(server.url(&format!("/api/shared/{}", link_id)))
+        .query(&FileQuery::default())
+        .json("link password")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let cookie = response.headers()["set-cookie"]
+        .to_str()
+        .unwrap()
+        .split(';')
+        .next()
+        .unwrap()
+        .to_owned();
+
+    let response = http
+        .get(&browse)
+        .query(&FileQuery::default())
+        .header("cookie", &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["cache-control"], "private");
+    assert_eq!(response.headers()["vary"], "cookie");
+    let root: FileResponse = response.json().await.unwrap();
+    assert_eq!(root.root, [dir.id]);
+
+    let response = http
+        .get(&browse)
+        .query(&FileQuery {
+            id: Some(sub.id),
+            include_ancestors: true,
+            ..Default::default()
+        })
+        .header("cookie", &cookie)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let listing: FileResponse = response.json().await.unwrap();
+    assert_eq!(listing.files[&sub.id].children, [file.id]);
+    assert!(listing.files.contains_key(&dir.id));
+
+    // A cookie that doesn't match the password hash is turned down
+    let response = http
+        .get(&browse)
+        .query(&FileQuery::default())
+        .header("cookie", format!("{}=stale", link_id))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn browse_link_without_password() {
+    let server = TestServer::start().await;
+    let owner = server.user("share_browse_open").await;
+    let dir = mkdir(&owner, None).await;
+    let response = owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: None,
+                audience: Default::default(),
+            },
+            id: dir.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    let ShareResponseType::Link { link_id, .. } = response.type_ else {
+        panic!("Expected a link");
+    };
+
+    // Nothing has to be unlocked first, so no cookies are needed
+    let response = reqwest::Client::new()
+        .get(server.url(&format!("/api/shared/{}/browse", link_id)))
+        .query(&FileQuery::default())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let root: FileResponse = response.json().await.unwrap();
+    assert_eq!(root.root, [dir.id]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn capabilities() {
+    let server = TestServer::start().await;
+    let owner = server.user("capability_owner").await;
+    let editor = server.user("capability_editor").await;
+    let stranger = server.user("capability_stranger").await;
+    let dir = mkdir(&owner, None).await;
+    let file = upload(&owner, Some(dir.id), b"capable").await;
+    owner
+        .share(&share_with(user_id(&editor).await, dir.id, true))
+        .await
+        .unwrap();
+    let query = |file_id, link_id| CapabilityQuery { file_id, link_id };
+
+    let mine = owner.capabilities(&query(dir.id, None)).await.unwrap();
+    assert_eq!(mine.access, AccessLevel::Owner);
+    assert!(mine.upload && mine.edit && mine.delete && mine.share);
+    assert!(!mine.claim);
+    // Only directories can be uploaded into
+    assert!(
+        !owner
+            .capabilities(&query(file.id, None))
+            .await
+            .unwrap()
+            .upload
+    );
+
+    // Editors can change what is inside of the shared directory, but not the directory itself
+    let shared = editor.capabilities(&query(dir.id, None)).await.unwrap();
+    assert_eq!(shared.access, AccessLevel::Edit);
+    assert!(shared.view && shared.upload);
+    assert!(!shared.edit && !shared.delete && !shared.share);
+    let child = editor.capabilities(&query(file.id, None)).await.unwrap();
+    assert!(child.edit && child.delete && !child.share);
+
+    assert_eq!(
+        status(stranger.capabilities(&query(dir.id, None)).await),
+        404
+    );
+
+    let response = owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: None,
+                audience: Default::default(),
+            },
+            id: dir.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    let ShareResponseType::Link { link_id, .. } = response.type_ else {
+        panic!("Expected a link");
+    };
+    let anonymous = server.client();
+    let linked = anonymous
+        .capabilities(&query(file.id, Some(link_id)))
+        .await
+        .unwrap();
+    assert_eq!(linked.access, AccessLevel::View);
+    assert!(linked.view);
+    assert!(!linked.upload && !linked.edit && !linked.delete && !linked.share);
+    assert_eq!(
+        status(anonymous.capabilities(&query(file.id, None)).await),
+        404
+    );
+
+    // Add only links can upload up to their size limit, but nothing else
+    sqlx::query(
+        "UPDATE share_link SET edit_permission = TRUE, add_only = TRUE, max_size = 100 WHERE id = ?",
+    )
+    .bind(link_id)
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    let add_only = anonymous
+        .capabilities(&query(dir.id, Some(link_id)))
+        .await
+        .unwrap();
+    assert!(add_only.upload && !add_only.edit && !add_only.delete);
+    let size: i64 = sqlx::query_scalar("SELECT size FROM file WHERE id = ?")
+        .bind(file.id)
+        .fetch_one(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(add_only.upload_limit, Some(100 - size));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restore_revoked_shares() {
+    let server = TestServer::start().await;
+    let owner = server.user("restore_owner").await;
+    let viewer = server.user("restore_viewer").await;
+    let viewer_id = user_id(&viewer).await;
+    let file = upload(&owner, None, b"restorable").await;
+    let request = share_with(viewer_id, file.id, true);
+    owner.share(&request).await.unwrap();
+    let response = owner
+        .share(&ShareRequest {
+            type_: ShareRequestType::Link {
+                expires: 3600,
+                password: Some("link password".into()),
+                audience: Default::default(),
+            },
+            id: file.id,
+            edit: false,
+        })
+        .await
+        .unwrap();
+    let ShareResponseType::Link { link_id, .. } = response.type_ else {
+        panic!("Expected a link");
+    };
+    let user_share = ShareIdentifier::User {
+        user_id: viewer_id,
+        file_id: file.id,
+    };
+    let link_share = ShareIdentifier::Link {
+        link_id,
+        password: None,
+    };
+    let none = RevokedShareQuery::default();
+    assert!(owner.revoked_shares(&none).await.unwrap().is_empty());
+    assert_eq!(status(owner.restore_share(&user_share).await), 404);
+
+    owner.delete_share(&user_share).await.unwrap();
+    owner.delete_share(&link_share).await.unwrap();
+    assert_eq!(status(viewer.download(file.id).await), 404);
+    let revoked = owner
+        .revoked_shares(&RevokedShareQuery {
+            file_id: Some(file.id),
+        })
+        .await
+        .unwrap();
+    assert_eq!(revoked.len(), 2);
+    assert!(revoked
+        .iter()
+        .all(|share| share.restorable_until > share.revoked_at));
+    // Nobody else can see or restore them
+    assert!(viewer.revoked_shares(&none).await.unwrap().is_empty());
+    assert_eq!(status(viewer.restore_share(&user_share).await), 404);
+
+    // The user gets the same wrapped key back, so they can decrypt the file again
+    owner.restore_share(&user_share).await.unwrap();
+    assert_eq!(viewer.download(file.id).await.unwrap(), b"restorable");
+    let shares = owner.share_users(file.id).await.unwrap();
+    assert!(shares.access[0].edit_permission);
+    let ShareRequestType::User { encrypted_key, .. } = request.type_ else {
+        unreachable!()
+    };
+    let response = viewer
+        .shared_files(&FileQuery::default(), &SharedFileQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(response.files[&file.id].upload.encrypted_key, encrypted_key);
+    assert_eq!(status(owner.restore_share(&user_share).await), 404);
+
+    // The link keeps its id and password
+    owner.restore_share(&link_share).await.unwrap();
+    let links = owner.share_links(file.id).await.unwrap();
+    let ShareResponseType::Link {
+        link_id: restored,
+        password_protected,
+        ..
+    } = links[0].type_
+    else {
+        panic!("Expected a link");
+    };
+    assert_eq!(restored, link_id);
+    assert!(password_protected);
+    assert!(owner.revoked_shares(&none).await.unwrap().is_empty());
+
+    // Shares that were revoked too long ago are gone for good
+    owner.delete_share(&user_share).await.unwrap();
+    sqlx::query(
+        "UPDATE revoked_share_user SET restorable_until = DATETIME(CURRENT_TIMESTAMP, '-1 minute')",
+    )
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    assert!(owner.revoked_shares(&none).await.unwrap().is_empty());
+    assert_eq!(status(owner.restore_share(&user_share).await), 404);
+    assert_eq!(clean_up(&server.pool).await.revoked_shares, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restoring_can_be_turned_off() {
+    let server =
+        TestServer::start_with(|config| config.share_restore_window = Duration::ZERO).await;
+    let owner = server.user("no_restore_owner").await;
+    let viewer = server.user("no_restore_viewer").await;
+    let viewer_id = user_id(&viewer).await;
+    let file = upload(&owner, None, b"gone for good").await;
+    owner
+        .share(&share_with(viewer_id, file.id, false))
+        .await
+        .unwrap();
+    let share = ShareIdentifier::User {
+        user_id: viewer_id,
+        file_id: file.id,
+    };
+    owner.delete_share(&share).await.unwrap();
+    assert!(owner
+        .revoked_shares(&RevokedShareQuery::default())
+        .await
+        .unwrap()
+        .is_empty());
+    assert_eq!(status(owner.restore_share(&share).await), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn link_audiences() {
+    let server = TestServer::start().await;
+    let owner = server.user("audience_owner").await;
+    let colleague = server.user("audience_colleague").await;
+    let outsider = server.user("audience_outsider").await;
+    let file = upload(&owner, None, b"internal").await;
+    let share = |audience| ShareRequest {
+        type_: ShareRequestType::Link {
+            expires: 3600,
+            password: None,
+            audience,
+        },
+        id: file.id,
+        edit: false,
+    };
+    let link = |response: ShareResponse| match response.type_ {
+        ShareResponseType::Link {
+            link_id, audience, ..
+        } => (link_id, audience),
+        _ => panic!("Expected a link"),
+    };
+    let query = |link_id| CapabilityQuery {
+        file_id: file.id,
+        link_id: Some(link_id),
+    };
+
+    // Emails aren't verified, so anyone could claim to be in a domain
+    assert_eq!(
+        status(
+            owner
+                .share(&share(LinkAudience::EmailDomains {
+                    domains: vec!["example.com".into()],
+                }))
+                .await
+        ),
+        400
+    );
+    let (link_id, _) = link(owner.share(&share(LinkAudience::Accounts)).await.unwrap());
+    sqlx::query(
+        r#"UPDATE share_link SET audience = '{"type":"emailDomains","domains":["example.com"]}' WHERE id = ?"#,
+    )
+    .bind(link_id)
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    colleague
+        .update_profile(&UserUpdate {
+            field: UserUpdateField::Email,
+            new_value: "colleague@example.com".into(),
+            password: PASSWORD.into(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(status(colleague.capabilities(&query(link_id)).await), 403);
+    assert!(owner.capabilities(&query(link_id)).await.is_ok());
+
+    // Anonymous users are told to log in, both when listing and downloading
+    let http = reqwest::Client::new();
+    let response = http
+        .post(server.url(&format!("/api/shared/{}", link_id)))
+        .query(&FileQuery::default())
+        .json(&None::<String>)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+    let response = http
+        .get(server.url(&format!("/api/file/data/{}", file.id)))
+        .query(&[("linkId", link_id)])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 401);
+
+    let (link_id, _) = link(
+        owner
+            .share(&share(LinkAudience::Users {
+                user_ids: vec![user_id(&outsider).await],
+            }))
+            .await
+            .unwrap(),
+    );
+    assert!(outsider.capabilities(&query(link_id)).await.is_ok());
+    assert_eq!(status(colleague.capabilities(&query(link_id)).await), 403);
+    let links = owner.share_links(file.id).await.unwrap();
+    assert!(links.iter().any(|link| matches!(
+        &link.type_,
+        ShareResponseType::Link { audience: LinkAudience::Users { user_ids }, .. } if user_ids.len() == 1
+    )));
+
+    let (link_id, _) = link(owner.share(&share(LinkAudience::Accounts)).await.unwrap());
+    assert!(outsider.capabilities(&query(link_id)).await.is_ok());
+    assert_eq!(
+        status(server.client().capabilities(&query(link_id)).await),
+        401
+    );
+
+    // An audience has to match someone
+    assert_eq!(
+        status(
+            owner
+                .share(&share(LinkAudience::Users { user_ids: vec![] }))
+                .await
+        ),
+        400
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mount_shared_directories() {
+    let server = TestServer::start().await;
+    let owner = server.user("mount_owner").await;
+    let viewer = server.user("mount_viewer").await;
+    let viewer_id = user_id(&viewer).await;
+    let dir = mkdir(&owner, None).await;
+    let child = mkdir(&owner, Some(dir.id)).await;
+    let file = upload(&owner, Some(child.id), b"mounted").await;
+    let own = mkdir(&viewer, None).await;
+
+    // Only directories directly shared with the user can be mounted
+    assert_eq!(status(viewer.mount(dir.id).await), 404);
+    assert_eq!(status(viewer.mount(own.id).await), 404);
+    let request = share_with(viewer_id, dir.id, false);
+    owner.share(&request).await.unwrap();
+    assert_eq!(status(viewer.mount(child.id).await), 404);
+    let shared_file = upload(&owner, None, b"not a directory").await;
+    owner
+        .share(&share_with(viewer_id, shared_file.id, false))
+        .await
+        .unwrap();
+    assert_eq!(status(viewer.mount(shared_file.id).await), 400);
+
+    // Browsing a shared directory through the user's own files needs a mount
+    let in_child = FileQuery {
+        id: Some(child.id),
+        include_ancestors: true,
+        ..Default::default()
+    };
+    assert_eq!(status(viewer.files(&in_child).await), 404);
+    let mount = viewer.mount(dir.id).await.unwrap();
+    assert_eq!(mount.file_id, dir.id);
+    assert_eq!(status(viewer.mount(dir.id).await), 409);
+
+    // The mount is listed next to the user's own files with the shared key
+    let response = viewer.files(&FileQuery::default()).await.unwrap();
+    assert_eq!(response.root, [own.id, dir.id]);
+    let ShareRequestType::User { encrypted_key, .. } = &request.type_ else {
+        unreachable!()
+    };
+    let mounted = &response.files[&dir.id];
+    assert_eq!(&mounted.upload.encrypted_key, encrypted_key);
+    assert_eq!(mounted.mount_id, Some(mount.id));
+    assert_eq!(mounted.children, [child.id]);
+    assert!(response.files[&own.id].mount_id.is_none());
+
+    // Everything below the mount can be reached through the same endpoint
+    let response = viewer.files(&in_child).await.unwrap();
+    assert_eq!(response.root, [dir.id]);
+    assert_eq!(response.files[&dir.id].mount_id, Some(mount.id));
+    assert_eq!(response.files[&child.id].children, [file.id]);
+    assert!(response.files[&child.id].mount_id.is_none());
+    // Files shared without a mount still have to go through the shared files
+    let not_mounted = FileQuery {
+        id: Some(shared_file.id),
+        ..Default::default()
+    };
+    assert_eq!(status(viewer.files(&not_mounted).await), 404);
+    // Other users can't remove the mount
+    assert_eq!(status(owner.unmount(mount.id).await), 404);
+
+    // Mounts of revoked shares are hidden until the share is restored
+    let share = ShareIdentifier::User {
+        user_id: viewer_id,
+        file_id: dir.id,
+    };
+    owner.delete_share(&share).await.unwrap();
+    assert!(!viewer.mounts().await.unwrap()[0].active);
+    assert_eq!(
+        viewer.files(&FileQuery::default()).await.unwrap().root,
+        [own.id]
+    );
+    assert_eq!(status(viewer.files(&in_child).await), 404);
+    owner.restore_share(&share).await.unwrap();
+    assert!(viewer.mounts().await.unwrap()[0].active);
+    assert_eq!(
+        viewer.files(&FileQuery::default()).await.unwrap().root,
+        [own.id, dir.id]
+    );
+
+    // Once the share can't be restored the mount is cleaned up
+    owner.delete_share(&share).await.unwrap();
+    sqlx::query(
+        "UPDATE revoked_share_user SET restorable_until = DATETIME(CURRENT_TIMESTAMP, '-1 minute')",
+    )
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    clean_up(&server.pool).await;
+    assert!(viewer.mounts().await.unwrap().is_empty());
+
+    // Unmounting leaves the share alone
+    owner.share(&request).await.unwrap();
+    let mount = viewer.mount(dir.id).await.unwrap();
+    viewer.unmount(mount.id).await.unwrap();
+    assert_eq!(status(viewer.unmount(mount.id).await), 404);
+    assert_eq!(viewer.download(file.id).await.unwrap(), b"mounted");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn claim_anonymous_upload() {
+    let server = TestServer::start().await;
+    let file = upload(&server.client(), None, b"left behind").await;
+    let Some(ShareResponse {
+        type_: ShareResponseType::Link { link_id, .. },
+        ..
+    }) = file.link
+    else {
+        panic!("Expected a link");
+    };
+    let client = server.user("share_claim").await;
+    let claim = |encrypted_key| ClaimRequest {
+        encrypted_key,
+        password: None,
+    };
+
+    // The key has to be wrapped with the user's public key
+    assert_eq!(
+        status(client.claim(link_id, &claim("not a key".into())).await),
+        400
+    );
+    assert_eq!(status(client.claim(link_id, &claim(fake(48))).await), 400);
+
+    // Claiming counts against the quota, and nothing changes if it doesn't fit
+    let used_space = client.profile().await.unwrap().used_space;
+    sqlx::query("UPDATE user SET total_space = ? WHERE username = 'share_claim'")
+        .bind(used_space + 10)
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(status(client.claim(link_id, &claim(fake(256))).await), 402);
+    assert_eq!(client.profile().await.unwrap().used_space, used_space);
+
+    sqlx::query("UPDATE user SET total_space = 1000000 WHERE username = 'share_claim'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    client.claim(link_id, &claim(fake(256))).await.unwrap();
+    assert!(client.profile().await.unwrap().used_space > used_space);
+    let root = client.files(&FileQuery::default()).await.unwrap().root;
+    assert_eq!(root, [file.id]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn anonymous_folder_size() {
+    let server = TestServer::start_with(|config| config.anonymous_folder_max_size = 1000).await;
+    let anonymous = server.client();
+    let dir = mkdir(&anonymous, None).await;
+    let Some(ShareResponse {
+        type_: ShareResponseType::Link { link_id, .. },
+        ..
+    }) = dir.link
+    else {
+        panic!("Expected a link");
+    };
+    let capabilities = anonymous
+        .capabilities(&CapabilityQuery {
+            file_id: dir.id,
+            link_id: Some(link_id),
+        })
+        .await
+        .unwrap();
+    assert!(capabilities.upload);
+    assert_eq!(capabilities.upload_limit, Some(1000 - dir.size));
+}
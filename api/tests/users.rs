@@ -0,0 +1,432 @@
+use lokr_api::{at_rest, users::reconcile_used_space};
+use lokr_client::types::{
+    error::ErrorType,
+    session::StepUpRequest,
+    share::{ShareRequest, ShareRequestType},
+    users::{LoginUser, UserUpdate, UserUpdateField},
+};
+use uuid::Uuid;
+
+mod common;
+
+use common::*;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn register_and_login() {
+    let server = TestServer::start().await;
+    let client = server.user("users_login").await;
+    let profile = client.profile().await.unwrap();
+    assert_eq!(profile.username, "users_login");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn register_duplicate_username() {
+    let server = TestServer::start().await;
+    let client = server.client();
+    client.register(&new_user("users_duplicate")).await.unwrap();
+    assert_eq!(
+        status(client.register(&new_user("users_duplicate")).await),
+        409
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn login_wrong_password() {
+    let server = TestServer::start().await;
+    let client = server.client();
+    client.register(&new_user("users_wrong")).await.unwrap();
+    let result = client
+        .login(&LoginUser {
+            username: "users_wrong".into(),
+            password: "not the password".into(),
+            totp_code: None,
+        })
+        .await;
+    assert_eq!(status(result), 401);
+    assert!(client.session().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn logout_ends_session() {
+    let server = TestServer::start().await;
+    let client = server.user("users_logout").await;
+    let session = client.session();
+    client.logout().await.unwrap();
+    // Reusing the old session shouldn't work after logging out
+    client.set_session(session);
+    assert_eq!(status(client.profile().await), 401);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn step_up_for_sensitive_operations() {
+    let server = TestServer::start().await;
+    let client = server.user("users_step_up").await;
+    // Logging in counts as entering the password
+    client.rewrap_keys(vec![]).await.unwrap();
+    let other = server.client();
+    other
+        .login(&LoginUser {
+            username: "users_step_up".into(),
+            password: PASSWORD.into(),
+            totp_code: None,
+        })
+        .await
+        .unwrap();
+
+    // After a while the password has to be entered again
+    sqlx::query("UPDATE session SET verified_at = DATETIME('now', '-1 hour')")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    // Sessions can still be managed, the web client doesn't ask for the password again
+    client.delete_session(2).await.unwrap();
+    assert_eq!(status(other.profile().await), 401);
+    match client.rewrap_keys(vec![]).await {
+        Err(lokr_client::Error::Api { status, kind, .. }) => {
+            assert_eq!(status, 403);
+            assert_eq!(kind, Some(ErrorType::StepUpRequired));
+        }
+        result => panic!("Expected a step up to be required, got {:?}", result),
+    }
+    let wrong = StepUpRequest {
+        password: Some("not the password".into()),
+        totp_code: None,
+    };
+    assert_eq!(status(client.verify_session(&wrong).await), 401);
+    assert_eq!(
+        status(client.verify_session(&StepUpRequest::default()).await),
+        400
+    );
+    client
+        .verify_session(&StepUpRequest {
+            password: Some(PASSWORD.into()),
+            totp_code: None,
+        })
+        .await
+        .unwrap();
+    client.rewrap_keys(vec![]).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reconcile_drifted_used_space() {
+    let server = TestServer::start().await;
+    let client = server.user("users_space").await;
+    let other = server.user("users_space_other").await;
+    let other_id = other.profile().await.unwrap().id;
+    let dir = mkdir(&client, None).await;
+    let file = upload(&client, Some(dir.id), b"counted").await;
+    for type_ in [
+        ShareRequestType::User {
+            user_id: other_id,
+            encrypted_key: fake(32),
+        },
+        ShareRequestType::Link {
+            expires: 3600,
+            password: Some("link password".into()),
+            audience: Default::default(),
+        },
+    ] {
+        client
+            .share(&ShareRequest {
+                type_,
+                id: file.id,
+                edit: false,
+            })
+            .await
+            .unwrap();
+    }
+    let used_space = client.profile().await.unwrap().used_space;
+
+    // The triggers and the reconciliation have to agree on how big everything is
+    assert_eq!(reconcile_used_space(&server.pool).await.unwrap(), 0);
+
+    sqlx::query("UPDATE user SET used_space = 12345 WHERE username = 'users_space'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    assert_eq!(reconcile_used_space(&server.pool).await.unwrap(), 1);
+    assert_eq!(client.profile().await.unwrap().used_space, used_space);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn key_manifest() {
+    let server = TestServer::start().await;
+    let owner = server.user("users_manifest").await;
+    let viewer = server.user("users_manifest_view").await;
+    let viewer_id = viewer.profile().await.unwrap().id;
+    let dir = mkdir(&owner, None).await;
+    let file = upload(&owner, Some(dir.id), b"backed up").await;
+
+    let manifest = owner.key_manifest().await.unwrap();
+    assert_eq!(manifest.files.len(), 2);
+    assert!(manifest.files.iter().all(|file| file.owned));
+    let entry = manifest
+        .files
+        .iter()
+        .find(|entry| entry.id == file.id)
+        .unwrap();
+    assert_eq!(entry.parent_id, Some(dir.id));
+    assert!(entry.key_nonce.is_some() && entry.file_nonce.is_some());
+
+    // Share both the directory and the file inside of it
+    let mut keys = Vec::new();
+    for id in [dir.id, file.id] {
+        let encrypted_key = fake(32);
+        keys.push((id, encrypted_key.clone()));
+        owner
+            .share(&ShareRequest {
+                type_: ShareRequestType::User {
+                    user_id: viewer_id,
+                    encrypted_key,
+                },
+                id,
+                edit: false,
+            })
+            .await
+            .unwrap();
+    }
+    let manifest = viewer.key_manifest().await.unwrap();
+    assert_eq!(manifest.files.len(), 2);
+    for (id, key) in &keys {
+        let entry = manifest.files.iter().find(|entry| entry.id == *id).unwrap();
+        assert!(!entry.owned);
+        // Both were shared directly, so both keys are the ones encrypted for the viewer
+        assert_eq!(&entry.encrypted_key, key);
+        assert!(entry.parent_id.is_none() && entry.key_nonce.is_none());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn avatar_encrypted_at_rest() {
+    let key = "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFE=";
+    let server =
+        TestServer::start_with(|config| config.at_rest_key = Some(key.parse().unwrap())).await;
+    let client = server.user("users_avatar").await;
+    let id = client.profile().await.unwrap().id;
+    let mut png = Vec::new();
+    image::RgbImage::new(8, 8)
+        .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+
+    let avatar = client.upload_avatar(png).await.unwrap();
+    assert_eq!(avatar.extension, "png");
+    let file = format!("{}.png", id);
+    let data = client.avatar(&file).await.unwrap();
+    assert_eq!(image::guess_format(&data).unwrap(), image::ImageFormat::Png);
+
+    // Only the server can read what it wrote to disk
+    let on_disk = std::fs::read(server.data_dir().join("avatars").join(&file)).unwrap();
+    assert!(image::guess_format(&on_disk).is_err());
+    assert!(at_rest::open(None, on_disk.clone()).is_err());
+    assert_eq!(
+        at_rest::open(Some(&key.parse().unwrap()), on_disk).unwrap(),
+        data
+    );
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn identicon_avatar_fallback() {
+    let server = TestServer::start().await;
+    let client = server.user("users_identicon").await;
+    let id = client.profile().await.unwrap().id;
+
+    // Users without an avatar get the same generated image every time
+    let identicon = client.avatar(&id.to_string()).await.unwrap();
+    assert_eq!(
+        image::guess_format(&identicon).unwrap(),
+        image::ImageFormat::Png
+    );
+    let decoded = image::load_from_memory(&identicon).unwrap();
+    assert_eq!((decoded.width(), decoded.height()), (256, 256));
+    assert_eq!(
+        client.avatar(&format!("{}.png", id)).await.unwrap(),
+        identicon
+    );
+    let cached = server
+        .data_dir()
+        .join("avatars")
+        .join(format!("{}.identicon.png", id));
+    assert!(cached.exists());
+    std::fs::remove_file(&cached).unwrap();
+    assert_eq!(client.avatar(&id.to_string()).await.unwrap(), identicon);
+    assert_eq!(status(client.avatar(&format!("{}.jpg", id)).await), 404);
+    assert_eq!(
+        status(client.avatar(&Uuid::new_v4().to_string()).await),
+        404
+    );
+
+    // Other users get a different one
+    let other = server.user("users_identicon_2").await;
+    let other_id = other.profile().await.unwrap().id;
+    assert_ne!(
+        client.avatar(&other_id.to_string()).await.unwrap(),
+        identicon
+    );
+
+    // Once an avatar is uploaded it's served instead
+    let mut jpeg = Vec::new();
+    image::RgbImage::new(8, 8)
+        .write_to(
+            &mut std::io::Cursor::new(&mut jpeg),
+            image::ImageFormat::Jpeg,
+        )
+        .unwrap();
+    client.upload_avatar(jpeg).await.unwrap();
+    let avatar = client.avatar(&id.to_string()).await.unwrap();
+    assert_eq!(
+        image::guess_format(&avatar).unwrap(),
+        image::ImageFormat::Jpeg
+    );
+    assert_eq!(client.avatar(&format!("{}.jpg", id)).await.unwrap(), avatar);
+    assert_eq!(status(client.avatar(&format!("{}.png", id)).await), 404);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn key_epoch_bumped_on_password_change() {
+    let server = TestServer::start().await;
+    let client = server.user("users_key_epoch").await;
+    assert_eq!(client.profile().await.unwrap().key_epoch, 0);
+    client
+        .update_profile(&UserUpdate {
+            field: UserUpdateField::Email,
+            new_value: "epoch@example.com".into(),
+            password: PASSWORD.into(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(client.profile().await.unwrap().key_epoch, 0);
+
+    // The private key is re-encrypted with the new password
+    client
+        .update_profile(&UserUpdate {
+            field: UserUpdateField::Password {
+                encrypted_private_key: fake(64),
+                salt: fake(16),
+                iv: fake(12),
+            },
+            new_value: "a new password".into(),
+            password: PASSWORD.into(),
+        })
+        .await
+        .unwrap();
+    let profile = client.profile().await.unwrap();
+    assert_eq!(profile.key_epoch, 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn server_time_for_totp() {
+    let server = TestServer::start().await;
+    let time = server.client().server_time().await.unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    assert!(time.unix_time.abs_diff(now) <= 5);
+    assert_eq!((time.totp_step, time.totp_skew), (30, 1));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn cross_site_requests_are_rejected() {
+    let server = TestServer::start().await;
+    let client = server.user("users_origin").await;
+    let cookie = format!("session={}", client.session().unwrap());
+    let http = reqwest::Client::new();
+    let url = server.url("/api/profile/preferences");
+    let host = reqwest::Url::parse(&url).unwrap();
+    let same_site = format!(
+        "http://{}:{}",
+        host.host_str().unwrap(),
+        host.port().unwrap()
+    );
+    let send = |origin: Option<&str>| {
+        let mut request = http
+            .put(&url)
+            .header("cookie", &cookie)
+            .header("content-type", "application/json")
+            .body("{}");
+        if let Some(origin) = origin {
+            request = request.header("origin", origin);
+        }
+        async { request.send().await.unwrap().status().as_u16() }
+    };
+
+    // Getting past the check means the empty preferences are turned down instead
+    assert_eq!(send(None).await, 422);
+    assert_eq!(send(Some(&same_site)).await, 422);
+    assert_eq!(send(Some("https://evil.example")).await, 403);
+    // Sandboxed frames send a `null` origin, which can't be traced back to a site
+    assert_eq!(send(Some("null")).await, 403);
+    assert_eq!(send(Some("not a url")).await, 403);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn legacy_hashes_are_upgraded_after_totp() {
+    use argon2::{
+        password_hash::{rand_core::OsRng, SaltString},
+        Argon2, Params, PasswordHasher,
+    };
+    use totp_rs::{Algorithm, TOTP};
+
+    let server = TestServer::start().await;
+    let admin = server.user("users_rehash_admin").await;
+    sqlx::query("UPDATE user SET is_admin = TRUE WHERE username = 'users_rehash_admin'")
+        .execute(&server.pool)
+        .await
+        .unwrap();
+    server
+        .client()
+        .register(&new_user("users_rehash"))
+        .await
+        .unwrap();
+    assert_eq!(admin.admin_stats().await.unwrap().legacy_password_hashes, 0);
+
+    // Hash the password with more iterations than the server is configured to use
+    let argon2 = Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        Params::new(1024, 2, 1, None).unwrap(),
+    );
+    let legacy = argon2
+        .hash_password(PASSWORD.as_bytes(), &SaltString::generate(&mut OsRng))
+        .unwrap()
+        .to_string();
+    let secret = vec![7u8; 20];
+    sqlx::query(
+        "UPDATE user SET password_hash = ?, totp_secret = ?, totp_enabled = TRUE WHERE username = 'users_rehash'",
+    )
+    .bind(&legacy)
+    .bind(&secret)
+    .execute(&server.pool)
+    .await
+    .unwrap();
+    assert_eq!(admin.admin_stats().await.unwrap().legacy_password_hashes, 1);
+
+    let login = |totp_code: Option<String>| {
+        let client = server.client();
+        async move {
+            client
+                .login(&LoginUser {
+                    username: "users_rehash".into(),
+                    password: PASSWORD.into(),
+                    totp_code,
+                })
+                .await
+        }
+    };
+    let totp = TOTP::new_unchecked(Algorithm::SHA1, 6, 1, 30, secret, None, String::new());
+    let code = totp.generate_current().unwrap();
+    let wrong = code
+        .chars()
+        .map(|c| if c == '0' { '1' } else { '0' })
+        .collect();
+    // Knowing the password isn't enough to change the stored hash
+    assert!(matches!(
+        login(None).await,
+        Err(lokr_client::Error::TotpRequired)
+    ));
+    assert_eq!(status(login(Some(wrong)).await), 401);
+    assert_eq!(admin.admin_stats().await.unwrap().legacy_password_hashes, 1);
+
+    login(Some(code)).await.unwrap();
+    assert_eq!(admin.admin_stats().await.unwrap().legacy_password_hashes, 0);
+}
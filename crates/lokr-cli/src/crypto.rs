@@ -0,0 +1,147 @@
+//! Client side encryption.
+//!
+//! Everything here has to produce the same output as the Web Crypto calls in
+//! `client/src/cryptoFunctions.ts`, otherwise files uploaded with the CLI
+//! couldn't be opened in the browser and the other way around.
+
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use anyhow::{anyhow, bail, Result};
+use argon2::{password_hash::SaltString, Algorithm, Argon2, Params, PasswordHasher, Version};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use rand::{rngs::OsRng, RngCore};
+use rsa::{
+    pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey},
+    Oaep, RsaPrivateKey, RsaPublicKey,
+};
+use sha2::Sha256;
+
+/// The number of PBKDF2 iterations used to derive the master key from the password
+const PBKDF2_ITERATIONS: u32 = 120_000;
+const RSA_KEY_BITS: usize = 4096;
+const NONCE_LENGTH: usize = 12;
+const SALT_LENGTH: usize = 16;
+
+/// An AES-256-GCM key
+pub type Key = aes_gcm::Key<Aes256Gcm>;
+
+pub fn encode(data: &[u8]) -> String {
+    BASE64_STANDARD.encode(data)
+}
+
+pub fn decode(data: &str) -> Result<Vec<u8>> {
+    Ok(BASE64_STANDARD.decode(data)?)
+}
+
+pub fn generate_key() -> Key {
+    Aes256Gcm::generate_key(OsRng)
+}
+
+pub fn generate_nonce() -> [u8; NONCE_LENGTH] {
+    let mut nonce = [0; NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+pub fn generate_salt() -> [u8; SALT_LENGTH] {
+    let mut salt = [0; SALT_LENGTH];
+    OsRng.fill_bytes(&mut salt);
+    salt
+}
+
+pub fn encrypt(key: &Key, nonce: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() != NONCE_LENGTH {
+        bail!("Invalid nonce length");
+    }
+    Aes256Gcm::new(key)
+        .encrypt(Nonce::from_slice(nonce), data)
+        .map_err(|_| anyhow!("Failed to encrypt data"))
+}
+
+pub fn decrypt(key: &Key, nonce: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    if nonce.len() != NONCE_LENGTH {
+        bail!("Invalid nonce length");
+    }
+    Aes256Gcm::new(key)
+        .decrypt(Nonce::from_slice(nonce), data)
+        .map_err(|_| anyhow!("Failed to decrypt data"))
+}
+
+/// Decrypt base64 encoded text, like file names and mime types
+pub fn decrypt_text(key: &Key, nonce: &str, data: &str) -> Result<String> {
+    Ok(String::from_utf8(decrypt(
+        key,
+        &decode(nonce)?,
+        &decode(data)?,
+    )?)?)
+}
+
+/// Derive the key that the user's private key is encrypted with from their password
+pub fn derive_master_key(password: &str, salt: &[u8]) -> Key {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), salt, PBKDF2_ITERATIONS).into()
+}
+
+/// Hash the password before it's sent to the server,
+/// so the server never sees the password that the master key is derived from
+pub fn hash_password(password: &str, salt: &SaltString) -> Result<String> {
+    let params = Params::new(512, 256, 1, Some(32)).expect("Parameters should be valid");
+    Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), salt)
+        .map_err(|e| anyhow!("Failed to hash password: {}", e))?
+        .to_string())
+}
+
+pub fn generate_private_key() -> Result<RsaPrivateKey> {
+    Ok(RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS)?)
+}
+
+pub fn wrap_private_key(
+    private_key: &RsaPrivateKey,
+    master_key: &Key,
+    nonce: &[u8],
+) -> Result<Vec<u8>> {
+    encrypt(master_key, nonce, private_key.to_pkcs8_der()?.as_bytes())
+}
+
+pub fn unwrap_private_key(
+    encrypted: &[u8],
+    master_key: &Key,
+    nonce: &[u8],
+) -> Result<RsaPrivateKey> {
+    let der = decrypt(master_key, nonce, encrypted)
+        .map_err(|_| anyhow!("Failed to decrypt private key, is the password correct?"))?;
+    Ok(RsaPrivateKey::from_pkcs8_der(&der)?)
+}
+
+pub fn export_public_key(public_key: &RsaPublicKey) -> Result<String> {
+    Ok(encode(public_key.to_public_key_der()?.as_bytes()))
+}
+
+pub fn import_public_key(public_key: &str) -> Result<RsaPublicKey> {
+    Ok(RsaPublicKey::from_public_key_der(&decode(public_key)?)?)
+}
+
+/// Encrypt a file key with a user's public key, used for files in the root
+/// directory and files shared directly with a user
+pub fn wrap_key_rsa(key: &Key, public_key: &RsaPublicKey) -> Result<Vec<u8>> {
+    Ok(public_key.encrypt(&mut OsRng, Oaep::new::<Sha256>(), key)?)
+}
+
+pub fn unwrap_key_rsa(encrypted: &[u8], private_key: &RsaPrivateKey) -> Result<Key> {
+    to_key(private_key.decrypt(Oaep::new::<Sha256>(), encrypted)?)
+}
+
+/// Encrypt a file key with the key of its parent directory
+pub fn wrap_key(key: &Key, parent_key: &Key, nonce: &[u8]) -> Result<Vec<u8>> {
+    encrypt(parent_key, nonce, key)
+}
+
+pub fn unwrap_key(encrypted: &[u8], parent_key: &Key, nonce: &[u8]) -> Result<Key> {
+    to_key(decrypt(parent_key, nonce, encrypted)?)
+}
+
+fn to_key(bytes: Vec<u8>) -> Result<Key> {
+    if bytes.len() != 32 {
+        bail!("Invalid key length");
+    }
+    Ok(*Key::from_slice(&bytes))
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use lokr_client::{
+    types::{
+        share::SharedFileQuery,
+        upload::{
+            FileMetadata, FileQuery, FileResponse, KeyAlgorithm, UploadMetadata, METADATA_VERSION,
+        },
+    },
+    Client, Error,
+};
+use rsa::RsaPrivateKey;
+use uuid::Uuid;
+
+use crate::{crypto::*, App};
+
+/// The size of the chunks that files are downloaded in.
+/// Each chunk is written to disk before the next one is requested,
+/// so at most one chunk is lost when a download is interrupted.
+const CHUNK_SIZE: u64 = 8 * 1024 * 1024;
+
+/// Decrypts the keys of the files in a [`FileResponse`].
+/// The key of a file is encrypted with the key of its parent, so keys
+/// are cached to avoid decrypting the same ancestors over and over.
+pub struct Keyring<'a> {
+    files: &'a HashMap<Uuid, FileMetadata>,
+    private_key: &'a RsaPrivateKey,
+    keys: HashMap<Uuid, Key>,
+}
+
+impl<'a> Keyring<'a> {
+    pub fn new(files: &'a HashMap<Uuid, FileMetadata>, private_key: &'a RsaPrivateKey) -> Self {
+        Self {
+            files,
+            private_key,
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn key(&mut self, id: Uuid) -> Result<Key> {
+        if let Some(key) = self.keys.get(&id) {
+            return Ok(*key);
+        }
+        let file = self
+            .files
+            .get(&id)
+            .with_context(|| format!("File {} not found", id))?;
+        if file.upload.metadata_version != METADATA_VERSION {
+            bail!(
+                "File {} uses metadata version {}, which this version of the CLI can't read",
+                id,
+                file.upload.metadata_version
+            );
+        }
+        if file.upload.key_algorithm == Some(KeyAlgorithm::XChaCha20Poly1305) {
+            bail!("The key of file {} is wrapped with XChaCha20-Poly1305, which the CLI doesn't support yet", id);
+        }
+        let encrypted_key = decode(&file.upload.encrypted_key)?;
+        // Files without a parent, or whose parent isn't accessible like the top of
+        // a share, have their key encrypted with the user's public key instead
+        let parent_id = file
+            .upload
+            .parent_id
+            .filter(|parent_id| self.files.contains_key(parent_id));
+        let key = match (parent_id, &file.upload.key_nonce) {
+            (Some(parent_id), Some(nonce)) => {
+                let parent_key = self.key(parent_id)?;
+                unwrap_key(&encrypted_key, &parent_key, &decode(nonce)?)?
+            }
+            _ => unwrap_key_rsa(&encrypted_key, self.private_key)?,
+        };
+        self.keys.insert(id, key);
+        Ok(key)
+    }
+
+    pub fn name(&mut self, id: Uuid) -> Result<String> {
+        let key = self.key(id)?;
+        let upload = &self.files[&id].upload;
+        decrypt_text(&key, &upload.name_nonce, &upload.encrypted_file_name)
+    }
+}
+
+/// Get a file along with its ancestors and children, looking through the files
+/// shared with the user if they don't own it
+pub async fn fetch(client: &Client, id: Uuid) -> Result<FileResponse> {
+    let query = FileQuery {
+        id: Some(id),
+        include_ancestors: true,
+        ..Default::default()
+    };
+    match client.files(&query).await {
+        Err(Error::Api { status, .. }) if status == 404 => Ok(client
+            .shared_files(&query, &SharedFileQuery::default())
+            .await?),
+        response => Ok(response?),
+    }
+}
+
+pub async fn list(app: &App, id: Option<Uuid>, shared: bool) -> Result<()> {
+    let profile = app.profile().await?;
+    let response = match id {
+        Some(id) => fetch(&app.client, id).await?,
+        None if shared => {
+            app.client
+                .shared_files(&FileQuery::default(), &SharedFileQuery::default())
+                .await?
+        }
+        None => app.client.files(&FileQuery::default()).await?,
+    };
+    let ids = match id {
+        Some(id) if response.files[&id].upload.is_directory => response.files[&id].children.clone(),
+        Some(id) => vec![id],
+        None => response.root,
+    };
+    let private_key = app.private_key(&profile)?;
+    let mut keyring = Keyring::new(&response.files, &private_key);
+    for id in ids {
+        let file = &response.files[&id];
+        let name = keyring.name(id).unwrap_or_else(|e| format!("<{}>", e));
+        if file.upload.is_directory {
+            println!("{}  {:>12}  {}/", id, "-", name);
+        } else {
+            println!("{}  {:>12}  {}", id, file.size, name);
+        }
+    }
+    Ok(())
+}
+
+/// Encrypt a new file's key with the key of its parent,
+/// or with the user's public key if it's in the root directory
+async fn wrap_new_key(
+    app: &App,
+    key: &Key,
+    parent: Option<Uuid>,
+) -> Result<(String, Option<String>)> {
+    let profile = app.profile().await?;
+    let Some(parent) = parent else {
+        let public_key = import_public_key(&profile.public_key)?;
+        return Ok((encode(&wrap_key_rsa(key, &public_key)?), None));
+    };
+    let response = fetch(&app.client, parent).await?;
+    if !response
+        .files
+        .get(&parent)
+        .is_some_and(|file| file.upload.is_directory)
+    {
+        bail!("{} is not a directory", parent);
+    }
+    let private_key = app.private_key(&profile)?;
+    let parent_key = Keyring::new(&response.files, &private_key).key(parent)?;
+    let nonce = generate_nonce();
+    Ok((
+        encode(&wrap_key(key, &parent_key, &nonce)?),
+        Some(encode(&nonce)),
+    ))
+}
+
+pub async fn mkdir(app: &App, name: String, parent: Option<Uuid>) -> Result<()> {
+    let key = generate_key();
+    let name_nonce = generate_nonce();
+    let (encrypted_key, key_nonce) = wrap_new_key(app, &key, parent).await?;
+    let metadata = UploadMetadata {
+        encrypted_file_name: encode(&encrypt(&key, &name_nonce, name.as_bytes())?),
+        encrypted_mime_type: None,
+        encrypted_key,
+        file_nonce: None,
+        key_nonce,
+        name_nonce: encode(&name_nonce),
+        mime_type_nonce: None,
+        metadata_version: METADATA_VERSION,
+        key_algorithm: None,
+        plaintext_size: None,
+        is_directory: true,
+        parent_id: parent,
+    };
+    println!("{}", app.client.upload(&metadata, None).await?.id);
+    Ok(())
+}
+
+pub async fn upload(app: &App, path: PathBuf, parent: Option<Uuid>) -> Result<()> {
+    let name = path
+        .file_name()
+        .context("Path is not a file")?
+        .to_string_lossy()
+        .to_string();
+    let data = fs::read(&path).with_context(|| format!("Could not read {}", path.display()))?;
+    let mime_type = mime_guess::from_path(&path).first_raw();
+
+    let key = generate_key();
+    let file_nonce = generate_nonce();
+    let name_nonce = generate_nonce();
+    let mime_type_nonce = generate_nonce();
+    let (encrypted_key, key_nonce) = wrap_new_key(app, &key, parent).await?;
+    let metadata = UploadMetadata {
+        encrypted_file_name: encode(&encrypt(&key, &name_nonce, name.as_bytes())?),
+        encrypted_mime_type: mime_type
+            .map(|mime_type| encrypt(&key, &mime_type_nonce, mime_type.as_bytes()))
+            .transpose()?
+            .map(|mime_type| encode(&mime_type)),
+        encrypted_key,
+        file_nonce: Some(encode(&file_nonce)),
+        key_nonce,
+        name_nonce: encode(&name_nonce),
+        mime_type_nonce: mime_type.map(|_| encode(&mime_type_nonce)),
+        metadata_version: METADATA_VERSION,
+        key_algorithm: None,
+        plaintext_size: Some(data.len() as i64),
+        is_directory: false,
+        parent_id: parent,
+    };
+    let data = encrypt(&key, &file_nonce, &data)?;
+    let response = app.client.upload(&metadata, Some(data)).await?;
+    if let Some(warning) = response.quota_warning {
+        eprintln!(
+            "Warning: {} of {} bytes are in use, uploads will be rejected past {} bytes",
+            warning.used_space,
+            warning.total_space,
+            warning.total_space + warning.grace_space
+        );
+    }
+    println!("{}", response.id);
+    Ok(())
+}
+
+pub async fn download(app: &App, id: Uuid, output: Option<PathBuf>) -> Result<()> {
+    let profile = app.profile().await?;
+    let response = fetch(&app.client, id).await?;
+    let file = &response.files[&id];
+    if file.upload.is_directory {
+        bail!("{} is a directory", id);
+    }
+    let file_nonce = file
+        .upload
+        .file_nonce
+        .as_deref()
+        .context("File is missing its nonce")?;
+    let private_key = app.private_key(&profile)?;
+    let mut keyring = Keyring::new(&response.files, &private_key);
+    let key = keyring.key(id)?;
+    let output = match output {
+        Some(output) => output,
+        // The name comes from the server, so make sure it can't point outside of
+        // the current directory
+        None => Path::new(&keyring.name(id)?)
+            .file_name()
+            .context("File has an invalid name")?
+            .into(),
+    };
+
+    // The encrypted data is downloaded to a separate file first, which is also
+    // what allows an interrupted download to pick up where it left off
+    let mut partial_path = output.clone().into_os_string();
+    partial_path.push(".part");
+    let partial_path = PathBuf::from(partial_path);
+    let size = file.ciphertext_size as u64;
+    let mut offset = fs::metadata(&partial_path).map_or(0, |metadata| metadata.len());
+    if offset > size {
+        offset = 0;
+    } else if offset > 0 {
+        eprintln!("Resuming download at {} of {} bytes", offset, size);
+    }
+    let mut partial = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&partial_path)?;
+    partial.set_len(offset)?;
+    while offset < size {
+        let end = (offset + CHUNK_SIZE).min(size);
+        let chunk = app.client.download_range(id, offset..end).await?;
+        if chunk.is_empty() {
+            bail!("Server returned no data at offset {}", offset);
+        }
+        partial.write_all(&chunk)?;
+        offset += chunk.len() as u64;
+    }
+    drop(partial);
+
+    let data = match decrypt(&key, &decode(file_nonce)?, &fs::read(&partial_path)?) {
+        Ok(data) => data,
+        Err(e) => {
+            // Start over the next time instead of resuming from corrupted data
+            fs::remove_file(&partial_path)?;
+            return Err(e.context("The downloaded file is corrupted, try downloading it again"));
+        }
+    };
+    fs::write(&output, data)?;
+    fs::remove_file(&partial_path)?;
+    println!("{}", output.display());
+    Ok(())
+}
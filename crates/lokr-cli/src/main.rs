@@ -0,0 +1,244 @@
+//! Command line client for Lokr.
+//!
+//! Files are encrypted and decrypted locally the same way the web client does it,
+//! so the server only ever sees encrypted data and files can be used from both.
+
+use std::{io::Write, path::PathBuf};
+
+use anyhow::{Context, Result};
+use argon2::password_hash::SaltString;
+use clap::{Parser, Subcommand};
+use lokr_client::{
+    types::users::{CreateUser, LoginUser, PublicUser, SessionUser, UserSearch},
+    Client, Error,
+};
+use rsa::RsaPrivateKey;
+use uuid::Uuid;
+
+use crate::{crypto::*, state::State};
+
+mod crypto;
+mod files;
+mod share;
+mod state;
+
+const DEFAULT_SERVER: &str = "https://lokr.cyanistic.com";
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    /// The URL of the Lokr server. Defaults to the server that was last logged in to.
+    #[arg(long, global = true, env = "LOKR_SERVER")]
+    server: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a new account
+    Register {
+        username: String,
+        #[arg(long)]
+        email: Option<String>,
+    },
+    /// Log in, asking for a TOTP code if the account has it enabled
+    Login {
+        username: String,
+        /// The TOTP code, so it doesn't have to be entered interactively
+        #[arg(long)]
+        totp: Option<String>,
+    },
+    /// End the current session
+    Logout,
+    /// Show the currently logged in user
+    Whoami,
+    /// List the contents of a directory
+    Ls {
+        /// The directory to list. Lists the root directory if not given.
+        id: Option<Uuid>,
+        /// List the files shared with you instead of your own
+        #[arg(long, conflicts_with = "id")]
+        shared: bool,
+    },
+    /// Create a directory
+    Mkdir {
+        name: String,
+        /// The directory to create it in, defaults to the root directory
+        #[arg(long)]
+        parent: Option<Uuid>,
+    },
+    /// Encrypt and upload a file
+    Upload {
+        path: PathBuf,
+        /// The directory to upload to, defaults to the root directory
+        #[arg(long)]
+        parent: Option<Uuid>,
+    },
+    /// Download and decrypt a file. Interrupted downloads are resumed when run again.
+    Download {
+        id: Uuid,
+        /// Where to save the file, defaults to the name of the file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Delete a file, or a directory and everything in it
+    Rm { id: Uuid },
+    /// Manage who a file is shared with
+    #[command(subcommand)]
+    Share(share::ShareCommand),
+}
+
+/// Everything a command needs to talk to the server
+pub struct App {
+    client: Client,
+    server: String,
+    state: State,
+}
+
+impl App {
+    fn new(server: Option<String>) -> Result<Self> {
+        let mut state = State::load()?;
+        let server = server
+            .or_else(|| state.server.clone())
+            .unwrap_or_else(|| DEFAULT_SERVER.to_string());
+        // Sessions only work on the server they were created on
+        if state.server.as_ref() != Some(&server) {
+            state = State {
+                server: Some(server.clone()),
+                session: None,
+            };
+        }
+        let client = Client::new(&server)?;
+        client.set_session(state.session);
+        Ok(Self {
+            client,
+            server,
+            state,
+        })
+    }
+
+    fn save_session(&mut self) -> Result<()> {
+        self.state.session = self.client.session();
+        self.state.save()
+    }
+
+    async fn profile(&self) -> Result<SessionUser> {
+        if self.client.session().is_none() {
+            anyhow::bail!("Not logged in, run `lokr-cli login` first");
+        }
+        Ok(self.client.profile().await?)
+    }
+
+    /// Decrypt the user's private key, which requires their password
+    fn private_key(&self, profile: &SessionUser) -> Result<RsaPrivateKey> {
+        let password = password()?;
+        let master_key = derive_master_key(&password, &decode(&profile.salt)?);
+        unwrap_private_key(
+            &decode(&profile.encrypted_private_key)?,
+            &master_key,
+            &decode(&profile.iv)?,
+        )
+    }
+
+    /// Find a user by their exact username
+    async fn find_user(&self, username: &str) -> Result<PublicUser> {
+        self.client
+            .search_users(username, &UserSearch::default())
+            .await?
+            .into_iter()
+            .find(|user| user.username == username)
+            .with_context(|| format!("User {} does not exist", username))
+    }
+}
+
+/// Read the password from `LOKR_PASSWORD` or ask for it
+fn password() -> Result<String> {
+    match std::env::var("LOKR_PASSWORD") {
+        Ok(password) => Ok(password),
+        Err(_) => Ok(rpassword::prompt_password("Password: ")?),
+    }
+}
+
+fn prompt(message: &str) -> Result<String> {
+    eprint!("{}", message);
+    std::io::stderr().flush()?;
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim().to_string())
+}
+
+async fn register(app: &App, username: String, email: Option<String>) -> Result<()> {
+    let password = password()?;
+    let salt = generate_salt();
+    let iv = generate_nonce();
+    eprintln!("Generating keys...");
+    let private_key = generate_private_key()?;
+    let master_key = derive_master_key(&password, &salt);
+    let user = CreateUser {
+        username,
+        email,
+        password: hash_password(&password, &SaltString::generate(&mut rand::rngs::OsRng))?,
+        iv: encode(&iv),
+        public_key: export_public_key(&private_key.to_public_key())?,
+        encrypted_private_key: encode(&wrap_private_key(&private_key, &master_key, &iv)?),
+        salt: encode(&salt),
+    };
+    println!("{}", app.client.register(&user).await?.message);
+    Ok(())
+}
+
+async fn login(app: &mut App, username: String, totp: Option<String>) -> Result<()> {
+    let password = password()?;
+    // Passwords are hashed with the salt from registration before being sent,
+    // users without one registered without hashing their password
+    let hashed = match app.find_user(&username).await?.password_salt {
+        Some(salt) => hash_password(
+            &password,
+            &SaltString::from_b64(&salt).map_err(|e| anyhow::anyhow!("Invalid salt: {}", e))?,
+        )?,
+        None => password,
+    };
+    let mut user = LoginUser {
+        username,
+        password: hashed,
+        totp_code: totp,
+    };
+    match app.client.login(&user).await {
+        Err(Error::TotpRequired) => {
+            user.totp_code = Some(prompt("TOTP code: ")?);
+            app.client.login(&user).await?
+        }
+        response => response?,
+    };
+    app.save_session()?;
+    println!("Logged in as {}", user.username);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut app = App::new(cli.server)?;
+    match cli.command {
+        Command::Register { username, email } => register(&app, username, email).await?,
+        Command::Login { username, totp } => login(&mut app, username, totp).await?,
+        Command::Logout => {
+            let response = app.client.logout().await;
+            // Forget the session even if the server already did
+            app.save_session()?;
+            println!("{}", response?.message);
+        }
+        Command::Whoami => {
+            let profile = app.profile().await?;
+            println!("{} ({})", profile.username, profile.id);
+        }
+        Command::Ls { id, shared } => files::list(&app, id, shared).await?,
+        Command::Mkdir { name, parent } => files::mkdir(&app, name, parent).await?,
+        Command::Upload { path, parent } => files::upload(&app, path, parent).await?,
+        Command::Download { id, output } => files::download(&app, id, output).await?,
+        Command::Rm { id } => println!("{}", app.client.delete_file(id).await?.message),
+        Command::Share(command) => share::run(&app, command).await?,
+    }
+    Ok(())
+}
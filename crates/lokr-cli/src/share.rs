@@ -0,0 +1,204 @@
+use anyhow::{bail, Result};
+use clap::Subcommand;
+use lokr_client::types::share::{
+    LinkAudience, ShareIdentifier, ShareRequest, ShareRequestType, ShareResponseType,
+    ShareUpdateRequest,
+};
+use uuid::Uuid;
+
+use crate::{crypto::*, files::fetch, files::Keyring, App};
+
+#[derive(Subcommand)]
+pub enum ShareCommand {
+    /// Share a file with another user
+    User {
+        id: Uuid,
+        username: String,
+        /// Let the user edit the file
+        #[arg(long)]
+        edit: bool,
+    },
+    /// Create a link to a file. Anyone with the link can decrypt the file.
+    Link {
+        id: Uuid,
+        /// The number of seconds until the link expires, 0 for never
+        #[arg(long, default_value_t = 0)]
+        expires: u64,
+        /// Ask for a password that has to be entered to use the link
+        #[arg(long)]
+        password: bool,
+        /// Let anyone with the link edit the file
+        #[arg(long)]
+        edit: bool,
+        /// Only let logged in users use the link
+        #[arg(long, conflicts_with = "user")]
+        accounts: bool,
+        /// Only let this user use the link, can be repeated
+        #[arg(long)]
+        user: Vec<String>,
+    },
+    /// List the users and links that a file is shared with
+    List { id: Uuid },
+    /// Change whether a user or link can edit a file
+    Edit {
+        #[command(subcommand)]
+        target: ShareTarget,
+        /// Whether editing should be allowed
+        #[arg(long, global = true, action = clap::ArgAction::Set, default_value_t = true)]
+        allow: bool,
+    },
+    /// Stop sharing a file with a user or delete a link
+    Revoke {
+        #[command(subcommand)]
+        target: ShareTarget,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ShareTarget {
+    User { id: Uuid, username: String },
+    Link { link_id: Uuid },
+}
+
+impl ShareTarget {
+    async fn identifier(self, app: &App) -> Result<ShareIdentifier> {
+        Ok(match self {
+            ShareTarget::User { id, username } => ShareIdentifier::User {
+                user_id: app.find_user(&username).await?.id,
+                file_id: id,
+            },
+            ShareTarget::Link { link_id } => ShareIdentifier::Link {
+                link_id,
+                password: None,
+            },
+        })
+    }
+}
+
+pub async fn run(app: &App, command: ShareCommand) -> Result<()> {
+    match command {
+        ShareCommand::User { id, username, edit } => {
+            let user = app.find_user(&username).await?;
+            let key = file_key(app, id).await?;
+            let public_key = import_public_key(&user.public_key)?;
+            app.client
+                .share(&ShareRequest {
+                    type_: ShareRequestType::User {
+                        user_id: user.id,
+                        encrypted_key: encode(&wrap_key_rsa(&key, &public_key)?),
+                    },
+                    id,
+                    edit,
+                })
+                .await?;
+            println!("Shared {} with {}", id, username);
+        }
+        ShareCommand::Link {
+            id,
+            expires,
+            password,
+            edit,
+            accounts,
+            user,
+        } => {
+            let key = file_key(app, id).await?;
+            let password = match password {
+                true => Some(rpassword::prompt_password("Link password: ")?),
+                false => None,
+            };
+            let audience = if !user.is_empty() {
+                let mut user_ids = Vec::new();
+                for username in &user {
+                    user_ids.push(app.find_user(username).await?.id);
+                }
+                LinkAudience::Users { user_ids }
+            } else if accounts {
+                LinkAudience::Accounts
+            } else {
+                LinkAudience::Anyone
+            };
+            let response = app
+                .client
+                .share(&ShareRequest {
+                    type_: ShareRequestType::Link {
+                        expires,
+                        password,
+                        audience,
+                    },
+                    id,
+                    edit,
+                })
+                .await?;
+            let ShareResponseType::Link { link_id, .. } = response.type_ else {
+                bail!("Server did not return a link");
+            };
+            // The key is put in the fragment so it's never sent to the server
+            println!(
+                "{}/share?linkId={}#{}",
+                app.server.trim_end_matches('/'),
+                link_id,
+                encode(&key)
+            );
+        }
+        ShareCommand::List { id } => {
+            let users = app.client.share_users(id).await?;
+            for access in users.access {
+                if let ShareResponseType::User { user_id } = access.type_ {
+                    let username = users
+                        .users
+                        .get(&user_id)
+                        .map_or("<unknown>", |user| &user.username);
+                    println!("user  {}  {}", permission(access.edit_permission), username);
+                }
+            }
+            for link in app.client.share_links(id).await? {
+                if let ShareResponseType::Link {
+                    link_id,
+                    expires_at,
+                    password_protected,
+                    ..
+                } = link.type_
+                {
+                    println!(
+                        "link  {}  {}  expires {}{}",
+                        permission(link.edit_permission),
+                        link_id,
+                        expires_at.map_or("never".to_string(), |expires| expires.to_rfc3339()),
+                        if password_protected {
+                            ", password protected"
+                        } else {
+                            ""
+                        }
+                    );
+                }
+            }
+        }
+        ShareCommand::Edit { target, allow } => {
+            let request = ShareUpdateRequest {
+                type_: target.identifier(app).await?,
+                edit: allow,
+            };
+            println!("{}", app.client.update_share(&request).await?.message);
+        }
+        ShareCommand::Revoke { target } => {
+            let share = target.identifier(app).await?;
+            println!("{}", app.client.delete_share(&share).await?.message);
+        }
+    }
+    Ok(())
+}
+
+async fn file_key(app: &App, id: Uuid) -> Result<Key> {
+    let profile = app.profile().await?;
+    let response = fetch(&app.client, id).await?;
+    let private_key = app.private_key(&profile)?;
+    Keyring::new(&response.files, &private_key).key(id)
+}
+
+fn permission(edit: bool) -> &'static str {
+    if edit {
+        "editor"
+    } else {
+        "viewer"
+    }
+}
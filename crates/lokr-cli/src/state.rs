@@ -0,0 +1,46 @@
+//! The state that is kept between runs of the CLI
+
+use std::{fs, io::Write, path::PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct State {
+    /// The server that the session belongs to
+    pub server: Option<String>,
+    pub session: Option<Uuid>,
+}
+
+impl State {
+    fn path() -> Result<PathBuf> {
+        Ok(dirs::config_dir()
+            .context("Could not find the config directory")?
+            .join("lokr")
+            .join("cli.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        serde_json::from_slice(&fs::read(&path)?)
+            .with_context(|| format!("Invalid state file at {}", path.display()))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path()?;
+        fs::create_dir_all(path.parent().expect("State path should have a parent"))?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        // The session id is as good as a password, so don't let other users read it
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(&path)?
+            .write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,565 @@
+//! A typed client for the Lokr API.
+//!
+//! Requests and responses use the same types as the server from [`lokr_types`],
+//! so a change to the API shows up as a compile error here instead of a
+//! deserialization error at runtime. Encryption is left to the caller, the
+//! client only sends and receives the already encrypted data.
+
+use std::{
+    ops::Range,
+    sync::{Arc, RwLock},
+};
+
+use reqwest::{
+    header::{CONTENT_TYPE, COOKIE, RANGE, SET_COOKIE},
+    multipart::{Form, Part},
+    redirect::Policy,
+    Method, RequestBuilder, Response, StatusCode, Url,
+};
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+pub use lokr_types as types;
+use lokr_types::{
+    admin::{AdminConfig, AdminStats},
+    error::{ErrorResponse, ErrorType},
+    instance::{Features, FeaturesUpdate, InstanceStats, MetadataVersions, ServerTime},
+    jobs::{JobInfo, JobQuery},
+    permissions::{Capabilities, CapabilityQuery},
+    public::{PublicProfile, PublicProfileUpdate, PublishRequest},
+    session::StepUpRequest,
+    share::{
+        ClaimRequest, Mount, MountRequest, RevokedShare, RevokedShareQuery, ShareIdentifier,
+        ShareRequest, ShareResponse, ShareUpdateRequest, SharedFileQuery, UserShareResponse,
+    },
+    upload::{
+        AliasRequest, DeletePreview, FileQuery, FileResponse, FingerprintQuery,
+        FingerprintResponse, RewrapKey, RewrapRequest, RewrapResponse, UpdateFile, UploadMetadata,
+        UploadResponse,
+    },
+    users::{
+        AvatarResponse, CreateUser, KeyManifest, LoginResponse, LoginUser, Preferences, PublicUser,
+        SessionUser, UserSearch, UserUpdate,
+    },
+    DryRunQuery, SuccessResponse,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The server rejected the request
+    #[error("{status}: {message}")]
+    Api {
+        status: StatusCode,
+        /// The kind of error reported by the server, if the response had one
+        kind: Option<ErrorType>,
+        message: String,
+    },
+    /// The user has TOTP enabled, so the login has to be retried with a code
+    #[error("A TOTP code is required to log in")]
+    TotpRequired,
+    /// A range download was asked for no bytes, which can't be put in a Range header
+    #[error("Can't download an empty range")]
+    EmptyRange,
+    #[error("Invalid URL: {0}")]
+    Url(#[from] url::ParseError),
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A client for a single Lokr server.
+/// Cloning the client is cheap and clones share the same session.
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+    session: Arc<RwLock<Option<Uuid>>>,
+}
+
+impl Client {
+    /// Create a client for the server at `base_url`, e.g. `https://lokr.cyanistic.com`
+    pub fn new(base_url: &str) -> Result<Self> {
+        Ok(Self {
+            // The login endpoint uses a redirect to ask for a TOTP code,
+            // which shouldn't be followed
+            http: reqwest::Client::builder()
+                .redirect(Policy::none())
+                // Logging in requires a user agent to label the session with
+                .user_agent(concat!("lokr-client/", env!("CARGO_PKG_VERSION")))
+                .build()?,
+            base_url: Url::parse(base_url)?,
+            session: Arc::default(),
+        })
+    }
+
+    /// The id of the current session, if logged in.
+    /// This can be stored and passed to [`Client::set_session`] later to stay logged in.
+    pub fn session(&self) -> Option<Uuid> {
+        *self.session.read().unwrap()
+    }
+
+    /// Use an existing session instead of logging in
+    pub fn set_session(&self, session: Option<Uuid>) {
+        *self.session.write().unwrap() = session;
+    }
+
+    fn request(&self, method: Method, path: &str) -> Result<RequestBuilder> {
+        let request = self.http.request(method, self.base_url.join(path)?);
+        // The session cookie is handled manually instead of with a cookie store since
+        // it's marked as `Secure`, which would stop it from being sent to a local server
+        Ok(match self.session() {
+            Some(session) => request.header(COOKIE, format!("session={}", session)),
+            None => request,
+        })
+    }
+
+    /// Check the status of the response, turning error responses into an [`Error::Api`]
+    async fn check(response: Response) -> Result<Response> {
+        let status = response.status();
+        if status.is_client_error() || status.is_server_error() {
+            return Err(match response.json::<ErrorResponse>().await {
+                Ok(body) => Error::Api {
+                    status,
+                    kind: Some(body.r#type),
+                    message: body.message,
+                },
+                // Errors from outside of the handlers, like a missing route, don't have a body
+                Err(_) => Error::Api {
+                    status,
+                    kind: None,
+                    message: status.canonical_reason().unwrap_or_default().to_string(),
+                },
+            });
+        }
+        Ok(response)
+    }
+
+    async fn send<T: DeserializeOwned>(request: RequestBuilder) -> Result<T> {
+        Ok(Self::check(request.send().await?).await?.json().await?)
+    }
+
+    /// Register a new user. This doesn't log the user in.
+    pub async fn register(&self, user: &CreateUser) -> Result<SuccessResponse> {
+        Self::send(self.request(Method::POST, "/api/register")?.json(user)).await
+    }
+
+    /// Log in and start a new session.
+    /// Returns [`Error::TotpRequired`] if the user has TOTP enabled and no code was given.
+    pub async fn login(&self, user: &LoginUser) -> Result<LoginResponse> {
+        let response = self
+            .request(Method::POST, "/api/login")?
+            .json(user)
+            .send()
+            .await?;
+        if response.status() == StatusCode::TEMPORARY_REDIRECT {
+            return Err(Error::TotpRequired);
+        }
+        let response = Self::check(response).await?;
+        self.set_session(Self::session_cookie(&response));
+        Ok(response.json().await?)
+    }
+
+    /// The session set by a response, if there is one
+    fn session_cookie(response: &Response) -> Option<Uuid> {
+        response
+            .headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|cookie| cookie.to_str().ok())
+            .find_map(|cookie| cookie.strip_prefix("session="))
+            .and_then(|cookie| Uuid::try_parse(cookie.split(';').next()?).ok())
+    }
+
+    /// End the current session
+    pub async fn logout(&self) -> Result<SuccessResponse> {
+        let response = Self::send(self.request(Method::POST, "/api/logout")?).await;
+        self.set_session(None);
+        response
+    }
+
+    /// Get the currently logged in user
+    pub async fn profile(&self) -> Result<SessionUser> {
+        Self::send(self.request(Method::GET, "/api/profile")?).await
+    }
+
+    /// Update the username, email or password of the logged in user.
+    /// Changing the email or password rotates the session, which is picked up here.
+    pub async fn update_profile(&self, update: &UserUpdate) -> Result<SuccessResponse> {
+        let response = self
+            .request(Method::PUT, "/api/profile")?
+            .json(update)
+            .send()
+            .await?;
+        let response = Self::check(response).await?;
+        if let Some(session) = Self::session_cookie(&response) {
+            self.set_session(Some(session));
+        }
+        Ok(response.json().await?)
+    }
+
+    /// Get the keys of every file the logged in user can access, for an offline backup
+    pub async fn key_manifest(&self) -> Result<KeyManifest> {
+        Self::send(self.request(Method::GET, "/api/profile/keys/manifest")?).await
+    }
+
+    /// Search for users by their username
+    pub async fn search_users(&self, query: &str, params: &UserSearch) -> Result<Vec<PublicUser>> {
+        let path = format!("/api/users/search/{}", query);
+        Self::send(self.request(Method::GET, &path)?.query(params)).await
+    }
+
+    /// Get a user by their id
+    pub async fn user(&self, id: Uuid) -> Result<PublicUser> {
+        Self::send(self.request(Method::GET, &format!("/api/user/{}", id))?).await
+    }
+
+    /// Enter the password or a TOTP code again, which sensitive
+    /// operations require to have been done recently
+    pub async fn verify_session(&self, request: &StepUpRequest) -> Result<SuccessResponse> {
+        Self::send(
+            self.request(Method::POST, "/api/session/verify")?
+                .json(request),
+        )
+        .await
+    }
+
+    /// Log out one of the logged in user's sessions by its number
+    pub async fn delete_session(&self, number: i64) -> Result<SuccessResponse> {
+        Self::send(self.request(Method::DELETE, &format!("/api/session/{}", number))?).await
+    }
+
+    /// Update the logged in user's preferences
+    pub async fn update_preferences(&self, preferences: &Preferences) -> Result<SuccessResponse> {
+        Self::send(
+            self.request(Method::PUT, "/api/profile/preferences")?
+                .json(preferences),
+        )
+        .await
+    }
+
+    /// Turn the logged in user's public profile on or off
+    pub async fn set_public_profile(&self, enabled: bool) -> Result<SuccessResponse> {
+        let request = PublicProfileUpdate { enabled };
+        Self::send(
+            self.request(Method::PUT, "/api/profile/public")?
+                .json(&request),
+        )
+        .await
+    }
+
+    /// List a share link on the logged in user's public profile
+    pub async fn publish_link(&self, request: &PublishRequest) -> Result<SuccessResponse> {
+        Self::send(
+            self.request(Method::POST, "/api/profile/public/files")?
+                .json(request),
+        )
+        .await
+    }
+
+    /// Remove a share link from the logged in user's public profile
+    pub async fn unpublish_link(&self, link_id: Uuid) -> Result<SuccessResponse> {
+        let path = format!("/api/profile/public/files/{}", link_id);
+        Self::send(self.request(Method::DELETE, &path)?).await
+    }
+
+    /// Replace the avatar of the logged in user with an image
+    pub async fn upload_avatar(&self, image: Vec<u8>) -> Result<AvatarResponse> {
+        Self::send(
+            self.request(Method::PUT, "/api/profile/upload")?
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .body(image),
+        )
+        .await
+    }
+
+    /// Download the avatar of a user, `file` being their id followed by the extension
+    /// of the avatar
+    pub async fn avatar(&self, file: &str) -> Result<Vec<u8>> {
+        let response = self
+            .request(Method::GET, &format!("/api/avatars/{}", file))?
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.bytes().await?.to_vec())
+    }
+
+    /// Get the public profile of a user
+    pub async fn public_profile(&self, username: &str) -> Result<PublicProfile> {
+        let path = format!("/api/user/{}/public", username);
+        Self::send(self.request(Method::GET, &path)?).await
+    }
+
+    /// Get the metadata of a file and its children, or of the user's root
+    /// directory if no id is given
+    pub async fn files(&self, query: &FileQuery) -> Result<FileResponse> {
+        Self::send(self.request(Method::GET, "/api/file")?.query(query)).await
+    }
+
+    /// Upload an encrypted file. `data` should be `None` for directories.
+    pub async fn upload(
+        &self,
+        metadata: &UploadMetadata,
+        data: Option<Vec<u8>>,
+    ) -> Result<UploadResponse> {
+        self.upload_with_fingerprint(metadata, data, None).await
+    }
+
+    /// Upload an encrypted file along with a keyed fingerprint of its plaintext,
+    /// which [`Client::find_fingerprints`] can look for later
+    pub async fn upload_with_fingerprint(
+        &self,
+        metadata: &UploadMetadata,
+        data: Option<Vec<u8>>,
+        fingerprint: Option<&str>,
+    ) -> Result<UploadResponse> {
+        let mut form = Form::new().part(
+            "metadata",
+            Part::text(serde_json::to_string(metadata).expect("Metadata should serialize"))
+                .mime_str("application/json")?,
+        );
+        if let Some(data) = data {
+            form = form.part("file", Part::bytes(data));
+        }
+        if let Some(fingerprint) = fingerprint {
+            form = form.text("fingerprint", fingerprint.to_owned());
+        }
+        Self::send(self.request(Method::POST, "/api/upload")?.multipart(form)).await
+    }
+
+    /// Replace the encrypted keys of many files in a single transaction
+    pub async fn rewrap_keys(&self, files: Vec<RewrapKey>) -> Result<RewrapResponse> {
+        Self::send(
+            self.request(Method::POST, "/api/files/rewrap")?
+                .json(&RewrapRequest { files }),
+        )
+        .await
+    }
+
+    /// Find the user's files that were uploaded with any of the given fingerprints
+    pub async fn find_fingerprints(&self, fingerprints: &[String]) -> Result<FingerprintResponse> {
+        Self::send(
+            self.request(Method::POST, "/api/file/fingerprints")?
+                .json(&FingerprintQuery {
+                    fingerprints: fingerprints.to_vec(),
+                }),
+        )
+        .await
+    }
+
+    /// Get the metadata of files shared with the user, or of the files
+    /// directly shared with them if no id is given
+    pub async fn shared_files(
+        &self,
+        query: &FileQuery,
+        filter: &SharedFileQuery,
+    ) -> Result<FileResponse> {
+        Self::send(
+            self.request(Method::GET, "/api/shared")?
+                .query(query)
+                .query(filter),
+        )
+        .await
+    }
+
+    /// Download the encrypted contents of a file
+    pub async fn download(&self, id: Uuid) -> Result<Vec<u8>> {
+        let response = self
+            .request(Method::GET, &format!("/api/file/data/{}", id))?
+            .send()
+            .await?;
+        Ok(Self::check(response).await?.bytes().await?.to_vec())
+    }
+
+    /// Download part of the encrypted contents of a file, used to download large
+    /// files in chunks and resume interrupted downloads
+    pub async fn download_range(&self, id: Uuid, range: Range<u64>) -> Result<Vec<u8>> {
+        if range.is_empty() {
+            return Err(Error::EmptyRange);
+        }
+        let response = self
+            .request(Method::GET, &format!("/api/file/data/{}", id))?
+            .header(RANGE, format!("bytes={}-{}", range.start, range.end - 1))
+            .send()
+            .await?;
+        let response = Self::check(response).await?;
+        // A server that ignores the range sends back the whole file
+        if response.status() == StatusCode::OK {
+            let bytes = response.bytes().await?;
+            let end = (range.end as usize).min(bytes.len());
+            return Ok(bytes[(range.start as usize).min(end)..end].to_vec());
+        }
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Move or rename a file
+    pub async fn update_file(&self, id: Uuid, update: &UpdateFile) -> Result<SuccessResponse> {
+        Self::send(
+            self.request(Method::PUT, &format!("/api/file/{}", id))?
+                .json(update),
+        )
+        .await
+    }
+
+    /// Make a file appear in another directory without storing its data again
+    pub async fn alias(&self, id: Uuid, alias: &AliasRequest) -> Result<UploadResponse> {
+        Self::send(
+            self.request(Method::POST, &format!("/api/file/{}/alias", id))?
+                .json(alias),
+        )
+        .await
+    }
+
+    /// Delete a file, including all of its children if it's a directory
+    pub async fn delete_file(&self, id: Uuid) -> Result<SuccessResponse> {
+        Self::send(self.request(Method::DELETE, &format!("/api/file/{}", id))?).await
+    }
+
+    /// Get what deleting a file would remove, without deleting anything
+    pub async fn preview_delete_file(&self, id: Uuid) -> Result<DeletePreview> {
+        Self::send(
+            self.request(Method::DELETE, &format!("/api/file/{}", id))?
+                .query(&DryRunQuery { dry_run: true }),
+        )
+        .await
+    }
+
+    /// Share a file with a user or create a share link for it
+    pub async fn share(&self, request: &ShareRequest) -> Result<ShareResponse> {
+        Self::send(self.request(Method::POST, "/api/share")?.json(request)).await
+    }
+
+    /// Move a file that was uploaded anonymously into the logged in user's account
+    pub async fn claim(&self, link_id: Uuid, request: &ClaimRequest) -> Result<SuccessResponse> {
+        Self::send(
+            self.request(Method::POST, &format!("/api/shared/{}/claim", link_id))?
+                .json(request),
+        )
+        .await
+    }
+
+    /// Get the active share links of a file
+    pub async fn share_links(&self, file_id: Uuid) -> Result<Vec<ShareResponse>> {
+        Self::send(self.request(Method::GET, &format!("/api/shared/{}/links", file_id))?).await
+    }
+
+    /// Get the users a file is directly shared with
+    pub async fn share_users(&self, file_id: Uuid) -> Result<UserShareResponse> {
+        Self::send(self.request(Method::GET, &format!("/api/shared/{}/users", file_id))?).await
+    }
+
+    /// Change the permissions of a share link or a user that a file is shared with
+    pub async fn update_share(&self, request: &ShareUpdateRequest) -> Result<SuccessResponse> {
+        Self::send(self.request(Method::PUT, "/api/share")?.json(request)).await
+    }
+
+    /// Delete a share link or stop sharing a file with a user
+    pub async fn delete_share(&self, share: &ShareIdentifier) -> Result<SuccessResponse> {
+        Self::send(self.request(Method::DELETE, "/api/shared")?.json(share)).await
+    }
+
+    /// Get the shares of the logged in user's files that were revoked recently enough to be restored
+    pub async fn revoked_shares(&self, query: &RevokedShareQuery) -> Result<Vec<RevokedShare>> {
+        Self::send(
+            self.request(Method::GET, "/api/shared/revoked")?
+                .query(query),
+        )
+        .await
+    }
+
+    /// Restore a share that was revoked, including the key wrapped for the user
+    pub async fn restore_share(&self, share: &ShareIdentifier) -> Result<SuccessResponse> {
+        Self::send(
+            self.request(Method::POST, "/api/shared/restore")?
+                .json(share),
+        )
+        .await
+    }
+
+    /// Mount a directory shared with the logged in user into their root
+    pub async fn mount(&self, file_id: Uuid) -> Result<Mount> {
+        Self::send(
+            self.request(Method::POST, "/api/mount")?
+                .json(&MountRequest { file_id }),
+        )
+        .await
+    }
+
+    /// Get the directories the logged in user mounted into their root
+    pub async fn mounts(&self) -> Result<Vec<Mount>> {
+        Self::send(self.request(Method::GET, "/api/mount")?).await
+    }
+
+    /// Remove a mount, the directory stays shared with the user
+    pub async fn unmount(&self, id: Uuid) -> Result<SuccessResponse> {
+        Self::send(self.request(Method::DELETE, &format!("/api/mount/{}", id))?).await
+    }
+
+    /// Get what the logged in user, or the link if one is given, can do with a file
+    pub async fn capabilities(&self, query: &CapabilityQuery) -> Result<Capabilities> {
+        Self::send(self.request(Method::GET, "/api/capabilities")?.query(query)).await
+    }
+
+    /// Get statistics about the whole instance, only works for admins
+    pub async fn admin_stats(&self) -> Result<AdminStats> {
+        Self::send(self.request(Method::GET, "/api/admin/stats")?).await
+    }
+
+    /// Get the configuration the server is running with, only works for admins
+    pub async fn admin_config(&self) -> Result<AdminConfig> {
+        Self::send(self.request(Method::GET, "/api/admin/config")?).await
+    }
+
+    /// Get the background jobs of every user, only works for admins
+    pub async fn admin_jobs(&self, query: &JobQuery) -> Result<Vec<JobInfo>> {
+        Self::send(self.request(Method::GET, "/api/admin/jobs")?.query(query)).await
+    }
+
+    /// Get the logged in user's background jobs, newest first
+    pub async fn jobs(&self) -> Result<Vec<JobInfo>> {
+        Self::send(self.request(Method::GET, "/api/jobs")?).await
+    }
+
+    /// Get one of the logged in user's background jobs
+    pub async fn job(&self, id: i64) -> Result<JobInfo> {
+        Self::send(self.request(Method::GET, &format!("/api/jobs/{}", id))?).await
+    }
+
+    /// Cancel one of the logged in user's background jobs that hasn't run yet
+    pub async fn cancel_job(&self, id: i64) -> Result<SuccessResponse> {
+        Self::send(self.request(Method::DELETE, &format!("/api/jobs/{}", id))?).await
+    }
+
+    /// Queue one of the logged in user's failed or cancelled background jobs again
+    pub async fn retry_job(&self, id: i64) -> Result<SuccessResponse> {
+        Self::send(self.request(Method::POST, &format!("/api/jobs/{}/retry", id))?).await
+    }
+
+    /// Get which optional features are enabled on the instance
+    pub async fn features(&self) -> Result<Features> {
+        Self::send(self.request(Method::GET, "/api/instance/features")?).await
+    }
+
+    /// Get the encrypted metadata formats the instance accepts uploads in
+    pub async fn metadata_versions(&self) -> Result<MetadataVersions> {
+        Self::send(self.request(Method::GET, "/api/instance/metadata-versions")?).await
+    }
+
+    /// Get the time on the server and the window TOTP codes are accepted in
+    pub async fn server_time(&self) -> Result<ServerTime> {
+        Self::send(self.request(Method::GET, "/api/time")?).await
+    }
+
+    /// Get the public statistics of the instance, if the operator shares any
+    pub async fn instance_stats(&self) -> Result<InstanceStats> {
+        Self::send(self.request(Method::GET, "/api/instance/stats")?).await
+    }
+
+    /// Turn optional features of the instance on or off, only works for admins
+    pub async fn update_features(&self, update: &FeaturesUpdate) -> Result<Features> {
+        Self::send(
+            self.request(Method::PUT, "/api/admin/features")?
+                .json(update),
+        )
+        .await
+    }
+}
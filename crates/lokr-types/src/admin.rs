@@ -0,0 +1,144 @@
+use std::ops::AddAssign;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::instance::Features;
+
+/// What the periodic cleanup removed, either in a single run or in total
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct CleanupStats {
+    /// Number of expired sessions that were removed
+    pub sessions: u64,
+    /// Number of expired share links that were removed
+    pub share_links: u64,
+    /// Number of anonymous files that were removed because no link points to them anymore
+    pub files: u64,
+    /// Bytes of file data freed by removing files
+    pub reclaimed_bytes: u64,
+    /// Number of finished jobs that were removed after being kept for 30 days
+    pub jobs: u64,
+    /// Number of revoked shares that were removed after they couldn't be restored anymore
+    pub revoked_shares: u64,
+}
+
+impl AddAssign for CleanupStats {
+    fn add_assign(&mut self, other: Self) {
+        self.sessions += other.sessions;
+        self.share_links += other.share_links;
+        self.files += other.files;
+        self.reclaimed_bytes += other.reclaimed_bytes;
+        self.jobs += other.jobs;
+        self.revoked_shares += other.revoked_shares;
+    }
+}
+
+/// Statistics about the whole instance
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AdminStats {
+    /// Number of registered users
+    pub users: i64,
+    /// Number of files and directories, including anonymous ones
+    pub files: i64,
+    /// Space used by all users combined, in bytes
+    pub used_space: i64,
+    /// Number of password hashes that weren't created with the current hashing parameters.
+    /// They are upgraded when their users next log in.
+    pub legacy_password_hashes: i64,
+    /// Number of cleanup runs since the server started
+    pub cleanup_runs: u64,
+    /// When the cleanup last ran, if it has run since the server started
+    pub last_cleanup_at: Option<DateTime<Utc>>,
+    /// Everything removed by cleanup runs since the server started
+    pub cleanup: CleanupStats,
+    /// Writes that were retried because the database was busy, since the server started
+    pub transaction_retries: RetryStats,
+}
+
+/// How often writes had to be retried because the database was busy
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RetryStats {
+    /// Number of retries across all writes
+    pub retries: u64,
+    /// Number of writes that failed because the database was still busy after the last attempt
+    pub exhausted: u64,
+}
+
+/// What the running server was built from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    #[cfg_attr(feature = "utoipa", schema(example = "0.1.0"))]
+    pub version: String,
+    /// The commit the server was built from, if it was built from a git checkout
+    #[cfg_attr(feature = "utoipa", schema(example = "d67189d"))]
+    pub git_hash: Option<String>,
+    /// Whether the server was built with optimizations
+    pub release: bool,
+}
+
+/// The configuration the server is running with after reading the environment
+/// and falling back to the defaults. Secrets are only shown as being set or not.
+/// Every option is named after its `LOKR_` environment variable.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct EffectiveConfig {
+    /// In bytes
+    pub max_upload_size: u64,
+    /// In bytes
+    pub max_avatar_size: u64,
+    /// In bytes
+    pub max_body_size: u64,
+    /// In seconds
+    pub request_timeout: u64,
+    /// In seconds
+    pub body_idle_timeout: u64,
+    pub cookie_secure: bool,
+    #[cfg_attr(feature = "utoipa", schema(example = "Lax"))]
+    pub cookie_same_site: String,
+    /// In KiB
+    pub argon2_memory: u32,
+    pub argon2_iterations: u32,
+    pub argon2_parallelism: u32,
+    pub data_dir: String,
+    pub client_dir: String,
+    pub quota_grace_percent: u32,
+    pub explain_denials: bool,
+    /// In seconds
+    pub step_up_window: u64,
+    /// Whether avatars and backups are encrypted at rest. The key itself is never shown.
+    pub at_rest_key: bool,
+    /// In bytes, 0 means there is no cap
+    pub monthly_transfer_cap: u64,
+    /// The numbers shared through `/api/instance/stats`
+    #[cfg_attr(feature = "utoipa", schema(example = "users,version"))]
+    pub public_stats: String,
+    pub db_retry_attempts: u32,
+    /// In milliseconds
+    pub db_retry_delay: u64,
+    /// In seconds
+    pub share_restore_window: u64,
+    /// Per user, 0 means there is no limit
+    pub max_concurrent_uploads: u32,
+    /// In bytes
+    pub anonymous_folder_max_size: u64,
+}
+
+/// Everything an operator needs to check what a running instance is using
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AdminConfig {
+    pub build: BuildInfo,
+    pub config: EffectiveConfig,
+    /// The optional features as they are right now, including changes made by admins
+    pub features: Features,
+}
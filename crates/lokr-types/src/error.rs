@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+
+/// The kind of error that caused a request to fail
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "utoipa", schema(example = "UserError"))]
+pub enum ErrorType {
+    JsonRejection,
+    SqlxError,
+    SerdeError,
+    ValidationError,
+    AuthError,
+    UserError,
+    /// A logged in user was denied access to a file they were shared files by
+    /// the owner before. Other users get a `UserError` saying the file wasn't found.
+    AccessDenied,
+    /// The request needs the user to have entered their password or a TOTP code
+    /// recently. Retry it after verifying the session with `POST /api/session/verify`.
+    StepUpRequired,
+    /// The user already has as many uploads in progress as the instance allows.
+    /// Retry once one of them has finished.
+    TooManyUploads,
+    Generic,
+}
+
+/// A JSON response for errors that includes the error type and message
+/// Used in HTTP responses to notify the client of errors
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorResponse {
+    pub r#type: ErrorType,
+    #[cfg_attr(feature = "utoipa", schema(example = "Something went wrong"))]
+    pub message: String,
+}
+
+/// A more descriptive error message for validation errors.
+/// The message of a `ValidationError` response is a JSON array of these.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ValidationErrorDetail {
+    /// The field that failed validation
+    pub field: String,
+    /// A detailed error message
+    pub message: String,
+}
@@ -0,0 +1,98 @@
+use serde::{Deserialize, Serialize};
+
+/// Which optional features are enabled on the instance.
+/// Admins can change these at runtime, so clients should check them
+/// instead of assuming what the instance supports.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Features {
+    /// Whether new users can create an account
+    pub registration: bool,
+    /// Whether files can be uploaded without an account, getting a temporary share link
+    pub anonymous_uploads: bool,
+    /// Whether users can publish share links on a public profile
+    pub public_profiles: bool,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            registration: true,
+            anonymous_uploads: true,
+            public_profiles: true,
+        }
+    }
+}
+
+/// A change to the features of the instance.
+/// Features that aren't included are left as they are.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct FeaturesUpdate {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub registration: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub anonymous_uploads: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_profiles: Option<bool>,
+}
+
+/// Coarse public numbers about the instance, for status pages and directories
+/// of instances. The operator chooses which of these are shared, the others
+/// are left out.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct InstanceStats {
+    /// Number of registered users, rounded down to two significant digits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "utoipa", schema(example = 1200))]
+    pub users: Option<i64>,
+    /// Number of files and directories, rounded down to two significant digits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "utoipa", schema(example = 56000))]
+    pub files: Option<i64>,
+    /// How long the server has been running in seconds, rounded down to the hour
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "utoipa", schema(example = 86400))]
+    pub uptime: Option<u64>,
+    /// The version of the server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "utoipa", schema(example = "0.1.0"))]
+    pub version: Option<String>,
+}
+
+/// The encrypted metadata formats the instance understands, so clients can
+/// pick one they both support before uploading
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataVersions {
+    /// The version new uploads should use
+    #[cfg_attr(feature = "utoipa", schema(example = 1))]
+    pub current: i64,
+    /// Every version uploads are accepted in, oldest first.
+    /// Files can be in any of these.
+    #[cfg_attr(feature = "utoipa", schema(example = json!([1])))]
+    pub supported: Vec<i64>,
+}
+
+/// The time on the server, so clients can warn users whose clock is far enough
+/// off for their TOTP codes to be rejected
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ServerTime {
+    /// Seconds since the UNIX epoch
+    #[cfg_attr(feature = "utoipa", schema(example = 1760486400))]
+    pub unix_time: u64,
+    /// How many seconds each TOTP code is valid for
+    #[cfg_attr(feature = "utoipa", schema(example = 30))]
+    pub totp_step: u64,
+    /// How many steps before or after the current one codes are still accepted from.
+    /// A clock that is more than `totpStep * totpSkew` seconds off will have its codes rejected.
+    #[cfg_attr(feature = "utoipa", schema(example = 1))]
+    pub totp_skew: u8,
+}
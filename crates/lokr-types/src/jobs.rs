@@ -0,0 +1,81 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// Where a background job is in its lifecycle
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum JobStatus {
+    /// Waiting to be run, either for the first time or to be retried
+    Pending = 0,
+    /// Failed on every attempt, see the last error for why
+    Failed = 1,
+    Completed = 2,
+    /// Cancelled before it could be run
+    Cancelled = 3,
+}
+
+impl TryFrom<i64> for JobStatus {
+    type Error = &'static str;
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Pending),
+            1 => Ok(Self::Failed),
+            2 => Ok(Self::Completed),
+            3 => Ok(Self::Cancelled),
+            _ => Err("Invalid job status value"),
+        }
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl<'r> sqlx::Decode<'r, sqlx::Sqlite> for JobStatus {
+    fn decode(
+        value: <sqlx::Sqlite as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value: i64 = <i64 as sqlx::Decode<sqlx::Sqlite>>::decode(value)?;
+        Ok(value.try_into()?)
+    }
+}
+
+/// A background job and how far along it is
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct JobInfo {
+    pub id: i64,
+    /// What the job does
+    #[cfg_attr(feature = "utoipa", schema(example = "deleteBlobs"))]
+    pub kind: String,
+    pub status: JobStatus,
+    /// Number of times the job has been attempted and failed
+    pub attempts: i64,
+    /// Why the most recent attempt failed
+    pub last_error: Option<String>,
+    /// The earliest time the job will be run at, if it is pending
+    pub run_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+    pub modified_at: DateTime<Utc>,
+    /// When the job completed, failed for good or was cancelled
+    pub finished_at: Option<DateTime<Utc>>,
+    /// The user who the job was run for. Only shown to admins,
+    /// and left out for jobs the server runs for itself.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+}
+
+/// Narrow down the jobs listed to admins
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[cfg_attr(feature = "utoipa", into_params(parameter_in = Query))]
+#[serde(rename_all = "camelCase")]
+pub struct JobQuery {
+    /// Only list jobs with this status
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "utoipa", param(inline))]
+    pub status: Option<JobStatus>,
+    /// Only list jobs that were run for this user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<Uuid>,
+}
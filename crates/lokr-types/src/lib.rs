@@ -0,0 +1,37 @@
+//! Request and response types of the Lokr API.
+//!
+//! These are shared by the server and Rust clients so both sides always agree on
+//! the shape of the data. Enable the `utoipa` feature to derive OpenAPI schemas,
+//! `sqlx` to decode the types that are stored in the database, and `validator`
+//! to validate incoming requests.
+
+use serde::{Deserialize, Serialize};
+
+pub mod admin;
+pub mod error;
+pub mod instance;
+pub mod jobs;
+pub mod permissions;
+pub mod public;
+pub mod session;
+pub mod share;
+pub mod upload;
+pub mod users;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct SuccessResponse {
+    #[cfg_attr(feature = "utoipa", schema(example = "Yay! It worked!"))]
+    pub message: String,
+}
+
+/// Lets destructive endpoints report what they would change instead of changing it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[cfg_attr(feature = "utoipa", into_params(parameter_in = Query))]
+#[serde(rename_all = "camelCase")]
+pub struct DryRunQuery {
+    /// Run every check and return what would be affected, without changing anything
+    #[serde(default)]
+    pub dry_run: bool,
+}
@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The file to check and the share link to check it through, if any.
+/// The password of a protected link is taken from the cookie set when it was unlocked.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[cfg_attr(feature = "utoipa", into_params(parameter_in = Query))]
+#[serde(rename_all = "camelCase")]
+pub struct CapabilityQuery {
+    pub file_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_id: Option<Uuid>,
+}
+
+/// How the credentials can access a file, ordered from least to most access
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum AccessLevel {
+    View,
+    Edit,
+    Owner,
+}
+
+/// What the current credentials allow on a file, so clients can show or hide
+/// actions up front instead of finding out when a request fails
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Capabilities {
+    pub file_id: Uuid,
+    pub access: AccessLevel,
+    /// Whether the metadata and data of the file can be downloaded
+    pub view: bool,
+    /// Whether files can be uploaded into the directory.
+    /// Always false for files that aren't directories.
+    pub upload: bool,
+    /// Whether the file can be renamed or moved
+    pub edit: bool,
+    pub delete: bool,
+    /// Whether the file can be shared, and its existing shares listed and changed
+    pub share: bool,
+    /// Whether the file can be claimed into the account of the logged in user,
+    /// which is only possible for files uploaded anonymously through the link
+    pub claim: bool,
+    /// How many more bytes can be uploaded through the link, if it has a size limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_limit: Option<i64>,
+}
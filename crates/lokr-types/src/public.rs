@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The maximum length of the public title of a file
+pub const MAX_PUBLIC_TITLE_LENGTH: usize = 100;
+
+/// Turn the public profile of the current user on or off
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct PublicProfileUpdate {
+    pub enabled: bool,
+}
+
+/// List a share link on the public profile of the current user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct PublishRequest {
+    pub link_id: Uuid,
+    /// Shown on the profile instead of the encrypted file name
+    #[cfg_attr(feature = "utoipa", schema(example = "Release notes"))]
+    pub title: String,
+    /// The key from the fragment of the share link. Anyone viewing the profile
+    /// gets this key, which is what allows them to decrypt the file.
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub key: String,
+}
+
+/// A user's public profile along with the files they chose to list on it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct PublicProfile {
+    pub id: Uuid,
+    #[cfg_attr(feature = "utoipa", schema(example = "sussyman"))]
+    pub username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// The file extension for the user's avatar
+    pub avatar_extension: Option<String>,
+    /// The version of the user's avatar, this changes whenever a new avatar is uploaded
+    pub avatar_version: i64,
+    /// The listed files, most recently published first
+    pub files: Vec<PublicFile>,
+}
+
+/// A share link listed on a public profile
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct PublicFile {
+    pub link_id: Uuid,
+    pub title: String,
+    /// The key needed to decrypt the file, the same as the fragment of the share link
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub key: String,
+    pub is_directory: bool,
+    /// The size of the encrypted file in bytes
+    pub size: i64,
+    /// Whether the link needs a password before the file can be accessed
+    pub password_protected: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub published_at: DateTime<Utc>,
+}
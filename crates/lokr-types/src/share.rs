@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::users::PublicUser;
+
+/// An enum representing the type of sharing
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum ShareRequestType {
+    #[serde(rename_all = "camelCase")]
+    User {
+        user_id: Uuid,
+        encrypted_key: String,
+    },
+    Link {
+        expires: u64,
+        password: Option<String>,
+        /// Who can use the link, anyone who has it by default
+        #[serde(default)]
+        audience: LinkAudience,
+    },
+}
+
+/// Who a share link works for, on top of having the link and its password
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum LinkAudience {
+    #[default]
+    Anyone,
+    /// Any logged in user
+    Accounts,
+    /// Logged in users with an email address in one of these domains.
+    /// Not accepted until email addresses are verified, since anyone can set theirs to any domain.
+    EmailDomains { domains: Vec<String> },
+    /// Only these users. Users are listed by id rather than username so
+    /// that nobody else can get access by taking a username that was freed up.
+    #[serde(rename_all = "camelCase")]
+    Users { user_ids: Vec<Uuid> },
+}
+
+/// A request to share a file with a user or generate a link
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct ShareRequest {
+    #[serde(flatten)]
+    pub type_: ShareRequestType,
+    pub id: Uuid,
+    /// Whether the user/link should have editing permissions
+    pub edit: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum ShareResponseType {
+    #[serde(rename_all = "camelCase")]
+    User { user_id: Uuid },
+    #[serde(rename_all = "camelCase")]
+    Link {
+        link_id: Uuid,
+        expires_at: Option<DateTime<Utc>>,
+        password_protected: bool,
+        /// Whether the link can only be used to add files
+        add_only: bool,
+        /// The maximum total size in bytes of the shared files when uploading through the link
+        #[serde(skip_serializing_if = "Option::is_none")]
+        max_size: Option<i64>,
+        /// Who can use the link
+        #[serde(default)]
+        audience: LinkAudience,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ShareResponse {
+    #[serde(flatten)]
+    pub type_: ShareResponseType,
+    pub edit_permission: bool,
+    pub created_at: DateTime<Utc>,
+    pub modified_at: DateTime<Utc>,
+}
+
+/// A share that was revoked recently enough to be restored
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RevokedShare {
+    #[serde(flatten)]
+    pub type_: ShareResponseType,
+    pub file_id: Uuid,
+    pub edit_permission: bool,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: DateTime<Utc>,
+    /// The share can't be restored after this
+    pub restorable_until: DateTime<Utc>,
+}
+
+/// Which revoked shares to list
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[serde(rename_all = "camelCase")]
+pub struct RevokedShareQuery {
+    /// Only list the shares of this file, instead of every file of the user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<Uuid>,
+}
+
+/// Extra query parameters for files shared with a user
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[serde(rename_all = "camelCase")]
+pub struct SharedFileQuery {
+    /// Only return files owned by this user
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner_id: Option<Uuid>,
+    /// Whether to group the root files by their owner in the response
+    #[serde(default)]
+    pub group_by_owner: bool,
+}
+
+/// A request to claim a file that was uploaded anonymously
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ClaimRequest {
+    /// The file key encrypted with the user's public key.
+    /// The key of an anonymous upload is encrypted with a key that isn't stored
+    /// on the server, so it has to be rewrapped before the file is claimed.
+    pub encrypted_key: String,
+    /// The password for the share link if it has one
+    pub password: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct UserShareResponse {
+    pub access: Vec<ShareResponse>,
+    pub users: HashMap<Uuid, PublicUser>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ShareIdentifier {
+    #[serde(rename_all = "camelCase")]
+    User { user_id: Uuid, file_id: Uuid },
+    #[serde(rename_all = "camelCase")]
+    Link {
+        link_id: Uuid,
+        /// If this is NULL, this is assumed to not be changing.
+        /// An empty string means remove the password
+        password: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct ShareUpdateRequest {
+    #[serde(flatten)]
+    pub type_: ShareIdentifier,
+    pub edit: bool,
+}
+
+/// A directory shared with the user that they mounted into their own root
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Mount {
+    pub id: Uuid,
+    /// The shared directory the mount points at
+    pub file_id: Uuid,
+    /// Whether the directory is still shared with the user. Mounts of revoked
+    /// shares are hidden from listings until the share is restored.
+    pub active: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Mount a directory that is directly shared with the user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct MountRequest {
+    pub file_id: Uuid,
+}
@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use uuid::Uuid;
+
+use crate::{share::ShareResponse, users::PublicUser};
+
+/// The format of the encrypted metadata that new uploads should use.
+/// Bumped whenever nonce sizes, the way keys are wrapped or the encrypted
+/// fields change, so files written in older formats can still be read.
+pub const METADATA_VERSION: i64 = 1;
+
+/// Every metadata version the server accepts uploads in, oldest first
+pub const SUPPORTED_METADATA_VERSIONS: &[i64] = &[1];
+
+/// Clients from before the metadata was versioned don't send a version
+fn default_metadata_version() -> i64 {
+    1
+}
+
+/// How the key of a file is wrapped, either with the key of its parent
+/// (or of the share link it was uploaded through) or with a user's public key
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum KeyAlgorithm {
+    /// AES-256-GCM with a 12 byte nonce
+    AesGcm = 0,
+    /// RSA-OAEP with SHA-256, using a 2048 to 8192 bit key
+    RsaOaep = 1,
+    /// XChaCha20-Poly1305 with a 24 byte nonce
+    XChaCha20Poly1305 = 2,
+}
+
+impl KeyAlgorithm {
+    /// The algorithm clients used before they had to say which one they used.
+    /// Keys with a nonce were wrapped with AES-GCM, the rest with RSA-OAEP.
+    pub fn infer(key_nonce: Option<&str>) -> Self {
+        match key_nonce {
+            Some(_) => Self::AesGcm,
+            None => Self::RsaOaep,
+        }
+    }
+
+    /// The lengths in bytes a 256 bit key can have once wrapped
+    pub fn wrapped_key_lengths(&self) -> &'static [usize] {
+        match self {
+            // The key followed by a 16 byte tag
+            Self::AesGcm | Self::XChaCha20Poly1305 => &[48],
+            // As long as the modulus of the RSA key
+            Self::RsaOaep => &[256, 384, 512, 1024],
+        }
+    }
+
+    /// The length in bytes of the nonce, if the algorithm uses one
+    pub fn nonce_length(&self) -> Option<usize> {
+        match self {
+            Self::AesGcm => Some(12),
+            Self::XChaCha20Poly1305 => Some(24),
+            Self::RsaOaep => None,
+        }
+    }
+}
+
+impl TryFrom<i64> for KeyAlgorithm {
+    type Error = &'static str;
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::AesGcm),
+            1 => Ok(Self::RsaOaep),
+            2 => Ok(Self::XChaCha20Poly1305),
+            _ => Err("Invalid key algorithm value"),
+        }
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl<'r> sqlx::Decode<'r, sqlx::Sqlite> for KeyAlgorithm {
+    fn decode(
+        value: <sqlx::Sqlite as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value: i64 = <i64 as sqlx::Decode<sqlx::Sqlite>>::decode(value)?;
+        Ok(value.try_into()?)
+    }
+}
+
+/// All data for the uploaded file.
+/// All encrypted fields are expected to be encrypted
+/// by the provided key, except for the key itself
+/// which is expected to be encrypted by the user's public key
+#[derive(Deserialize, Debug, Serialize, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UploadMetadata {
+    /// The encrypted name of the file to be uploaded
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub encrypted_file_name: String,
+    /// The encrypted mime type of the file to be uploaded
+    /// Optional in case the mime type is not known
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub encrypted_mime_type: Option<String>,
+    /// The key used to encrypt the file
+    /// Should be encrypted by the user's public key
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub encrypted_key: String,
+    /// We need to use a diffent nonce for each
+    /// piece of data that we encrypt for security reasons
+    /// The nonce for the file (not encrypted) can be null
+    /// if the file is a directory
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub file_nonce: Option<String>,
+    /// The nonce for the encryption key (not encrypted)
+    /// Not neeeded if the file is in the root directory
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub key_nonce: Option<String>,
+    /// The nonce for the file name (not encrypted)
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub name_nonce: String,
+    /// The nonce for the file mime type(not encrypted)
+    /// can be null if the file does not have a mime type
+    #[cfg_attr(feature = "utoipa", schema(content_encoding = "base64"))]
+    pub mime_type_nonce: Option<String>,
+    /// Whether the file is a directory
+    #[serde(default)]
+    pub is_directory: bool,
+    /// The direct parent id of the file
+    /// Should be null if in the root directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<Uuid>,
+    /// The format the encrypted fields are in, see `/api/instance/metadata-versions`
+    /// for the versions the server accepts. Defaults to 1 if not given.
+    #[serde(default = "default_metadata_version")]
+    #[cfg_attr(feature = "utoipa", schema(example = 1))]
+    pub metadata_version: i64,
+    /// How the encrypted key was wrapped. If left out it is inferred from
+    /// the key nonce, see [`KeyAlgorithm::infer`]. Always set in responses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_algorithm: Option<KeyAlgorithm>,
+    /// The size of the file before it was encrypted. If left out it is assumed to
+    /// be the size of the uploaded data minus the 16 byte tag of AES-GCM, which is
+    /// wrong for data that was encrypted in chunks. Only used when uploading,
+    /// files report it as `size`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub plaintext_size: Option<i64>,
+}
+
+/// The size and id of the uploaded file
+/// Also has a flag to indicate if the file is a directory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UploadResponse {
+    pub id: Uuid,
+    /// The size of the uploaded data, which is what counts towards the owner's space
+    pub size: i64,
+    /// The size of the file before it was encrypted
+    pub plaintext_size: i64,
+    pub is_directory: bool,
+    /// Used to handle the case where the file is uploaded
+    /// by an anonymous user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<ShareResponse>,
+    /// Set when the upload put the owner of the file over their total space
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quota_warning: Option<QuotaWarning>,
+}
+
+/// Uploads that go over the total space of the owner still succeed as long as they
+/// stay within the grace space the server allows on top of it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct QuotaWarning {
+    /// The space used by the owner after the upload
+    pub used_space: i64,
+    pub total_space: i64,
+    /// How much space past the total space can still be used,
+    /// uploads that would go further than that are rejected
+    pub grace_space: i64,
+}
+
+/// Metadata of a file or directory
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct FileMetadata {
+    /// The id of the file or directory
+    pub id: Uuid,
+    #[serde(flatten)]
+    pub upload: UploadMetadata,
+    pub created_at: DateTime<Utc>,
+    pub modified_at: DateTime<Utc>,
+    pub owner_id: Option<Uuid>,
+    pub uploader_id: Option<Uuid>,
+    /// The size of the file before it was encrypted, as declared by the uploader
+    pub size: i64,
+    /// The size of the encrypted data that is stored and downloaded,
+    /// which is what counts towards the owner's space
+    pub ciphertext_size: i64,
+    /// Whether or not the user has edit permission to this file
+    /// if this is not set then the file should inherit the edit permissions
+    /// of the parent. This will not be sent when a user is querying their
+    /// own files, as they will always have edit permissions.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edit_permission: Option<bool>,
+    /// The number of users the file is directly shared with.
+    /// Only sent to the owner of the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_user_count: Option<i64>,
+    /// The number of active links directly sharing the file.
+    /// Only sent to the owner of the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub active_link_count: Option<i64>,
+    /// Whether one of the file's ancestors is shared with a user or an active link,
+    /// meaning the file is accessible through it. Only sent to the owner of the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shared_via_ancestor: Option<bool>,
+    /// The key epoch of the owner when the key of the file was last wrapped.
+    /// If a cached key has a different epoch it should be fetched again.
+    /// Only sent to the owner of the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_epoch: Option<i64>,
+    /// The id of the mount if this is a shared directory that the user mounted
+    /// into their own root. Mounted directories are listed with the shared key,
+    /// like in the files shared with the user.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mount_id: Option<Uuid>,
+    /// The id the data of the file is stored under if the file is an alias,
+    /// which is the file the first alias was made of. That file may have been
+    /// deleted since. Only sent to the owner of the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias_of: Option<Uuid>,
+    /// The children of the directory.
+    /// Only present if the file is a directory.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[serde(rename_all = "camelCase")]
+#[serde_inline_default]
+pub struct FileQuery {
+    /// The id of the file or directory to get.
+    /// If not provided, the root of the currently
+    /// authorized user directory is returned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    /// The maximum depth to return children for
+    #[serde_inline_default(1)]
+    #[cfg_attr(feature = "utoipa", param(maximum = 20, default = 1))]
+    pub depth: u32,
+    /// The offset to start returning children from
+    #[cfg_attr(feature = "utoipa", param(default = 0))]
+    #[serde_inline_default(0)]
+    pub offset: u32,
+    /// The maximum number of children to return
+    #[cfg_attr(feature = "utoipa", param(default = 50))]
+    #[serde_inline_default(50)]
+    pub limit: u32,
+    /// Whether to include the ancestors of the
+    /// chain of the file in the response
+    #[serde(default)]
+    pub include_ancestors: bool,
+}
+
+impl Default for FileQuery {
+    fn default() -> Self {
+        Self {
+            id: None,
+            depth: 1,
+            offset: 0,
+            limit: 50,
+            include_ancestors: false,
+        }
+    }
+}
+
+#[cfg(feature = "utoipa")]
+impl FileMetadata {
+    fn example() -> HashMap<Uuid, Self> {
+        use chrono::TimeZone;
+
+        let parent_uuid = Uuid::try_parse_ascii(b"123e4567-e89b-12d3-a456-426614174000").unwrap();
+        let child_uuid = Uuid::try_parse_ascii(b"21f981a7-d21f-4aa5-9f6b-09005235236a").unwrap();
+        let user_id = Uuid::try_parse_ascii(b"dae2b0f0-d84b-42c8-aebd-58a71ee1fb86").unwrap();
+
+        let date = Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+        let first = FileMetadata {
+            id: parent_uuid,
+            upload: UploadMetadata {
+                encrypted_file_name: "encryptedFileName".into(),
+                encrypted_mime_type: Some("encryptedMimeType".into()),
+                encrypted_key: "encryptedKey".into(),
+                file_nonce: Some("exampleNonce".into()),
+                key_nonce: Some("exampleNonce".into()),
+                name_nonce: "exampleNonce".into(),
+                mime_type_nonce: Some("exampleNonce".into()),
+                metadata_version: METADATA_VERSION,
+                key_algorithm: Some(KeyAlgorithm::AesGcm),
+                plaintext_size: None,
+                is_directory: true,
+                parent_id: None,
+            },
+            size: 0,
+            ciphertext_size: 0,
+            edit_permission: None,
+            shared_user_count: Some(1),
+            active_link_count: Some(0),
+            shared_via_ancestor: Some(false),
+            key_epoch: Some(0),
+            mount_id: None,
+            alias_of: None,
+            created_at: date,
+            modified_at: date,
+            owner_id: Some(user_id),
+            uploader_id: Some(user_id),
+            children: vec![child_uuid],
+        };
+        let child = FileMetadata {
+            id: child_uuid,
+            upload: UploadMetadata {
+                encrypted_file_name: "encryptedFileName".into(),
+                encrypted_mime_type: Some("encryptedMimeType".into()),
+                encrypted_key: "encryptedKey".into(),
+                file_nonce: Some("exampleNonce".into()),
+                key_nonce: Some("exampleNonce".into()),
+                name_nonce: "exampleNonce".into(),
+                mime_type_nonce: Some("exampleNonce".into()),
+                metadata_version: METADATA_VERSION,
+                key_algorithm: Some(KeyAlgorithm::AesGcm),
+                plaintext_size: None,
+                is_directory: false,
+                parent_id: Some(parent_uuid),
+            },
+            size: 32,
+            ciphertext_size: 48,
+            created_at: date,
+            modified_at: date,
+            owner_id: Some(user_id),
+            uploader_id: Some(user_id),
+            children: vec![],
+            edit_permission: None,
+            shared_user_count: Some(0),
+            active_link_count: Some(0),
+            shared_via_ancestor: Some(true),
+            key_epoch: Some(0),
+            mount_id: None,
+            alias_of: None,
+        };
+        HashMap::from([(parent_uuid, first), (child_uuid, child)])
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct FileResponse {
+    #[cfg_attr(feature = "utoipa", schema(example = FileMetadata::example))]
+    pub files: HashMap<Uuid, FileMetadata>,
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(example = "same kind of thing as files, but with `PublicUser` schema...")
+    )]
+    pub users: HashMap<Uuid, PublicUser>,
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(example = "123e4567-e89b-12d3-a456-426614174000")
+    )]
+    pub root: Vec<Uuid>,
+    /// The ids in `root` grouped by the owner of the file.
+    /// Only present when requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owners: Option<HashMap<Uuid, Vec<Uuid>>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[serde(rename_all = "camelCase")]
+pub struct LinkParams {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link_id: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum UpdateFile {
+    /// Move the file to a new parent
+    #[serde(rename_all = "camelCase")]
+    Move {
+        /// The new parent id of the file
+        parent_id: Option<Uuid>,
+        #[cfg_attr(
+            feature = "utoipa",
+            schema(
+                example = "38ZP4XEKLikREzyy9ttdaKLZ8WiWCd2i8ptTCwRwMlc=",
+                content_encoding = "base64"
+            )
+        )]
+        encrypted_key: String,
+        /// The new nonce for the encryption key
+        #[cfg_attr(
+            feature = "utoipa",
+            schema(example = "nonce", content_encoding = "base64")
+        )]
+        key_nonce: Option<String>,
+        /// How the new key was wrapped, inferred from the nonce if left out
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_algorithm: Option<KeyAlgorithm>,
+    },
+    /// Rename the file
+    #[serde(rename_all = "camelCase")]
+    Rename {
+        /// The new encrypted name of the file
+        #[cfg_attr(
+            feature = "utoipa",
+            schema(
+                example = "38ZP4XEKLikREzyy9ttdaKLZ8WiWCd2i8ptTCwRwMlc=",
+                content_encoding = "base64"
+            )
+        )]
+        encrypted_name: String,
+        /// The new nonce for the file name
+        /// We use a new one for each name for security reasons
+        #[cfg_attr(
+            feature = "utoipa",
+            schema(example = "nonce", content_encoding = "base64")
+        )]
+        name_nonce: String,
+    },
+}
+
+/// Make a file appear in another directory without storing its data again.
+/// The alias gets the name and key of the file, with the key wrapped for the new parent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct AliasRequest {
+    /// The directory to put the alias in, or the root directory if left out
+    pub parent_id: Option<Uuid>,
+    /// The key of the file, encrypted with the key of the new parent
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            example = "38ZP4XEKLikREzyy9ttdaKLZ8WiWCd2i8ptTCwRwMlc=",
+            content_encoding = "base64"
+        )
+    )]
+    pub encrypted_key: String,
+    /// Only needed if the alias has a parent
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(example = "nonce", content_encoding = "base64")
+    )]
+    pub key_nonce: Option<String>,
+    /// How the key was wrapped, inferred from the nonce if left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_algorithm: Option<KeyAlgorithm>,
+}
+
+/// What deleting a file would remove, sent instead of deleting it on a dry run
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct DeletePreview {
+    /// The file followed by everything inside of it
+    pub ids: Vec<Uuid>,
+    /// Number of files that would be deleted, not counting directories
+    pub files: i64,
+    /// Number of directories that would be deleted, including the file itself
+    pub directories: i64,
+    /// Bytes of file data that would be freed. Data that aliases outside of
+    /// the deleted files still refer to is kept, so it isn't counted.
+    pub bytes: i64,
+    /// Number of users the deleted files are directly shared with
+    pub shared_users: i64,
+    /// Number of share links to the deleted files that would stop working
+    pub share_links: i64,
+}
+
+/// The files in a directory uploaded by a single user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UploaderSummary {
+    /// The id of the uploader, this is null for anonymous uploads
+    pub uploader_id: Option<Uuid>,
+    /// The total size of the uploaded files in bytes
+    pub total_size: i64,
+    /// The ids of the uploaded files
+    pub files: Vec<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct UploaderResponse {
+    pub uploaders: Vec<UploaderSummary>,
+    pub users: HashMap<Uuid, PublicUser>,
+}
+
+/// The most fingerprints that can be looked up in a single request
+pub const MAX_FINGERPRINT_LOOKUP: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct FingerprintQuery {
+    /// The fingerprints to look for, as sent when the files were uploaded
+    pub fingerprints: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct FingerprintResponse {
+    /// The ids of the files with each fingerprint that was found.
+    /// Fingerprints without any files are left out.
+    pub files: HashMap<String, Vec<Uuid>>,
+}
+
+/// The most keys that can be re-wrapped in a single request
+pub const MAX_REWRAP_BATCH: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RewrapKey {
+    pub file_id: Uuid,
+    /// The file key encrypted with the key of its parent,
+    /// or with the owner's public key if the file is in the root directory
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            example = "38ZP4XEKLikREzyy9ttdaKLZ8WiWCd2i8ptTCwRwMlc=",
+            content_encoding = "base64"
+        )
+    )]
+    pub encrypted_key: String,
+    /// The nonce used to encrypt the key, only needed if the file has a parent
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(example = "nonce", content_encoding = "base64")
+    )]
+    pub key_nonce: Option<String>,
+    /// How the key was wrapped, inferred from the nonce if left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_algorithm: Option<KeyAlgorithm>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RewrapRequest {
+    pub files: Vec<RewrapKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RewrapResult {
+    pub file_id: Uuid,
+    /// Whether the key of the file was updated
+    pub updated: bool,
+    /// Why the key was not updated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct RewrapResponse {
+    /// The result for each key, in the same order as the request
+    pub results: Vec<RewrapResult>,
+    /// The new key epoch of the user, given to every updated file
+    pub key_epoch: i64,
+}
@@ -0,0 +1,573 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_inline_default::serde_inline_default;
+use uuid::Uuid;
+
+pub const MIN_PASSWORD_LENGTH: u64 = 8;
+pub const MAX_PASSWORD_LENGTH: u64 = 256;
+pub const MIN_USERNAME_LENGTH: u64 = 3;
+pub const MAX_USERNAME_LENGTH: u64 = 20;
+pub const PUBLIC_KEY_LENGTH: usize = 550; // Length I ended up with after encoding the public key
+
+/// A struct representing a new user to be created
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "validator", derive(validator::Validate))]
+#[serde(rename_all = "camelCase")]
+pub struct CreateUser {
+    /// The name of the user to create
+    #[cfg_attr(feature = "validator", validate(length(min = MIN_USERNAME_LENGTH, max = MAX_USERNAME_LENGTH), custom(function = "validate_username")))]
+    // I would use the max and min constants here, but they are not allowed in the attribute
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(min_length = 3, max_length = 20, example = "sussyman")
+    )]
+    pub username: String,
+    /// The new user's password
+    /// Should be hashed using Argon2 before being sent to the backend
+    #[cfg_attr(
+        feature = "validator",
+        validate(length(min = MIN_PASSWORD_LENGTH, max = MAX_PASSWORD_LENGTH))
+    )]
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            min_length = 8,
+            max_length = 64,
+            example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
+        )
+    )]
+    pub password: String,
+    /// Optional email for the user
+    #[cfg_attr(feature = "validator", validate(email))]
+    #[cfg_attr(feature = "utoipa", schema(example = "sussyman@amogus.com"))]
+    pub email: Option<String>,
+    /// The initialization vector for the AES encrypted user's private key
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(content_encoding = "base64", example = "l+EEL/mHKlkxlEG0")
+    )]
+    pub iv: String,
+    /// The user's public key
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            content_encoding = "base64",
+            example = "d4Ogp+CI5mkdCCfXxDmmxor9FKMTQ5dq4gAvCECgcFs="
+        )
+    )]
+    pub public_key: String,
+    /// The user's private key encrypted using their password
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            content_encoding = "base64",
+            example = "38ZP4XEKLikREzyy9ttdaKLZ8WiWCd2i8ptTCwRwMlc="
+        )
+    )]
+    pub encrypted_private_key: String,
+    /// The salt for the PBKDF2 key derivation function
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(content_encoding = "base64", example = "iKJcRJf7fwtO6est")
+    )]
+    pub salt: String,
+}
+
+/// A struct representing a user logging in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "validator", derive(validator::Validate))]
+#[serde(rename_all = "camelCase")]
+pub struct LoginUser {
+    #[cfg_attr(feature = "validator", validate(length(min = MIN_USERNAME_LENGTH, max = MAX_USERNAME_LENGTH), custom(function = "validate_username")))]
+    #[cfg_attr(feature = "utoipa", schema(example = "sussyman"))]
+    pub username: String,
+    #[cfg_attr(
+        feature = "validator",
+        validate(length(min = MIN_PASSWORD_LENGTH, max = MAX_PASSWORD_LENGTH))
+    )]
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
+        )
+    )]
+    pub password: String,
+    /// The totp code provided by the user. Should always be exactly 6 digits
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[cfg_attr(feature = "validator", validate(length(min = 6, max = 6)))]
+    #[cfg_attr(feature = "utoipa", schema(example = "696969"))]
+    pub totp_code: Option<String>,
+}
+
+/// A successful login response
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct LoginResponse {
+    /// The initialization vector for the AES encrypted user's private key
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(content_encoding = "base64", example = "BukSfO6yaQ")
+    )]
+    pub iv: String,
+    /// The user's public key
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            content_encoding = "base64",
+            example = "QQe22k5wy-88PUFIW1P7MkgxoyMyalmjnffAuUNgMuE"
+        )
+    )]
+    pub public_key: String,
+    /// The user's private key encrypted using their password
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            content_encoding = "base64",
+            example = "9WNx5GS9CSaqesguryWS-jiY8Vb0VMMjMtV5JJECk9A"
+        )
+    )]
+    pub encrypted_private_key: String,
+    /// The salt for the PBKDF2 key derivation function
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(content_encoding = "base64", example = "iKJcRJf7fwtO6est")
+    )]
+    pub salt: String,
+    /// The user's preferred theme
+    pub theme: Theme,
+}
+
+/// Verify that the username only contains alphanumeric characters and underscores
+#[cfg(feature = "validator")]
+pub fn validate_username(username: &str) -> Result<(), validator::ValidationError> {
+    use std::ops::ControlFlow;
+
+    use validator::ValidationError;
+
+    match username
+        .chars()
+        .try_fold((0, 0), |(alphanumeric, underscore), c| {
+            if c.is_alphanumeric() {
+                ControlFlow::Continue((alphanumeric + 1, underscore))
+            } else if c == '_' {
+                ControlFlow::Continue((alphanumeric, underscore + 1))
+            } else {
+                ControlFlow::Break(ValidationError::new(
+                    r#"must only contain alphanumeric characters and _"#,
+                ))
+            }
+        }) {
+        ControlFlow::Continue((a, u)) => {
+            if a > u {
+                Ok(())
+            } else {
+                // So we don't end up with usernames like "_a_" or "______"
+                Err(ValidationError::new(
+                    r#"must contain more alphanumeric characters than underscores"#,
+                ))
+            }
+        }
+        ControlFlow::Break(e) => Err(e),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum Theme {
+    System = 0,
+    Dark = 1,
+    Light = 2,
+}
+
+impl TryFrom<i64> for Theme {
+    type Error = &'static str;
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::System),
+            1 => Ok(Self::Dark),
+            2 => Ok(Self::Light),
+            _ => Err("Invalid theme value"),
+        }
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl<'r> sqlx::Decode<'r, sqlx::Sqlite> for Theme {
+    fn decode(
+        value: <sqlx::Sqlite as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value: i64 = <i64 as sqlx::Decode<sqlx::Sqlite>>::decode(value)?;
+        Ok(value.try_into()?)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum FileSortOrder {
+    Name = 0,
+    Modified = 1,
+    Created = 2,
+    Size = 3,
+    Uploader = 4,
+    Owner = 5,
+    Extension = 6,
+}
+
+impl TryFrom<i64> for FileSortOrder {
+    type Error = &'static str;
+
+    fn try_from(value: i64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Name),
+            1 => Ok(Self::Modified),
+            2 => Ok(Self::Created),
+            3 => Ok(Self::Size),
+            4 => Ok(Self::Uploader),
+            5 => Ok(Self::Owner),
+            6 => Ok(Self::Extension),
+            _ => Err("Invalid file sort order value"),
+        }
+    }
+}
+
+#[cfg(feature = "sqlx")]
+impl<'r> sqlx::Decode<'r, sqlx::Sqlite> for FileSortOrder {
+    fn decode(
+        value: <sqlx::Sqlite as sqlx::Database>::ValueRef<'r>,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        let value: i64 = <i64 as sqlx::Decode<sqlx::Sqlite>>::decode(value)?;
+        Ok(value.try_into()?)
+    }
+}
+
+/// A struct representing the currently logged in user
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct SessionUser {
+    pub id: Uuid,
+    /// The name of the user
+    #[cfg_attr(feature = "utoipa", schema(example = "sussyman"))]
+    pub username: String,
+    /// Optional email for the user
+    #[cfg_attr(feature = "utoipa", schema(example = "sussyman@amogus.com"))]
+    pub email: Option<String>,
+    /// The initialization vector for the AES encrypted user's private key
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(content_encoding = "base64", example = "BukSfO6yaQ")
+    )]
+    pub iv: String,
+    /// The user's public key
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            content_encoding = "base64",
+            example = "QQe22k5wy-88PUFIW1P7MkgxoyMyalmjnffAuUNgMuE"
+        )
+    )]
+    pub public_key: String,
+    /// The user's private key encrypted using their password
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            content_encoding = "base64",
+            example = "9WNx5GS9CSaqesguryWS-jiY8Vb0VMMjMtV5JJECk9A"
+        )
+    )]
+    pub encrypted_private_key: String,
+    /// The salt for the PBKDF2 key derivation function
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(content_encoding = "base64", example = "iKJcRJf7fwtO6est")
+    )]
+    pub salt: String,
+    /// The salt for the user's hashed password if applicable
+    pub password_salt: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// The file extension for the user's avatar
+    pub avatar_extension: Option<String>,
+    /// The version of the user's avatar, this changes whenever a new avatar is uploaded
+    pub avatar_version: i64,
+    /// Whether the user has TOTP enabled
+    pub totp_enabled: bool,
+    /// Whether the user has verified their TOTP key
+    pub totp_verified: bool,
+    /// The theme preference of the user
+    pub theme: Theme,
+    /// Default sort order for files
+    pub sort_order: FileSortOrder,
+    /// Whether the user prefers a grid view for files
+    #[cfg_attr(feature = "utoipa", schema(example = true))]
+    pub grid_view: bool,
+    /// Whether users this user has shared files with are told why they can't access a file
+    pub explain_denials: bool,
+    /// The total amount of space available to the user
+    #[cfg_attr(feature = "utoipa", schema(example = 1_000_000_000))]
+    pub total_space: i64,
+    /// The amount of space used by the user
+    #[cfg_attr(feature = "utoipa", schema(example = 0))]
+    pub used_space: i64,
+    /// Bytes uploaded to the user's files this month (UTC)
+    pub uploaded_this_month: i64,
+    /// Bytes downloaded from the user's files this month (UTC), including through share links
+    pub downloaded_this_month: i64,
+    /// How many bytes can be downloaded from the user's files each month, if there is a limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transfer_cap: Option<i64>,
+    /// Bumped whenever the user's keys are rotated. Keys cached with an older
+    /// epoch are stale and should be fetched again.
+    pub key_epoch: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[cfg_attr(feature = "sqlx", derive(sqlx::FromRow))]
+#[serde(rename_all = "camelCase")]
+pub struct PublicUser {
+    /// The id of the user
+    pub id: Uuid,
+    /// The name of the user
+    #[cfg_attr(feature = "utoipa", schema(example = "sussyman"))]
+    pub username: String,
+    /// Optional email for the user
+    #[cfg_attr(feature = "utoipa", schema(example = "sussyman@amogus.com"))]
+    pub email: Option<String>,
+    /// The user's public key
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            content_encoding = "base64",
+            example = "QQe22k5wy-88PUFIW1P7MkgxoyMyalmjnffAuUNgMuE"
+        )
+    )]
+    pub public_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// The file extension for the user's avatar
+    pub avatar_extension: Option<String>,
+    /// The version of the user's avatar, this changes whenever a new avatar is uploaded
+    pub avatar_version: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    /// The password salt used when registering
+    pub password_salt: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[cfg_attr(feature = "validator", derive(validator::Validate))]
+#[serde(rename_all = "camelCase")]
+pub struct CheckUsage {
+    #[cfg_attr(
+        feature = "validator",
+        validate(length(min = 3, max = 20), custom(function = "validate_username"))
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[cfg_attr(feature = "validator", validate(email))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+}
+
+/// Update the currently authenticated user's profile
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct UserUpdate {
+    /// The field to update
+    #[serde(flatten)]
+    pub field: UserUpdateField,
+    /// The new value for the field
+    #[cfg_attr(feature = "utoipa", schema(example = "sussyman2"))]
+    pub new_value: String,
+    /// The user's current password to prevent accidental or
+    /// malicious updates
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
+        )
+    )]
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum UserUpdateField {
+    Username,
+    Email,
+    /// Update the user's password
+    /// Requires a new encrypted private key to be provided since
+    /// the password is used to derive the key for the AES encryption.
+    /// This also requires a new salt and iv for security
+    #[serde(rename_all = "camelCase")]
+    Password {
+        encrypted_private_key: String,
+        salt: String,
+        iv: String,
+    },
+}
+
+/// Request an update to the currently authenticated user's TOTP settings
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase", tag = "type")]
+pub enum TOTPRequest {
+    /// Enable or disable TOTP for the currently authenticated user
+    Enable {
+        enable: bool,
+
+        #[cfg_attr(
+            feature = "utoipa",
+            schema(
+                example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
+            )
+        )]
+        password: String,
+    },
+    /// Regenerate the currently authenticated user's TOTP secret
+    Regenerate {
+        #[cfg_attr(
+            feature = "utoipa",
+            schema(
+                example = "$argon2id$v=19$m=16,t=2,p=1$aUtKY1JKZjdmd3RPNmVzdA$/XFnfdBI9vbMEPNeCqlGbw"
+            )
+        )]
+        password: String,
+    },
+    /// Verify the currently authenticated user's TOTP
+    /// using the provided TOTP code
+    Verify {
+        #[cfg_attr(feature = "utoipa", schema(example = "696969"))]
+        code: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct TOTPResponse {
+    /// The base64 encoded QR code for the TOTP secret.
+    /// Encoded as a PNG image to allow for easy presentation to the user.
+    #[cfg_attr(
+        feature = "utoipa",
+        schema(
+            content_encoding = "base64",
+            example = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAABQAAAAUCAYAAACNiR0N"
+        )
+    )]
+    pub qr_code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+#[cfg_attr(feature = "utoipa", into_params(parameter_in = Query))]
+#[serde_inline_default]
+pub struct UserSearch {
+    #[serde(default)]
+    #[cfg_attr(feature = "utoipa", param(inline))]
+    pub sort: SortOrder,
+    #[serde_inline_default(10)]
+    pub limit: u32,
+    #[serde_inline_default(0)]
+    pub offset: u32,
+}
+
+impl Default for UserSearch {
+    fn default() -> Self {
+        Self {
+            sort: SortOrder::default(),
+            limit: 10,
+            offset: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub enum SortOrder {
+    #[default]
+    BestMatch,
+    Alphabetical,
+    Shortest,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+pub struct AvatarResponse {
+    pub extension: String,
+    /// The new version of the avatar
+    pub version: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::IntoParams))]
+pub struct AvatarParams {
+    /// The version of the avatar, responses are cached indefinitely
+    /// if this matches the current version
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct Preferences {
+    pub theme: Theme,
+    pub grid_view: bool,
+    pub sort_order: FileSortOrder,
+    /// Tell users this user has shared files with why they can't access one of
+    /// this user's files, instead of saying it doesn't exist. Left unchanged if not given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub explain_denials: Option<bool>,
+}
+
+/// Everything needed to decrypt a user's files without the database.
+/// Clients can store this offline so files can still be recovered from their
+/// encrypted data alone if the database is ever lost.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct KeyManifest {
+    pub user_id: Uuid,
+    /// When the manifest was generated
+    pub created_at: DateTime<Utc>,
+    /// The initialization vector for the AES encrypted user's private key
+    pub iv: String,
+    /// The user's private key encrypted using their password
+    pub encrypted_private_key: String,
+    /// The salt for the PBKDF2 key derivation function
+    pub salt: String,
+    /// Every file owned by or shared with the user
+    pub files: Vec<ManifestFile>,
+}
+
+/// The keys and nonces of a single file in a [`KeyManifest`].
+/// Keys of files with a `parentId` are encrypted with the key of their parent,
+/// all other keys are encrypted with the user's public key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "utoipa", derive(utoipa::ToSchema))]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestFile {
+    pub id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<Uuid>,
+    pub is_directory: bool,
+    /// Whether the user owns the file, otherwise it is shared with them
+    pub owned: bool,
+    pub encrypted_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_nonce: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_nonce: Option<String>,
+    pub encrypted_name: String,
+    pub name_nonce: String,
+    /// The format the encrypted fields of the file are in
+    pub metadata_version: i64,
+}